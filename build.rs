@@ -1,17 +1,77 @@
 extern crate bindgen;
 extern crate cbindgen;
+extern crate sha2;
 
+use sha2::{Digest, Sha256};
 use std::env;
+use std::fs;
+
+// Checks the installed token-update circuit against the hash committed in
+// include/tokens.circuit.sha256 (see `make pin-circuit`), and fails the build if the pin is
+// missing or does not match. Both backends are built from the update-state release that
+// generates this circuit, so both are pinned. The AG2PC backend also checks the circuit when
+// it is loaded at runtime.
+fn pin_circuit(project_dir: &str, embed: bool) {
+    let deps_dir = env::var("ZK_DEPS_INSTALL").unwrap_or(format!("{}/deps/root", project_dir));
+    let circuit_file = format!(
+        "{}/include/emp-tool/circuits/files/tokens.circuit.txt",
+        deps_dir
+    );
+    let pinned_file = format!("{}/include/tokens.circuit.sha256", project_dir);
+    println!("cargo:rerun-if-changed={}", circuit_file);
+    println!("cargo:rerun-if-changed={}", pinned_file);
+    println!("cargo:rerun-if-env-changed=ZK_DEPS_INSTALL");
+
+    let pinned = match fs::read_to_string(&pinned_file) {
+        Ok(p) => p.trim().to_lowercase(),
+        Err(e) => panic!(
+            "no pinned circuit hash in {} ({}): review the circuit, then run `make pin-circuit` and commit the result",
+            pinned_file, e
+        ),
+    };
+    if pinned.len() != 64 || !pinned.chars().all(|c| c.is_ascii_hexdigit()) {
+        panic!("{} is not a sha256 hash: '{}'", pinned_file, pinned);
+    }
+
+    let circuit = match fs::read(&circuit_file) {
+        Ok(c) => c,
+        Err(e) => panic!("could not read circuit {}: {}", circuit_file, e),
+    };
+    let hash: String = Sha256::digest(&circuit)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+    if pinned != hash {
+        panic!(
+            "circuit {} has hash {}, expected {} (pinned in {})",
+            circuit_file, hash, pinned, pinned_file
+        );
+    }
+    println!("cargo:rustc-env=ZK_CIRCUIT_SHA256={}", pinned);
+
+    if embed {
+        let out_file = format!("{}/tokens.circuit.txt", env::var("OUT_DIR").unwrap());
+        fs::write(out_file, &circuit).expect("Couldn't embed circuit!");
+    }
+}
 
 fn main() {
     let project_dir = env::var("CARGO_MANIFEST_DIR").unwrap();
 
     println!("cargo:rustc-link-search={}/deps/root/lib", project_dir); // the "-L" flag
+    println!("cargo:rerun-if-env-changed=AG2PC");
+    println!("cargo:rustc-check-cfg=cfg(ag2pc)");
     if env::var("AG2PC").is_ok() {
         println!("cargo:rustc-link-lib=tokenm-utils"); // the "-l" flag
         println!("cargo:rustc-link-lib=test-e2e"); // the "-l" flag
+        println!("cargo:rustc-cfg=ag2pc"); // the linked backend, see mpcwrapper::linked_mpc_security
+        pin_circuit(
+            &project_dir,
+            env::var("CARGO_FEATURE_EMBED_CIRCUIT").is_ok(),
+        );
     } else {
         println!("cargo:rustc-link-lib=token-utils"); // the "-l" flag
+        pin_circuit(&project_dir, false);
     }
 
     // Create bindings
@@ -8,6 +8,7 @@ extern crate zkchannels;
 use pairing::bls12_381::Bls12;
 use std::time::Instant;
 use zkchannels::cl::Signature;
+use zkchannels::database::{HashMapDatabase, StateDatabase};
 use zkchannels::util::encode_short_bytes_to_fr;
 use zkchannels::zkproofs;
 use zkchannels::zkproofs::{ChannelState, CustomerState, MerchantState};
@@ -32,6 +33,7 @@ macro_rules! measure_two_arg {
 }
 
 fn execute_pay_protocol(
+    db: &mut dyn StateDatabase,
     channel_state: &mut ChannelState<Bls12>,
     cust_state: &mut CustomerState<Bls12>,
     merch_state: &mut MerchantState<Bls12>,
@@ -43,6 +45,7 @@ fn execute_pay_protocol(
         zkproofs::pay::customer_prepare(rng, &channel_state, amount, cust_state).unwrap();
 
     assert!(zkproofs::pay::merchant_prepare(
+        db,
         &session_id,
         nonce,
         amount,
@@ -50,17 +53,19 @@ fn execute_pay_protocol(
     ));
 
     let (payment, new_cust_state, pay_time) = measure_two_arg!(
-        zkproofs::pay::customer_update_state(rng, &channel_state, &cust_state, amount)
+        zkproofs::pay::customer_update_state(rng, &channel_state, &cust_state, amount).unwrap()
     );
     println!(">> Time to generate payment proof: {} ms", pay_time);
 
     let (new_close_token, verify_time) = measure_one_arg!(zkproofs::pay::merchant_update_state(
         rng,
+        db,
         &channel_state,
         &session_id,
         &payment,
         merch_state
-    ));
+    )
+    .unwrap());
     println!(">> Time to verify payment proof: {} ms", verify_time);
 
     let rt_pair1 = zkproofs::pay::customer_unmask(
@@ -73,7 +78,7 @@ fn execute_pay_protocol(
 
     // send revoke token and get pay-token in response
     let new_pay_token_result =
-        zkproofs::pay::merchant_validate_rev_lock(&session_id, &rt_pair1, merch_state);
+        zkproofs::pay::merchant_validate_rev_lock(db, &session_id, &rt_pair1, merch_state);
     let new_pay_token = handle_bolt_result!(new_pay_token_result);
 
     // verify the pay token and update internal state
@@ -90,12 +95,14 @@ fn main() {
     let mut channel_state =
         zkproofs::ChannelState::<Bls12>::new(String::from("Direct channel A -> B"), false);
     let rng = &mut rand::thread_rng();
+    let db_url = "redis://127.0.0.1/".to_string();
+    let mut db = HashMapDatabase::new("", db_url.clone()).unwrap();
 
     let b0_customer = 150;
     let b0_merchant = 10;
 
     let (mut channel_token, mut merch_state, mut channel_state) =
-        zkproofs::merchant_init(rng, &mut channel_state, "Merchant Bob");
+        zkproofs::merchant_init(rng, db_url, &mut channel_state, "Merchant Bob");
 
     let mut cust_state =
         zkproofs::customer_init(rng, &mut channel_token, b0_customer, b0_merchant, "Alice");
@@ -116,10 +123,11 @@ fn main() {
 
     // obtain payment token for pay protocol
     let init_state = zkproofs::activate::customer_init(&cust_state).unwrap();
-    let pay_token = zkproofs::activate::merchant_init(rng, &init_state, &mut merch_state);
-    assert!(merch_state
-        .unlink_nonces
-        .contains(&encode_short_bytes_to_fr::<Bls12>(cust_state.nonce.0).to_string()));
+    let pay_token =
+        zkproofs::activate::merchant_init(rng, &mut db, &init_state, &mut merch_state).unwrap();
+    assert!(
+        db.is_member_unlink_set(&encode_short_bytes_to_fr::<Bls12>(cust_state.nonce.0).to_string())
+    );
 
     assert!(zkproofs::activate::customer_finalize(
         &mut channel_state,
@@ -129,9 +137,10 @@ fn main() {
 
     // start unlink phase
     let (session_id, unlink_payment, unlinked_cust_state) =
-        zkproofs::unlink::customer_update_state(rng, &channel_state, &cust_state);
+        zkproofs::unlink::customer_update_state(rng, &channel_state, &cust_state).unwrap();
     let new_close_token_result = zkproofs::unlink::merchant_update_state(
         rng,
+        &mut db,
         &channel_state,
         &session_id,
         &unlink_payment,
@@ -149,7 +158,12 @@ fn main() {
 
     // send revoke token and get pay-token in response
     let new_pay_token_result: BoltResult<Signature<Bls12>> =
-        zkproofs::unlink::merchant_validate_rev_lock(&session_id, &rt_pair, &mut merch_state);
+        zkproofs::unlink::merchant_validate_rev_lock(
+            &mut db,
+            &session_id,
+            &rt_pair,
+            &mut merch_state,
+        );
     let new_pay_token = handle_bolt_result!(new_pay_token_result);
 
     // verify the pay token and update internal state
@@ -167,6 +181,7 @@ fn main() {
     let pay_amount2 = 20;
     println!("Customer makes first payment: {}", pay_amount1);
     execute_pay_protocol(
+        &mut db,
         &mut channel_state,
         &mut cust_state,
         &mut merch_state,
@@ -176,6 +191,7 @@ fn main() {
 
     println!("Customer makes second payment: {}", pay_amount2);
     execute_pay_protocol(
+        &mut db,
         &mut channel_state,
         &mut cust_state,
         &mut merch_state,
@@ -155,7 +155,9 @@ char *ffishim_bls12_tze_check_wpk(char *ser_wpk);
 
 char *ffishim_bls12_channel_setup(const char *channel_name, uint32_t third_party_support);
 
-char *ffishim_bls12_init_merchant_init(char *ser_channel_state, const char *name_ptr);
+char *ffishim_bls12_init_merchant_init(char *db_url_str,
+                                       char *ser_channel_state,
+                                       const char *name_ptr);
 
 char *ffishim_bls12_init_customer_init(char *ser_channel_token,
                                        int64_t balance_customer,
@@ -215,6 +217,13 @@ char *ffishim_bls12_pay_customer_update_state(char *ser_channel_state,
                                               char *ser_customer_state,
                                               int64_t amount);
 
+char *ffishim_bls12_pay_generate_amount_tau(void);
+
+char *ffishim_bls12_pay_customer_update_state_hidden(char *ser_channel_state,
+                                                     char *ser_customer_state,
+                                                     int64_t amount,
+                                                     char *ser_amount_tau);
+
 char *ffishim_bls12_pay_merchant_update_state(char *ser_channel_state,
                                               char *ser_session_id,
                                               char *ser_pay_proof,
@@ -252,6 +261,26 @@ char *ffishim_bls12_merchant_close(char *ser_channel_state,
                                    char *ser_cust_close,
                                    char *ser_merch_state);
 
+char *ffishim_bls12_customer_mutual_close(char *ser_channel_state,
+                                          char *ser_channel_token,
+                                          char *ser_cust_state);
+
+char *ffishim_bls12_merchant_mutual_close(char *ser_channel_token,
+                                          char *ser_mutual_close,
+                                          char *ser_merch_state);
+
+char *ffishim_bls12_verify_mutual_close(char *ser_channel_token, char *ser_mutual_close);
+
+char *ffishim_bls12_customer_force_close_on_stall(char *ser_channel_state, char *ser_cust_state);
+
+char *ffishim_bls12_verify_merchant_stall_evidence(char *ser_channel_token, char *ser_evidence);
+
+char *ffishim_bls12_tze_verify_cust_close_message(char *ser_channel_token, char *ser_cust_close);
+
+char *ffishim_bls12_tze_verify_revoke_message(char *ser_cust_close, char *ser_rev_lock_pair);
+
+char *ffishim_bls12_tze_verify_merch_close_message(char *ser_channel_token, char *ser_merch_close);
+
 void mpc_free_string(char *pointer);
 
 char *get_self_delay_be_hex(char *ser_channel_state);
@@ -261,7 +290,8 @@ char *mpc_channel_setup(const char *channel_name,
                         int64_t bal_min_cust,
                         int64_t bal_min_merch,
                         int64_t val_cpfp,
-                        uint32_t third_party_support);
+                        uint32_t third_party_support,
+                        char *ser_network);
 
 char *mpc_init_merchant(char *db_url_str, char *ser_channel_state, const char *name_ptr);
 
@@ -276,7 +306,8 @@ char *mpc_init_customer(char *ser_merch_pk,
                         int64_t cust_bal,
                         int64_t merch_bal,
                         char *ser_tx_fee_info,
-                        const char *name_ptr);
+                        const char *name_ptr,
+                        char *ser_network);
 
 char *mpc_load_customer_wallet(char *ser_cust_state,
                                char *ser_channel_token,
@@ -300,6 +331,11 @@ char *mpc_activate_customer_finalize(char *ser_pay_token, char *ser_cust_state);
 
 char *mpc_prepare_payment_customer(char *ser_channel_state, int64_t amount, char *ser_cust_state);
 
+char *mpc_merchant_issue_refund_voucher(char *ser_nonce,
+                                        int64_t amount,
+                                        int64_t expiry,
+                                        char *ser_merch_state);
+
 char *mpc_prepare_payment_merchant(char *ser_channel_state,
                                    char *ser_session_id,
                                    char *ser_nonce,
@@ -389,7 +425,8 @@ char *form_merch_close_transaction(char *ser_escrow_txid,
                                    int64_t merch_bal_sats,
                                    int64_t fee_mc,
                                    int64_t val_cpfp,
-                                   char *ser_self_delay);
+                                   char *ser_self_delay,
+                                   char *ser_network);
 
 char *customer_sign_merch_close_tx(char *ser_cust_sk, char *ser_merch_tx_preimage);
 
@@ -493,6 +530,44 @@ char *merch_sign_mutual_close_tx(char *ser_tx_index,
                                  char *ser_cust_sig,
                                  char *ser_merch_sk);
 
+char *taproot_mutual_close_nonce(char *ser_tx_index,
+                                 uint32_t index,
+                                 int64_t input_amount,
+                                 int64_t cust_amount,
+                                 int64_t merch_amount,
+                                 char *ser_merch_close_pk,
+                                 char *ser_cust_close_pk,
+                                 char *ser_merch_pk,
+                                 char *ser_cust_pk,
+                                 char *ser_sk);
+
+char *taproot_mutual_close_partial_sign(char *ser_tx_index,
+                                        uint32_t index,
+                                        int64_t input_amount,
+                                        int64_t cust_amount,
+                                        int64_t merch_amount,
+                                        char *ser_merch_close_pk,
+                                        char *ser_cust_close_pk,
+                                        char *ser_merch_pk,
+                                        char *ser_cust_pk,
+                                        char *ser_sk,
+                                        char *ser_cust_pub_nonce,
+                                        char *ser_merch_pub_nonce);
+
+char *taproot_mutual_close_finalize(char *ser_tx_index,
+                                    uint32_t index,
+                                    int64_t input_amount,
+                                    int64_t cust_amount,
+                                    int64_t merch_amount,
+                                    char *ser_merch_close_pk,
+                                    char *ser_cust_close_pk,
+                                    char *ser_merch_pk,
+                                    char *ser_cust_pk,
+                                    char *ser_cust_pub_nonce,
+                                    char *ser_merch_pub_nonce,
+                                    char *ser_cust_partial_sig,
+                                    char *ser_merch_partial_sig);
+
 char *create_child_tx_to_bump_fee_via_p2wpkh_input(char *ser_tx_index1,
                                                    uint32_t index1,
                                                    int64_t input_amount1,
@@ -43,6 +43,9 @@ type setupResp struct {
 	InitCustState   string `json:"init_state"`
 	InitHash        string `json:"init_hash"`
 	SelfDelayBE     string `json:"self_delay_be"`
+	RefundVoucher   string `json:"refund_voucher"`
+	PubNonce        string `json:"pub_nonce"`
+	PartialSig      string `json:"partial_sig"`
 	Error           string `json:"error"`
 }
 
@@ -51,6 +54,10 @@ type ChannelState struct {
 	BalMinMerch    int64   `json:"bal_min_merch"`
 	ValCpfp        int64   `json:"val_cpfp"`
 	KeyCom         string  `json:"key_com"`
+	AnchorOutputs  bool    `json:"anchor_outputs"`
+	Network        string  `json:"network"`
+	MpcSecurity    string  `json:"mpc_security"`
+	CircuitHash    *string `json:"circuit_hash"`
 	Name           string  `json:"name"`
 	ThirdParty     bool    `json:"third_party"`
 	MerchPayOutPk  *string `json:"merch_payout_pk"`
@@ -77,6 +84,9 @@ type MerchState struct {
 	NetConfig        *map[string]interface{} `json:"net_config"`
 	DbUrl            string                  `json:"db_url"`
 	RefundPolicy     string                  `json:"refund_policy"`
+	SessionPolicy    *map[string]interface{} `json:"session_policy"`
+	AnchorOutputs    bool                    `json:"anchor_outputs"`
+	Network          string                  `json:"network"`
 }
 
 type CustState struct {
@@ -124,6 +134,7 @@ type ChannelToken struct {
 	PkM        string `json:"pk_m"`
 	EscrowTxId string `json:"escrow_txid"`
 	MerchTxId  string `json:"merch_txid"`
+	Network    string `json:"network"`
 }
 
 type MaskedTxInputs struct {
@@ -193,8 +204,8 @@ func GetSelfDelayBE(channelState ChannelState) (string, error) {
 	return r.SelfDelayBE, err
 }
 
-func ChannelSetup(name string, selfDelay int16, balMinCust int64, balMinMerch int64, valCpfp int64, channelThirdPartySupport bool) (ChannelState, error) {
-	resp := C.GoString(C.mpc_channel_setup(C.CString(name), C.uint16_t(selfDelay), C.int64_t(balMinCust), C.int64_t(balMinMerch), C.int64_t(valCpfp), C.uint(btoi(channelThirdPartySupport))))
+func ChannelSetup(name string, selfDelay int16, balMinCust int64, balMinMerch int64, valCpfp int64, channelThirdPartySupport bool, network string) (ChannelState, error) {
+	resp := C.GoString(C.mpc_channel_setup(C.CString(name), C.uint16_t(selfDelay), C.int64_t(balMinCust), C.int64_t(balMinMerch), C.int64_t(valCpfp), C.uint(btoi(channelThirdPartySupport)), C.CString(network)))
 	r, err := processCResponse(resp)
 	if err != nil {
 		return ChannelState{}, err
@@ -251,14 +262,14 @@ func LoadMerchantWallet(merchState MerchState, channelState ChannelState, skC st
 	return channelState, merchState, err
 }
 
-func InitCustomer(pkM string, custBal int64, merchBal int64, txFeeInfo TransactionFeeInfo, name string) (ChannelToken, CustState, error) {
+func InitCustomer(pkM string, custBal int64, merchBal int64, txFeeInfo TransactionFeeInfo, name string, network string) (ChannelToken, CustState, error) {
 	serTxFeeInfo, err := json.Marshal(txFeeInfo)
 	if err != nil {
 		return ChannelToken{}, CustState{}, err
 	}
 
 	resp := C.GoString(C.mpc_init_customer(C.CString(pkM), C.int64_t(custBal),
-		C.int64_t(merchBal), C.CString(string(serTxFeeInfo)), C.CString(name)))
+		C.int64_t(merchBal), C.CString(string(serTxFeeInfo)), C.CString(name), C.CString(network)))
 	r, err := processCResponse(resp)
 	if err != nil {
 		return ChannelToken{}, CustState{}, err
@@ -336,9 +347,9 @@ func SignEscrowTx(txid string, index uint32, custInputSk string, inputAmt int64,
 	return r.SignedTx, r.TxIdBe, r.TxIdLe, r.HashPrevOut, err
 }
 
-func FormMerchCloseTx(escrowTxId_LE string, custPk string, merchPk string, merchClosePk string, merchChildPk string, custBal int64, merchBal int64, feeMC int64, valCpfp int64, toSelfDelay string) (string, error) {
+func FormMerchCloseTx(escrowTxId_LE string, custPk string, merchPk string, merchClosePk string, merchChildPk string, custBal int64, merchBal int64, feeMC int64, valCpfp int64, toSelfDelay string, network string) (string, error) {
 	resp := C.GoString(C.form_merch_close_transaction(C.CString(escrowTxId_LE), C.CString(custPk), C.CString(merchPk),
-		C.CString(merchClosePk), C.CString(merchChildPk), C.int64_t(custBal), C.int64_t(merchBal), C.int64_t(feeMC), C.int64_t(valCpfp), C.CString(toSelfDelay)))
+		C.CString(merchClosePk), C.CString(merchChildPk), C.int64_t(custBal), C.int64_t(merchBal), C.int64_t(feeMC), C.int64_t(valCpfp), C.CString(toSelfDelay), C.CString(network)))
 	r, err := processCResponse(resp)
 	if err != nil {
 		return "", err
@@ -636,6 +647,21 @@ func PreparePaymentCustomer(channelState ChannelState, amount int64, custState C
 	return revState, state, r.RevLockCom, r.SessionId, newCustState, err
 }
 
+func MerchantIssueRefundVoucher(nonce string, amount int64, expiry int64, merchState MerchState) (string, error) {
+	serMerchState, err := json.Marshal(merchState)
+	if err != nil {
+		return "", err
+	}
+
+	resp := C.GoString(C.mpc_merchant_issue_refund_voucher(C.CString(nonce), C.int64_t(amount), C.int64_t(expiry), C.CString(string(serMerchState))))
+	r, err := processCResponse(resp)
+	if err != nil {
+		return "", err
+	}
+
+	return r.RefundVoucher, nil
+}
+
 func PreparePaymentMerchant(channelState ChannelState, sessionId string, nonce string, revLockCom string, amount int64, justification string, merchState MerchState) (string, MerchState, error) {
 	serChannelState, err := json.Marshal(channelState)
 	if err != nil {
@@ -1028,6 +1054,44 @@ func MerchantSignMutualCloseTx(txid_LE string, index uint32, inputAmount int64,
 	return r.SignedTx, r.TxIdLe, err
 }
 
+func TaprootMutualCloseNonce(txid_LE string, index uint32, inputAmount int64, custAmount int64, merchAmount int64, merchClosePk string, custClosePk string, merchPk string, custPk string, sk string) (string, error) {
+	// round 1 of signing the taproot mutual close tx, the secret nonce stays in the library
+	resp := C.GoString(C.taproot_mutual_close_nonce(C.CString(txid_LE), C.uint(index), C.int64_t(inputAmount), C.int64_t(custAmount), C.int64_t(merchAmount), C.CString(merchClosePk),
+		C.CString(custClosePk), C.CString(merchPk), C.CString(custPk), C.CString(sk)))
+	r, err := processCResponse(resp)
+	if err != nil {
+		return "", err
+	}
+
+	return r.PubNonce, err
+}
+
+func TaprootMutualClosePartialSign(txid_LE string, index uint32, inputAmount int64, custAmount int64, merchAmount int64, merchClosePk string, custClosePk string, merchPk string, custPk string, sk string,
+	custPubNonce string, merchPubNonce string) (string, error) {
+	// round 2 of signing the taproot mutual close tx
+	resp := C.GoString(C.taproot_mutual_close_partial_sign(C.CString(txid_LE), C.uint(index), C.int64_t(inputAmount), C.int64_t(custAmount), C.int64_t(merchAmount), C.CString(merchClosePk),
+		C.CString(custClosePk), C.CString(merchPk), C.CString(custPk), C.CString(sk), C.CString(custPubNonce), C.CString(merchPubNonce)))
+	r, err := processCResponse(resp)
+	if err != nil {
+		return "", err
+	}
+
+	return r.PartialSig, err
+}
+
+func TaprootMutualCloseFinalize(txid_LE string, index uint32, inputAmount int64, custAmount int64, merchAmount int64, merchClosePk string, custClosePk string, merchPk string, custPk string,
+	custPubNonce string, merchPubNonce string, custPartialSig string, merchPartialSig string) (string, string, error) {
+	// aggregate both partial signatures into the signed taproot mutual close tx
+	resp := C.GoString(C.taproot_mutual_close_finalize(C.CString(txid_LE), C.uint(index), C.int64_t(inputAmount), C.int64_t(custAmount), C.int64_t(merchAmount), C.CString(merchClosePk),
+		C.CString(custClosePk), C.CString(merchPk), C.CString(custPk), C.CString(custPubNonce), C.CString(merchPubNonce), C.CString(custPartialSig), C.CString(merchPartialSig)))
+	r, err := processCResponse(resp)
+	if err != nil {
+		return "", "", err
+	}
+
+	return r.SignedTx, r.TxIdLe, err
+}
+
 func CreateChildTxToBumpFeeViaP2WPKH(txid1_LE string, index1 uint32, inputAmount1 int64, sk1 string,
 	txid2_LE string, index2 uint32, inputAmount2 int64, sk2 string,
 	txFee int64, outputPk string) (string, string, error) {
@@ -69,7 +69,7 @@ func Test_fullProtocolWithValidUTXO(t *testing.T) {
 	feeCC := txFeeInfo.FeeCC
 	feeMC := txFeeInfo.FeeMC
 
-	channelState, err := ChannelSetup("channel", selfDelay, txFeeInfo.BalMinCust, txFeeInfo.BalMinMerch, txFeeInfo.ValCpFp, false)
+	channelState, err := ChannelSetup("channel", selfDelay, txFeeInfo.BalMinCust, txFeeInfo.BalMinMerch, txFeeInfo.ValCpFp, false, "regtest")
 	assert.Nil(t, err)
 
 	channelState, merchState, err := InitMerchant(dbUrl, channelState, "merch")
@@ -87,7 +87,7 @@ func Test_fullProtocolWithValidUTXO(t *testing.T) {
 
 	merchPKM := fmt.Sprintf("%v", *merchState.PkM)
 
-	channelToken, custState, err := InitCustomer(merchPKM, custBal, merchBal, txFeeInfo, "cust")
+	channelToken, custState, err := InitCustomer(merchPKM, custBal, merchBal, txFeeInfo, "cust", channelState.Network)
 	assert.Nil(t, err)
 
 	fix_customer_wallet := os.Getenv("FIX_CUSTOMER_WALLET")
@@ -220,7 +220,7 @@ func Test_fullProtocolWithValidUTXO(t *testing.T) {
 	fmt.Println("TX1: signedEscrowTx => ", signedEscrowTx)
 	fmt.Println("========================================")
 
-	merchTxPreimage, err := FormMerchCloseTx(escrowTxid_LE, custPk, merchPk, merchClosePk, merchChildPk, custBal, merchBal, feeMC, txFeeInfo.ValCpFp, toSelfDelay)
+	merchTxPreimage, err := FormMerchCloseTx(escrowTxid_LE, custPk, merchPk, merchClosePk, merchChildPk, custBal, merchBal, feeMC, txFeeInfo.ValCpFp, toSelfDelay, channelState.Network)
 	assert.Nil(t, err)
 
 	fmt.Println("merch TxPreimage => ", merchTxPreimage)
@@ -614,3 +614,44 @@ func TestPayUpdateCustomer(t *testing.T) {
 	t.Log("\n*-*", success, "*-*\n")
 	assert.Nil(t, err)
 }
+
+func TestTaprootMutualClose(t *testing.T) {
+	custSk := "0000000000000000000000000000000000000000000000000000000000000033"
+	merchSk := "0000000000000000000000000000000000000000000000000000000000000044"
+	custPk := "02463b3d9f662621fb1b4be8fbbe2520125a216cdfc9dae3debcba4850c690d45b"
+	merchPk := "0270e6b44a2ac6083ab673bacb5cb7ca554b795b416e702c1c980bb7b87c78b8e9"
+	custClosePk := "03defdea4cdb677750a420fee807eacf21eb9898ae79b9768766e4faa04a2d4a34"
+	merchClosePk := "037962414450c76c1689c7b48f8202ec37fb224cf5ac0bfa1570328a8a3d7c77ab"
+	escrowTxid_LE := "4242424242424242424242424242424242424242424242424242424242424242"
+
+	var index uint32 = 0
+	escrowedAmount := int64(1000000)
+	custAmount := int64(700000)
+	merchAmount := int64(299000)
+	custPubNonce, err := TaprootMutualCloseNonce(escrowTxid_LE, index, escrowedAmount, custAmount, merchAmount, merchClosePk, custClosePk, merchPk, custPk, custSk)
+	assert.Nil(t, err)
+	merchPubNonce, err := TaprootMutualCloseNonce(escrowTxid_LE, index, escrowedAmount, custAmount, merchAmount, merchClosePk, custClosePk, merchPk, custPk, merchSk)
+	assert.Nil(t, err)
+
+	custPartialSig, err := TaprootMutualClosePartialSign(escrowTxid_LE, index, escrowedAmount, custAmount, merchAmount, merchClosePk, custClosePk, merchPk, custPk, custSk,
+		custPubNonce, merchPubNonce)
+	assert.Nil(t, err)
+	merchPartialSig, err := TaprootMutualClosePartialSign(escrowTxid_LE, index, escrowedAmount, custAmount, merchAmount, merchClosePk, custClosePk, merchPk, custPk, merchSk,
+		custPubNonce, merchPubNonce)
+	assert.Nil(t, err)
+
+	// each nonce signs once
+	_, err = TaprootMutualClosePartialSign(escrowTxid_LE, index, escrowedAmount, custAmount, merchAmount, merchClosePk, custClosePk, merchPk, custPk, custSk,
+		custPubNonce, merchPubNonce)
+	assert.NotNil(t, err)
+
+	_, _, err = TaprootMutualCloseFinalize(escrowTxid_LE, index, escrowedAmount, custAmount, merchAmount, merchClosePk, custClosePk, merchPk, custPk,
+		custPubNonce, merchPubNonce, merchPartialSig, merchPartialSig)
+	assert.NotNil(t, err)
+
+	signedCloseTx, closeTxid, err := TaprootMutualCloseFinalize(escrowTxid_LE, index, escrowedAmount, custAmount, merchAmount, merchClosePk, custClosePk, merchPk, custPk,
+		custPubNonce, merchPubNonce, custPartialSig, merchPartialSig)
+	assert.Nil(t, err)
+	fmt.Println("Signed taproot mutual close tx: ", signedCloseTx)
+	fmt.Println("txId: ", closeTxid)
+}
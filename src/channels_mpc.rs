@@ -1,17 +1,20 @@
 use super::*;
-use util::{compute_hash160, hmac_sign, sha2_hash_to_slice};
+use util::{compute_hash160, get_unix_time, hmac_sign, sha2_hash_to_slice};
 
-use bindings::{cb_receive, cb_send, load_circuit_file, ConnType};
+use bindings::{cb_receive, cb_send, ConnType};
 use channels_util::{
-    ChannelStatus, FundingTxInfo, NegativePaymentPolicy, PaymentStatus, ProtocolStatus,
+    BitcoinNetworkType, ChannelStatus, FundingTxInfo, MpcSecurity, NegativePaymentPolicy,
+    PaymentStatus, ProtocolStatus, SessionPolicy,
 };
 use database::{MaskedMPCInputs, MaskedTxMPCInputs, SessionState, StateDatabase};
-use mpcwrapper::{mpc_build_masked_tokens_cust, mpc_build_masked_tokens_merch, CIRCUIT_FILE};
+use mpcwrapper::{
+    check_mpc_backend, circuit_hash, get_circuit, linked_mpc_security,
+    mpc_build_masked_tokens_cust, mpc_build_masked_tokens_merch, selected_mpc_security,
+};
 use rand::Rng;
 use sha2::{Digest, Sha256};
-use std::ffi::{c_void, CString};
+use std::ffi::c_void;
 use std::fmt::Debug;
-use std::{env, ptr};
 use wallet::{State, NONCE_LEN};
 use zkchan_tx::fixed_size_array::{FixedSizeArray16, FixedSizeArray32, FixedSizeArray64};
 use zkchan_tx::transactions::btc::{
@@ -20,7 +23,7 @@ use zkchan_tx::transactions::btc::{
     get_private_key, merchant_form_close_transaction,
 };
 use zkchan_tx::transactions::ClosePublicKeys;
-use zkchan_tx::{BitcoinNetwork, BitcoinTransactionParameters, Transaction};
+use zkchan_tx::{BitcoinNetwork, BitcoinTransaction, BitcoinTransactionParameters, Transaction};
 
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct NetworkConfig {
@@ -28,6 +31,9 @@ pub struct NetworkConfig {
     pub path: String,
     pub dest_ip: String,
     pub dest_port: i32,
+    /// SOCKS5 proxy (e.g. a local Tor daemon) used to reach dest_ip when conn_type is TORNETIO
+    #[serde(default)]
+    pub proxy: Option<String>,
 }
 
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
@@ -38,6 +44,8 @@ pub struct ChannelMPCToken {
     // pk_m
     pub escrow_txid: FixedSizeArray32,
     pub merch_txid: FixedSizeArray32,
+    #[serde(default)]
+    pub network: BitcoinNetworkType,
 }
 
 impl ChannelMPCToken {
@@ -49,14 +57,36 @@ impl ChannelMPCToken {
         return !self.pk_c.is_none();
     }
 
-    pub fn compute_channel_id(&self) -> Result<[u8; 32], String> {
-        if self.pk_c.is_none() {
-            return Err(String::from("pk_c is not initialized yet"));
-        }
+    ///
+    /// Canonical encoding of the channel token:
+    /// pk_c (33 bytes) || pk_m (33 bytes) || escrow_txid || merch_txid || network (1 byte)
+    ///
+    pub fn serialize_compact(&self) -> Result<Vec<u8>, String> {
+        let pk_c = match self.pk_c {
+            Some(pk) => pk,
+            None => return Err(String::from("pk_c is not initialized yet")),
+        };
+        let network: u8 = match self.network {
+            BitcoinNetworkType::Mainnet => 0,
+            BitcoinNetworkType::Testnet => 1,
+            BitcoinNetworkType::Regtest => 2,
+        };
 
-        // check txids are set
-        let input = serde_json::to_vec(&self).unwrap();
+        let mut output_buf = Vec::new();
+        output_buf.extend_from_slice(&pk_c.serialize());
+        output_buf.extend_from_slice(&self.pk_m.serialize());
+        output_buf.extend_from_slice(&self.escrow_txid.0);
+        output_buf.extend_from_slice(&self.merch_txid.0);
+        output_buf.push(network);
+        Ok(output_buf)
+    }
 
+    ///
+    /// compute_channel_id - sha256 of the canonical encoding (not of the JSON token, as in
+    /// earlier releases, so older channels get a new id)
+    ///
+    pub fn compute_channel_id(&self) -> Result<[u8; 32], String> {
+        let input = self.serialize_compact()?;
         return Ok(sha2_hash_to_slice(&input));
     }
 }
@@ -73,8 +103,8 @@ impl fmt::Display for ChannelMPCToken {
 
         write!(
             f,
-            "ChannelMPCToken : (\npkc={}\npkm={}\nescrow_txid={:?}\nmerch_txid={:?}\n)",
-            pkc_hex, pkm_hex, escrow_txid_hex, merch_txid_hex
+            "ChannelMPCToken : (\npkc={}\npkm={}\nescrow_txid={:?}\nmerch_txid={:?}\nnetwork={}\n)",
+            pkc_hex, pkm_hex, escrow_txid_hex, merch_txid_hex, self.network
         )
     }
 }
@@ -100,12 +130,26 @@ impl fmt::Display for TransactionFeeInfo {
     }
 }
 
+// max fee (in sats) carried by close txs when anchor outputs are enabled;
+// the rest of the fee is supplied at broadcast time via CPFP on the anchors
+pub static ANCHOR_MAX_CLOSE_FEE: i64 = 330;
+
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct ChannelMPCState {
     bal_min_cust: i64,
     bal_min_merch: i64,
     val_cpfp: i64,
     key_com: FixedSizeArray32,
+    // fields added after the initial release default to the behavior of the
+    // channels created before them, so that stored channel states still load
+    #[serde(default)]
+    anchor_outputs: bool,
+    #[serde(default)]
+    network: BitcoinNetworkType,
+    #[serde(default = "linked_mpc_security")]
+    mpc_security: MpcSecurity,
+    #[serde(default)]
+    circuit_hash: Option<FixedSizeArray32>,
     pub name: String,
     pub third_party: bool,
     pub merch_payout_pk: Option<secp256k1::PublicKey>,
@@ -128,6 +172,10 @@ impl ChannelMPCState {
             bal_min_merch, // dust limit (546)
             val_cpfp,
             key_com: FixedSizeArray32([0u8; 32]),
+            anchor_outputs: false,
+            network: BitcoinNetworkType::Testnet,
+            mpc_security: selected_mpc_security(),
+            circuit_hash: Some(FixedSizeArray32(circuit_hash())),
             name: name.to_string(),
             third_party: third_party_support,
             merch_payout_pk: None,
@@ -157,6 +205,90 @@ impl ChannelMPCState {
         self.key_com = FixedSizeArray32(key_com);
     }
 
+    // anchor mode: the close txs only carry a near-zero fee and each party bumps
+    // the fee at broadcast time via its anchor output (see AnchorOutput).
+    // must be set before the merchant state is initialized
+    pub fn set_anchor_outputs(&mut self, enabled: bool) -> Result<(), String> {
+        if enabled && self.val_cpfp < self.bal_min_cust.max(self.bal_min_merch) {
+            return Err(format!(
+                "val_cpfp must be above the dust limit to use anchor outputs: {}",
+                self.val_cpfp
+            ));
+        }
+        self.anchor_outputs = enabled;
+        Ok(())
+    }
+
+    pub fn has_anchor_outputs(&self) -> bool {
+        return self.anchor_outputs;
+    }
+
+    pub fn validate_close_fees(&self, fee_cc: i64, fee_mc: i64) -> Result<(), String> {
+        validate_anchor_close_fees(self.anchor_outputs, fee_cc, fee_mc)
+    }
+
+    // must be set before the merchant state is initialized
+    pub fn set_network(&mut self, network: BitcoinNetworkType) {
+        self.network = network;
+    }
+
+    pub fn get_network(&self) -> BitcoinNetworkType {
+        return self.network;
+    }
+
+    // security model of the payment MPC, defaults to the backend selected at runtime (AG2PC env var).
+    // must be set before the merchant state is initialized
+    pub fn set_mpc_security(&mut self, mpc_security: MpcSecurity) -> Result<(), String> {
+        check_mpc_backend(mpc_security)?;
+        self.mpc_security = mpc_security;
+        self.circuit_hash = Some(FixedSizeArray32(circuit_hash()));
+        Ok(())
+    }
+
+    pub fn get_mpc_security(&self) -> MpcSecurity {
+        return self.mpc_security;
+    }
+
+    // both parties must run the channel's security model, whatever their defaults are
+    pub fn validate_mpc_security(&self) -> Result<(), String> {
+        match check_mpc_backend(self.mpc_security) {
+            Ok(_) => Ok(()),
+            Err(e) => Err(format!("channel requires {} MPC: {}", self.mpc_security, e)),
+        }
+    }
+
+    // hash of the circuit the merchant runs the payment MPC with (none for channels opened
+    // before both backends were pinned)
+    pub fn get_circuit_hash(&self) -> Option<[u8; 32]> {
+        self.circuit_hash.as_ref().map(|h| h.0)
+    }
+
+    pub fn validate_circuit_hash(&self) -> Result<(), String> {
+        let local = Some(circuit_hash());
+        if self.get_circuit_hash() != local {
+            let to_hex = |h: Option<[u8; 32]>| match h {
+                Some(h) => hex::encode(h),
+                None => String::from("none"),
+            };
+            return Err(format!(
+                "channel uses circuit {} but this build uses circuit {}",
+                to_hex(self.get_circuit_hash()),
+                to_hex(local)
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn validate_network(&self, channel_token: &ChannelMPCToken) -> Result<(), String> {
+        if channel_token.network != self.network {
+            return Err(format!(
+                "channel token network ({}) does not match channel state network ({})",
+                channel_token.network, self.network
+            ));
+        }
+        Ok(())
+    }
+
     pub fn set_merchant_public_keys(
         &mut self,
         merch_payout_pk: secp256k1::PublicKey,
@@ -177,6 +309,64 @@ impl ChannelMPCState {
     }
 }
 
+fn validate_anchor_close_fees(
+    anchor_outputs: bool,
+    fee_cc: i64,
+    fee_mc: i64,
+) -> Result<(), String> {
+    if !anchor_outputs {
+        return Ok(());
+    }
+    if fee_cc > ANCHOR_MAX_CLOSE_FEE || fee_mc > ANCHOR_MAX_CLOSE_FEE {
+        return Err(format!(
+            "close tx fees (fee_cc={}, fee_mc={}) exceed the max allowed with anchor outputs: {}",
+            fee_cc, fee_mc, ANCHOR_MAX_CLOSE_FEE
+        ));
+    }
+    Ok(())
+}
+
+// output of a close tx that one party can spend on its own right away,
+// so the party can attach a child tx to bump the fee of the close tx (CPFP).
+// with anchor outputs enabled, each party has one on the close txs they broadcast:
+//  - cust-close: the customer's cpfp output (cust payout pk) and the merchant's
+//    to_merchant output (merch payout pk)
+//  - merch-close: the merchant's cpfp output (merch child pk); the customer
+//    responds with cust-close-from-merch-close and bumps that via its own anchor
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct AnchorOutput {
+    pub txid_le: FixedSizeArray32,
+    pub index: u32,
+    pub value: i64,
+}
+
+// find the P2WPKH output of the signed tx that pays to pk
+pub fn find_anchor_output<N: BitcoinNetwork>(
+    signed_tx: &Vec<u8>,
+    txid_le: [u8; 32],
+    pk: &secp256k1::PublicKey,
+) -> Result<AnchorOutput, String> {
+    let mut script_pubkey = vec![0x00, 0x14];
+    script_pubkey.extend_from_slice(&compute_hash160(&pk.serialize().to_vec()));
+    let tx = match BitcoinTransaction::<N>::from_transaction_bytes(signed_tx) {
+        Ok(tx) => tx,
+        Err(e) => return Err(format!("could not decode the close tx: {}", e)),
+    };
+    for (index, output) in tx.parameters.outputs.iter().enumerate() {
+        if output.script_pub_key == script_pubkey {
+            return Ok(AnchorOutput {
+                txid_le: FixedSizeArray32(txid_le),
+                index: index as u32,
+                value: output.amount.0,
+            });
+        }
+    }
+    Err(format!(
+        "no anchor output for {} in the close tx",
+        hex::encode(pk.serialize().to_vec())
+    ))
+}
+
 #[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct MaskedMPCOutputs {
     pt_masked: FixedSizeArray32,
@@ -353,6 +543,7 @@ impl CustomerMPCState {
         min_fee: i64,
         max_fee: i64,
         fee_mc: i64,
+        network: BitcoinNetworkType,
     ) -> ChannelMPCToken {
         assert!(self.state.is_none());
 
@@ -379,6 +570,7 @@ impl CustomerMPCState {
             pk_m: pk_m.clone(),
             escrow_txid: FixedSizeArray32([0u8; 32]),
             merch_txid: FixedSizeArray32([0u8; 32]),
+            network,
         };
     }
 
@@ -507,31 +699,8 @@ impl CustomerMPCState {
         self.net_config = Some(net_config);
     }
 
-    pub fn get_circuit_file(&self) -> *mut c_void {
-        let using_ag2pc = match env::var("AG2PC") {
-            Ok(_s) => true,
-            Err(_e) => false,
-        };
-
-        let circuit_file = match using_ag2pc {
-            true => match env::var("ZK_DEPS_INSTALL") {
-                Ok(s) => format!("{}{}", s, CIRCUIT_FILE),
-                Err(e) => panic!("ZK_DEPS_INSTALL env not set: {}", e),
-            },
-            false => String::new(),
-        };
-
-        let cf_ptr = match using_ag2pc {
-            true => {
-                let cf_ptr = unsafe {
-                    let c_str = CString::new(circuit_file).unwrap();
-                    load_circuit_file(c_str.as_ptr() as *const i8)
-                };
-                cf_ptr
-            }
-            false => ptr::null_mut(),
-        };
-        return cf_ptr;
+    pub fn get_circuit_file(&self, channel_state: &ChannelMPCState) -> Result<*mut c_void, String> {
+        get_circuit(channel_state.get_mpc_security())
     }
 
     pub fn get_fee_cc(&self) -> i64 {
@@ -577,6 +746,10 @@ impl CustomerMPCState {
         send_cb: cb_send,
         receive_cb: cb_receive,
     ) -> Result<String, String> {
+        channel_state.validate_mpc_security()?;
+        channel_state.validate_circuit_hash()?;
+        channel_state.validate_close_fees(self.fee_cc, new_state.fee_mc)?;
+
         let min_cust_bal = channel_state.bal_min_cust + self.fee_cc + channel_state.val_cpfp;
         if new_state.bc <= min_cust_bal {
             return Err(format!(
@@ -727,6 +900,8 @@ impl CustomerMPCState {
         String,
     > {
         let fee_mc = self.get_current_state().fee_mc;
+        channel_state.validate_close_fees(self.fee_cc, fee_mc)?;
+
         let escrow_init_balance = self.cust_balance + self.merch_balance;
         let merch_init_balance = escrow_init_balance - channel_state.get_val_cpfp() - fee_mc;
         let escrow_index = 0;
@@ -782,6 +957,8 @@ impl CustomerMPCState {
         orig_escrow_sig: &Vec<u8>,
         orig_merch_sig: &Vec<u8>,
     ) -> Result<bool, String> {
+        channel_state.validate_network(channel_token)?;
+
         let (escrow_tx_preimage, merch_tx_preimage, _, _) =
             self.construct_close_transaction_preimage::<N>(channel_state, channel_token)?;
 
@@ -970,6 +1147,15 @@ impl CustomerMPCState {
         return close_tx;
     }
 
+    // customer's anchor on a signed cust-close tx (the cpfp output)
+    pub fn get_close_tx_anchor<N: BitcoinNetwork>(
+        &self,
+        signed_tx: &Vec<u8>,
+        txid_le: [u8; 32],
+    ) -> Result<AnchorOutput, String> {
+        find_anchor_output::<N>(signed_tx, txid_le, &self.payout_pk)
+    }
+
     pub fn change_channel_status(
         &mut self,
         new_channel_status: ChannelStatus,
@@ -1020,6 +1206,139 @@ fn xor_in_place(a: &mut [u8], b: &[u8]) {
     }
 }
 
+// Snapshot of everything a payment changes in the customer state. A payment can be
+// rolled back as long as the merchant's signatures on the new closing transactions
+// have not been obtained: until then, the old state is the only one the customer
+// can close on, and it has not been revoked yet.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct CustomerPaymentSession {
+    state: Option<State>,
+    rev_lock: FixedSizeArray32,
+    rev_secret: FixedSizeArray32,
+    t: FixedSizeArray16,
+    cust_balance: i64,
+    merch_balance: i64,
+    pay_token_mask_com: FixedSizeArray32,
+    masked_outputs: HashMap<i32, MaskedMPCOutputs>,
+    pay_tokens: HashMap<i32, FixedSizeArray32>,
+    close_escrow_signature: Option<String>,
+    close_merch_signature: Option<String>,
+    protocol_status: ProtocolStatus,
+    // pay_update_customer() installs a default connection if none was configured
+    net_config: Option<NetworkConfig>,
+    // what is needed to resume the payment with the merchant after a disconnect
+    session_id: Option<FixedSizeArray16>,
+    revoked_state: Option<RevokedState>,
+    mpc_result: Option<String>,
+}
+
+impl CustomerPaymentSession {
+    pub fn new(cust_state: &CustomerMPCState) -> Self {
+        CustomerPaymentSession {
+            state: cust_state.state,
+            rev_lock: cust_state.rev_lock,
+            rev_secret: cust_state.rev_secret,
+            t: cust_state.t,
+            cust_balance: cust_state.cust_balance,
+            merch_balance: cust_state.merch_balance,
+            pay_token_mask_com: cust_state.pay_token_mask_com,
+            masked_outputs: cust_state.masked_outputs.clone(),
+            pay_tokens: cust_state.pay_tokens.clone(),
+            close_escrow_signature: cust_state.close_escrow_signature.clone(),
+            close_merch_signature: cust_state.close_merch_signature.clone(),
+            protocol_status: cust_state.protocol_status.clone(),
+            net_config: cust_state.net_config.clone(),
+            session_id: None,
+            revoked_state: None,
+            mpc_result: None,
+        }
+    }
+
+    pub fn set_prepare_output(&mut self, session_id: [u8; 16], revoked_state: RevokedState) {
+        self.session_id = Some(FixedSizeArray16(session_id));
+        self.revoked_state = Some(revoked_state);
+    }
+
+    pub fn set_mpc_result(&mut self, success: String) {
+        self.mpc_result = Some(success);
+    }
+
+    pub fn get_session_id(&self) -> Option<[u8; 16]> {
+        self.session_id.map(|s| s.0)
+    }
+
+    pub fn get_revoked_state(&self) -> Option<RevokedState> {
+        self.revoked_state.clone()
+    }
+
+    pub fn get_mpc_result(&self) -> Option<String> {
+        self.mpc_result.clone()
+    }
+
+    pub fn get_status(&self, cust_state: &CustomerMPCState) -> PaymentStatus {
+        let index = cust_state.index;
+        if self.has_new_close_signatures(cust_state) {
+            match self.has_new_pay_token(cust_state) {
+                true => PaymentStatus::Complete,
+                false => PaymentStatus::Revoked,
+            }
+        } else if self.masked_outputs.get(&index) != cust_state.masked_outputs.get(&index) {
+            PaymentStatus::Update
+        } else {
+            PaymentStatus::Prepare
+        }
+    }
+
+    fn has_new_close_signatures(&self, cust_state: &CustomerMPCState) -> bool {
+        self.close_escrow_signature != cust_state.close_escrow_signature
+            || self.close_merch_signature != cust_state.close_merch_signature
+    }
+
+    fn has_new_pay_token(&self, cust_state: &CustomerMPCState) -> bool {
+        let index = cust_state.index;
+        cust_state.pay_tokens.get(&index).is_some()
+            && self.pay_tokens.get(&index) != cust_state.pay_tokens.get(&index)
+    }
+
+    pub fn commit(self, cust_state: &CustomerMPCState) -> Result<(), String> {
+        if self.state == cust_state.state {
+            return Err(String::from("payment session has not started"));
+        }
+        if !self.has_new_close_signatures(cust_state) {
+            return Err(String::from(
+                "payment incomplete: no closing signatures on the new state",
+            ));
+        }
+        if !self.has_new_pay_token(cust_state) {
+            return Err(String::from(
+                "payment incomplete: no pay token on the new state",
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn abort(self, cust_state: &mut CustomerMPCState) -> Result<(), String> {
+        if self.has_new_close_signatures(cust_state) {
+            // the customer may already have revoked the old state by now
+            return Err(String::from(
+                "cannot roll back payment: closing signatures on the new state were already obtained",
+            ));
+        }
+        cust_state.state = self.state;
+        cust_state.rev_lock = self.rev_lock;
+        cust_state.rev_secret = self.rev_secret;
+        cust_state.t = self.t;
+        cust_state.cust_balance = self.cust_balance;
+        cust_state.merch_balance = self.merch_balance;
+        cust_state.pay_token_mask_com = self.pay_token_mask_com;
+        cust_state.masked_outputs = self.masked_outputs;
+        cust_state.pay_tokens = self.pay_tokens;
+        cust_state.protocol_status = self.protocol_status;
+        cust_state.net_config = self.net_config;
+        Ok(())
+    }
+}
+
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct RevokedState {
     pub rev_lock: FixedSizeArray32,
@@ -1065,6 +1384,53 @@ pub struct MerchCloseTx {
     self_delay: String,
 }
 
+static REFUND_VOUCHER_TAG: &[u8] = b"zkchannels/refund-voucher";
+static REFUND_VOUCHER_KEY_TAG: &[u8] = b"zkchannels/refund-voucher-key";
+
+// Authorizes a single negative payment (refund) of the given amount before expiry.
+// Issued and signed by the merchant with its voucher key (never with the escrow key sk_m),
+// redeemed by the customer as the justification.
+// The merchant can not tell which channel a payment is made on, so the voucher is bound
+// to the channel through the nonce of the customer's current state instead.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct RefundVoucher {
+    pub id: FixedSizeArray16,
+    pub nonce: FixedSizeArray16,
+    pub amount: i64,
+    pub expiry: i64,
+    pub signature: FixedSizeArray64,
+}
+
+impl RefundVoucher {
+    fn message(
+        id: &[u8; 16],
+        nonce: &[u8; NONCE_LEN],
+        amount: i64,
+        expiry: i64,
+    ) -> secp256k1::Message {
+        // tagged hash (as in BIP340), so the signed digest can't be a valid message
+        // in any other context
+        let tag = sha2_hash_to_slice(&REFUND_VOUCHER_TAG.to_vec());
+        let mut buf = tag.to_vec();
+        buf.extend_from_slice(&tag);
+        buf.extend_from_slice(id);
+        buf.extend_from_slice(nonce);
+        buf.extend_from_slice(&amount.to_be_bytes());
+        buf.extend_from_slice(&expiry.to_be_bytes());
+        secp256k1::Message::from_slice(&sha2_hash_to_slice(&buf)).unwrap()
+    }
+
+    pub fn verify(&self, voucher_pk: &secp256k1::PublicKey) -> bool {
+        let secp = secp256k1::Secp256k1::verification_only();
+        let sig = match secp256k1::Signature::from_compact(&self.signature.get_bytes()) {
+            Ok(s) => s,
+            Err(_) => return false,
+        };
+        let msg = RefundVoucher::message(&self.id.0, &self.nonce.0, self.amount, self.expiry);
+        secp.verify(&msg, &sig, voucher_pk).is_ok()
+    }
+}
+
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct MerchantMPCState {
     id: String,
@@ -1091,6 +1457,12 @@ pub struct MerchantMPCState {
     pub net_config: Option<NetworkConfig>,
     pub db_url: String,
     refund_policy: NegativePaymentPolicy,
+    #[serde(default)]
+    session_policy: SessionPolicy,
+    #[serde(default)]
+    anchor_outputs: bool,
+    #[serde(default)]
+    network: BitcoinNetworkType,
 }
 
 impl MerchantMPCState {
@@ -1158,6 +1530,9 @@ impl MerchantMPCState {
             net_config: None,
             db_url: db_url,
             refund_policy: NegativePaymentPolicy::REJECT,
+            session_policy: SessionPolicy::default(),
+            anchor_outputs: channel.has_anchor_outputs(),
+            network: channel.get_network(),
         }
     }
 
@@ -1200,10 +1575,26 @@ impl MerchantMPCState {
         Ok(())
     }
 
+    pub fn get_network(&self) -> BitcoinNetworkType {
+        return self.network;
+    }
+
     pub fn set_refund_policy(&mut self, policy: NegativePaymentPolicy) {
         self.refund_policy = policy;
     }
 
+    pub fn get_refund_policy(&self) -> NegativePaymentPolicy {
+        self.refund_policy.clone()
+    }
+
+    pub fn set_session_policy(&mut self, policy: SessionPolicy) {
+        self.session_policy = policy;
+    }
+
+    pub fn get_session_policy(&self) -> &SessionPolicy {
+        return &self.session_policy;
+    }
+
     pub fn get_secret_key(&self) -> Vec<u8> {
         return self.sk_m.0.to_vec();
     }
@@ -1298,6 +1689,13 @@ impl MerchantMPCState {
             ));
         }
 
+        if channel_token.network != self.network {
+            return Err(format!(
+                "channel token network ({}) does not match merch state network ({})",
+                channel_token.network, self.network
+            ));
+        }
+
         // cache prevout from escrow_txid and escrow_prevout
         let mut escrow_prevout = [0u8; 32];
         let mut merch_prevout = [0u8; 32];
@@ -1336,6 +1734,9 @@ impl MerchantMPCState {
             return Err(String::from("initial state not well-formed"));
         }
 
+        // with anchor outputs, fee_cc is bounded by max_fee inside the mpc
+        validate_anchor_close_fees(self.anchor_outputs, s0.max_fee, s0.fee_mc)?;
+
         let nonce_hex_str = hex::encode(s0.get_nonce());
 
         self.activate_map.insert(channel_id_str, s0);
@@ -1353,17 +1754,96 @@ impl MerchantMPCState {
         Ok(true)
     }
 
+    // The voucher key is derived from sk_m, so it needs no new state, but the escrow key itself
+    // never signs anything other than transactions
+    fn get_voucher_secret_key(&self) -> Result<secp256k1::SecretKey, String> {
+        let key = hmac_sign(self.sk_m.0.to_vec(), &REFUND_VOUCHER_KEY_TAG.to_vec());
+        Ok(handle_error_util!(secp256k1::SecretKey::from_slice(&key)))
+    }
+
+    // Public key that refund vouchers are signed with
+    pub fn get_voucher_pubkey(&self) -> Result<secp256k1::PublicKey, String> {
+        let secp = secp256k1::Secp256k1::signing_only();
+        let voucher_sk = self.get_voucher_secret_key()?;
+        Ok(secp256k1::PublicKey::from_secret_key(&secp, &voucher_sk))
+    }
+
+    // Merchant issues a voucher that authorizes a refund (negative payment) of the given amount
+    // from the state with the given nonce
+    pub fn issue_refund_voucher<R: Rng>(
+        &self,
+        csprng: &mut R,
+        nonce: [u8; NONCE_LEN],
+        amount: i64,
+        expiry: i64,
+    ) -> Result<RefundVoucher, String> {
+        if amount >= 0 {
+            return Err(format!(
+                "Refund voucher amount must be negative: {}",
+                amount
+            ));
+        }
+        let mut id = [0u8; 16];
+        csprng.fill_bytes(&mut id);
+
+        let secp = secp256k1::Secp256k1::signing_only();
+        let voucher_sk = self.get_voucher_secret_key()?;
+        let msg = RefundVoucher::message(&id, &nonce, amount, expiry);
+        let sig = secp.sign(&msg, &voucher_sk);
+
+        Ok(RefundVoucher {
+            id: FixedSizeArray16(id),
+            nonce: FixedSizeArray16(nonce),
+            amount: amount,
+            expiry: expiry,
+            signature: FixedSizeArray64::new(sig.serialize_compact()),
+        })
+    }
+
+    // Verifies the refund voucher presented for a negative payment. The voucher is only marked
+    // as spent once the old state is revoked (see verify_revoked_state()), so it can be redeemed
+    // again if the session fails or goes stale before that
     pub fn process_justification(
         &self,
+        db: &mut dyn StateDatabase,
+        nonce: [u8; NONCE_LEN],
         amount: i64,
-        signature: Option<String>,
-    ) -> Result<bool, String> {
-        // TODO: ZKC-17 verify justification for negative payments
-        if signature.is_some() {
-            println!("Verify justification for negative payment: {}", amount);
-            return Ok(true);
+        justification: Option<String>,
+    ) -> Result<Option<RefundVoucher>, String> {
+        let ser_voucher = match justification {
+            Some(s) => s,
+            None => return Ok(None),
+        };
+        let voucher: RefundVoucher = match serde_json::from_str(&ser_voucher) {
+            Ok(v) => v,
+            Err(e) => return Err(format!("Could not parse refund voucher: {}", e)),
+        };
+        if !voucher.verify(&self.get_voucher_pubkey()?) {
+            return Err(String::from("Invalid signature on refund voucher"));
+        }
+        if voucher.nonce.0 != nonce {
+            return Err(String::from(
+                "Refund voucher was issued for a different channel state",
+            ));
+        }
+        if voucher.amount != amount {
+            return Err(format!(
+                "Refund voucher is for {}, not for {}",
+                voucher.amount, amount
+            ));
+        }
+        if voucher.expiry <= get_unix_time() {
+            return Err(String::from("Refund voucher has expired"));
+        }
+
+        let voucher_id_hex = hex::encode(voucher.id.0);
+        if db.check_spent_voucher_map(&voucher_id_hex) {
+            return Err(format!(
+                "Refund voucher {} has been spent already",
+                voucher_id_hex
+            ));
         }
-        return Ok(false);
+        return Ok(Some(voucher));
     }
 
     pub fn generate_pay_mask_commitment<R: Rng>(
@@ -1395,11 +1875,40 @@ impl MerchantMPCState {
             ));
         }
 
-        // concurrent payment check - if specified nonce is part of an existing active session
+        // drop expired and failed sessions. Only nonces that never got past Prepare become
+        // free for a retry (see StateDatabase::clear_stale_sessions)
+        let now = get_unix_time();
+        db.clear_stale_sessions(now)?;
+
+        // a customer that rolled back a payment retries from the old state with the same
+        // nonce: the session on that nonce can be superseded if its MPC has not run yet.
+        // It is only marked as failed once this session passes all the checks below.
+        let mut superseded_session = None;
         if db.check_dup_nonce_to_session_id(&nonce_hex, &session_id_hex) {
+            let prev_session_id_hex = db.get_session_id_from_nonce(&nonce_hex)?;
+            match db.load_session_state(&prev_session_id_hex) {
+                Ok(s) if s.status == PaymentStatus::Prepare => {
+                    superseded_session = Some((prev_session_id_hex, s))
+                }
+                // concurrent payment check - specified nonce is part of an existing active session
+                _ => {
+                    return Err(format!(
+                        "Cannot reuse nonce with a different session id: {} {}",
+                        session_id_hex, nonce_hex
+                    ))
+                }
+            }
+        }
+
+        // bound the number of open sessions and the retries for a given nonce
+        let mut active_sessions = db.get_active_session_count(now)?;
+        if superseded_session.is_some() {
+            active_sessions -= 1;
+        }
+        if active_sessions >= self.session_policy.max_concurrent_sessions {
             return Err(format!(
-                "Cannot reuse nonce with a different session id: {} {}",
-                session_id_hex, nonce_hex
+                "Too many active payment sessions: {}",
+                active_sessions
             ));
         }
 
@@ -1418,22 +1927,33 @@ impl MerchantMPCState {
             return Err(format!("Can only run unlink once."));
         }
 
+        let mut refund_voucher = None;
         if amount < 0 {
-            let payment_result = match self.refund_policy {
-                NegativePaymentPolicy::REJECT => false,
+            refund_voucher = match self.refund_policy {
+                NegativePaymentPolicy::REJECT => None,
                 NegativePaymentPolicy::CHECK_JUSTIFICATION => {
-                    match self.process_justification(amount, justification) {
+                    match self.process_justification(db, nonce, amount, justification) {
                         Ok(s) => s,
                         Err(e) => return Err(e.to_string()),
                     }
                 }
             };
 
-            if !payment_result {
+            if refund_voucher.is_none() {
                 return Err(format!("Sorry, refunds are not allowed for this channel"));
             }
         }
 
+        // only requests that passed the checks above count as attempts on the nonce
+        let attempts_expiry = now + self.session_policy.session_timeout;
+        let attempts = db.update_nonce_attempts(&nonce_hex, attempts_expiry)?;
+        if attempts > self.session_policy.max_attempts_per_nonce {
+            return Err(format!(
+                "Too many payment attempts for nonce: {}",
+                nonce_hex
+            ));
+        }
+
         // pick mask_pay and form commitment to it
         let mut pay_mask = [0u8; 32];
         let mut pay_mask_r = [0u8; 16];
@@ -1445,6 +1965,12 @@ impl MerchantMPCState {
         pay_mask_buf.extend_from_slice(&pay_mask_r);
         let paytoken_mask_com = sha2_hash_to_slice(&pay_mask_buf);
 
+        // all checks passed: the retry takes over the nonce from the earlier session
+        if let Some((prev_session_id_hex, mut prev_session_state)) = superseded_session {
+            prev_session_state.status = PaymentStatus::Error;
+            db.update_session_state(&prev_session_id_hex, &prev_session_state);
+        }
+
         // store pay_mask for use in mpc protocol later
         db.update_nonce_mask_map(&nonce_hex, pay_mask, pay_mask_r)?;
 
@@ -1454,6 +1980,9 @@ impl MerchantMPCState {
             rev_lock_com: FixedSizeArray32(rev_lock_com),
             amount: amount,
             status: PaymentStatus::Prepare,
+            expiry: now + self.session_policy.session_timeout,
+            refund_voucher: refund_voucher,
+            masks_released: false,
         };
         if !db.save_new_session_state(&session_id_hex, &sess_state) {
             println!(
@@ -1492,6 +2021,8 @@ impl MerchantMPCState {
         fee_mc: i64,
         val_cpfp: i64,
     ) -> Result<(Vec<u8>, Vec<u8>), String> {
+        validate_anchor_close_fees(self.anchor_outputs, fee_cc, fee_mc)?;
+
         let escrow_init_balance = funding_tx.init_cust_bal + funding_tx.init_merch_bal;
         let merch_init_balance = escrow_init_balance - val_cpfp - fee_mc;
         let escrow_index = 0;
@@ -1577,6 +2108,21 @@ impl MerchantMPCState {
         let _res = self.change_channel_status(escrow_txid.clone(), ChannelStatus::None);
     }
 
+    // merchant's anchor on a signed close tx: the cpfp output of merch-close
+    // or the to_merchant output of cust-close
+    pub fn get_close_tx_anchor<N: BitcoinNetwork>(
+        &self,
+        signed_tx: &Vec<u8>,
+        txid_le: [u8; 32],
+        from_merch_close: bool,
+    ) -> Result<AnchorOutput, String> {
+        let pk = match from_merch_close {
+            true => &self.child_pk,
+            false => &self.payout_pk,
+        };
+        find_anchor_output::<N>(signed_tx, txid_le, pk)
+    }
+
     pub fn get_closing_tx<N: BitcoinNetwork>(
         &mut self,
         escrow_txid: [u8; 32],
@@ -1702,32 +2248,8 @@ impl MerchantMPCState {
         Ok(())
     }
 
-    pub fn get_circuit_file(&self) -> *mut c_void {
-        // Box<Circuit>
-        let using_ag2pc = match env::var("AG2PC") {
-            Ok(_s) => true,
-            Err(_e) => false,
-        };
-
-        let circuit_file = match using_ag2pc {
-            true => match env::var("ZK_DEPS_INSTALL") {
-                Ok(s) => format!("{}{}", s, CIRCUIT_FILE),
-                Err(e) => panic!("ZK_DEPS_INSTALL env not set: {}", e),
-            },
-            false => String::new(),
-        };
-        // println!("Circuit: {}", circuit_file);
-        let cf_ptr = match using_ag2pc {
-            true => {
-                let cf_ptr = unsafe {
-                    let c_str = CString::new(circuit_file).unwrap();
-                    load_circuit_file(c_str.as_ptr() as *const i8)
-                };
-                cf_ptr
-            }
-            false => ptr::null_mut(),
-        };
-        return cf_ptr; // Box::new(Circuit { ptr: cf_ptr }
+    pub fn get_circuit_file(&self, channel_state: &ChannelMPCState) -> Result<*mut c_void, String> {
+        get_circuit(channel_state.get_mpc_security())
     }
 
     // for merchant side
@@ -1749,15 +2271,31 @@ impl MerchantMPCState {
         //     return Err(String::from("epsilon below dust limit!"));
         // }
 
+        channel_state.validate_mpc_security()?;
+        channel_state.validate_circuit_hash()?;
+
         // check db is connected
         db.is_connected()?;
 
         let session_id_hex = hex::encode(&session_id);
-        let session_state = match db.load_session_state(&session_id_hex) {
+        let mut session_state = match db.load_session_state(&session_id_hex) {
             Ok(s) => s,
             Err(e) => return Err(e.to_string()),
         };
 
+        if session_state.is_expired(get_unix_time()) {
+            return Err(format!(
+                "merch::execute_mpc_context - session {} has expired.",
+                &session_id_hex
+            ));
+        }
+        if session_state.status != PaymentStatus::Prepare {
+            return Err(format!(
+                "merch::execute_mpc_context - session {} is not ready for the MPC: {}",
+                &session_id_hex, session_state.status
+            ));
+        }
+
         let amount = session_state.amount;
         let nonce = session_state.nonce.0;
         let rev_lock_com = session_state.rev_lock_com.0;
@@ -1846,7 +2384,7 @@ impl MerchantMPCState {
             &pay_mask_r,
             &escrow_mask_bytes,
             &verify_success_bytes,
-        );
+        )?;
 
         // store the rev_lock_com => (pt_mask_bytes, escrow_mask_bytes, merch_mask_bytes)
         //    println!("=================================================================");
@@ -1868,8 +2406,16 @@ impl MerchantMPCState {
             verify_success: FixedSizeArray16(verify_success_bytes),
         };
 
-        // let nonce_hex = hex::encode(nonce);
+        // a retry on the same nonce may have superseded this session during the MPC
+        if db.check_dup_nonce_to_session_id(&nonce_hex, &session_id_hex) {
+            return Err(format!(
+                "merch::execute_mpc_context - session {} was superseded by a retry.",
+                &session_id_hex
+            ));
+        }
         db.update_masked_mpc_inputs(&session_id_hex, mask_bytes);
+        session_state.status = PaymentStatus::Update;
+        db.update_session_state(&session_id_hex, &session_state);
 
         Ok(true)
     }
@@ -1887,11 +2433,16 @@ impl MerchantMPCState {
 
         let session_id_hex = hex::encode(session_id);
         // retrieve session_state
-        let session_state = match db.load_session_state(&session_id_hex) {
+        let mut session_state = match db.load_session_state(&session_id_hex) {
             Ok(s) => s,
             Err(e) => return Err(e.to_string()),
         };
 
+        // failed, aborted or expired sessions never release the pay token mask
+        if session_state.status == PaymentStatus::Error {
+            return Err(format!("session has failed: {}", &session_id_hex));
+        }
+
         let nonce_hex = hex::encode(session_state.nonce.0);
         let rev_lock_com = session_state.rev_lock_com.0;
 
@@ -1918,6 +2469,11 @@ impl MerchantMPCState {
         // verify that RL_i not in the S_spent
         let rev_lock_r = hex::encode(&rev_lock);
         if db.check_rev_lock_map(&rev_lock_r) {
+            // the customer lost the connection after revoking in this session:
+            // hand out the same pay token mask again
+            if session_state.status == PaymentStatus::Revoked {
+                return Ok((pt_mask, pt_mask_r));
+            }
             return Err(String::from(
                 "attempting to revoke with a rev_lock that is already revoked",
             ));
@@ -1926,6 +2482,13 @@ impl MerchantMPCState {
         let rev_sec_r = hex::encode(&rev_sec);
         // add (n_i, RL_i) to S_spent map
         db.update_spent_map(&nonce_hex, &rev_lock_r)?;
+        // a spent nonce can not be retried, so its attempt count is no longer needed
+        db.clear_nonce_attempts(&nonce_hex);
+        // the refund was paid out
+        if let Some(voucher) = &session_state.refund_voucher {
+            db.update_spent_voucher_map(&hex::encode(voucher.id.0), voucher.expiry)?;
+        }
+        db.prune_spent_voucher_map(get_unix_time())?;
         // add (RL_i, RS_i) to RL map
         db.update_rev_lock_map(&rev_lock_r, &rev_sec_r)?;
         // check if n_i in the unlink map. if so, remove it
@@ -1933,6 +2496,8 @@ impl MerchantMPCState {
             // remove nonce from unlink set
             assert!(db.remove_from_unlink_set(&nonce_hex));
         }
+        session_state.status = PaymentStatus::Revoked;
+        db.update_session_state(&session_id_hex, &session_state);
 
         Ok((pt_mask, pt_mask_r))
     }
@@ -1947,6 +2512,7 @@ mod tests {
     use rand_xorshift::XorShiftRng;
     use sha2::Digest;
     use sha2::Sha256;
+    use std::ptr;
     use zkchan_tx::Testnet;
 
     fn generate_test_txs<R: Rng>(csprng: &mut R, b0_cust: i64, b0_merch: i64) -> FundingTxInfo {
@@ -2014,7 +2580,7 @@ mod tests {
 
         // initialize the channel token on with pks
         // generate and send initial state to the merchant
-        let mut channel_token = cust_state.generate_init_state(&mut rng, &merch_state.pk_m, tx_fee_info.min_fee, tx_fee_info.max_fee, tx_fee_info.fee_mc);
+        let mut channel_token = cust_state.generate_init_state(&mut rng, &merch_state.pk_m, tx_fee_info.min_fee, tx_fee_info.max_fee, tx_fee_info.fee_mc, channel_state.get_network());
 
         // at this point, cust/merch have both exchanged initial sigs (escrow-tx + merch-close-tx)
         let funding_tx_info = generate_test_txs(&mut rng, b0_cust, b0_merch);
@@ -2070,13 +2636,13 @@ mod tests {
         cust_state.update_pay_com(pay_token_mask_com);
 
         // cust_state.set_mpc_connect_type(2);
-        cust_state.set_network_config(NetworkConfig { conn_type: 1, dest_ip: String::from("127.0.0.1"), dest_port: 12347, path: String::from("foobar") });
+        cust_state.set_network_config(NetworkConfig { conn_type: 1, dest_ip: String::from("127.0.0.1"), dest_port: 12347, path: String::from("foobar"), proxy: None });
         // prepare the customer inputs
         let s0 = s_0.clone();
         let s1 = s_1.clone();
 
         println!("hello, customer!");
-        let circuit = cust_state.get_circuit_file();
+        let circuit = cust_state.get_circuit_file(&channel_state).unwrap();
         let res = cust_state.execute_mpc_context(&channel_state, &channel_token, s0, s1, pay_token_mask_com, r_com, amount, circuit,
             ptr::null_mut(),
             None,
@@ -2199,7 +2765,7 @@ mod tests {
 
         // initialize the channel token on with pks
         // generate and send initial state to the merchant
-        let mut channel_token = cust_state.generate_init_state(&mut rng, &merch_state.pk_m, tx_fee_info.min_fee, tx_fee_info.max_fee, tx_fee_info.fee_mc);
+        let mut channel_token = cust_state.generate_init_state(&mut rng, &merch_state.pk_m, tx_fee_info.min_fee, tx_fee_info.max_fee, tx_fee_info.fee_mc, channel.get_network());
 
         // at this point, cust/merch have both exchanged initial sigs (escrow-tx + merch-close-tx)
         let funding_tx_info = generate_test_txs(&mut rng, b0_cust, b0_merch);
@@ -2280,11 +2846,12 @@ mod tests {
             dest_ip: String::from("127.0.0.1"),
             dest_port: 12347,
             path: String::from("foobar"),
+            proxy: None,
         });
 
         // prepare the merchant inputs
         println!("hello, merchant!");
-        let circuit = merch_state.get_circuit_file(); // can be preloaded and cached
+        let circuit = merch_state.get_circuit_file(&channel_state).unwrap();
         let res = merch_state.execute_mpc_context(
             &mut rng,
             &mut db as &mut dyn StateDatabase,
@@ -2384,6 +2951,7 @@ mod tests {
             tx_fee_info.min_fee,
             tx_fee_info.max_fee,
             tx_fee_info.fee_mc,
+            channel_state.get_network(),
         );
 
         let cust_sk = [4u8; 32];
@@ -2412,4 +2980,150 @@ mod tests {
         let orig_channel_token: ChannelMPCToken = serde_json::from_str(&ser_channel_token).unwrap();
         assert_eq!(channel_token, orig_channel_token);
     }
+
+    #[test]
+    fn mpc_anchor_outputs_bound_close_fees() {
+        let mut channel_state =
+            ChannelMPCState::new(String::from("Channel A <-> B"), 1487, 546, 546, 1000, false);
+        assert!(channel_state.validate_close_fees(1000, 1000).is_ok());
+
+        channel_state.set_anchor_outputs(true).unwrap();
+        assert!(channel_state.has_anchor_outputs());
+        assert!(channel_state.validate_close_fees(1000, 1000).is_err());
+        assert!(channel_state
+            .validate_close_fees(ANCHOR_MAX_CLOSE_FEE, ANCHOR_MAX_CLOSE_FEE)
+            .is_ok());
+
+        // anchor outputs must not be dust
+        let mut dust_channel_state =
+            ChannelMPCState::new(String::from("Channel A <-> B"), 1487, 546, 546, 300, false);
+        assert!(dust_channel_state.set_anchor_outputs(true).is_err());
+    }
+
+    #[test]
+    fn mpc_close_tx_has_anchor_for_each_party() {
+        let secp = secp256k1::Secp256k1::new();
+        let cust_payout_sk = secp256k1::SecretKey::from_slice(&[0x11u8; 32]).unwrap();
+        let merch_payout_sk = secp256k1::SecretKey::from_slice(&[0x22u8; 32]).unwrap();
+        let merch_child_sk = secp256k1::SecretKey::from_slice(&[0x33u8; 32]).unwrap();
+        let cust_payout_pk = secp256k1::PublicKey::from_secret_key(&secp, &cust_payout_sk);
+        let merch_payout_pk = secp256k1::PublicKey::from_secret_key(&secp, &merch_payout_sk);
+        let merch_child_pk = secp256k1::PublicKey::from_secret_key(&secp, &merch_child_sk);
+
+        let p2wpkh = |pk: &secp256k1::PublicKey| {
+            let mut script = vec![0x00, 0x14];
+            script.extend_from_slice(&compute_hash160(&pk.serialize().to_vec()));
+            script
+        };
+        let output = |value: i64, script: Vec<u8>| {
+            let mut out = value.to_le_bytes().to_vec();
+            out.push(script.len() as u8);
+            out.extend(script);
+            out
+        };
+
+        // cust-close layout: to_customer (p2wsh), to_merchant, op_return, cust cpfp
+        let mut cust_close_tx = vec![0x02, 0x00, 0x00, 0x00, 0x00, 0x01, 0x01];
+        cust_close_tx.extend_from_slice(&[0xaa; 36]);
+        cust_close_tx.extend_from_slice(&[0x00, 0xff, 0xff, 0xff, 0xff]);
+        cust_close_tx.push(0x04);
+        cust_close_tx.extend(output(9000, [vec![0x00, 0x20], vec![0xbb; 32]].concat()));
+        cust_close_tx.extend(output(5000, p2wpkh(&merch_payout_pk)));
+        cust_close_tx.extend(output(0, [vec![0x6a, 0x20], vec![0xcc; 32]].concat()));
+        cust_close_tx.extend(output(1000, p2wpkh(&cust_payout_pk)));
+        cust_close_tx.extend_from_slice(&[0x00, 0x00, 0x00, 0x00, 0x00]); // witness and locktime
+
+        let txid = [0x01u8; 32];
+        let cust_anchor =
+            find_anchor_output::<Testnet>(&cust_close_tx, txid, &cust_payout_pk).unwrap();
+        assert_eq!(cust_anchor.index, 3);
+        assert_eq!(cust_anchor.value, 1000);
+        assert_eq!(cust_anchor.txid_le, FixedSizeArray32(txid));
+
+        let merch_anchor =
+            find_anchor_output::<Testnet>(&cust_close_tx, txid, &merch_payout_pk).unwrap();
+        assert_eq!(merch_anchor.index, 1);
+        assert_eq!(merch_anchor.value, 5000);
+
+        assert!(find_anchor_output::<Testnet>(&cust_close_tx, txid, &merch_child_pk).is_err());
+        assert!(find_anchor_output::<Testnet>(
+            &cust_close_tx[..60].to_vec(),
+            txid,
+            &cust_payout_pk
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn mpc_states_from_before_new_fields_still_load() {
+        let mut rng = XorShiftRng::seed_from_u64(0x5dbe62598d313d76);
+        let mut channel_state =
+            ChannelMPCState::new(String::from("Channel A <-> B"), 1487, 546, 546, 1000, false);
+        let merch_state = MerchantMPCState::new(
+            &mut rng,
+            String::from("redis://127.0.0.1/"),
+            &mut channel_state,
+            String::from("Merchant"),
+        );
+
+        let mut ser_channel_state = serde_json::to_value(&channel_state).unwrap();
+        for field in ["anchor_outputs", "network", "mpc_security", "circuit_hash"].iter() {
+            ser_channel_state.as_object_mut().unwrap().remove(*field);
+        }
+        let old_channel_state: ChannelMPCState = serde_json::from_value(ser_channel_state).unwrap();
+        assert!(!old_channel_state.has_anchor_outputs());
+        assert_eq!(old_channel_state.get_network(), BitcoinNetworkType::Testnet);
+        assert_eq!(old_channel_state.get_mpc_security(), linked_mpc_security());
+
+        let mut ser_merch_state = serde_json::to_value(&merch_state).unwrap();
+        for field in ["session_policy", "anchor_outputs", "network"].iter() {
+            ser_merch_state.as_object_mut().unwrap().remove(*field);
+        }
+        let old_merch_state: MerchantMPCState = serde_json::from_value(ser_merch_state).unwrap();
+        assert_eq!(old_merch_state.get_network(), BitcoinNetworkType::Testnet);
+        assert_eq!(
+            *old_merch_state.get_session_policy(),
+            SessionPolicy::default()
+        );
+    }
+
+    #[test]
+    fn mpc_channel_id_uses_canonical_encoding() {
+        let pk_c = secp256k1::PublicKey::from_slice(
+            &hex::decode("0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798")
+                .unwrap(),
+        )
+        .unwrap();
+        let pk_m = secp256k1::PublicKey::from_slice(
+            &hex::decode("02c6047f9441ed7d6d3045406e95c07cd85c778e4b8cef3ca7abac09b95c709ee5")
+                .unwrap(),
+        )
+        .unwrap();
+        let mut channel_token = ChannelMPCToken {
+            pk_c: None,
+            pk_m: pk_m,
+            escrow_txid: FixedSizeArray32([0x11; 32]),
+            merch_txid: FixedSizeArray32([0x22; 32]),
+            network: BitcoinNetworkType::Testnet,
+        };
+        assert_eq!(
+            channel_token.compute_channel_id().unwrap_err(),
+            "pk_c is not initialized yet"
+        );
+
+        channel_token.set_customer_pk(pk_c);
+        let ser_channel_token = channel_token.serialize_compact().unwrap();
+        assert_eq!(ser_channel_token.len(), 131);
+        assert_eq!(
+            hex::encode(channel_token.compute_channel_id().unwrap()),
+            "65c3029ab49d781361734b565f640ce0c390c71e6401061a8be9b72b48a35aee"
+        );
+
+        // the channel id is bound to the network
+        channel_token.network = BitcoinNetworkType::Regtest;
+        assert_eq!(
+            hex::encode(channel_token.compute_channel_id().unwrap()),
+            "43f65e81dc5c7291804015b2c68837440dd80151302261e0f24e3acb917b801e"
+        );
+    }
 }
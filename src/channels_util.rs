@@ -1,5 +1,6 @@
 use super::*;
 use std::fmt::Display;
+use std::str::FromStr;
 
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct FundingTxInfo {
@@ -11,6 +12,74 @@ pub struct FundingTxInfo {
     pub merch_prevout: FixedSizeArray32,
 }
 
+// bitcoin network a channel is opened on
+#[derive(Copy, Clone, Debug, PartialEq, Display, Serialize, Deserialize)]
+pub enum BitcoinNetworkType {
+    Mainnet,
+    Testnet,
+    Regtest,
+}
+
+// evaluates $body with $N bound to the zkchan_tx network params of $network, e.g.
+// with_bitcoin_network!(channel_state.get_network(), N => cust_state.customer_close::<N>(..))
+#[macro_export]
+macro_rules! with_bitcoin_network {
+    ($network:expr, $N:ident => $body:expr) => {
+        match $network {
+            $crate::channels_util::BitcoinNetworkType::Mainnet => {
+                type $N = ::zkchan_tx::Mainnet;
+                $body
+            }
+            $crate::channels_util::BitcoinNetworkType::Testnet => {
+                type $N = ::zkchan_tx::Testnet;
+                $body
+            }
+            // regtest has its own address prefix (bcrt), so it can't reuse the testnet params
+            $crate::channels_util::BitcoinNetworkType::Regtest => {
+                type $N = ::zkchan_tx::Regtest;
+                $body
+            }
+        }
+    };
+}
+
+// channels from before the network was recorded were opened on testnet
+impl Default for BitcoinNetworkType {
+    fn default() -> Self {
+        BitcoinNetworkType::Testnet
+    }
+}
+
+impl FromStr for BitcoinNetworkType {
+    type Err = String;
+    fn from_str(s: &str) -> Result<BitcoinNetworkType, String> {
+        match s.to_lowercase().as_str() {
+            "mainnet" => Ok(BitcoinNetworkType::Mainnet),
+            "testnet" => Ok(BitcoinNetworkType::Testnet),
+            "regtest" => Ok(BitcoinNetworkType::Regtest),
+            _ => Err(format!("unsupported bitcoin network: {}", s)),
+        }
+    }
+}
+
+// security model of the MPC that updates the channel state
+#[derive(Copy, Clone, Debug, PartialEq, Display, Serialize, Deserialize)]
+pub enum MpcSecurity {
+    SemiHonest, // emp-sh2pc (faster, for trusted customers)
+    Malicious,  // emp-ag2pc (authenticated garbling)
+}
+
+impl FromStr for MpcSecurity {
+    type Err = String;
+    fn from_str(s: &str) -> Result<MpcSecurity, String> {
+        match s.to_lowercase().as_str() {
+            "semi-honest" | "semihonest" => Ok(MpcSecurity::SemiHonest),
+            "malicious" => Ok(MpcSecurity::Malicious),
+            _ => Err(format!("unsupported mpc security model: {}", s)),
+        }
+    }
+}
+
 #[derive(Clone, Debug, PartialEq, Display, Serialize, Deserialize)]
 pub enum ProtocolStatus {
     New,
@@ -44,6 +113,50 @@ pub enum PaymentStatus {
     Prepare,
     Update,
     Error,
+    Revoked,  // old state revoked (merchant) or new closing signatures obtained (customer)
+    Complete, // customer holds a pay token on the new state
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SessionPolicy {
+    pub max_concurrent_sessions: usize, // sessions in Prepare/Update that have not expired
+    pub max_attempts_per_nonce: u32,    // sessions started for a nonce within session_timeout
+    pub session_timeout: i64,           // seconds until a session is stale and may be cleared
+}
+
+impl Default for SessionPolicy {
+    fn default() -> Self {
+        SessionPolicy {
+            max_concurrent_sessions: 100,
+            max_attempts_per_nonce: 3,
+            session_timeout: 600,
+        }
+    }
+}
+
+// hash lock of a conditional payment: the new state is only released to the customer
+// if the preimage of the hash is revealed before the expiry (unix time)
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct HashLock {
+    pub hash: FixedSizeArray32, // sha256 of the preimage
+    pub expiry: i64,
+}
+
+impl HashLock {
+    pub fn new(preimage: &[u8; 32], expiry: i64) -> Self {
+        HashLock {
+            hash: FixedSizeArray32(util::sha2_hash_to_slice(&preimage.to_vec())),
+            expiry,
+        }
+    }
+
+    pub fn is_expired(&self, now: i64) -> bool {
+        self.expiry <= now
+    }
+
+    pub fn is_unlocked_by(&self, preimage: &[u8; 32]) -> bool {
+        util::sha2_hash_to_slice(&preimage.to_vec()) == self.hash.0
+    }
 }
 
 #[derive(Clone, Debug, PartialEq, Display, Serialize, Deserialize)]
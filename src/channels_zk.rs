@@ -1,8 +1,9 @@
 use super::*;
-use channels_util::{ChannelStatus, ProtocolStatus};
+use channels_util::{ChannelStatus, HashLock, PaymentStatus, ProtocolStatus};
 use cl::{BlindKeyPair, Signature};
+use database::StateDatabase;
 use nizk::{NIZKProof, NIZKPublicParams, NIZKSecretParams};
-use pairing::Engine;
+use pairing::{CurveAffine, CurveProjective, Engine};
 use ped92::{CSMultiParams, Commitment};
 use rand::Rng;
 use serde::{Deserialize, Serialize};
@@ -12,9 +13,12 @@ use std::fmt;
 use util::{
     encode_short_bytes_to_fr, hash_pubkey_to_fr, hash_secret_to_fr, hash_to_fr, sha3_hash_to_slice,
 };
-use wallet::Wallet;
+use wallet::{PendingAmount, Wallet};
 use zkchan_tx::fixed_size_array::FixedSizeArray16;
 
+// number of payment rounds for which the customer keeps a transcript
+pub const MAX_PAYMENT_TRANSCRIPTS: usize = 8;
+
 #[derive(Debug)]
 pub struct BoltError {
     details: String,
@@ -113,21 +117,44 @@ impl<E: Engine> ChannelToken<E> {
         return !self.pk_c.is_none();
     }
 
-    pub fn compute_channel_id(&self) -> E::Fr
-    where
-        <E as pairing::Engine>::G1: serde::Serialize,
-        <E as pairing::Engine>::G2: serde::Serialize,
-        <E as ff::ScalarEngine>::Fr: serde::Serialize,
-    {
-        if self.pk_c.is_none() {
-            panic!("pk_c is not initialized yet");
+    ///
+    /// Canonical encoding of the channel token (same layout as the compact TZE channel token):
+    /// pk_c || pk_m || X || Y[0..l] || mpk.g1 || mpk.g2 || comParams[0..n], where secp256k1 keys
+    /// and group elements are in compressed form
+    ///
+    pub fn serialize_compact(&self) -> Result<Vec<u8>, String> {
+        let pk_c = match self.pk_c {
+            Some(pk) => pk,
+            None => return Err(String::from("pk_c is not initialized yet")),
+        };
+        let mut output_buf = Vec::new();
+        output_buf.extend_from_slice(&pk_c.serialize());
+        output_buf.extend_from_slice(&self.pk_m.serialize());
+        output_buf.extend_from_slice(&serialize_compact_point(&self.cl_pk_m.X));
+        for y in self.cl_pk_m.Y.iter() {
+            output_buf.extend_from_slice(&serialize_compact_point(y));
+        }
+        output_buf.extend_from_slice(&serialize_compact_point(&self.mpk.g1));
+        output_buf.extend_from_slice(&serialize_compact_point(&self.mpk.g2));
+        for base in self.comParams.pub_bases.iter() {
+            output_buf.extend_from_slice(&serialize_compact_point(base));
         }
-        let input = serde_json::to_vec(&self).unwrap();
+        Ok(output_buf)
+    }
 
-        return hash_to_fr::<E>(input);
+    ///
+    /// compute_channel_id - hash of the canonical encoding. Earlier releases hashed the JSON token,
+    /// so channels opened with them (and their close tokens) carry a different id.
+    ///
+    pub fn compute_channel_id(&self) -> Result<E::Fr, String> {
+        let input = self.serialize_compact()?;
+        Ok(hash_to_fr::<E>(input))
     }
+}
 
-    // add a method to compute hash on chain: SHA256 + RIPEMD160?
+// group elements in compressed form
+fn serialize_compact_point<G: CurveProjective>(point: &G) -> Vec<u8> {
+    point.into_affine().into_compressed().as_ref().to_vec()
 }
 
 ///
@@ -207,6 +234,28 @@ pub struct CustomerState<E: Engine> {
     pay_tokens: HashMap<i32, Signature<E>>,
     pub protocol_status: ProtocolStatus,
     channel_status: ChannelStatus,
+    // transcripts of the latest payment rounds (revocations released and tokens received)
+    #[serde(default)]
+    transcripts: Vec<PaymentTranscript<E>>,
+}
+
+///
+/// Transcript of a payment round kept by the customer: the revocation released to the merchant,
+/// the new state with its close token and the pay token once the merchant releases it
+///
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(bound(serialize = "<E as ff::ScalarEngine>::Fr: serde::Serialize, \
+                           <E as pairing::Engine>::G1: serde::Serialize"))]
+#[serde(
+    bound(deserialize = "<E as ff::ScalarEngine>::Fr: serde::Deserialize<'de>, \
+                         <E as pairing::Engine>::G1: serde::Deserialize<'de>")
+)]
+pub struct PaymentTranscript<E: Engine> {
+    pub rev_lock_pair: RevLockPair,
+    pub wallet: Wallet<E>,
+    pub close_token: Signature<E>,
+    pub pay_token: Option<Signature<E>>,
+    pub status: PaymentStatus,
 }
 
 #[derive(Clone, Serialize, Deserialize)]
@@ -306,7 +355,7 @@ impl<E: Engine> CustomerState<E> {
         // hash the wallet pub key
         channel_token.set_customer_pk(&pk_c);
         // compute the channel ID
-        let channelId = channel_token.compute_channel_id();
+        let channelId = channel_token.compute_channel_id().unwrap();
         // initialize wallet vector
         let wallet = Wallet {
             channelId: channelId,
@@ -314,6 +363,7 @@ impl<E: Engine> CustomerState<E> {
             rev_lock: rl,
             bc: cust_bal,
             bm: merch_bal,
+            pending: None,
         };
 
         assert!(channel_token.is_init());
@@ -338,6 +388,7 @@ impl<E: Engine> CustomerState<E> {
             pay_tokens: pt_db,
             protocol_status: ProtocolStatus::New,
             channel_status: ChannelStatus::None,
+            transcripts: Vec::new(),
         };
     }
 
@@ -345,6 +396,15 @@ impl<E: Engine> CustomerState<E> {
         return self.wallet.clone();
     }
 
+    // the wallet the current pay token is on: its balances differ from get_wallet() once
+    // the pay token of an expired conditional payment has been taken
+    pub fn get_pay_wallet(&self) -> Wallet<E> {
+        let mut wallet = self.wallet.clone();
+        wallet.bc = self.cust_balance;
+        wallet.bm = self.merch_balance;
+        return wallet;
+    }
+
     pub fn get_secret_key(&self) -> secp256k1::SecretKey {
         return self.sk_c.clone();
     }
@@ -362,6 +422,18 @@ impl<E: Engine> CustomerState<E> {
         return close_token.clone();
     }
 
+    pub fn get_pay_token(&self) -> Option<cl::Signature<E>> {
+        return self.pay_tokens.get(&self.index).cloned();
+    }
+
+    pub fn get_payment_transcripts(&self) -> &[PaymentTranscript<E>] {
+        return &self.transcripts;
+    }
+
+    pub fn get_last_payment_transcript(&self) -> Option<PaymentTranscript<E>> {
+        return self.transcripts.last().cloned();
+    }
+
     pub fn verify_init_close_token(
         &mut self,
         channel: &ChannelState<E>,
@@ -463,16 +535,48 @@ impl<E: Engine> CustomerState<E> {
         channel: &ChannelState<E>,
         pay_token: &Signature<E>,
     ) -> bool {
-        // unblind and verify signature
-        let cp = channel.cp.as_ref().unwrap();
-        let mpk = cp.pub_params.mpk.clone();
         // we don't want to include "close" prefix here (even if it is set)
         let wallet = self.wallet.as_fr_vec();
         //println!("verify_pay_token - Wallet: {}", &self.wallet);
+        self.unmask_pay_token(channel, &wallet, pay_token)
+    }
+
+    // for the pay token the merchant re-issues once a conditional payment has expired: it is
+    // on the balances of the expire branch, while the close token stays on the pending wallet
+    // (which settles to the same balances after the expiry)
+    pub fn pay_unmask_expired_customer(
+        &mut self,
+        channel: &ChannelState<E>,
+        pay_token: &Signature<E>,
+    ) -> bool {
+        let pending = match &self.wallet.pending {
+            Some(p) => p.clone(),
+            None => return false,
+        };
+        let mut wallet = self.wallet.clone();
+        wallet.bc += pending.amount;
+        wallet.bm -= pending.amount;
+        if !self.unmask_pay_token(channel, &wallet.as_fr_vec(), pay_token) {
+            return false;
+        }
+        self.cust_balance = wallet.bc;
+        self.merch_balance = wallet.bm;
+        return true;
+    }
+
+    fn unmask_pay_token(
+        &mut self,
+        channel: &ChannelState<E>,
+        wallet: &Vec<E::Fr>,
+        pay_token: &Signature<E>,
+    ) -> bool {
+        // unblind and verify signature
+        let cp = channel.cp.as_ref().unwrap();
+        let mpk = cp.pub_params.mpk.clone();
 
         let is_pay_valid = cp.pub_params.pk.verify_blind(
             &mpk,
-            &wallet,
+            wallet,
             &self.coms.clone().unwrap().tau,
             &pay_token,
         );
@@ -483,8 +587,15 @@ impl<E: Engine> CustomerState<E> {
                 .pk
                 .unblind(&self.coms.clone().unwrap().tau, &pay_token);
             let pk = cp.pub_params.pk.get_pub_key();
-            let is_valid = pk.verify(&mpk, &wallet, &unblind_pay_token);
+            let is_valid = pk.verify(&mpk, wallet, &unblind_pay_token);
             if is_valid {
+                // complete the transcript of the current payment round
+                if let Some(transcript) = self.transcripts.last_mut() {
+                    if transcript.wallet.nonce == self.wallet.nonce {
+                        transcript.pay_token = Some(unblind_pay_token.clone());
+                        transcript.status = PaymentStatus::Complete;
+                    }
+                }
                 self.pay_tokens.insert(self.index, unblind_pay_token);
             }
             return is_valid;
@@ -511,13 +622,80 @@ impl<E: Engine> CustomerState<E> {
         csprng: &mut R,
         channel: &ChannelState<E>,
         amount: i64,
-    ) -> (
+    ) -> ResultBoltType<(
+        NIZKProof<E>,
+        ClosedCommitments<E>,
+        FixedSizeArray16,
+        FixedSizeArray32,
+        CustomerState<E>,
+    )> {
+        self.generate_payment_internal(csprng, channel, amount, None, None)
+    }
+
+    // for a conditional channel pay (amount is pending in the new wallet until claimed)
+    pub fn generate_conditional_payment<R: Rng>(
+        &self,
+        csprng: &mut R,
+        channel: &ChannelState<E>,
+        amount: i64,
+        hash_lock: HashLock,
+    ) -> ResultBoltType<(
+        NIZKProof<E>,
+        ClosedCommitments<E>,
+        FixedSizeArray16,
+        FixedSizeArray32,
+        CustomerState<E>,
+    )> {
+        let pending = PendingAmount { amount, hash_lock };
+        self.generate_payment_internal(csprng, channel, amount, None, Some(pending))
+    }
+
+    // for channel pay with an intermediary (amount is only revealed as a commitment). With an
+    // amount_floor, the proof also shows that the amount is at least amount_floor
+    pub fn generate_hidden_payment<R: Rng>(
+        &self,
+        csprng: &mut R,
+        channel: &ChannelState<E>,
+        amount: i64,
+        amount_tau: E::Fr,
+        amount_floor: Option<i64>,
+    ) -> ResultBoltType<(
+        NIZKProof<E>,
+        ClosedCommitments<E>,
+        FixedSizeArray16,
+        FixedSizeArray32,
+        CustomerState<E>,
+    )> {
+        if let Some(floor) = amount_floor {
+            if amount < floor {
+                return Err(BoltError::new(
+                    "generate_hidden_payment - amount is below the floor to prove.",
+                ));
+            }
+        }
+        self.generate_payment_internal(
+            csprng,
+            channel,
+            amount,
+            Some((amount_tau, amount_floor)),
+            None,
+        )
+    }
+
+    fn generate_payment_internal<R: Rng>(
+        &self,
+        csprng: &mut R,
+        channel: &ChannelState<E>,
+        amount: i64,
+        hidden_amount: Option<(E::Fr, Option<i64>)>,
+        pending: Option<PendingAmount>,
+    ) -> ResultBoltType<(
         NIZKProof<E>,
         ClosedCommitments<E>,
         FixedSizeArray16,
         FixedSizeArray32,
         CustomerState<E>,
-    ) {
+    )> {
         // generate new hash lock
         let mut new_rev_secret = [0u8; 32];
         csprng.fill_bytes(&mut new_rev_secret);
@@ -545,6 +723,7 @@ impl<E: Engine> CustomerState<E> {
             rev_lock: self.wallet.rev_lock.clone(),
             bc: self.cust_balance,
             bm: self.merch_balance,
+            pending: self.wallet.pending.clone(),
         };
         let new_wallet = Wallet {
             channelId: self.wallet.channelId.clone(),
@@ -552,6 +731,7 @@ impl<E: Engine> CustomerState<E> {
             rev_lock: new_wallet_rl,
             bc: new_cust_bal,
             bm: new_merch_bal,
+            pending,
         };
 
         let new_rl_com = cp
@@ -569,19 +749,40 @@ impl<E: Engine> CustomerState<E> {
 
         // 3 - generate new blinded and randomized pay token
         let i = self.index;
-        let prev_pay_token = self.pay_tokens.get(&i).unwrap();
+        let prev_pay_token = match self.pay_tokens.get(&i) {
+            Some(t) => t,
+            None => {
+                return Err(BoltError::new(
+                    "generate_payment - no pay token for the current state.",
+                ))
+            }
+        };
         //println!("Found prev pay token: {}", prev_pay_token);
 
-        let pay_proof = cp.pub_params.prove(
-            csprng,
-            old_wallet,
-            new_wallet.clone(),
-            new_s_com.clone(),
-            new_rho,
-            new_tau,
-            new_tau_bar,
-            &prev_pay_token,
-        );
+        let pay_proof = match hidden_amount {
+            Some((tau, floor)) => cp.pub_params.prove_hidden_amount(
+                csprng,
+                old_wallet,
+                new_wallet.clone(),
+                new_s_com.clone(),
+                new_rho,
+                new_tau,
+                new_tau_bar,
+                &prev_pay_token,
+                tau,
+                floor,
+            ),
+            None => cp.pub_params.prove(
+                csprng,
+                old_wallet,
+                new_wallet.clone(),
+                new_s_com.clone(),
+                new_rho,
+                new_tau,
+                new_tau_bar,
+                &prev_pay_token,
+            ),
+        };
 
         // update internal state after proof has been verified by remote
         let new_cw = CustomerState {
@@ -611,6 +812,7 @@ impl<E: Engine> CustomerState<E> {
             pay_tokens: self.pay_tokens.clone(),
             protocol_status: self.protocol_status.clone(),
             channel_status: self.channel_status.clone(),
+            transcripts: self.transcripts.clone(),
         };
 
         let commitments = ClosedCommitments {
@@ -618,13 +820,13 @@ impl<E: Engine> CustomerState<E> {
             s_com: new_s_com,
             s_bar_com: new_s_bar_com,
         };
-        return (
+        return Ok((
             pay_proof,
             commitments,
             self.nonce.clone(),
             self.rev_lock.clone(),
             new_cw,
-        );
+        ));
     }
 
     // update the internal state of the customer wallet
@@ -653,6 +855,22 @@ impl<E: Engine> CustomerState<E> {
     ) -> ResultBoltType<(FixedSizeArray32, FixedSizeArray32)> {
         if self.verify_close_token(channel, close_token) {
             let old_wallet = self.old_kp.unwrap();
+            // record the revocation before it is released to the merchant
+            let transcript = PaymentTranscript {
+                rev_lock_pair: RevLockPair {
+                    rev_lock: old_wallet.rev_lock,
+                    rev_secret: old_wallet.rev_secret,
+                },
+                wallet: self.wallet.clone(),
+                close_token: self.get_close_token(),
+                pay_token: None,
+                status: PaymentStatus::Revoked,
+            };
+            if self.transcripts.len() >= MAX_PAYMENT_TRANSCRIPTS {
+                let excess = self.transcripts.len() + 1 - MAX_PAYMENT_TRANSCRIPTS;
+                self.transcripts.drain(..excess);
+            }
+            self.transcripts.push(transcript);
             return Ok((old_wallet.rev_lock, old_wallet.rev_secret));
         }
 
@@ -712,6 +930,22 @@ pub struct ChannelcloseM {
     pub signature: secp256k1::Signature,
 }
 
+impl ChannelcloseM {
+    pub fn hash_to_slice(&self) -> [u8; 32] {
+        merch_close_message_hash(&self.address, &self.revoke)
+    }
+}
+
+// message signed by the merchant: <dest_addr || revoke-token (DER)>
+fn merch_close_message_hash(address: &str, revoke: &Option<secp256k1::Signature>) -> [u8; 32] {
+    let mut msg = Vec::new();
+    msg.extend(address.as_bytes());
+    if let Some(r) = revoke {
+        msg.extend(r.serialize_der().to_vec());
+    }
+    sha3_hash_to_slice(&msg)
+}
+
 ///
 /// Merchant State
 ///
@@ -733,15 +967,28 @@ pub struct MerchantState<E: Engine> {
     sk: secp256k1::SecretKey,
     // sk_m
     comParams: CSMultiParams<E>,
-    pub keys: HashMap<String, String>,
-    pub unlink_nonces: HashSet<String>,
-    pub spent_nonces: HashSet<String>,
-    pub pay_tokens: HashMap<String, cl::Signature<E>>,
+    #[serde(default = "default_db_url")]
+    pub db_url: String,
+    // bookkeeping carried by states serialized before it moved to the state
+    // database: only read from old JSON and moved over by migrate_to_db()
+    #[serde(default, skip_serializing)]
+    keys: HashMap<String, String>,
+    #[serde(default, skip_serializing)]
+    unlink_nonces: HashSet<String>,
+    #[serde(default, skip_serializing)]
+    spent_nonces: HashSet<String>,
+    #[serde(default, skip_serializing)]
+    pay_tokens: HashMap<String, cl::Signature<E>>,
+}
+
+fn default_db_url() -> String {
+    "redis://127.0.0.1/".to_string()
 }
 
 impl<E: Engine> MerchantState<E> {
     pub fn new<R: Rng>(
         csprng: &mut R,
+        db_url: String,
         channel: &mut ChannelState<E>,
         id: String,
     ) -> (Self, ChannelState<E>) {
@@ -769,7 +1016,8 @@ impl<E: Engine> MerchantState<E> {
                 pk: rev_wpk,
                 sk: rev_wsk,
                 comParams: nizkParams.pubParams.comParams.clone(),
-                keys: HashMap::new(), // store rev_locks/revoke_tokens
+                db_url: db_url,
+                keys: HashMap::new(),
                 unlink_nonces: HashSet::new(),
                 spent_nonces: HashSet::new(),
                 pay_tokens: HashMap::new(),
@@ -792,6 +1040,41 @@ impl<E: Engine> MerchantState<E> {
         };
     }
 
+    ///
+    /// migrate_to_db - moves the rev_locks, nonces and pay tokens of a merchant state
+    /// serialized by an older version into the state database. Entries already in the
+    /// database are kept as is, so the migration can run every time the state is loaded.
+    ///
+    pub fn migrate_to_db(&mut self, db: &mut dyn StateDatabase) -> Result<(), String>
+    where
+        <E as pairing::Engine>::G1: serde::Serialize,
+    {
+        for (rev_lock_hex, rev_secret_hex) in self.keys.drain() {
+            if !db.check_rev_lock_map(&rev_lock_hex) {
+                db.update_rev_lock_map(&rev_lock_hex, &rev_secret_hex)?;
+            }
+        }
+        for nonce in self.unlink_nonces.drain() {
+            db.update_unlink_set(&nonce)?;
+        }
+        for nonce in self.spent_nonces.drain() {
+            if !db.check_spent_map(&nonce) {
+                db.update_spent_map(&nonce, &String::new())?;
+            }
+        }
+        for (rev_lock_hex, pay_token) in self.pay_tokens.drain() {
+            if db.get_pay_token(&rev_lock_hex).is_err() {
+                let pay_token_json = serde_json::to_string(&pay_token).unwrap();
+                db.update_pay_token_map(&rev_lock_hex, &pay_token_json)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn get_secret_key(&self) -> secp256k1::SecretKey {
+        return self.sk.clone();
+    }
+
     pub fn issue_close_token<R: Rng>(
         &self,
         csprng: &mut R,
@@ -815,6 +1098,26 @@ impl<E: Engine> MerchantState<E> {
             .sign_blind(csprng, &cp.pub_params.mpk, com.clone());
     }
 
+    // pay token on the balances of the expire branch of a conditional payment: the pending
+    // amount moves back from bm to bc in the committed wallet (bc and bm are the 4th and 5th
+    // committed values)
+    fn issue_expire_pay_token<R: Rng>(
+        &self,
+        csprng: &mut R,
+        cp: &ChannelParams<E>,
+        s_com: &Commitment<E>,
+        amount: i64,
+    ) -> Signature<E> {
+        let mut c = s_com.c.clone();
+        let mut bc = self.comParams.pub_bases[4].clone();
+        bc.mul_assign(util::convert_int_to_fr::<E>(amount));
+        c.add_assign(&bc);
+        let mut bm = self.comParams.pub_bases[5].clone();
+        bm.mul_assign(util::convert_int_to_fr::<E>(-amount));
+        c.add_assign(&bm);
+        self.issue_pay_token(csprng, cp, &Commitment { c })
+    }
+
     pub fn issue_init_close_token<R: Rng>(
         &self,
         csprng: &mut R,
@@ -831,50 +1134,159 @@ impl<E: Engine> MerchantState<E> {
         self.keypair.sign(csprng, &init_state.as_fr_vec())
     }
 
-    fn store_rev_lock_with_token(&mut self, rev_lock: &FixedSizeArray32, pay_token: Signature<E>) {
+    fn store_rev_lock_with_token(
+        &self,
+        db: &mut dyn StateDatabase,
+        rev_lock: &FixedSizeArray32,
+        pay_token: &Signature<E>,
+    ) -> ResultBoltType<()>
+    where
+        <E as pairing::Engine>::G1: serde::Serialize,
+    {
         // convert rev_lock into hex string
         let rev_lock_str = hex::encode(rev_lock.0);
-        self.pay_tokens.insert(rev_lock_str, pay_token);
+        let pay_token_json = serde_json::to_string(pay_token).unwrap();
+        match db.update_pay_token_map(&rev_lock_str, &pay_token_json) {
+            Ok(_) => Ok(()),
+            Err(e) => Err(BoltError::new(&e)),
+        }
     }
 
-    fn get_pay_token(&self, rev_lock: &[u8; 32]) -> Signature<E> {
+    fn get_pay_token(
+        &self,
+        db: &mut dyn StateDatabase,
+        rev_lock: &[u8; 32],
+    ) -> ResultBoltType<Signature<E>>
+    where
+        <E as pairing::Engine>::G1: for<'de> serde::Deserialize<'de>,
+    {
         let rev_lock_str = hex::encode(&rev_lock);
-        return self.pay_tokens.get(&rev_lock_str).unwrap().clone();
+        let pay_token_json = match db.get_pay_token(&rev_lock_str) {
+            Ok(s) => s,
+            Err(e) => return Err(BoltError::new(&e)),
+        };
+        match serde_json::from_str(&pay_token_json) {
+            Ok(t) => Ok(t),
+            Err(e) => Err(BoltError::new(&e.to_string())),
+        }
     }
 
     pub fn verify_payment<R: Rng>(
-        &mut self,
+        &self,
         csprng: &mut R,
+        db: &mut dyn StateDatabase,
         channel: &ChannelState<E>,
         proof: &NIZKProof<E>,
         coms: &ClosedCommitments<E>,
         nonce: &FixedSizeArray16,
         rev_lock: &FixedSizeArray32,
         amount: i64,
-    ) -> ResultBoltType<Signature<E>> {
-        let cp = channel.cp.as_ref().unwrap();
+    ) -> ResultBoltType<Signature<E>>
+    where
+        <E as pairing::Engine>::G1: serde::Serialize,
+    {
         let pay_proof = proof.clone();
         let prev_nonce = encode_short_bytes_to_fr::<E>(nonce.0);
         let epsilon = util::convert_int_to_fr::<E>(amount);
 
         if self.nizkParams.verify(pay_proof, epsilon, coms, prev_nonce) {
-            // 1 - proceed with generating close and pay token
-            let close_token = self.issue_close_token(csprng, cp, &coms.s_bar_com);
-            let pay_token = self.issue_pay_token(csprng, cp, &coms.s_com);
-            // let's store the pay token with the rev_lock for now
-            self.store_rev_lock_with_token(rev_lock, pay_token);
-            return Ok(close_token);
+            return self.issue_payment_tokens(csprng, db, channel, coms, rev_lock);
         }
         Err(BoltError::new(
             "verify_payment - Failed to validate NIZK PoK for payment.",
         ))
     }
 
+    pub fn verify_conditional_payment<R: Rng>(
+        &self,
+        csprng: &mut R,
+        db: &mut dyn StateDatabase,
+        channel: &ChannelState<E>,
+        proof: &NIZKProof<E>,
+        coms: &ClosedCommitments<E>,
+        nonce: &FixedSizeArray16,
+        rev_lock: &FixedSizeArray32,
+        amount: i64,
+        pending: &PendingAmount,
+    ) -> ResultBoltType<(Signature<E>, Signature<E>)>
+    where
+        <E as pairing::Engine>::G1: serde::Serialize,
+    {
+        let pay_proof = proof.clone();
+        let prev_nonce = encode_short_bytes_to_fr::<E>(nonce.0);
+        let epsilon = util::convert_int_to_fr::<E>(amount);
+
+        if self
+            .nizkParams
+            .verify_conditional(pay_proof, epsilon, coms, prev_nonce, pending)
+        {
+            let close_token = self.issue_payment_tokens(csprng, db, channel, coms, rev_lock)?;
+            let cp = channel.cp.as_ref().unwrap();
+            let expire_pay_token =
+                self.issue_expire_pay_token(csprng, cp, &coms.s_com, pending.amount);
+            return Ok((close_token, expire_pay_token));
+        }
+        Err(BoltError::new(
+            "verify_conditional_payment - Failed to validate NIZK PoK for payment.",
+        ))
+    }
+
+    // only checks the proof: an intermediary has to check both sides of a payment before it
+    // issues tokens for either one (see issue_payment_tokens())
+    pub fn verify_hidden_payment(
+        &self,
+        proof: &NIZKProof<E>,
+        coms: &ClosedCommitments<E>,
+        nonce: &FixedSizeArray16,
+        amount_com: &Commitment<E>,
+        amount_floor: Option<i64>,
+    ) -> ResultBoltType<()> {
+        let pay_proof = proof.clone();
+        let prev_nonce = encode_short_bytes_to_fr::<E>(nonce.0);
+
+        if self.nizkParams.verify_hidden_amount(
+            pay_proof,
+            amount_com,
+            amount_floor,
+            coms,
+            prev_nonce,
+        ) {
+            return Ok(());
+        }
+        Err(BoltError::new(
+            "verify_hidden_payment - Failed to validate NIZK PoK for payment.",
+        ))
+    }
+
+    pub fn issue_payment_tokens<R: Rng>(
+        &self,
+        csprng: &mut R,
+        db: &mut dyn StateDatabase,
+        channel: &ChannelState<E>,
+        coms: &ClosedCommitments<E>,
+        rev_lock: &FixedSizeArray32,
+    ) -> ResultBoltType<Signature<E>>
+    where
+        <E as pairing::Engine>::G1: serde::Serialize,
+    {
+        let cp = channel.cp.as_ref().unwrap();
+        // 1 - proceed with generating close and pay token
+        let close_token = self.issue_close_token(csprng, cp, &coms.s_bar_com);
+        let pay_token = self.issue_pay_token(csprng, cp, &coms.s_com);
+        // let's store the pay token with the rev_lock for now
+        self.store_rev_lock_with_token(db, rev_lock, &pay_token)?;
+        Ok(close_token)
+    }
+
     pub fn verify_revoke_message(
         &self,
+        db: &mut dyn StateDatabase,
         rev_lock: &FixedSizeArray32,
         rev_secret: &FixedSizeArray32,
-    ) -> ResultBoltType<Signature<E>> {
+    ) -> ResultBoltType<Signature<E>>
+    where
+        <E as pairing::Engine>::G1: for<'de> serde::Deserialize<'de>,
+    {
         let rl = rev_lock.0;
         let rs = rev_secret.0.to_vec();
         if sha3_hash_to_slice(&rs) != rl {
@@ -884,35 +1296,30 @@ impl<E: Engine> MerchantState<E> {
             ));
         }
 
-        let new_pay_token = self.get_pay_token(&rl);
-        return Ok(new_pay_token);
+        self.get_pay_token(db, &rl)
     }
 
-    // pub fn sign_revoke_message(
-    //     &self,
-    //     address: String,
-    //     revoke_token: &Option<secp256k1::Signature>,
-    // ) -> ChannelcloseM {
-    //     let secp = secp256k1::Secp256k1::signing_only();
-    //     let mut msg = Vec::new();
-    //     msg.extend(address.as_bytes());
-    //     if !revoke_token.is_none() {
-    //         let r = revoke_token.unwrap().serialize_der().to_vec();
-    //         msg.extend(r);
-    //     }
-    //     let msg2 = secp256k1::Message::from_slice(&hash_to_slice(&msg)).unwrap();
-    //     let merch_sig = secp.sign(&msg2, &self.sk);
-    //     return ChannelcloseM {
-    //         address: address.clone(),
-    //         revoke: revoke_token.clone(),
-    //         signature: merch_sig,
-    //     };
-    // }
+    pub fn sign_revoke_message(
+        &self,
+        address: String,
+        revoke_token: &Option<secp256k1::Signature>,
+    ) -> ChannelcloseM {
+        let secp = secp256k1::Secp256k1::signing_only();
+        let msg = secp256k1::Message::from_slice(&merch_close_message_hash(&address, revoke_token))
+            .unwrap();
+        let merch_sig = secp.sign(&msg, &self.sk);
+        return ChannelcloseM {
+            address: address,
+            revoke: revoke_token.clone(),
+            signature: merch_sig,
+        };
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use database::HashMapDatabase;
     use pairing::bls12_381::Bls12;
     use pairing::bn256::Bn256;
 
@@ -920,14 +1327,19 @@ mod tests {
     fn channel_util_works_with_Bls12() {
         let mut channel = ChannelState::<Bls12>::new(String::from("Channel A <-> B"), false);
         let rng = &mut rand::thread_rng();
+        let mut db = HashMapDatabase::new("", "".to_string()).unwrap();
 
         let b0_cust = 100;
         let b0_merch = 20;
         // each party executes the init algorithm on the agreed initial challenge balance
         // in order to derive the channel tokens
         // initialize on the merchant side with balance: b0_merch
-        let (mut merch_state, mut channel) =
-            MerchantState::<Bls12>::new(rng, &mut channel, String::from("Merchant B"));
+        let (mut merch_state, mut channel) = MerchantState::<Bls12>::new(
+            rng,
+            "redis://127.0.0.1/".to_string(),
+            &mut channel,
+            String::from("Merchant B"),
+        );
 
         // initialize the merchant wallet with the balance
         let mut channel_token = merch_state.init(&mut channel);
@@ -944,7 +1356,7 @@ mod tests {
 
         // first return the close token, then wait for escrow-tx confirmation
         // then send the pay-token after confirmation
-        let channelId = channel_token.compute_channel_id();
+        let channelId = channel_token.compute_channel_id().unwrap();
         assert_eq!(channelId, cust_state.get_wallet().channelId);
         let close_token = merch_state.issue_init_close_token(rng, &cust_state.get_wallet());
         // unblind tokens and verify signatures
@@ -958,12 +1370,13 @@ mod tests {
         // pay protocol tests
         let amount = 10;
         let (pay_proof, new_com, old_nonce, old_rev_lock, new_cw) =
-            cust_state.generate_payment(rng, &channel, amount);
+            cust_state.generate_payment(rng, &channel, amount).unwrap();
 
         // new pay_token is not sent until revoke_token is obtained from the customer
         let new_close_token = merch_state
             .verify_payment(
                 rng,
+                &mut db,
                 &channel,
                 &pay_proof,
                 &new_com,
@@ -992,7 +1405,7 @@ mod tests {
         //println!("5 - Revoke token => {}", revoke_token);
 
         let new_pay_token = merch_state
-            .verify_revoke_message(&rev_lock, &rev_secret)
+            .verify_revoke_message(&mut db, &rev_lock, &rev_secret)
             .unwrap();
         assert!(cust_state.pay_unmask_customer(&channel, &new_pay_token));
 
@@ -1000,33 +1413,42 @@ mod tests {
     }
 
     #[test]
-    #[should_panic(expected = "pk_c is not initialized yet")]
-    fn compute_channel_id_panics() {
+    fn compute_channel_id_requires_pk_c() {
         let mut channel = ChannelState::<Bls12>::new(String::from("Channel A <-> B"), false);
         let rng = &mut rand::thread_rng();
 
         // initialize on the merchant side with balance: b0_merch
-        let (mut merch_state, mut channel) =
-            MerchantState::<Bls12>::new(rng, &mut channel, String::from("Merchant B"));
+        let (mut merch_state, mut channel) = MerchantState::<Bls12>::new(
+            rng,
+            "redis://127.0.0.1/".to_string(),
+            &mut channel,
+            String::from("Merchant B"),
+        );
 
         // initialize the merchant wallet with the balance
         let channel_token = merch_state.init(&mut channel);
 
-        let _channelId = channel_token.compute_channel_id();
+        let err = channel_token.compute_channel_id().unwrap_err();
+        assert_eq!(err, "pk_c is not initialized yet");
     }
 
     #[test]
     fn channel_util_works_with_Bn256() {
         let mut channel = ChannelState::<Bn256>::new(String::from("Channel A <-> B"), false);
         let rng = &mut rand::thread_rng();
+        let mut db = HashMapDatabase::new("", "".to_string()).unwrap();
 
         let b0_cust = 100;
         let b0_merch = 20;
         // each party executes the init algorithm on the agreed initial challenge balance
         // in order to derive the channel tokens
         // initialize on the merchant side with balance: b0_merch
-        let (mut merch_state, mut channel) =
-            MerchantState::<Bn256>::new(rng, &mut channel, String::from("Merchant B"));
+        let (mut merch_state, mut channel) = MerchantState::<Bn256>::new(
+            rng,
+            "redis://127.0.0.1/".to_string(),
+            &mut channel,
+            String::from("Merchant B"),
+        );
 
         // initialize the merchant wallet with the balance
         let mut channel_token = merch_state.init(&mut channel);
@@ -1043,7 +1465,7 @@ mod tests {
 
         // first return the close token, then wait for escrow-tx confirmation
         // then send the pay-token after confirmation
-        let channelId = channel_token.compute_channel_id();
+        let channelId = channel_token.compute_channel_id().unwrap();
         assert_eq!(channelId, cust_state.get_wallet().channelId);
         let close_token = merch_state.issue_init_close_token(rng, &cust_state.get_wallet());
         // unblind tokens and verify signatures
@@ -1055,12 +1477,13 @@ mod tests {
         // pay protocol tests
         let amount = 10;
         let (pay_proof, new_com, old_nonce, old_rev_lock, new_cw) =
-            cust_state.generate_payment(rng, &channel, amount);
+            cust_state.generate_payment(rng, &channel, amount).unwrap();
 
         // new pay_token is not sent until revoke_token is obtained from the customer
         let new_close_token = merch_state
             .verify_payment(
                 rng,
+                &mut db,
                 &channel,
                 &pay_proof,
                 &new_com,
@@ -1089,7 +1512,7 @@ mod tests {
         //println!("5 - Revoke token => {}", revoke_token);
 
         let new_pay_token = merch_state
-            .verify_revoke_message(&rev_lock, &rev_secret)
+            .verify_revoke_message(&mut db, &rev_lock, &rev_secret)
             .unwrap();
         assert!(cust_state.pay_unmask_customer(&channel, &new_pay_token));
 
@@ -1,6 +1,5 @@
 extern crate bufstream;
 extern crate confy;
-extern crate libc;
 extern crate rand;
 extern crate redis;
 extern crate secp256k1;
@@ -9,14 +8,13 @@ extern crate sha2;
 extern crate structopt;
 extern crate time;
 extern crate zkchan_tx;
+#[macro_use]
 extern crate zkchannels;
 
 use bufstream::BufStream;
-use libc::{c_int, c_void};
 use rand::Rng;
 use redis::Commands;
 use serde::{Deserialize, Serialize};
-use std::ffi::CString;
 use std::fs::File;
 use std::io::{BufRead, Read, Write};
 use std::net::{SocketAddr, TcpListener, TcpStream};
@@ -27,33 +25,18 @@ use std::thread::sleep;
 use std::time::Duration;
 use std::time::Instant;
 use structopt::StructOpt;
-use zkchan_tx::Testnet;
-use zkchannels::bindings::Receive_return;
+use zkchan_tx::transactions::btc::merchant_form_close_transaction;
+use zkchan_tx::BitcoinNetwork;
+use zkchannels::channels_util::{BitcoinNetworkType, MpcSecurity};
 use zkchannels::database::create_db_connection;
 use zkchannels::database::{RedisDatabase, StateDatabase};
 use zkchannels::mpc;
+use zkchannels::socks5;
+use zkchannels::util::get_unix_time;
 use zkchannels::FundingTxInfo;
 
 static TX_FEE_INFO_KEY: &str = "tx_fee_info";
 
-extern "C" fn cb_send_data(_data: *mut c_void, _len: c_int, _peer: *mut c_void) -> *mut i8 {
-    println!("Sending some data!");
-    return ptr::null_mut();
-}
-
-extern "C" fn cb_recv_data(_peer: *mut c_void) -> Receive_return {
-    println!("Receiving some data..");
-    let data_str = String::from("some data");
-    let data = CString::new("some data").unwrap().into_raw();
-    let err = CString::new("none").unwrap().into_raw();
-    let r = Receive_return {
-        r0: data,
-        r1: data_str.len() as i32,
-        r2: err,
-    };
-    return r;
-}
-
 macro_rules! measure_one_arg {
     ($x: expr) => {{
         let s = Instant::now();
@@ -101,7 +84,7 @@ macro_rules! print_error_result {
 
 macro_rules! create_connection {
     ($e: expr) => {
-        &mut Conn::new($e.own_ip, $e.own_port, $e.other_ip, $e.other_port)
+        &mut Conn::new($e.own_ip, $e.own_port, $e.other_ip, $e.other_port, $e.proxy)
     };
 }
 
@@ -109,6 +92,9 @@ macro_rules! create_connection {
 pub struct ZKChannelConfig {
     version: u8,
     db_url: String,
+    // merchant accepts refunds that come with a voucher it issued
+    #[serde(default)]
+    accept_refunds: bool,
     // set_fees: bool,
 }
 
@@ -117,10 +103,20 @@ impl Default for ZKChannelConfig {
         ZKChannelConfig {
             version: 0,
             db_url: "redis://127.0.0.1/".to_string(),
+            accept_refunds: false,
             // set_fees: false,
         }
     }
 }
+
+impl ZKChannelConfig {
+    fn refund_policy(&self) -> mpc::NegativePaymentPolicy {
+        match self.accept_refunds {
+            true => mpc::NegativePaymentPolicy::CHECK_JUSTIFICATION,
+            false => mpc::NegativePaymentPolicy::REJECT,
+        }
+    }
+}
 #[derive(Clone, Debug, Deserialize)]
 enum Party {
     MERCH,
@@ -168,10 +164,18 @@ pub struct Open {
     other_ip: String,
     #[structopt(short = "q", long = "other-port")]
     other_port: String,
+    #[structopt(long = "proxy")]
+    proxy: Option<String>,
     #[structopt(short = "b", long = "self-delay", default_value = "1487")]
     self_delay: u16,
     #[structopt(short = "n", long = "channel-name", default_value = "")]
     channel_name: String,
+    #[structopt(long = "anchor-outputs")]
+    anchor_outputs: bool,
+    #[structopt(long = "network", default_value = "testnet")]
+    network: BitcoinNetworkType,
+    #[structopt(long = "mpc-security")]
+    mpc_security: Option<MpcSecurity>,
 }
 
 #[derive(Clone, Debug, StructOpt, Deserialize)]
@@ -194,6 +198,8 @@ pub struct Init {
     other_ip: String,
     #[structopt(short = "q", long = "other-port")]
     other_port: String,
+    #[structopt(long = "proxy")]
+    proxy: Option<String>,
     #[structopt(short = "f", long = "tx-fee", default_value = "1000")]
     tx_fee: i64,
     #[structopt(short = "n", long = "channel-name", default_value = "")]
@@ -214,6 +220,8 @@ pub struct Activate {
     other_ip: String,
     #[structopt(short = "q", long = "other-port")]
     other_port: String,
+    #[structopt(long = "proxy")]
+    proxy: Option<String>,
     #[structopt(short = "n", long = "channel-name", default_value = "")]
     channel_name: String,
     #[structopt(short = "b")]
@@ -234,14 +242,30 @@ pub struct Pay {
     other_ip: String,
     #[structopt(short = "q", long = "other-port")]
     other_port: String,
+    #[structopt(long = "proxy")]
+    proxy: Option<String>,
     #[structopt(short = "n", long = "channel-name", default_value = "")]
     channel_name: String,
+    #[structopt(long = "voucher")]
+    voucher: Option<PathBuf>,
     #[structopt(short = "b")]
     benchmark: bool,
     #[structopt(short)]
     verbose: bool,
 }
 
+#[derive(Clone, Debug, StructOpt, Deserialize)]
+pub struct Voucher {
+    #[structopt(short = "n", long = "nonce")]
+    nonce: String,
+    #[structopt(short = "a", long = "amount", allow_hyphen_values = true)]
+    amount: i64,
+    #[structopt(short = "e", long = "expiry", default_value = "86400")]
+    expiry: i64,
+    #[structopt(short = "f", long = "file")]
+    file: PathBuf,
+}
+
 #[derive(Clone, Debug, StructOpt, Deserialize)]
 pub struct Close {
     #[structopt(long = "party")]
@@ -270,6 +294,8 @@ pub enum Command {
     PAY(Pay),
     #[structopt(name = "close")] // for generating closing txs
     CLOSE(Close),
+    #[structopt(name = "voucher")] // for issuing refund vouchers (merchant only)
+    VOUCHER(Voucher),
 }
 
 impl FromStr for Command {
@@ -319,6 +345,18 @@ pub fn write_pathfile(path_buf: PathBuf, content: String) -> Result<(), String>
     }
 }
 
+pub fn read_pathfile(path_buf: PathBuf) -> Result<String, String> {
+    let mut file = match File::open(path_buf) {
+        Ok(n) => n,
+        Err(e) => return Err(e.to_string()),
+    };
+    let mut content = String::new();
+    match file.read_to_string(&mut content) {
+        Ok(_) => Ok(content),
+        Err(e) => return Err(e.to_string()),
+    }
+}
+
 pub fn generate_keypair<R: Rng>(csprng: &mut R) -> (secp256k1::PublicKey, secp256k1::SecretKey) {
     let secp = secp256k1::Secp256k1::new();
 
@@ -367,6 +405,38 @@ pub fn get_file_from_db(
     }
 }
 
+// returns (merch_tx_preimage, merch_txid, merch_prevout) for the merch-close-tx
+fn form_merch_close_tx<N: BitcoinNetwork>(
+    escrow_txid: Vec<u8>,
+    cust_pk: Vec<u8>,
+    merch_pk: Vec<u8>,
+    merch_close_pk: Vec<u8>,
+    merch_child_pk: Vec<u8>,
+    cust_bal: i64,
+    merch_bal: i64,
+    fee_mc: i64,
+    val_cpfp: i64,
+    to_self_delay_be: [u8; 2],
+) -> Result<(Vec<u8>, [u8; 32], [u8; 32]), String> {
+    let (merch_tx_preimage, tx_params) =
+        handle_error_result!(merchant_form_close_transaction::<N>(
+            escrow_txid,
+            cust_pk,
+            merch_pk,
+            merch_close_pk,
+            merch_child_pk,
+            cust_bal,
+            merch_bal,
+            fee_mc,
+            val_cpfp,
+            to_self_delay_be
+        ));
+    let (merch_txid, merch_prevout) = handle_error_result!(
+        zkchan_tx::txutil::merchant_generate_transaction_id(tx_params)
+    );
+    Ok((merch_tx_preimage, merch_txid, merch_prevout))
+}
+
 fn get_tx_fee_info() -> mpc::TransactionFeeInfo {
     let min_threshold = 546; // dust limit
     let tx_fee_info = mpc::TransactionFeeInfo {
@@ -428,32 +498,53 @@ struct Cli {
 
 pub struct Conn {
     pub in_addr: SocketAddr,
-    pub out_addr: SocketAddr,
+    pub out_addr: String,
     pub own_port: i32,
+    pub other_ip: String,
     pub other_port: i32,
+    pub proxy: Option<String>,
 }
 
 impl Conn {
-    pub fn new(own_ip: String, own_port: String, other_ip: String, other_port: String) -> Conn {
+    pub fn new(
+        own_ip: String,
+        own_port: String,
+        other_ip: String,
+        other_port: String,
+        proxy: Option<String>,
+    ) -> Conn {
         let in_addr = own_ip + ":" + own_port.as_ref();
         let in_addr_sock = SocketAddr::from_str(in_addr.as_ref()).unwrap();
         let own_p = own_port.parse().unwrap_or(0);
 
-        let out_addr = other_ip + ":" + other_port.as_ref();
-        let out_addr_sock = SocketAddr::from_str(out_addr.as_ref()).unwrap();
+        // with a proxy the other party may be a hostname (e.g. an onion service)
+        // that is only resolved by the proxy, so only validate direct addresses
+        let out_addr = other_ip.clone() + ":" + other_port.as_ref();
+        if proxy.is_none() {
+            SocketAddr::from_str(out_addr.as_ref()).unwrap();
+        }
         let other_p = other_port.parse().unwrap_or(0);
 
         Conn {
             in_addr: in_addr_sock,
-            out_addr: out_addr_sock,
+            out_addr,
             own_port: own_p,
+            other_ip,
             other_port: other_p,
+            proxy,
+        }
+    }
+
+    fn connect(&self) -> Result<TcpStream, String> {
+        match &self.proxy {
+            Some(proxy) => socks5::connect(proxy, &self.other_ip, self.other_port as u16),
+            None => TcpStream::connect(&self.out_addr).map_err(|e| e.to_string()),
         }
     }
 
     pub fn send(&mut self, msg: &[String]) {
         for i in 1..6 {
-            match TcpStream::connect(self.out_addr) {
+            match self.connect() {
                 Ok(stream) => {
                     let mut buf_stream = BufStream::new(stream);
                     for msg0 in msg {
@@ -539,7 +630,15 @@ fn main() -> Result<(), confy::ConfyError> {
         }
         Command::OPEN(open) => match open.party {
             Party::MERCH => {
-                match merch::open(&cfg, create_connection!(open), &db_url, open.self_delay) {
+                match merch::open(
+                    &cfg,
+                    create_connection!(open),
+                    &db_url,
+                    open.self_delay,
+                    open.anchor_outputs,
+                    open.network,
+                    open.mpc_security,
+                ) {
                     Err(e) => println!("Channel opening phase failed with error: {}", e),
                     _ => (),
                 }
@@ -551,6 +650,7 @@ fn main() -> Result<(), confy::ConfyError> {
                     open.cust_bal,
                     open.merch_bal,
                     open.channel_name,
+                    open.network,
                 ) {
                     Err(e) => println!("Channel opening phase failed with error: {}", e),
                     _ => (),
@@ -602,6 +702,7 @@ fn main() -> Result<(), confy::ConfyError> {
             Party::MERCH => {
                 let (mut channel_state, mut merch_state) =
                     merch::load_merchant_state_info(&db_url).unwrap();
+                merch_state.set_refund_policy(cfg.refund_policy());
                 let result = merch::pay(
                     Some(0),
                     create_connection!(unlink),
@@ -620,6 +721,7 @@ fn main() -> Result<(), confy::ConfyError> {
                     create_connection!(unlink),
                     &db_url,
                     unlink.channel_name,
+                    None,
                     unlink.verbose,
                 );
                 if unlink.benchmark {
@@ -638,6 +740,7 @@ fn main() -> Result<(), confy::ConfyError> {
             Party::MERCH => {
                 let (mut channel_state, mut merch_state) =
                     merch::load_merchant_state_info(&db_url).unwrap();
+                merch_state.set_refund_policy(cfg.refund_policy());
                 loop {
                     let result = merch::pay(
                         pay.amount.clone(),
@@ -661,6 +764,7 @@ fn main() -> Result<(), confy::ConfyError> {
                     create_connection!(pay),
                     &db_url,
                     pay.channel_name,
+                    pay.voucher,
                     pay.verbose,
                 );
                 if pay.benchmark {
@@ -683,6 +787,14 @@ fn main() -> Result<(), confy::ConfyError> {
                 close.channel_id
             )),
         },
+        Command::VOUCHER(voucher) => print_error_result!(merch::issue_refund_voucher(
+            &cfg,
+            &db_url,
+            voucher.nonce,
+            voucher.amount,
+            voucher.expiry,
+            voucher.file
+        )),
     }
 
     println!("******************************************");
@@ -693,12 +805,11 @@ mod cust {
     use super::*;
     use std::ptr;
     use zkchan_tx::fixed_size_array::FixedSizeArray32;
-    use zkchan_tx::transactions::btc::merchant_form_close_transaction;
     use zkchan_tx::txutil::{
         customer_form_escrow_transaction, customer_sign_escrow_transaction,
         customer_sign_merch_close_transaction,
     };
-    use zkchannels::bindings::ConnType_NETIO;
+    use zkchannels::bindings::{ConnType_NETIO, ConnType_TORNETIO};
     use zkchannels::channels_mpc::{
         ChannelMPCState, ChannelMPCToken, CustomerMPCState, NetworkConfig,
     };
@@ -710,6 +821,7 @@ mod cust {
         b0_cust: i64,
         b0_merch: i64,
         channel_name: String,
+        network: BitcoinNetworkType,
     ) -> Result<(), String> {
         if channel_name == "" {
             return Err(String::from("missing channel-name"));
@@ -725,6 +837,16 @@ mod cust {
         let channel_state: ChannelMPCState = serde_json::from_str(&msg0.get(0).unwrap()).unwrap();
         let pk_m: secp256k1::PublicKey = serde_json::from_str(&msg0.get(1).unwrap()).unwrap();
 
+        if channel_state.get_network() != network {
+            return Err(format!(
+                "merchant channel is on {}, expected {}",
+                channel_state.get_network(),
+                network
+            ));
+        }
+        channel_state.validate_mpc_security()?;
+        channel_state.validate_circuit_hash()?;
+
         // check cust-bal meets min bal
         let cust_min_bal =
             tx_fee_info.fee_cc + channel_state.get_bal_min_cust() + channel_state.get_val_cpfp();
@@ -746,6 +868,7 @@ mod cust {
             b0_merch,
             &tx_fee_info,
             channel_name.as_str(),
+            network,
         );
 
         println!("Saving the initial customer state...");
@@ -833,8 +956,8 @@ mod cust {
         let merch_bal = cust_state.merch_balance;
         let merch_close_pk = channel_state.merch_payout_pk.unwrap().serialize().to_vec();
         let merch_child_pk = channel_state.merch_child_pk.unwrap().serialize().to_vec();
-        let (merch_tx_preimage, _) =
-            handle_error_result!(merchant_form_close_transaction::<Testnet>(
+        let (merch_tx_preimage, _, _) = with_bitcoin_network!(channel_state.get_network(), N => {
+            form_merch_close_tx::<N>(
                 escrow_txid_be.to_vec(),
                 cust_pk.clone(),
                 merch_pk.clone(),
@@ -844,8 +967,9 @@ mod cust {
                 merch_bal,
                 tx_fee_info.fee_mc,
                 channel_state.get_val_cpfp(),
-                to_self_delay_be
-            ));
+                to_self_delay_be,
+            )?
+        });
 
         // get the cust-sig on the merch-close-tx
         let cust_sig = handle_error_result!(customer_sign_merch_close_transaction(
@@ -886,12 +1010,15 @@ mod cust {
 
         // now sign the customer's initial closing txs
         println!("Signing the initial closing transactions...");
-        let got_close_tx = match cust_state.sign_initial_closing_transaction::<Testnet>(
-            &channel_state,
-            &channel_token,
-            &escrow_sig,
-            &merch_sig,
-        ) {
+        let sign_result = with_bitcoin_network!(channel_state.get_network(), N => {
+            cust_state.sign_initial_closing_transaction::<N>(
+                &channel_state,
+                &channel_token,
+                &escrow_sig,
+                &merch_sig,
+            )
+        });
+        let got_close_tx = match sign_result {
             Ok(n) => n,
             Err(e) => return Err(e.to_string()),
         };
@@ -991,10 +1118,22 @@ mod cust {
         conn: &mut Conn,
         db_url: &String,
         channel_name: String,
+        voucher_file: Option<PathBuf>,
         verbose: bool,
     ) -> Result<u128, String> {
         let rng = &mut rand::thread_rng();
         let mut db_conn = handle_error_result!(create_db_connection(db_url.clone()));
+
+        // a refund (negative payment) has to be authorized by a voucher from the merchant
+        let ser_voucher = match voucher_file {
+            Some(f) => read_pathfile(f)?.trim().to_string(),
+            None => String::new(),
+        };
+        if amount < 0 && ser_voucher.is_empty() {
+            return Err(String::from(
+                "A refund voucher from the merchant is required for negative payments",
+            ));
+        }
         let key = format!("id:{}", channel_name);
 
         // load the channel state from DB
@@ -1047,7 +1186,13 @@ mod cust {
         let rev_lock_com_str = hex::encode(&rev_lock_com);
         let old_nonce_str = hex::encode(&old_state.get_nonce());
 
-        let msg = [session_id_str, old_nonce_str, rev_lock_com_str, amount_str];
+        let msg = [
+            session_id_str,
+            old_nonce_str,
+            rev_lock_com_str,
+            amount_str,
+            ser_voucher,
+        ];
         let msg1 = conn.send_and_wait(
             &msg,
             Some(String::from("amount, nonce and rev_lock com")),
@@ -1057,11 +1202,22 @@ mod cust {
         let mut pay_token_mask_com = [0u8; 32];
         pay_token_mask_com.copy_from_slice(pay_token_mask_com_vec.as_slice());
 
-        let nc = NetworkConfig {
-            conn_type: ConnType_NETIO,
-            path: String::new(),
-            dest_ip: String::from("127.0.0.1"),
-            dest_port: conn.own_port,
+        // when talking to the merchant through a proxy, route the mpc through it as well
+        let nc = match &conn.proxy {
+            Some(proxy) => NetworkConfig {
+                conn_type: ConnType_TORNETIO,
+                path: String::new(),
+                dest_ip: conn.other_ip.clone(),
+                dest_port: conn.own_port,
+                proxy: Some(proxy.clone()),
+            },
+            None => NetworkConfig {
+                conn_type: ConnType_NETIO,
+                path: String::new(),
+                dest_ip: String::from("127.0.0.1"),
+                dest_port: conn.own_port,
+                proxy: None,
+            },
         };
         cust_state.set_network_config(nc);
 
@@ -1076,8 +1232,8 @@ mod cust {
             amount,
             &mut cust_state,
             ptr::null_mut(),
-            Some(cb_send_data),
-            Some(cb_recv_data),
+            None,
+            None,
         ) {
             Ok(n) => n,
             Err(e) => return Err(e.to_string()),
@@ -1124,6 +1280,13 @@ mod cust {
             true => println!("Transaction succeeded!"),
             false => println!("Transaction failed!"),
         }
+        if verbose {
+            // the merchant needs this nonce to issue a refund voucher for the next payment
+            println!(
+                "Nonce of the current state: {}",
+                hex::encode(&cust_state.get_current_state().get_nonce())
+            );
+        }
 
         let rc = cust_save_state_in_db(
             &mut db_conn,
@@ -1219,7 +1382,6 @@ mod merch {
     use super::*;
     use std::ptr;
     use zkchan_tx::fixed_size_array::FixedSizeArray32;
-    use zkchan_tx::transactions::btc::merchant_form_close_transaction;
     use zkchannels::bindings::ConnType_NETIO;
     use zkchannels::channels_mpc::{
         ChannelMPCState, ChannelMPCToken, InitCustState, MerchantMPCState, NetworkConfig,
@@ -1231,10 +1393,13 @@ mod merch {
     static CHANNEL_STATE_KEY: &str = "channel_state";
 
     pub fn open(
-        _cfg: &ZKChannelConfig,
+        cfg: &ZKChannelConfig,
         conn: &mut Conn,
         db_url: &String,
         self_delay: u16,
+        anchor_outputs: bool,
+        network: BitcoinNetworkType,
+        mpc_security: Option<MpcSecurity>,
     ) -> Result<(), String> {
         let merch_state_info = load_merchant_state_info(&db_url);
         let tx_fee_info = get_tx_fee_info();
@@ -1255,9 +1420,15 @@ mod merch {
                     let s = format!("Dust limit must be greater than 0!");
                     return Err(s);
                 }
+                channel_state.set_anchor_outputs(anchor_outputs)?;
+                channel_state.set_network(network);
+                if let Some(m) = mpc_security {
+                    channel_state.set_mpc_security(m)?;
+                }
 
-                let merch_state =
+                let mut merch_state =
                     mpc::init_merchant(rng, db_url.clone(), &mut channel_state, "Merchant");
+                merch_state.set_refund_policy(cfg.refund_policy());
 
                 let mut db = handle_error_result!(get_merch_db_connection(db_url.clone()));
 
@@ -1265,7 +1436,16 @@ mod merch {
 
                 (channel_state, merch_state)
             }
-            Ok(n) => (n.0, n.1),
+            Ok(n) => {
+                if n.0.get_network() != network {
+                    return Err(format!(
+                        "existing merchant channel state is on {}, not {}",
+                        n.0.get_network(),
+                        network
+                    ));
+                }
+                (n.0, n.1)
+            }
         };
 
         // send initial channel info
@@ -1322,19 +1502,21 @@ mod merch {
         let merch_bal = init_cust_state.merch_bal;
 
         // form the merch-close-tx
-        let (merch_tx_preimage, tx_params) =
-            handle_error_result!(merchant_form_close_transaction::<Testnet>(
-                escrow_txid.to_vec(),
-                cust_pk.clone(),
-                merch_pk,
-                merch_close_pk,
-                merch_child_pk,
-                cust_bal,
-                merch_bal,
-                tx_fee_info.fee_mc,
-                channel_state.get_val_cpfp(),
-                to_self_delay_be
-            ));
+        let (merch_tx_preimage, merch_txid, merch_prevout) =
+            with_bitcoin_network!(channel_state.get_network(), N => {
+                form_merch_close_tx::<N>(
+                    escrow_txid.to_vec(),
+                    cust_pk.clone(),
+                    merch_pk,
+                    merch_close_pk,
+                    merch_child_pk,
+                    cust_bal,
+                    merch_bal,
+                    tx_fee_info.fee_mc,
+                    channel_state.get_val_cpfp(),
+                    to_self_delay_be,
+                )?
+            });
 
         // verify merch signature from customer
         let is_ok =
@@ -1355,10 +1537,6 @@ mod merch {
             );
         }
 
-        let (merch_txid, merch_prevout) = handle_error_result!(
-            zkchan_tx::txutil::merchant_generate_transaction_id(tx_params)
-        );
-
         // construct the funding tx info given info available
         let funding_tx = FundingTxInfo {
             init_cust_bal: cust_bal,
@@ -1372,16 +1550,18 @@ mod merch {
         // now proceed to sign the cust-close transactions (escrow + merch-close-tx)
         // Uncomment for verbose/debugging
         // println!("Signing customer's initial closing tx...");
-        let (escrow_sig, merch_sig) = merch_state.sign_initial_closing_transaction::<Testnet>(
-            funding_tx,
-            rev_lock,
-            cust_pk,
-            cust_close_pk,
-            to_self_delay_be,
-            tx_fee_info.fee_cc,
-            tx_fee_info.fee_mc,
-            tx_fee_info.val_cpfp,
-        )?;
+        let (escrow_sig, merch_sig) = with_bitcoin_network!(channel_state.get_network(), N => {
+            merch_state.sign_initial_closing_transaction::<N>(
+                funding_tx,
+                rev_lock,
+                cust_pk,
+                cust_close_pk,
+                to_self_delay_be,
+                tx_fee_info.fee_cc,
+                tx_fee_info.fee_mc,
+                tx_fee_info.val_cpfp,
+            )?
+        });
 
         let msg3 = [
             handle_error_result!(serde_json::to_string(&merch_txid)),
@@ -1520,7 +1700,7 @@ mod merch {
         // );
 
         let justification = match amount < 0 {
-            true => Some(format!("empty-sig")),
+            true => msg0.get(4).cloned(),
             false => None,
         };
 
@@ -1544,6 +1724,7 @@ mod merch {
             path: String::new(),
             dest_ip: String::from("127.0.0.1"),
             dest_port: conn.other_port,
+            proxy: None,
         };
         merch_state.set_network_config(nc);
 
@@ -1556,8 +1737,8 @@ mod merch {
             pay_token_mask_com,
             merch_state,
             ptr::null_mut(),
-            Some(cb_send_data),
-            Some(cb_recv_data),
+            None,
+            None,
         ));
 
         // confirm customer got mpc output
@@ -1659,6 +1840,42 @@ mod merch {
         }
     }
 
+    pub fn issue_refund_voucher(
+        cfg: &ZKChannelConfig,
+        db_url: &String,
+        nonce: String,
+        amount: i64,
+        expiry: i64,
+        out_file: PathBuf,
+    ) -> Result<(), String> {
+        let rng = &mut rand::thread_rng();
+        if cfg.refund_policy() == mpc::NegativePaymentPolicy::REJECT {
+            return Err(String::from(
+                "Refunds are rejected: set accept_refunds = true in the zkchannel_cfg config",
+            ));
+        }
+        let (_, merch_state) = load_merchant_state_info(db_url)?;
+
+        let nonce_vec = handle_error_result!(hex::decode(nonce));
+        if nonce_vec.len() != 16 {
+            return Err(String::from("The nonce must be 16 bytes long"));
+        }
+        let mut nonce_ar = [0u8; 16];
+        nonce_ar.copy_from_slice(nonce_vec.as_slice());
+        let voucher = mpc::merchant_issue_refund_voucher(
+            rng,
+            nonce_ar,
+            amount,
+            get_unix_time() + expiry,
+            &merch_state,
+        )?;
+
+        let voucher_json = handle_error_result!(serde_json::to_string(&voucher));
+        write_pathfile(out_file, voucher_json)?;
+        println!("Issued refund voucher for {}", amount);
+        Ok(())
+    }
+
     pub fn close(db_url: &String, out_file: PathBuf, channel_id: String) -> Result<(), String> {
         // output the merch-close-tx (only thing merchant can broadcast to close channel)
         let mut db = handle_error_result!(get_merch_db_connection(db_url.clone()));
@@ -1702,6 +1919,8 @@ mod merch {
         ));
         write_pathfile(out_file, hex::encode(merch_close_tx))?;
         println!("merch-close-tx signed txid: {}", hex::encode(txid_be));
+        // vouchers that have expired can no longer be presented
+        db.prune_spent_voucher_map(get_unix_time())?;
         Ok(())
     }
 }
@@ -248,13 +248,15 @@ pub struct Close {
     #[structopt(short = "c", long = "cust-close")]
     cust_close: Option<PathBuf>,
     #[structopt(short = "f", long = "file")]
-    file: PathBuf,
+    file: Option<PathBuf>,
     #[structopt(short = "e", long = "from-merch")]
     from_merch_close: bool,
     #[structopt(short = "n", long = "channel-name", default_value = "")]
     channel_name: String,
     #[structopt(short = "d", long = "decompress-cust-close")]
     decompress: bool,
+    #[structopt(short = "m", long = "mutual")]
+    mutual: bool,
 }
 
 #[derive(Clone, Debug, StructOpt, Deserialize)]
@@ -642,6 +644,18 @@ fn main() -> Result<(), confy::ConfyError> {
             }
         },
         Command::CLOSE(close) => match close.party {
+            Party::MERCH if close.mutual => print_error_result!(merch::mutual_close(
+                &db_url,
+                close.cust_close,
+                close.file,
+                close.channel_name
+            )),
+            Party::CUST if close.mutual => print_error_result!(cust::mutual_close(
+                &db_url,
+                close.cust_close,
+                close.file,
+                close.channel_name
+            )),
             Party::MERCH => print_error_result!(merch::close(
                 &db_url,
                 close.cust_close,
@@ -666,6 +680,7 @@ mod cust {
     use super::*;
     use pairing::bls12_381::Bls12;
     use zkchannels::channels_zk::{ChannelState, ChannelToken, CustomerState};
+    use zkchannels::zkproofs::ChannelMutualClose;
 
     pub fn open(
         conn: &mut Conn,
@@ -751,7 +766,7 @@ mod cust {
             handle_error_result!(serde_json::from_str(&ser_channel_token));
 
         // println!("Channel token: {}", &channel_token.compute_channel_id());
-        let chan_id = channel_token.compute_channel_id();
+        let chan_id = handle_error_result!(channel_token.compute_channel_id());
 
         // now sign the customer's initial closing txs
         log!("Verified the closing token...", true);
@@ -909,8 +924,9 @@ mod cust {
         let mut cust_state: CustomerState<Bls12> =
             handle_error_result!(serde_json::from_str(&ser_cust_state));
 
-        let (session_id, unlink_payment, unlinked_cust_state) =
-            zkproofs::unlink::customer_update_state(rng, &channel_state, &cust_state);
+        let (session_id, unlink_payment, unlinked_cust_state) = handle_error_result!(
+            zkproofs::unlink::customer_update_state(rng, &channel_state, &cust_state)
+        );
 
         // send to merchant
         let session_id_str = hex::encode(&session_id);
@@ -1015,8 +1031,12 @@ mod cust {
         }
 
         // step 2 - customer update state
-        let (payment, new_cust_state) =
-            zkproofs::pay::customer_update_state(rng, &channel_state, &cust_state, amount);
+        let (payment, new_cust_state) = handle_error_result!(zkproofs::pay::customer_update_state(
+            rng,
+            &channel_state,
+            &cust_state,
+            amount
+        ));
 
         // send to merchant
         let payment_str = handle_error_result!(serde_json::to_string(&payment));
@@ -1088,11 +1108,15 @@ mod cust {
 
     pub fn close(
         db_url: &String,
-        out_file: PathBuf,
+        out_file: Option<PathBuf>,
         from_merch_close: bool,
         channel_name: String,
         decompress_cust_close: bool,
     ) -> Result<(), String> {
+        let out_file = match out_file {
+            Some(f) => f,
+            None => return Err(String::from("missing the output file (--file)")),
+        };
         let mut db_conn = handle_error_result!(create_db_connection(db_url.clone()));
         let key = format!("id:{}", channel_name);
 
@@ -1162,12 +1186,25 @@ mod cust {
             let mut close_fixed_vec = hex::decode(close_fixed[2..].to_string()).unwrap();
             close_fixed_vec.reverse();
 
+            message_map.insert("version", cust_close.version.to_string());
             message_map.insert("channel_id", hex::encode(&channel_id_vec));
             message_map.insert("rev_lock", hex::encode(&rev_lock_vec));
             message_map.insert("cust_bal", cust_close.message.bc.to_string());
             message_map.insert("merch_bal", cust_close.message.bm.to_string());
             message_map.insert("close", hex::encode(&close_fixed_vec));
 
+            // the customer signs the versioned close message hash
+            let cust_close_hash = zkproofs::cust_close_message::<Bls12>(
+                &cust_close.message,
+                &cust_close.merch_signature,
+            );
+            let mut cust_sig_map = HashMap::new();
+            cust_sig_map.insert("hash", hex::encode(&cust_close_hash));
+            cust_sig_map.insert(
+                "signature",
+                hex::encode(&cust_close.cust_signature.serialize_der().to_vec()),
+            );
+
             let json = [
                 "{\"merch_pk\":",
                 serde_json::to_string(&merch_pk_map).unwrap().as_str(),
@@ -1175,6 +1212,8 @@ mod cust {
                 serde_json::to_string(&message_map).unwrap().as_str(),
                 ", \"signature\":",
                 serde_json::to_string(&signature_map).unwrap().as_str(),
+                ", \"cust_sig\":",
+                serde_json::to_string(&cust_sig_map).unwrap().as_str(),
                 "}",
             ]
             .concat();
@@ -1196,6 +1235,70 @@ mod cust {
         Ok(())
     }
 
+    pub fn mutual_close(
+        db_url: &String,
+        signed_close: Option<PathBuf>,
+        out_file: Option<PathBuf>,
+        channel_name: String,
+    ) -> Result<(), String> {
+        let mut db_conn = handle_error_result!(create_db_connection(db_url.clone()));
+        let key = format!("id:{}", channel_name);
+
+        // load the channel token from DB
+        let channel_token_key = format!("cust:{}:channel_token", channel_name);
+        let ser_channel_token =
+            handle_error_result!(get_file_from_db(&mut db_conn, &key, &channel_token_key));
+        let channel_token: ChannelToken<Bls12> =
+            handle_error_result!(serde_json::from_str(&ser_channel_token));
+
+        // check the mutual close countersigned by the merchant
+        if let Some(f) = signed_close {
+            let ser_mutual_close = handle_error_result!(read_pathfile(f));
+            let mutual_close: ChannelMutualClose<Bls12> =
+                handle_error_result!(serde_json::from_str(&ser_mutual_close));
+            if !zkproofs::verify_mutual_close(&channel_token, &mutual_close) {
+                return Err(String::from(
+                    "mutual close is not signed by both customer and merchant",
+                ));
+            }
+            println!("Mutual close is signed by both parties:");
+            println!("cust_bal => {}", mutual_close.message.bc);
+            println!("merch_bal => {}", mutual_close.message.bm);
+            return Ok(());
+        }
+
+        let out_file = match out_file {
+            Some(f) => f,
+            None => return Err(String::from("missing the output file (--file)")),
+        };
+
+        // load the channel state from DB
+        let channel_state_key = format!("cust:{}:channel_state", channel_name);
+        let ser_channel_state =
+            handle_error_result!(get_file_from_db(&mut db_conn, &key, &channel_state_key));
+        let channel_state: ChannelState<Bls12> =
+            handle_error_result!(serde_json::from_str(&ser_channel_state));
+
+        // load the customer state from DB
+        let cust_state_key = format!("cust:{}:cust_state", channel_name);
+        let ser_cust_state =
+            handle_error_result!(get_file_from_db(&mut db_conn, &key, &cust_state_key));
+        let cust_state: CustomerState<Bls12> =
+            handle_error_result!(serde_json::from_str(&ser_cust_state));
+
+        let mutual_close = handle_error_result!(zkproofs::customer_mutual_close(
+            &channel_state,
+            &channel_token,
+            &cust_state
+        ));
+        println!("Signed the mutual close on the current state:");
+        println!("current_state =>\n{}\n", mutual_close.message);
+
+        let mutual_close_json_str = handle_error_result!(serde_json::to_string(&mutual_close));
+        write_pathfile(out_file, mutual_close_json_str)?;
+        Ok(())
+    }
+
     fn cust_save_state_in_db(
         db_conn: &mut redis::Connection,
         channel_name: String,
@@ -1238,7 +1341,7 @@ mod merch {
     use zkchannels::channels_zk::{ChannelState, ChannelToken, ChannelcloseM, MerchantState};
     use zkchannels::database::StateDatabase;
     use zkchannels::wallet::Wallet;
-    use zkchannels::zkproofs::ChannelcloseC;
+    use zkchannels::zkproofs::{ChannelMutualClose, ChannelcloseC};
 
     static MERCH_STATE_KEY: &str = "merch_state";
     static CHANNEL_TOKEN_KEY: &str = "channel_token";
@@ -1262,7 +1365,7 @@ mod merch {
                 }
 
                 let (channel_token, merch_state, channel_state) =
-                    zkproofs::merchant_init(rng, &mut channel_state, "Merchant");
+                    zkproofs::merchant_init(rng, db_url.clone(), &mut channel_state, "Merchant");
 
                 let mut db = handle_error_result!(get_merch_db_connection(db_url.clone()));
 
@@ -1362,12 +1465,12 @@ mod merch {
             serde_json::from_str(&msg2.get(0).unwrap()).unwrap();
         let s0: Wallet<Bls12> = serde_json::from_str(msg2[1].as_ref()).unwrap();
 
-        let pay_token = zkproofs::activate::merchant_init(
-            // &mut db as &mut dyn StateDatabase,
+        let pay_token = handle_error_result!(zkproofs::activate::merchant_init(
             rng,
+            &mut db as &mut dyn StateDatabase,
             &s0,
             &mut merch_state,
-        );
+        ));
 
         let msg3 = [handle_error_result!(serde_json::to_string(&pay_token))];
         conn.send(&msg3);
@@ -1413,8 +1516,9 @@ mod merch {
             "Could not load the merchant state DB"
         );
 
-        let merch_state: MerchantState<Bls12> =
+        let mut merch_state: MerchantState<Bls12> =
             handle_error_result!(serde_json::from_str(&ser_merch_state));
+        handle_error_result!(merch_state.migrate_to_db(&mut db));
 
         Ok((channel_state, channel_token, merch_state))
     }
@@ -1426,7 +1530,7 @@ mod merch {
         merch_state: &mut MerchantState<Bls12>,
     ) -> Result<(), String> {
         let rng = &mut rand::thread_rng();
-        let db = handle_error_result!(get_merch_db_connection(db_url.clone()));
+        let mut db = handle_error_result!(get_merch_db_connection(db_url.clone()));
 
         let msg0 = conn.wait_for(None, false);
         // get the session id
@@ -1438,6 +1542,7 @@ mod merch {
             serde_json::from_str(msg0.get(1).unwrap()).unwrap();
         let new_close_token = handle_error_result!(zkproofs::unlink::merchant_update_state(
             rng,
+            &mut db as &mut dyn StateDatabase,
             &channel_state,
             &session_id,
             &unlink_payment,
@@ -1458,6 +1563,7 @@ mod merch {
         let ser_rt_pair = msg2.get(0).unwrap();
         let rt_pair = handle_error_result!(serde_json::from_str(ser_rt_pair));
         let new_pay_token = handle_option_result!(zkproofs::unlink::merchant_validate_rev_lock(
+            &mut db as &mut dyn StateDatabase,
             &session_id,
             &rt_pair,
             merch_state
@@ -1502,6 +1608,7 @@ mod merch {
         let amount = i64::from_be_bytes(amount_buf);
 
         let is_ok = zkproofs::pay::merchant_prepare(
+            &mut db as &mut dyn StateDatabase,
             &session_id,
             FixedSizeArray16(nonce),
             amount,
@@ -1519,13 +1626,14 @@ mod merch {
         );
 
         let payment: zkproofs::Payment<Bls12> = serde_json::from_str(msg2.get(0).unwrap()).unwrap();
-        let new_close_token = zkproofs::pay::merchant_update_state(
+        let new_close_token = handle_error_result!(zkproofs::pay::merchant_update_state(
             rng,
+            &mut db as &mut dyn StateDatabase,
             &channel_state,
             &session_id,
             &payment,
             merch_state,
-        );
+        ));
 
         let msg3 = [handle_error_result!(serde_json::to_string(
             &new_close_token
@@ -1541,6 +1649,7 @@ mod merch {
         let ser_rt_pair = msg4.get(0).unwrap();
         let rt_pair = handle_error_result!(serde_json::from_str(ser_rt_pair));
         let new_pay_token = handle_option_result!(zkproofs::pay::merchant_validate_rev_lock(
+            &mut db as &mut dyn StateDatabase,
             &session_id,
             &rt_pair,
             merch_state
@@ -1605,7 +1714,7 @@ mod merch {
         channel_token: &ChannelToken<Bls12>,
     ) -> Result<(), String> {
         let key = String::from("cli:merch_channels");
-        let channel_id = channel_token.compute_channel_id();
+        let channel_id = handle_error_result!(channel_token.compute_channel_id());
         let cid = format!("{}", &channel_id.into_repr());
         let mut cid_vec = hex::decode(cid[2..].to_string()).unwrap();
         cid_vec.reverse();
@@ -1629,7 +1738,7 @@ mod merch {
     pub fn close(
         db_url: &String,
         close_token: Option<PathBuf>,
-        out_file: PathBuf,
+        out_file: Option<PathBuf>,
         channel_id: String,
     ) -> Result<(), String> {
         // output the merch-close-tx (only thing merchant can broadcast to close channel)
@@ -1645,8 +1754,9 @@ mod merch {
             get_file_from_db(&mut db.conn, &key1, &MERCH_STATE_KEY.to_string()),
             "Could not load the merchant state DB"
         );
-        let merch_state: MerchantState<Bls12> =
+        let mut merch_state: MerchantState<Bls12> =
             handle_error_result!(serde_json::from_str(&ser_merch_state));
+        handle_error_result!(merch_state.migrate_to_db(&mut db));
 
         let key2 = String::from("cli:merch_channels");
         let channel_token_key = format!("id:{}", channel_id);
@@ -1667,10 +1777,15 @@ mod merch {
 
         match close_token {
             Some(c) => {
+                let out_file = match out_file {
+                    Some(f) => f,
+                    None => return Err(String::from("missing the output file (--file)")),
+                };
                 let cust_close_json = handle_error_result!(read_pathfile(c));
                 let cust_close_msg: ChannelcloseC<Bls12> =
                     handle_error_result!(serde_json::from_str(&cust_close_json));
                 let rt_pair = handle_error_result!(zkproofs::force_merchant_close(
+                    &mut db as &mut dyn StateDatabase,
                     &channel_state,
                     &channel_token,
                     &cust_close_msg,
@@ -1687,4 +1802,67 @@ mod merch {
         };
         Ok(())
     }
+
+    pub fn mutual_close(
+        db_url: &String,
+        mutual_close: Option<PathBuf>,
+        out_file: Option<PathBuf>,
+        channel_id: String,
+    ) -> Result<(), String> {
+        let mut db = handle_error_result!(get_merch_db_connection(db_url.clone()));
+
+        if channel_id == "" {
+            list_channels(&mut db.conn);
+            return Ok(());
+        }
+
+        let mutual_close_file = match mutual_close {
+            Some(f) => f,
+            None => {
+                return Err(String::from(
+                    "missing the customer's mutual close request (--cust-close)",
+                ))
+            }
+        };
+        let out_file = match out_file {
+            Some(f) => f,
+            None => return Err(String::from("missing the output file (--file)")),
+        };
+
+        let key1 = String::from("cli:merch_db");
+        let ser_merch_state = handle_error_with_string!(
+            get_file_from_db(&mut db.conn, &key1, &MERCH_STATE_KEY.to_string()),
+            "Could not load the merchant state DB"
+        );
+        let mut merch_state: MerchantState<Bls12> =
+            handle_error_result!(serde_json::from_str(&ser_merch_state));
+        handle_error_result!(merch_state.migrate_to_db(&mut db));
+
+        let key2 = String::from("cli:merch_channels");
+        let channel_token_key = format!("id:{}", channel_id);
+        let ser_channel_token = handle_error_with_string!(
+            get_file_from_db(&mut db.conn, &key2, &channel_token_key),
+            "Invalid channel ID"
+        );
+        let channel_token: ChannelToken<Bls12> =
+            handle_error_result!(serde_json::from_str(&ser_channel_token));
+
+        let ser_mutual_close = handle_error_result!(read_pathfile(mutual_close_file));
+        let mutual_close: ChannelMutualClose<Bls12> =
+            handle_error_result!(serde_json::from_str(&ser_mutual_close));
+
+        let signed_close = handle_error_result!(zkproofs::merchant_mutual_close(
+            &mut db as &mut dyn StateDatabase,
+            &channel_token,
+            &mutual_close,
+            &merch_state,
+        ));
+        println!("Countersigned the mutual close:");
+        println!("cust_bal => {}", signed_close.message.bc);
+        println!("merch_bal => {}", signed_close.message.bm);
+
+        let signed_close_json_str = handle_error_result!(serde_json::to_string(&signed_close));
+        write_pathfile(out_file, signed_close_json_str)?;
+        Ok(())
+    }
 }
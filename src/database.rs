@@ -1,5 +1,6 @@
 use super::*;
-use channels_util::PaymentStatus;
+use channels_mpc::RefundVoucher;
+use channels_util::{HashLock, PaymentStatus};
 use redis::{Commands, Connection};
 use std::collections::hash_map::RandomState;
 use zkchan_tx::fixed_size_array::{FixedSizeArray16, FixedSizeArray32};
@@ -93,6 +94,44 @@ pub struct SessionState {
     pub rev_lock_com: FixedSizeArray32,
     pub amount: i64,
     pub status: PaymentStatus,
+    // sessions stored without an expiry are treated as expired
+    #[serde(default)]
+    pub expiry: i64,
+    // voucher that authorized a refund, marked as spent once the old state is revoked
+    pub refund_voucher: Option<RefundVoucher>,
+    // set once the tx masks have been handed out: the session can no longer fail
+    #[serde(default)]
+    pub masks_released: bool,
+}
+
+impl SessionState {
+    pub fn is_expired(&self, now: i64) -> bool {
+        self.expiry <= now
+    }
+
+    // a session that still holds merchant resources
+    pub fn is_active(&self, now: i64) -> bool {
+        !self.is_expired(now)
+            && (self.status == PaymentStatus::Prepare || self.status == PaymentStatus::Update)
+    }
+
+    // a session that can be removed: failed, or expired before the old state was revoked.
+    // revoked sessions are kept so that the pay token mask can still be re-sent
+    pub fn is_stale(&self, now: i64) -> bool {
+        self.status == PaymentStatus::Error
+            || (self.is_expired(now) && self.status != PaymentStatus::Revoked)
+    }
+}
+
+// a payment that is only final once the hash lock is claimed
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ConditionalPayment {
+    pub hash_lock: HashLock,
+    pub nonce: FixedSizeArray16,
+    // pay token (json) on the balances of the expire branch, released once the hash lock
+    // has expired if the customer revoked the old state
+    #[serde(default)]
+    pub expire_pay_token: String,
 }
 
 pub trait StateDatabase {
@@ -116,6 +155,13 @@ pub trait StateDatabase {
         session_state: &SessionState,
     ) -> bool;
     fn clear_session_state(&mut self, session_id_hex: &String) -> bool;
+    fn get_active_session_count(&mut self, now: i64) -> Result<usize, String>;
+    // removes stale sessions and their nonce and masked input entries
+    fn clear_stale_sessions(&mut self, now: i64) -> Result<usize, String>;
+    // counts the sessions started for a nonce. The count is dropped by clear_stale_sessions()
+    // once the expiry (set by the first attempt) has passed
+    fn update_nonce_attempts(&mut self, nonce_hex: &String, expiry: i64) -> Result<u32, String>;
+    fn clear_nonce_attempts(&mut self, nonce_hex: &String) -> bool;
 
     // spent rev_lock map methods
     fn update_spent_map(
@@ -124,6 +170,16 @@ pub trait StateDatabase {
         rev_lock_hex: &String,
     ) -> Result<bool, String>;
     fn check_spent_map(&mut self, nonce_hex: &String) -> bool;
+    fn remove_from_spent_map(&mut self, nonce_hex: &String) -> bool;
+    // spent refund voucher methods (false if the voucher was spent already)
+    fn update_spent_voucher_map(
+        &mut self,
+        voucher_id_hex: &String,
+        expiry: i64,
+    ) -> Result<bool, String>;
+    fn check_spent_voucher_map(&mut self, voucher_id_hex: &String) -> bool;
+    // drops the spent vouchers that have expired (they are rejected by their expiry anyway)
+    fn prune_spent_voucher_map(&mut self, now: i64) -> Result<usize, String>;
     // rev_lock map methods
     fn update_rev_lock_map(
         &mut self,
@@ -132,6 +188,14 @@ pub trait StateDatabase {
     ) -> Result<bool, String>;
     fn check_rev_lock_map(&mut self, rev_lock_hex: &String) -> bool;
     fn get_rev_secret(&mut self, rev_lock_hex: &String) -> Result<String, String>;
+    // pay token map methods
+    fn update_pay_token_map(
+        &mut self,
+        rev_lock_hex: &String,
+        pay_token_json: &String,
+    ) -> Result<bool, String>;
+    fn get_pay_token(&mut self, rev_lock_hex: &String) -> Result<String, String>;
+    fn remove_from_pay_token_map(&mut self, rev_lock_hex: &String) -> bool;
     // unlink set methods
     fn update_unlink_set(&mut self, nonce: &String) -> Result<bool, String>;
     fn get_unlink_set(&mut self) -> Result<HashSet<String>, String>;
@@ -148,6 +212,7 @@ pub trait StateDatabase {
         nonce_hex: &String,
         session_id_hex: &String,
     ) -> Result<bool, String>;
+    fn get_session_id_from_nonce(&mut self, nonce_hex: &String) -> Result<String, String>;
     // nonce to pay mask methods
     fn update_nonce_mask_map(
         &mut self,
@@ -166,6 +231,14 @@ pub trait StateDatabase {
         mask_bytes: MaskedMPCInputs,
     ) -> bool;
     fn get_masked_mpc_inputs(&mut self, nonce_hex: &String) -> Result<MaskedMPCInputs, String>;
+    // conditional payment methods (keyed by the revealed rev_lock)
+    fn update_conditional_payment_map(
+        &mut self,
+        id_hex: &String,
+        payment: &ConditionalPayment,
+    ) -> Result<bool, String>;
+    fn get_conditional_payment(&mut self, id_hex: &String) -> Result<ConditionalPayment, String>;
+    fn remove_from_conditional_payment_map(&mut self, id_hex: &String) -> bool;
     // helper methods
     fn clear_state(&mut self) -> bool;
 }
@@ -173,12 +246,59 @@ pub trait StateDatabase {
 pub struct RedisDatabase {
     pub conn: redis::Connection,
     session_map_key: String,
+    session_expiry_key: String,
     nonce_to_session_key: String,
+    nonce_attempts_key: String,
+    nonce_attempts_expiry_key: String,
     unlink_set_key: String,
     spent_map_key: String,
+    spent_voucher_map_key: String,
+    spent_voucher_expiry_key: String,
     rev_lock_map_key: String,
+    pay_token_map_key: String,
     nonce_mask_map_key: String,
     masked_bytes_key: String,
+    conditional_payment_map_key: String,
+}
+
+impl RedisDatabase {
+    // keeps a sorted set of session ids by expiry next to the session map, so that
+    // counting active sessions and finding stale ones does not scan every session
+    fn index_session_expiry(
+        &mut self,
+        session_id_hex: &String,
+        session_state: &SessionState,
+    ) -> bool {
+        let key = self.session_expiry_key.clone();
+        let res = match session_state.status {
+            PaymentStatus::Prepare | PaymentStatus::Update => {
+                self.conn.zadd::<String, i64, String, i32>(
+                    key,
+                    session_id_hex.clone(),
+                    session_state.expiry,
+                )
+            }
+            // failed sessions are stale right away
+            PaymentStatus::Error => {
+                self.conn
+                    .zadd::<String, i64, String, i32>(key, session_id_hex.clone(), 0)
+            }
+            // revoked sessions are never cleared
+            PaymentStatus::Revoked | PaymentStatus::Complete => self
+                .conn
+                .zrem::<String, String, i32>(key, session_id_hex.clone()),
+        };
+        match res {
+            Ok(_) => true,
+            Err(e) => {
+                eprintln!(
+                    "index_session_expiry: failed to index: {} => {}",
+                    session_id_hex, e
+                );
+                false
+            }
+        }
+    }
 }
 
 impl StateDatabase for RedisDatabase {
@@ -190,12 +310,19 @@ impl StateDatabase for RedisDatabase {
         Ok(RedisDatabase {
             conn: conn,
             session_map_key: format!("{}:hashmap:session", prefix),
+            session_expiry_key: format!("{}:zset:session_expiry", prefix),
             unlink_set_key: format!("{}:hashset:unlink", prefix),
             spent_map_key: format!("{}:hashmap:spent", prefix),
+            spent_voucher_map_key: format!("{}:hashmap:spent_voucher", prefix),
+            spent_voucher_expiry_key: format!("{}:zset:spent_voucher_expiry", prefix),
             rev_lock_map_key: format!("{}:hashmap:revlock", prefix),
+            pay_token_map_key: format!("{}:hashmap:paytoken", prefix),
             nonce_to_session_key: format!("{}:hashmap:nonce_session", prefix),
+            nonce_attempts_key: format!("{}:hashmap:nonce_attempts", prefix),
+            nonce_attempts_expiry_key: format!("{}:zset:nonce_attempts_expiry", prefix),
             nonce_mask_map_key: format!("{}:hashmap:nonce_paymasks", prefix),
             masked_bytes_key: format!("{}:hashmap:masked_bytes", prefix),
+            conditional_payment_map_key: format!("{}:hashmap:conditional_payment", prefix),
         })
     }
 
@@ -233,7 +360,7 @@ impl StateDatabase for RedisDatabase {
         ) {
             // 1 if field is a new field and value was set.
             // 0 if field already exists in the hash and no operation was performed
-            Ok(c) => c != 0,
+            Ok(c) => c != 0 && self.index_session_expiry(session_id_hex, session_state),
             Err(_) => false,
         }
     }
@@ -256,7 +383,7 @@ impl StateDatabase for RedisDatabase {
             session_id_hex.clone(),
             ser_session_state,
         ) {
-            Ok(c) => c >= 0,
+            Ok(c) => c >= 0 && self.index_session_expiry(session_id_hex, session_state),
             Err(_) => false,
         }
     }
@@ -276,6 +403,16 @@ impl StateDatabase for RedisDatabase {
     }
 
     fn clear_session_state(&mut self, session_id_hex: &String) -> bool {
+        if let Err(e) = self
+            .conn
+            .zrem::<String, String, i32>(self.session_expiry_key.clone(), session_id_hex.clone())
+        {
+            eprintln!(
+                "clear_session_state: failed to remove from expiry index: {} => {}",
+                session_id_hex, e
+            );
+            return false;
+        }
         match self
             .conn
             .hdel(self.session_map_key.clone(), session_id_hex.clone())
@@ -291,6 +428,115 @@ impl StateDatabase for RedisDatabase {
         }
     }
 
+    fn get_active_session_count(&mut self, now: i64) -> Result<usize, String> {
+        // failed sessions are indexed with an expiry of 0 and revoked ones are not indexed
+        match self.conn.zcount::<String, String, String, usize>(
+            self.session_expiry_key.clone(),
+            format!("({}", now),
+            String::from("+inf"),
+        ) {
+            Ok(c) => Ok(c),
+            Err(e) => Err(format!("get_active_session_count: {}", e.to_string())),
+        }
+    }
+
+    fn clear_stale_sessions(&mut self, now: i64) -> Result<usize, String> {
+        // only visit the sessions that expired or failed, instead of scanning all sessions
+        let candidates = match self.conn.zrangebyscore::<String, String, i64, Vec<String>>(
+            self.session_expiry_key.clone(),
+            String::from("-inf"),
+            now,
+        ) {
+            Ok(s) => s,
+            Err(e) => return Err(format!("clear_stale_sessions: {}", e.to_string())),
+        };
+        let mut count = 0;
+        for session_id_hex in candidates {
+            let t = match self.load_session_state(&session_id_hex) {
+                Ok(t) => t,
+                Err(_) => {
+                    // the session is gone already, drop it from the index
+                    self.clear_session_state(&session_id_hex);
+                    continue;
+                }
+            };
+            if !t.is_stale(now) {
+                continue;
+            }
+            let nonce_hex = hex::encode(t.nonce.0);
+            // free up the nonce for a retry if the mpc never ran on it (and no newer session
+            // took it). Once past Prepare the customer may hold outputs of the mpc, so the
+            // nonce stays taken for good, unless the spent map keeps it from being reused
+            let nonce_taken =
+                t.status != PaymentStatus::Prepare && !self.check_spent_map(&nonce_hex);
+            if !nonce_taken && !self.check_dup_nonce_to_session_id(&nonce_hex, &session_id_hex) {
+                let _: i32 = handle_error_util!(self
+                    .conn
+                    .hdel(self.nonce_to_session_key.clone(), nonce_hex));
+            }
+            let _: i32 = handle_error_util!(self
+                .conn
+                .hdel(self.masked_bytes_key.clone(), session_id_hex.clone()));
+            if self.clear_session_state(&session_id_hex) {
+                count += 1;
+            }
+        }
+
+        // attempt counts of nonces whose retry window has passed
+        let expired_nonces = match self.conn.zrangebyscore::<String, String, i64, Vec<String>>(
+            self.nonce_attempts_expiry_key.clone(),
+            String::from("-inf"),
+            now,
+        ) {
+            Ok(n) => n,
+            Err(e) => return Err(format!("clear_stale_sessions: {}", e.to_string())),
+        };
+        for nonce_hex in expired_nonces {
+            self.clear_nonce_attempts(&nonce_hex);
+        }
+        Ok(count)
+    }
+
+    fn update_nonce_attempts(&mut self, nonce_hex: &String, expiry: i64) -> Result<u32, String> {
+        let attempts = match self.conn.hincr::<String, String, i64, i64>(
+            self.nonce_attempts_key.clone(),
+            nonce_hex.clone(),
+            1,
+        ) {
+            Ok(c) => c as u32,
+            Err(e) => return Err(format!("update_nonce_attempts: {}", e.to_string())),
+        };
+        if attempts == 1 {
+            let _: i32 = handle_error_util!(self.conn.zadd(
+                self.nonce_attempts_expiry_key.clone(),
+                nonce_hex.clone(),
+                expiry
+            ));
+        }
+        Ok(attempts)
+    }
+
+    fn clear_nonce_attempts(&mut self, nonce_hex: &String) -> bool {
+        match self
+            .conn
+            .hdel::<String, String, i32>(self.nonce_attempts_key.clone(), nonce_hex.clone())
+            .and_then(|_| {
+                self.conn.zrem::<String, String, i32>(
+                    self.nonce_attempts_expiry_key.clone(),
+                    nonce_hex.clone(),
+                )
+            }) {
+            Ok(_) => true,
+            Err(e) => {
+                eprintln!(
+                    "clear_nonce_attempts: failed to delete key: {} => {}",
+                    nonce_hex, e
+                );
+                false
+            }
+        }
+    }
+
     // spent map calls
     fn update_spent_map(
         &mut self,
@@ -320,6 +566,73 @@ impl StateDatabase for RedisDatabase {
         }
     }
 
+    fn remove_from_spent_map(&mut self, nonce_hex: &String) -> bool {
+        match self
+            .conn
+            .hdel(self.spent_map_key.clone(), nonce_hex.clone())
+        {
+            Ok(c) => c,
+            Err(e) => {
+                eprintln!("remove_from_spent_map: {}", e.to_string());
+                false
+            }
+        }
+    }
+
+    fn update_spent_voucher_map(
+        &mut self,
+        voucher_id_hex: &String,
+        expiry: i64,
+    ) -> Result<bool, String> {
+        let is_new = match self.conn.hset_nx::<String, String, i64, i32>(
+            self.spent_voucher_map_key.clone(),
+            voucher_id_hex.clone(),
+            expiry,
+        ) {
+            Ok(s) => s != 0,
+            Err(e) => return Err(e.to_string()),
+        };
+        if is_new {
+            let _: i32 = handle_error_util!(self.conn.zadd(
+                self.spent_voucher_expiry_key.clone(),
+                voucher_id_hex.clone(),
+                expiry
+            ));
+        }
+        Ok(is_new)
+    }
+
+    fn check_spent_voucher_map(&mut self, voucher_id_hex: &String) -> bool {
+        match self
+            .conn
+            .hexists(self.spent_voucher_map_key.clone(), voucher_id_hex.clone())
+        {
+            Ok(s) => s,
+            Err(_) => false,
+        }
+    }
+
+    fn prune_spent_voucher_map(&mut self, now: i64) -> Result<usize, String> {
+        let expired = match self.conn.zrangebyscore::<String, String, i64, Vec<String>>(
+            self.spent_voucher_expiry_key.clone(),
+            String::from("-inf"),
+            now,
+        ) {
+            Ok(v) => v,
+            Err(e) => return Err(format!("prune_spent_voucher_map: {}", e.to_string())),
+        };
+        for voucher_id_hex in expired.iter() {
+            let _: i32 = handle_error_util!(self
+                .conn
+                .hdel(self.spent_voucher_map_key.clone(), voucher_id_hex.clone()));
+            let _: i32 = handle_error_util!(self.conn.zrem(
+                self.spent_voucher_expiry_key.clone(),
+                voucher_id_hex.clone()
+            ));
+        }
+        Ok(expired.len())
+    }
+
     // rev_lock map calls
     fn update_rev_lock_map(
         &mut self,
@@ -359,6 +672,45 @@ impl StateDatabase for RedisDatabase {
         }
     }
 
+    // pay token map calls
+    fn update_pay_token_map(
+        &mut self,
+        rev_lock_hex: &String,
+        pay_token_json: &String,
+    ) -> Result<bool, String> {
+        match self.conn.hset::<String, String, String, i32>(
+            self.pay_token_map_key.clone(),
+            rev_lock_hex.clone(),
+            pay_token_json.clone(),
+        ) {
+            Ok(s) => Ok(s != 0),
+            Err(e) => return Err(e.to_string()),
+        }
+    }
+
+    fn get_pay_token(&mut self, rev_lock_hex: &String) -> Result<String, String> {
+        match self
+            .conn
+            .hget::<String, String, String>(self.pay_token_map_key.clone(), rev_lock_hex.clone())
+        {
+            Ok(s) => Ok(s),
+            Err(e) => return Err(e.to_string()),
+        }
+    }
+
+    fn remove_from_pay_token_map(&mut self, rev_lock_hex: &String) -> bool {
+        match self
+            .conn
+            .hdel(self.pay_token_map_key.clone(), rev_lock_hex.clone())
+        {
+            Ok(c) => c,
+            Err(e) => {
+                eprintln!("remove_from_pay_token_map: {}", e.to_string());
+                false
+            }
+        }
+    }
+
     // unlink set calls
     fn update_unlink_set(&mut self, nonce: &String) -> Result<bool, String> {
         match self
@@ -431,6 +783,23 @@ impl StateDatabase for RedisDatabase {
                 return false;
             }
         }
+        match self.conn.del(self.spent_voucher_map_key.clone()) {
+            Ok(c) => c,
+            Err(e) => {
+                eprintln!("could not delete: {} => {}", self.spent_voucher_map_key, e);
+                return false;
+            }
+        }
+        match self.conn.del(self.spent_voucher_expiry_key.clone()) {
+            Ok(c) => c,
+            Err(e) => {
+                eprintln!(
+                    "could not delete: {} => {}",
+                    self.spent_voucher_expiry_key, e
+                );
+                return false;
+            }
+        }
         match self.conn.del(self.rev_lock_map_key.clone()) {
             Ok(c) => c,
             Err(e) => {
@@ -438,6 +807,13 @@ impl StateDatabase for RedisDatabase {
                 return false;
             }
         }
+        match self.conn.del(self.pay_token_map_key.clone()) {
+            Ok(c) => c,
+            Err(e) => {
+                eprintln!("could not delete: {} => {}", self.pay_token_map_key, e);
+                return false;
+            }
+        }
         match self.conn.del(self.nonce_to_session_key.clone()) {
             Ok(c) => c,
             Err(e) => {
@@ -445,6 +821,30 @@ impl StateDatabase for RedisDatabase {
                 return false;
             }
         }
+        match self.conn.del(self.session_expiry_key.clone()) {
+            Ok(c) => c,
+            Err(e) => {
+                eprintln!("could not delete: {} => {}", self.session_expiry_key, e);
+                return false;
+            }
+        }
+        match self.conn.del(self.nonce_attempts_key.clone()) {
+            Ok(c) => c,
+            Err(e) => {
+                eprintln!("could not delete: {} => {}", self.nonce_attempts_key, e);
+                return false;
+            }
+        }
+        match self.conn.del(self.nonce_attempts_expiry_key.clone()) {
+            Ok(c) => c,
+            Err(e) => {
+                eprintln!(
+                    "could not delete: {} => {}",
+                    self.nonce_attempts_expiry_key, e
+                );
+                return false;
+            }
+        }
         match self.conn.del(self.nonce_mask_map_key.clone()) {
             Ok(c) => c,
             Err(e) => {
@@ -459,6 +859,16 @@ impl StateDatabase for RedisDatabase {
                 return false;
             }
         }
+        match self.conn.del(self.conditional_payment_map_key.clone()) {
+            Ok(c) => c,
+            Err(e) => {
+                eprintln!(
+                    "could not delete: {} => {}",
+                    self.conditional_payment_map_key, e
+                );
+                return false;
+            }
+        }
         return true;
     }
 
@@ -492,6 +902,16 @@ impl StateDatabase for RedisDatabase {
         }
     }
 
+    fn get_session_id_from_nonce(&mut self, nonce_hex: &String) -> Result<String, String> {
+        match self
+            .conn
+            .hget::<String, String, String>(self.nonce_to_session_key.clone(), nonce_hex.clone())
+        {
+            Ok(s) => Ok(s),
+            Err(e) => Err(format!("get_session_id_from_nonce: {}", e.to_string())),
+        }
+    }
+
     // nonce -> masks calls
     fn update_nonce_mask_map(
         &mut self,
@@ -588,17 +1008,72 @@ impl StateDatabase for RedisDatabase {
 
         Ok(t)
     }
+
+    // conditional payment calls
+    fn update_conditional_payment_map(
+        &mut self,
+        id_hex: &String,
+        payment: &ConditionalPayment,
+    ) -> Result<bool, String> {
+        let ser_payment = handle_error_util!(serde_json::to_string(payment));
+        match self.conn.hset::<String, String, String, i32>(
+            self.conditional_payment_map_key.clone(),
+            id_hex.clone(),
+            ser_payment,
+        ) {
+            Ok(s) => Ok(s != 0),
+            Err(e) => return Err(e.to_string()),
+        }
+    }
+
+    fn get_conditional_payment(&mut self, id_hex: &String) -> Result<ConditionalPayment, String> {
+        let ser_payment = match self.conn.hget::<String, String, String>(
+            self.conditional_payment_map_key.clone(),
+            id_hex.clone(),
+        ) {
+            Ok(s) => s,
+            Err(e) => {
+                return Err(format!(
+                    "get_conditional_payment: key({}) field({}) => {}",
+                    &self.conditional_payment_map_key,
+                    id_hex,
+                    e.to_string()
+                ))
+            }
+        };
+
+        let t: ConditionalPayment = handle_error_util!(serde_json::from_str(&ser_payment));
+
+        Ok(t)
+    }
+
+    fn remove_from_conditional_payment_map(&mut self, id_hex: &String) -> bool {
+        match self
+            .conn
+            .hdel(self.conditional_payment_map_key.clone(), id_hex.clone())
+        {
+            Ok(c) => c,
+            Err(e) => {
+                eprintln!("remove_from_conditional_payment_map: {}", e.to_string());
+                false
+            }
+        }
+    }
 }
 
 #[derive(Debug)]
 pub struct HashMapDatabase {
     pub session_state_map: HashMap<String, SessionState>,
     pub nonce_session_map: HashMap<String, String>,
+    pub nonce_attempts_map: HashMap<String, (u32, i64)>, // (attempts, expiry)
     pub nonce_mask_map: HashMap<String, PayMaskMap>,
     pub unlink_map: HashSet<String>,
     pub spent_lock_map: HashMap<String, String>,
+    pub spent_voucher_map: HashMap<String, i64>,
     pub rev_lock_map: HashMap<String, String>,
+    pub pay_token_map: HashMap<String, String>,
     pub mask_mpc_bytes: HashMap<String, MaskedMPCInputs>,
+    pub conditional_payment_map: HashMap<String, ConditionalPayment>,
 }
 
 impl StateDatabase for HashMapDatabase {
@@ -606,11 +1081,15 @@ impl StateDatabase for HashMapDatabase {
         Ok(HashMapDatabase {
             session_state_map: HashMap::new(),
             nonce_session_map: HashMap::new(),
+            nonce_attempts_map: HashMap::new(),
             nonce_mask_map: HashMap::new(),
             unlink_map: HashSet::new(),
             spent_lock_map: HashMap::new(),
+            spent_voucher_map: HashMap::new(),
             rev_lock_map: HashMap::new(),
+            pay_token_map: HashMap::new(),
             mask_mpc_bytes: HashMap::new(),
+            conditional_payment_map: HashMap::new(),
         })
     }
 
@@ -661,31 +1140,99 @@ impl StateDatabase for HashMapDatabase {
         return true;
     }
 
+    fn get_active_session_count(&mut self, now: i64) -> Result<usize, String> {
+        Ok(self
+            .session_state_map
+            .values()
+            .filter(|s| s.is_active(now))
+            .count())
+    }
+
+    fn clear_stale_sessions(&mut self, now: i64) -> Result<usize, String> {
+        let stale: Vec<(String, SessionState)> = self
+            .session_state_map
+            .iter()
+            .filter(|(_, s)| s.is_stale(now))
+            .map(|(k, s)| (k.clone(), s.clone()))
+            .collect();
+        for (session_id_hex, session_state) in stale.iter() {
+            let nonce_hex = hex::encode(session_state.nonce.0);
+            // as above, a nonce that got past Prepare is only freed once it is spent
+            let nonce_taken = session_state.status != PaymentStatus::Prepare
+                && !self.spent_lock_map.contains_key(&nonce_hex);
+            if !nonce_taken && self.nonce_session_map.get(&nonce_hex) == Some(session_id_hex) {
+                self.nonce_session_map.remove(&nonce_hex);
+            }
+            self.mask_mpc_bytes.remove(session_id_hex);
+            self.session_state_map.remove(session_id_hex);
+        }
+        // attempt counts of nonces whose retry window has passed
+        self.nonce_attempts_map
+            .retain(|_, (_, expiry)| *expiry > now);
+        Ok(stale.len())
+    }
+
+    fn update_nonce_attempts(&mut self, nonce_hex: &String, expiry: i64) -> Result<u32, String> {
+        let attempts = self
+            .nonce_attempts_map
+            .entry(nonce_hex.clone())
+            .or_insert((0, expiry));
+        attempts.0 += 1;
+        Ok(attempts.0)
+    }
+
+    fn clear_nonce_attempts(&mut self, nonce_hex: &String) -> bool {
+        self.nonce_attempts_map.remove(nonce_hex);
+        true
+    }
+
     fn update_spent_map(&mut self, nonce: &String, rev_lock: &String) -> Result<bool, String> {
-        match self.spent_lock_map.insert(nonce.clone(), rev_lock.clone()) {
-            Some(c) => c,
-            None => return Err(format!("could not update spent_map")),
-        };
-        Ok(true)
+        Ok(self
+            .spent_lock_map
+            .insert(nonce.clone(), rev_lock.clone())
+            .is_none())
     }
 
     fn check_spent_map(&mut self, nonce: &String) -> bool {
         return self.spent_lock_map.get(nonce).is_some();
     }
 
+    fn remove_from_spent_map(&mut self, nonce: &String) -> bool {
+        self.spent_lock_map.remove(nonce).is_some()
+    }
+
+    fn update_spent_voucher_map(
+        &mut self,
+        voucher_id_hex: &String,
+        expiry: i64,
+    ) -> Result<bool, String> {
+        if self.spent_voucher_map.contains_key(voucher_id_hex) {
+            return Ok(false);
+        }
+        self.spent_voucher_map
+            .insert(voucher_id_hex.clone(), expiry);
+        Ok(true)
+    }
+
+    fn check_spent_voucher_map(&mut self, voucher_id_hex: &String) -> bool {
+        self.spent_voucher_map.contains_key(voucher_id_hex)
+    }
+
+    fn prune_spent_voucher_map(&mut self, now: i64) -> Result<usize, String> {
+        let count = self.spent_voucher_map.len();
+        self.spent_voucher_map.retain(|_, expiry| *expiry > now);
+        Ok(count - self.spent_voucher_map.len())
+    }
+
     fn update_rev_lock_map(
         &mut self,
         rev_lock_hex: &String,
         rev_secret_hex: &String,
     ) -> Result<bool, String> {
-        match self
-            .spent_lock_map
+        Ok(self
+            .rev_lock_map
             .insert(rev_lock_hex.clone(), rev_secret_hex.clone())
-        {
-            Some(c) => c,
-            None => return Err(format!("could not update spent_map")),
-        };
-        Ok(true)
+            .is_none())
     }
 
     fn check_rev_lock_map(&mut self, rev_lock_hex: &String) -> bool {
@@ -699,6 +1246,33 @@ impl StateDatabase for HashMapDatabase {
         }
     }
 
+    fn update_pay_token_map(
+        &mut self,
+        rev_lock_hex: &String,
+        pay_token_json: &String,
+    ) -> Result<bool, String> {
+        Ok(self
+            .pay_token_map
+            .insert(rev_lock_hex.clone(), pay_token_json.clone())
+            .is_none())
+    }
+
+    fn get_pay_token(&mut self, rev_lock_hex: &String) -> Result<String, String> {
+        match self.pay_token_map.get(rev_lock_hex) {
+            Some(c) => Ok(c.clone()),
+            None => {
+                return Err(format!(
+                    "could not find pay token for rev_lock: {}",
+                    rev_lock_hex
+                ))
+            }
+        }
+    }
+
+    fn remove_from_pay_token_map(&mut self, rev_lock_hex: &String) -> bool {
+        self.pay_token_map.remove(rev_lock_hex).is_some()
+    }
+
     fn update_unlink_set(&mut self, nonce: &String) -> Result<bool, String> {
         Ok(self.unlink_map.insert(nonce.clone()))
     }
@@ -722,10 +1296,14 @@ impl StateDatabase for HashMapDatabase {
         self.session_state_map.clear();
         self.unlink_map.clear();
         self.spent_lock_map.clear();
+        self.spent_voucher_map.clear();
         self.rev_lock_map.clear();
+        self.pay_token_map.clear();
         self.nonce_session_map.clear();
         self.nonce_mask_map.clear();
         self.mask_mpc_bytes.clear();
+        self.nonce_attempts_map.clear();
+        self.conditional_payment_map.clear();
         return true;
     }
 
@@ -750,6 +1328,16 @@ impl StateDatabase for HashMapDatabase {
         Ok(true)
     }
 
+    fn get_session_id_from_nonce(&mut self, nonce_hex: &String) -> Result<String, String> {
+        match self.nonce_session_map.get(nonce_hex) {
+            Some(s) => Ok(s.clone()),
+            None => Err(format!(
+                "could not find session id for nonce: {}",
+                nonce_hex
+            )),
+        }
+    }
+
     fn update_nonce_mask_map(
         &mut self,
         nonce_hex: &String,
@@ -801,6 +1389,33 @@ impl StateDatabase for HashMapDatabase {
             }
         }
     }
+
+    fn update_conditional_payment_map(
+        &mut self,
+        id_hex: &String,
+        payment: &ConditionalPayment,
+    ) -> Result<bool, String> {
+        Ok(self
+            .conditional_payment_map
+            .insert(id_hex.clone(), payment.clone())
+            .is_none())
+    }
+
+    fn get_conditional_payment(&mut self, id_hex: &String) -> Result<ConditionalPayment, String> {
+        match self.conditional_payment_map.get(id_hex) {
+            Some(p) => Ok(p.clone()),
+            None => {
+                return Err(format!(
+                    "could not find conditional payment for: {}",
+                    id_hex
+                ))
+            }
+        }
+    }
+
+    fn remove_from_conditional_payment_map(&mut self, id_hex: &String) -> bool {
+        self.conditional_payment_map.remove(id_hex).is_some()
+    }
 }
 
 pub fn get_file_from_db(
@@ -1001,6 +1616,9 @@ mod tests {
             rev_lock_com: FixedSizeArray32(rev_lock_com),
             amount: amount,
             status: PaymentStatus::Prepare,
+            expiry: 1000,
+            refund_voucher: None,
+            masks_released: false,
         };
 
         let result = db.save_new_session_state(&session_id, &session_state);
@@ -1051,4 +1669,78 @@ mod tests {
         let result = db.check_dup_nonce_to_session_id(&nonce, &session_id2);
         assert!(result); // should be true, there's a different existing session id with same nonce
     }
+
+    #[test]
+    fn test_redis_stale_sessions() {
+        let db_url = "redis://127.0.0.1/".to_string();
+        let mut db = RedisDatabase::new("test", db_url).unwrap();
+        db.clear_state();
+
+        let now = 1000;
+        let nonce = [4u8; 16];
+        let nonce_hex = hex::encode(nonce);
+        let rev_lock_com = hash_to_slice(&[1u8; 32].to_vec());
+        let mut sessions = Vec::new();
+        for (i, (status, expiry)) in vec![
+            (PaymentStatus::Prepare, now + 600), // active
+            (PaymentStatus::Update, now + 600),  // active
+            (PaymentStatus::Prepare, now),       // expired
+            (PaymentStatus::Error, now + 600),   // failed
+            (PaymentStatus::Revoked, now),       // revoked, kept to re-send the pay token mask
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            let session_id = hex::encode([i as u8 + 1; 16]);
+            let session_state = SessionState {
+                nonce: FixedSizeArray16([i as u8 + 1; 16]),
+                rev_lock_com: FixedSizeArray32(rev_lock_com),
+                amount: 100,
+                status: status,
+                expiry: expiry,
+                refund_voucher: None,
+                masks_released: false,
+            };
+            assert!(db.save_new_session_state(&session_id, &session_state));
+            sessions.push(session_id);
+        }
+        assert_eq!(db.get_active_session_count(now).unwrap(), 2);
+
+        // the expired session holds the nonce, the failed one another nonce
+        let failed_nonce_hex = hex::encode([5u8; 16]);
+        db.update_nonce_to_session_id(&nonce_hex, &sessions[2])
+            .unwrap();
+        db.update_nonce_to_session_id(&failed_nonce_hex, &sessions[3])
+            .unwrap();
+        assert!(db.check_dup_nonce_to_session_id(&nonce_hex, &sessions[0]));
+
+        assert_eq!(db.clear_stale_sessions(now).unwrap(), 2);
+        assert!(db.check_session_id(&sessions[0]).unwrap());
+        assert!(!db.check_session_id(&sessions[2]).unwrap());
+        assert!(!db.check_session_id(&sessions[3]).unwrap());
+        assert!(db.check_session_id(&sessions[4]).unwrap());
+        // the nonce of the session that expired before the mpc is free again for a retry
+        assert!(!db.check_dup_nonce_to_session_id(&nonce_hex, &sessions[0]));
+        // but not the one of the session that got past Prepare
+        assert!(db.check_dup_nonce_to_session_id(&failed_nonce_hex, &sessions[0]));
+
+        // once the timeout has passed, the remaining sessions are cleared too
+        assert_eq!(db.get_active_session_count(now + 600).unwrap(), 0);
+        assert_eq!(db.clear_stale_sessions(now + 600).unwrap(), 2);
+        assert!(db.check_session_id(&sessions[4]).unwrap());
+
+        assert_eq!(db.update_nonce_attempts(&nonce_hex, now + 600).unwrap(), 1);
+        assert_eq!(db.update_nonce_attempts(&nonce_hex, now + 600).unwrap(), 2);
+        // the attempts are dropped once the nonce is spent or its session aborted
+        assert!(db.clear_nonce_attempts(&nonce_hex));
+        assert_eq!(db.update_nonce_attempts(&nonce_hex, now + 600).unwrap(), 1);
+        // and once the retry window of the first attempt has passed
+        assert_eq!(db.update_nonce_attempts(&nonce_hex, now + 1200).unwrap(), 2);
+        db.clear_stale_sessions(now + 599).unwrap();
+        assert_eq!(db.update_nonce_attempts(&nonce_hex, now + 1200).unwrap(), 3);
+        db.clear_stale_sessions(now + 600).unwrap();
+        assert_eq!(db.update_nonce_attempts(&nonce_hex, now + 1200).unwrap(), 1);
+        db.clear_state();
+        assert_eq!(db.update_nonce_attempts(&nonce_hex, now + 1200).unwrap(), 1);
+    }
 }
@@ -2,19 +2,24 @@
 pub mod ffishim {
     extern crate libc;
 
-    use pairing::bls12_381::Bls12;
+    use database::{RedisDatabase, StateDatabase};
+    use ff::PrimeField;
+    use pairing::bls12_381::{Bls12, Fr};
     use pairing::CurveProjective;
     use pairing::{
         bls12_381::{G1Uncompressed, G2Uncompressed},
         EncodedPoint,
     };
+    use util;
     use zkproofs;
 
     use serde::Deserialize;
 
     use libc::c_char;
+    use std::cell::RefCell;
     use std::collections::HashMap;
     use std::ffi::{CStr, CString};
+    use std::ops::{Deref, DerefMut};
     use std::str;
     use zkchan_tx::fixed_size_array::FixedSizeArray16;
 
@@ -48,6 +53,63 @@ pub mod ffishim {
     pub type ResultSerdeType<T> = Result<T, serde_json::error::Error>;
     type CURVE = Bls12;
 
+    thread_local! {
+        // the merchant's connection is opened by the first call and reused by later ones
+        static MERCH_DB: RefCell<Option<(String, RedisDatabase)>> = RefCell::new(None);
+    }
+
+    // a merchant connection borrowed from MERCH_DB, handed back when dropped
+    struct MerchantDatabase {
+        db_url: String,
+        db: Option<RedisDatabase>,
+    }
+
+    impl Deref for MerchantDatabase {
+        type Target = RedisDatabase;
+        fn deref(&self) -> &RedisDatabase {
+            self.db.as_ref().unwrap()
+        }
+    }
+
+    impl DerefMut for MerchantDatabase {
+        fn deref_mut(&mut self) -> &mut RedisDatabase {
+            self.db.as_mut().unwrap()
+        }
+    }
+
+    impl Drop for MerchantDatabase {
+        fn drop(&mut self) {
+            let db_url = self.db_url.clone();
+            let db = self.db.take();
+            MERCH_DB.with(|cached| {
+                if let Some(db) = db {
+                    *cached.borrow_mut() = Some((db_url, db));
+                }
+            });
+        }
+    }
+
+    // returns the connection to the merchant's db_url and moves over the
+    // bookkeeping of merchant states serialized by older versions
+    fn open_merchant_db(
+        merch_state: &mut zkproofs::MerchantState<CURVE>,
+    ) -> Result<MerchantDatabase, String> {
+        let db_url = merch_state.db_url.clone();
+        let cached_db = MERCH_DB.with(|cached| match cached.borrow_mut().take() {
+            Some((url, db)) if url == db_url => Some(db),
+            _ => None,
+        });
+        let mut db = match cached_db {
+            Some(db) => db,
+            None => RedisDatabase::new("bls12", db_url.clone())?,
+        };
+        merch_state.migrate_to_db(&mut db)?;
+        Ok(MerchantDatabase {
+            db_url: db_url,
+            db: Some(db),
+        })
+    }
+
     fn deserialize_result_object<'a, T>(serialized: *mut c_char) -> ResultSerdeType<T>
     where
         T: Deserialize<'a>,
@@ -117,6 +179,7 @@ pub mod ffishim {
 
     #[no_mangle]
     pub extern "C" fn ffishim_bls12_init_merchant_init(
+        db_url_str: *mut c_char,
         ser_channel_state: *mut c_char,
         name_ptr: *const c_char,
     ) -> *mut c_char {
@@ -128,8 +191,11 @@ pub mod ffishim {
         let bytes = unsafe { CStr::from_ptr(name_ptr).to_bytes() };
         let name: &str = str::from_utf8(bytes).unwrap(); // make sure the bytes are UTF-8
 
+        let db_url_bytes = unsafe { CStr::from_ptr(db_url_str).to_bytes() };
+        let db_url = handle_errors!(str::from_utf8(db_url_bytes)).to_string();
+
         let (channel_token, merch_state, channel_state) =
-            zkproofs::merchant_init(rng, &mut channel_state, name);
+            zkproofs::merchant_init(rng, db_url, &mut channel_state, name);
 
         let ser = [
             "{\'channel_token\':\'",
@@ -194,7 +260,7 @@ pub mod ffishim {
             deserialize_result_object(ser_channel_token);
         let channel_token = handle_errors!(channel_token_result);
 
-        let id = channel_token.compute_channel_id();
+        let id = handle_errors!(channel_token.compute_channel_id());
         let ser = [
             "{\'channel_id\':\'",
             serde_json::to_string(&id).unwrap().as_str(),
@@ -312,7 +378,14 @@ pub mod ffishim {
             deserialize_result_object(ser_merch_state);
         let mut merch_state = handle_errors!(merch_state_result);
 
-        let pay_token = zkproofs::activate::merchant_init(rng, &init_state, &mut merch_state);
+        let mut db = handle_errors!(open_merchant_db(&mut merch_state));
+
+        let pay_token = handle_errors!(zkproofs::activate::merchant_init(
+            rng,
+            &mut *db,
+            &init_state,
+            &mut merch_state
+        ));
 
         let ser = [
             "{\'merch_state\':\'",
@@ -424,8 +497,9 @@ pub mod ffishim {
         let cust_state = handle_errors!(cust_state_result);
 
         // Generate the payment proof
-        let (session_id, payment, new_cust_state) =
-            zkproofs::unlink::customer_update_state(rng, &channel_state, &cust_state);
+        let (session_id, payment, new_cust_state) = handle_errors!(
+            zkproofs::unlink::customer_update_state(rng, &channel_state, &cust_state)
+        );
         // Serialize the results and return to caller
         let ser = [
             "{\'session_id\':\'",
@@ -471,8 +545,11 @@ pub mod ffishim {
             deserialize_result_object(ser_merch_state);
         let mut merch_state = handle_errors!(merch_state_result);
 
+        let mut db = handle_errors!(open_merchant_db(&mut merch_state));
+
         let close_token = zkproofs::unlink::merchant_update_state(
             rng,
+            &mut *db,
             &channel_state,
             &session_id,
             &payment,
@@ -558,8 +635,11 @@ pub mod ffishim {
             deserialize_result_object(ser_merch_state);
         let mut merch_state = handle_errors!(merch_state_result);
 
+        let mut db = handle_errors!(open_merchant_db(&mut merch_state));
+
         // send revoke token and get pay-token in response
         let pay_token_result = zkproofs::unlink::merchant_validate_rev_lock(
+            &mut *db,
             &session_id,
             &revoked_state,
             &mut merch_state,
@@ -680,8 +760,11 @@ pub mod ffishim {
             deserialize_result_object(ser_merchant_state);
         let mut merch_state = handle_errors!(merch_state_result);
 
+        let mut db = handle_errors!(open_merchant_db(&mut merch_state));
+
         // Generate the payment proof
         let accepted = zkproofs::pay::merchant_prepare(
+            &mut *db,
             &session_id,
             FixedSizeArray16(nonce_fixed),
             amount,
@@ -717,9 +800,71 @@ pub mod ffishim {
             deserialize_result_object(ser_customer_state);
         let cust_state = handle_errors!(cust_state_result);
 
+        // Generate the payment proof
+        let (payment, new_cust_state) = handle_errors!(zkproofs::pay::customer_update_state(
+            rng,
+            &channel_state,
+            &cust_state,
+            amount
+        ));
+        // Serialize the results and return to caller
+        let ser = [
+            "{\'payment\':\'",
+            serde_json::to_string(&payment).unwrap().as_str(),
+            "\', \'cust_state\':\'",
+            serde_json::to_string(&new_cust_state).unwrap().as_str(),
+            "\'}",
+        ]
+        .concat();
+        let cser = CString::new(ser).unwrap();
+        cser.into_raw()
+    }
+
+    #[no_mangle]
+    pub extern "C" fn ffishim_bls12_pay_generate_amount_tau() -> *mut c_char {
+        let rng = &mut rand::thread_rng();
+        let amount_tau = zkproofs::pay::generate_amount_tau::<_, CURVE>(rng);
+        let ser = [
+            "{\'amount_tau\':\'",
+            serde_json::to_string(&amount_tau).unwrap().as_str(),
+            "\'}",
+        ]
+        .concat();
+        let cser = CString::new(ser).unwrap();
+        cser.into_raw()
+    }
+
+    #[no_mangle]
+    pub extern "C" fn ffishim_bls12_pay_customer_update_state_hidden(
+        ser_channel_state: *mut c_char,
+        ser_customer_state: *mut c_char,
+        amount: i64,
+        ser_amount_tau: *mut c_char,
+    ) -> *mut c_char {
+        let rng = &mut rand::thread_rng();
+        // Deserialize the channel state
+        let channel_state_result: ResultSerdeType<zkproofs::ChannelState<CURVE>> =
+            deserialize_result_object(ser_channel_state);
+        let channel_state = handle_errors!(channel_state_result);
+
+        // Deserialize the cust state
+        let cust_state_result: ResultSerdeType<zkproofs::CustomerState<CURVE>> =
+            deserialize_result_object(ser_customer_state);
+        let cust_state = handle_errors!(cust_state_result);
+
+        // Deserialize the blinding value of the amount commitment
+        let amount_tau_result: ResultSerdeType<Fr> = deserialize_result_object(ser_amount_tau);
+        let amount_tau = handle_errors!(amount_tau_result);
+
         // Generate the payment proof
         let (payment, new_cust_state) =
-            zkproofs::pay::customer_update_state(rng, &channel_state, &cust_state, amount);
+            handle_errors!(zkproofs::pay::customer_update_state_hidden(
+                rng,
+                &channel_state,
+                &cust_state,
+                amount,
+                amount_tau,
+            ));
         // Serialize the results and return to caller
         let ser = [
             "{\'payment\':\'",
@@ -763,13 +908,16 @@ pub mod ffishim {
             deserialize_result_object(ser_merch_state);
         let mut merch_state = handle_errors!(merch_state_result);
 
-        let close_token = zkproofs::pay::merchant_update_state(
+        let mut db = handle_errors!(open_merchant_db(&mut merch_state));
+
+        let close_token = handle_errors!(zkproofs::pay::merchant_update_state(
             rng,
+            &mut *db,
             &channel_state,
             &session_id,
             &payment,
             &mut merch_state,
-        );
+        ));
         let ser = [
             "{\'close_token\':\'",
             serde_json::to_string(&close_token).unwrap().as_str(),
@@ -809,8 +957,11 @@ pub mod ffishim {
             deserialize_result_object(ser_merch_state);
         let mut merch_state = handle_errors!(merch_state_result);
 
+        let mut db = handle_errors!(open_merchant_db(&mut merch_state));
+
         let close_token_result = zkproofs::pay::multi_customer_update_state(
             rng,
+            &mut *db,
             &channel_state,
             &sender_payment,
             &receiver_payment,
@@ -902,9 +1053,15 @@ pub mod ffishim {
             deserialize_result_object(ser_merch_state);
         let mut merch_state = handle_errors!(merch_state_result);
 
+        let mut db = handle_errors!(open_merchant_db(&mut merch_state));
+
         // send revoke token and get pay-token in response
-        let pay_token_result =
-            zkproofs::pay::merchant_validate_rev_lock(&session_id, &revoke_token, &mut merch_state);
+        let pay_token_result = zkproofs::pay::merchant_validate_rev_lock(
+            &mut *db,
+            &session_id,
+            &revoke_token,
+            &mut merch_state,
+        );
         let pay_token = handle_errors!(pay_token_result);
 
         let ser = [
@@ -978,8 +1135,11 @@ pub mod ffishim {
             deserialize_result_object(ser_merch_state);
         let mut merch_state = handle_errors!(merch_state_result);
 
+        let mut db = handle_errors!(open_merchant_db(&mut merch_state));
+
         // send revoke token and get pay-token in response
         let pay_token_result = zkproofs::pay::multi_merchant_unmask(
+            &mut *db,
             &sender_revoke_token,
             &receiver_revoke_token,
             &mut merch_state,
@@ -1068,11 +1228,47 @@ pub mod ffishim {
         signature_map.insert("h1".to_string(), hex::encode(&h1));
         signature_map.insert("h2".to_string(), hex::encode(&h2));
 
+        // encode the closing state (field elements in little-endian)
+        let mut message_map = HashMap::new();
+        let cid = format!("{}", cust_close.message.channelId.into_repr());
+        let mut channel_id_vec = hex::decode(&cid[2..]).unwrap();
+        channel_id_vec.reverse();
+        let rlock = format!("{}", cust_close.message.rev_lock.into_repr());
+        let mut rev_lock_vec = hex::decode(&rlock[2..]).unwrap();
+        rev_lock_vec.reverse();
+        let close_fixed = format!(
+            "{}",
+            util::hash_to_fr::<CURVE>("close".as_bytes().to_vec()).into_repr()
+        );
+        let mut close_fixed_vec = hex::decode(&close_fixed[2..]).unwrap();
+        close_fixed_vec.reverse();
+
+        message_map.insert("version".to_string(), cust_close.version.to_string());
+        message_map.insert("channel_id".to_string(), hex::encode(&channel_id_vec));
+        message_map.insert("rev_lock".to_string(), hex::encode(&rev_lock_vec));
+        message_map.insert("cust_bal".to_string(), cust_close.message.bc.to_string());
+        message_map.insert("merch_bal".to_string(), cust_close.message.bm.to_string());
+        message_map.insert("close".to_string(), hex::encode(&close_fixed_vec));
+
+        // the customer signs the versioned close message hash
+        let cust_close_hash =
+            zkproofs::cust_close_message::<CURVE>(&cust_close.message, &cust_close.merch_signature);
+        let mut cust_sig_map = HashMap::new();
+        cust_sig_map.insert("hash".to_string(), hex::encode(&cust_close_hash));
+        cust_sig_map.insert(
+            "signature".to_string(),
+            hex::encode(&cust_close.cust_signature.serialize_der().to_vec()),
+        );
+
         let ser = [
             "{\'merch_pk\':\'",
             serde_json::to_string(&merch_pk_map).unwrap().as_str(),
+            "\', \'message\':\'",
+            serde_json::to_string(&message_map).unwrap().as_str(),
             "\', \'signature\':\'",
             serde_json::to_string(&signature_map).unwrap().as_str(),
+            "\', \'cust_sig\':\'",
+            serde_json::to_string(&cust_sig_map).unwrap().as_str(),
             "\'}",
         ]
         .concat();
@@ -1106,13 +1302,16 @@ pub mod ffishim {
         // Deserialize the merch state
         let merch_state_result: ResultSerdeType<zkproofs::MerchantState<CURVE>> =
             deserialize_result_object(ser_merch_state);
-        let merch_state = handle_errors!(merch_state_result);
+        let mut merch_state = handle_errors!(merch_state_result);
+
+        let mut db = handle_errors!(open_merchant_db(&mut merch_state));
 
         // // Deserialize the destination address as a string
         // let ser_addr_bytes = unsafe { CStr::from_ptr(ser_address).to_bytes() };
         // let address: &str = str::from_utf8(ser_addr_bytes).unwrap(); // make sure the bytes are UTF-8
 
         let keys = handle_errors!(zkproofs::force_merchant_close(
+            &mut *db,
             &channel_state,
             &channel_token,
             &cust_close,
@@ -1134,79 +1333,237 @@ pub mod ffishim {
         cser.into_raw()
     }
 
-    // #[no_mangle]
-    // pub extern "C" fn ffishim_bls12_tze_verify_cust_close_message(
-    //     ser_channel_token: *mut c_char,
-    //     ser_wpk: *mut c_char,
-    //     ser_close_msg: *mut c_char,
-    //     ser_close_token: *mut c_char,
-    // ) -> *mut c_char {
-    //     // Deserialize the channel token
-    //     let channel_token_result: ResultSerdeType<zkproofs::ChannelToken<CURVE>> =
-    //         deserialize_result_object(ser_channel_token);
-    //     let channel_token = handle_errors!(channel_token_result);
-
-    //     // Deserialize the wpk
-    //     let wpk_result: ResultSerdeType<secp256k1::PublicKey> = deserialize_result_object(ser_wpk);
-    //     let wpk = handle_errors!(wpk_result);
-
-    //     // Deserialize the close wallet
-    //     let close_msg_result: ResultSerdeType<zkproofs::Wallet<CURVE>> =
-    //         deserialize_result_object(ser_close_msg);
-    //     let close_msg = handle_errors!(close_msg_result);
-
-    //     // Deserialize the close token
-    //     let close_token_result: ResultSerdeType<zkproofs::Signature<CURVE>> =
-    //         deserialize_result_object(ser_close_token);
-    //     let close_token = handle_errors!(close_token_result);
-
-    //     // check the signatures
-    //     let token_valid =
-    //         zkproofs::tze_verify_cust_close_message(&channel_token, &wpk, &close_msg, &close_token);
-    //     let ser = [
-    //         "{\"result\":\"",
-    //         serde_json::to_string(&token_valid).unwrap().as_str(),
-    //         "\"}",
-    //     ]
-    //     .concat();
-    //     let cser = CString::new(ser).unwrap();
-    //     cser.into_raw()
-    // }
+    #[no_mangle]
+    pub extern "C" fn ffishim_bls12_customer_mutual_close(
+        ser_channel_state: *mut c_char,
+        ser_channel_token: *mut c_char,
+        ser_cust_state: *mut c_char,
+    ) -> *mut c_char {
+        // Deserialize the channel state
+        let channel_state_result: ResultSerdeType<zkproofs::ChannelState<CURVE>> =
+            deserialize_result_object(ser_channel_state);
+        let channel_state = handle_errors!(channel_state_result);
 
-    // #[no_mangle]
-    // pub extern "C" fn ffishim_bls12_tze_verify_merch_close_message(
-    //     ser_channel_token: *mut c_char,
-    //     ser_wpk: *mut c_char,
-    //     ser_merch_close: *mut c_char,
-    // ) -> *mut c_char {
-    //     // Deserialize the channel token
-    //     let channel_token_result: ResultSerdeType<zkproofs::ChannelToken<CURVE>> =
-    //         deserialize_result_object(ser_channel_token);
-    //     let channel_token = handle_errors!(channel_token_result);
-
-    //     // Deserialize the wpk
-    //     let wpk_result: ResultSerdeType<secp256k1::PublicKey> = deserialize_result_object(ser_wpk);
-    //     let wpk = handle_errors!(wpk_result);
-
-    //     // Deserialize the merch close
-    //     //let revoke_token: secp256k1::Signature = deserialize_object(ser_revoke_token);
-    //     let merch_close_result: ResultSerdeType<zkproofs::ChannelcloseM> =
-    //         deserialize_result_object(ser_merch_close);
-    //     let merch_close = handle_errors!(merch_close_result);
-
-    //     let revoke_token_valid =
-    //         zkproofs::tze_verify_revoke_message(&wpk, &merch_close.revoke.unwrap());
-    //     let merch_close_valid =
-    //         zkproofs::tze_verify_merch_close_message(&channel_token, &merch_close);
-    //     let token_valid = revoke_token_valid && merch_close_valid;
-
-    //     let ser = [
-    //         "{\'result\':\'",
-    //         serde_json::to_string(&token_valid).unwrap().as_str(),
-    //         "\'}",
-    //     ]
-    //     .concat();
-    //     let cser = CString::new(ser).unwrap();
-    //     cser.into_raw()
-    // }
+        // Deserialize the channel token
+        let channel_token_result: ResultSerdeType<zkproofs::ChannelToken<CURVE>> =
+            deserialize_result_object(ser_channel_token);
+        let channel_token = handle_errors!(channel_token_result);
+
+        // Deserialize the cust state
+        let cust_state_result: ResultSerdeType<zkproofs::CustomerState<CURVE>> =
+            deserialize_result_object(ser_cust_state);
+        let cust_state = handle_errors!(cust_state_result);
+
+        let mutual_close = handle_errors!(zkproofs::customer_mutual_close(
+            &channel_state,
+            &channel_token,
+            &cust_state
+        ));
+        let ser = [
+            "{\'mutual_close\':\'",
+            serde_json::to_string(&mutual_close).unwrap().as_str(),
+            "\'}",
+        ]
+        .concat();
+        let cser = CString::new(ser).unwrap();
+        cser.into_raw()
+    }
+
+    #[no_mangle]
+    pub extern "C" fn ffishim_bls12_merchant_mutual_close(
+        ser_channel_token: *mut c_char,
+        ser_mutual_close: *mut c_char,
+        ser_merch_state: *mut c_char,
+    ) -> *mut c_char {
+        // Deserialize the channel token
+        let channel_token_result: ResultSerdeType<zkproofs::ChannelToken<CURVE>> =
+            deserialize_result_object(ser_channel_token);
+        let channel_token = handle_errors!(channel_token_result);
+
+        // Deserialize the customer's mutual close request
+        let mutual_close_result: ResultSerdeType<zkproofs::ChannelMutualClose<CURVE>> =
+            deserialize_result_object(ser_mutual_close);
+        let mutual_close = handle_errors!(mutual_close_result);
+
+        // Deserialize the merch state
+        let merch_state_result: ResultSerdeType<zkproofs::MerchantState<CURVE>> =
+            deserialize_result_object(ser_merch_state);
+        let mut merch_state = handle_errors!(merch_state_result);
+
+        let mut db = handle_errors!(open_merchant_db(&mut merch_state));
+
+        let signed_close = handle_errors!(zkproofs::merchant_mutual_close(
+            &mut *db,
+            &channel_token,
+            &mutual_close,
+            &merch_state
+        ));
+        let ser = [
+            "{\'mutual_close\':\'",
+            serde_json::to_string(&signed_close).unwrap().as_str(),
+            "\'}",
+        ]
+        .concat();
+        let cser = CString::new(ser).unwrap();
+        cser.into_raw()
+    }
+
+    #[no_mangle]
+    pub extern "C" fn ffishim_bls12_verify_mutual_close(
+        ser_channel_token: *mut c_char,
+        ser_mutual_close: *mut c_char,
+    ) -> *mut c_char {
+        // Deserialize the channel token
+        let channel_token_result: ResultSerdeType<zkproofs::ChannelToken<CURVE>> =
+            deserialize_result_object(ser_channel_token);
+        let channel_token = handle_errors!(channel_token_result);
+
+        // Deserialize the signed mutual close
+        let mutual_close_result: ResultSerdeType<zkproofs::ChannelMutualClose<CURVE>> =
+            deserialize_result_object(ser_mutual_close);
+        let mutual_close = handle_errors!(mutual_close_result);
+
+        let is_valid = zkproofs::verify_mutual_close(&channel_token, &mutual_close);
+        let ser = [
+            "{\'result\':\'",
+            serde_json::to_string(&is_valid).unwrap().as_str(),
+            "\'}",
+        ]
+        .concat();
+        let cser = CString::new(ser).unwrap();
+        cser.into_raw()
+    }
+
+    #[no_mangle]
+    pub extern "C" fn ffishim_bls12_customer_force_close_on_stall(
+        ser_channel_state: *mut c_char,
+        ser_cust_state: *mut c_char,
+    ) -> *mut c_char {
+        // Deserialize the channel state
+        let channel_state_result: ResultSerdeType<zkproofs::ChannelState<CURVE>> =
+            deserialize_result_object(ser_channel_state);
+        let channel_state = handle_errors!(channel_state_result);
+
+        // Deserialize the cust state
+        let cust_state_result: ResultSerdeType<zkproofs::CustomerState<CURVE>> =
+            deserialize_result_object(ser_cust_state);
+        let cust_state = handle_errors!(cust_state_result);
+
+        let evidence = handle_errors!(zkproofs::pay::customer_force_close_on_stall(
+            &channel_state,
+            &cust_state
+        ));
+        let ser = [
+            "{\'evidence\':\'",
+            serde_json::to_string(&evidence).unwrap().as_str(),
+            "\'}",
+        ]
+        .concat();
+        let cser = CString::new(ser).unwrap();
+        cser.into_raw()
+    }
+
+    #[no_mangle]
+    pub extern "C" fn ffishim_bls12_verify_merchant_stall_evidence(
+        ser_channel_token: *mut c_char,
+        ser_evidence: *mut c_char,
+    ) -> *mut c_char {
+        // Deserialize the channel token
+        let channel_token_result: ResultSerdeType<zkproofs::ChannelToken<CURVE>> =
+            deserialize_result_object(ser_channel_token);
+        let channel_token = handle_errors!(channel_token_result);
+
+        // Deserialize the stall evidence
+        let evidence_result: ResultSerdeType<zkproofs::MerchantStallEvidence<CURVE>> =
+            deserialize_result_object(ser_evidence);
+        let evidence = handle_errors!(evidence_result);
+
+        let is_valid = zkproofs::verify_merchant_stall_evidence(&channel_token, &evidence);
+        let ser = [
+            "{\'result\':\'",
+            serde_json::to_string(&is_valid).unwrap().as_str(),
+            "\'}",
+        ]
+        .concat();
+        let cser = CString::new(ser).unwrap();
+        cser.into_raw()
+    }
+
+    #[no_mangle]
+    pub extern "C" fn ffishim_bls12_tze_verify_cust_close_message(
+        ser_channel_token: *mut c_char,
+        ser_cust_close: *mut c_char,
+    ) -> *mut c_char {
+        // Deserialize the channel token
+        let channel_token_result: ResultSerdeType<zkproofs::ChannelToken<CURVE>> =
+            deserialize_result_object(ser_channel_token);
+        let channel_token = handle_errors!(channel_token_result);
+
+        // Deserialize the customer close structure
+        let cust_close_result: ResultSerdeType<zkproofs::ChannelcloseC<CURVE>> =
+            deserialize_result_object(ser_cust_close);
+        let cust_close = handle_errors!(cust_close_result);
+
+        let is_valid = zkproofs::tze_verify_cust_close_message(&channel_token, &cust_close);
+        let ser = [
+            "{\'result\':\'",
+            serde_json::to_string(&is_valid).unwrap().as_str(),
+            "\'}",
+        ]
+        .concat();
+        let cser = CString::new(ser).unwrap();
+        cser.into_raw()
+    }
+
+    #[no_mangle]
+    pub extern "C" fn ffishim_bls12_tze_verify_revoke_message(
+        ser_cust_close: *mut c_char,
+        ser_rev_lock_pair: *mut c_char,
+    ) -> *mut c_char {
+        // Deserialize the customer close structure
+        let cust_close_result: ResultSerdeType<zkproofs::ChannelcloseC<CURVE>> =
+            deserialize_result_object(ser_cust_close);
+        let cust_close = handle_errors!(cust_close_result);
+
+        // Deserialize the rev_lock/rev_secret pair
+        let rt_pair_result: ResultSerdeType<zkproofs::RevLockPair> =
+            deserialize_result_object(ser_rev_lock_pair);
+        let rt_pair = handle_errors!(rt_pair_result);
+
+        let is_valid = zkproofs::tze_verify_revoke_message(&cust_close.message, &rt_pair);
+        let ser = [
+            "{\'result\':\'",
+            serde_json::to_string(&is_valid).unwrap().as_str(),
+            "\'}",
+        ]
+        .concat();
+        let cser = CString::new(ser).unwrap();
+        cser.into_raw()
+    }
+
+    #[no_mangle]
+    pub extern "C" fn ffishim_bls12_tze_verify_merch_close_message(
+        ser_channel_token: *mut c_char,
+        ser_merch_close: *mut c_char,
+    ) -> *mut c_char {
+        // Deserialize the channel token
+        let channel_token_result: ResultSerdeType<zkproofs::ChannelToken<CURVE>> =
+            deserialize_result_object(ser_channel_token);
+        let channel_token = handle_errors!(channel_token_result);
+
+        // Deserialize the merch close
+        let merch_close_result: ResultSerdeType<zkproofs::ChannelcloseM> =
+            deserialize_result_object(ser_merch_close);
+        let merch_close = handle_errors!(merch_close_result);
+
+        let is_valid = zkproofs::tze_verify_merch_close_message(&channel_token, &merch_close);
+        let ser = [
+            "{\'result\':\'",
+            serde_json::to_string(&is_valid).unwrap().as_str(),
+            "\'}",
+        ]
+        .concat();
+        let cser = CString::new(ser).unwrap();
+        cser.into_raw()
+    }
 }
@@ -7,7 +7,7 @@ pub mod ffishim_mpc {
         ChannelMPCState, ChannelMPCToken, CustomerMPCState, InitCustState, MerchantMPCState,
         TransactionFeeInfo,
     };
-    use channels_util::FundingTxInfo;
+    use channels_util::{BitcoinNetworkType, FundingTxInfo};
     use database::{MaskedTxMPCInputs, RedisDatabase, StateDatabase};
     use hex::FromHexError;
     use libc::{c_char, c_void};
@@ -17,8 +17,11 @@ pub mod ffishim_mpc {
     use serde::Deserialize;
     use std::ffi::{CStr, CString};
     use std::str;
+    use std::str::FromStr;
+    use std::sync::Mutex;
+    use taproot;
     use wallet::State;
-    use zkchan_tx::Testnet;
+    use zkchan_tx::BitcoinNetwork;
 
     fn error_message(s: String) -> *mut c_char {
         let ser = ["{\'error\':\'", &s, "\'}"].concat();
@@ -72,6 +75,77 @@ pub mod ffishim_mpc {
         }
     }
 
+    fn deserialize_network(serialized: *mut c_char) -> Result<BitcoinNetworkType, String> {
+        let network = deserialize_string(serialized)?;
+        BitcoinNetworkType::from_str(&network)
+    }
+
+    // returns (merch_tx_preimage, txid_be, hash_prevout) for the merch-close-tx
+    fn form_merch_close_tx_helper<N: BitcoinNetwork>(
+        escrow_txid_be: Vec<u8>,
+        cust_pk: Vec<u8>,
+        merch_pk: Vec<u8>,
+        merch_close_pk: Vec<u8>,
+        merch_child_pk: Vec<u8>,
+        cust_bal_sats: i64,
+        merch_bal_sats: i64,
+        fee_mc: i64,
+        val_cpfp: i64,
+        self_delay_be: [u8; 2],
+    ) -> Result<(Vec<u8>, Vec<u8>, Vec<u8>), String> {
+        let (merch_tx_preimage, tx_params) =
+            match zkchan_tx::transactions::btc::merchant_form_close_transaction::<N>(
+                escrow_txid_be,
+                cust_pk,
+                merch_pk,
+                merch_close_pk,
+                merch_child_pk,
+                cust_bal_sats,
+                merch_bal_sats,
+                fee_mc,
+                val_cpfp,
+                self_delay_be,
+            ) {
+                Ok(n) => n,
+                Err(e) => return Err(e.to_string()),
+            };
+        let (txid_be, prevout) =
+            match zkchan_tx::txutil::merchant_generate_transaction_id(tx_params) {
+                Ok(n) => n,
+                Err(e) => return Err(e.to_string()),
+            };
+        Ok((merch_tx_preimage, txid_be.to_vec(), prevout.to_vec()))
+    }
+
+    fn form_merch_close_tx(
+        network: BitcoinNetworkType,
+        escrow_txid_be: Vec<u8>,
+        cust_pk: Vec<u8>,
+        merch_pk: Vec<u8>,
+        merch_close_pk: Vec<u8>,
+        merch_child_pk: Vec<u8>,
+        cust_bal_sats: i64,
+        merch_bal_sats: i64,
+        fee_mc: i64,
+        val_cpfp: i64,
+        self_delay_be: [u8; 2],
+    ) -> Result<(Vec<u8>, Vec<u8>, Vec<u8>), String> {
+        with_bitcoin_network!(network, N => {
+            form_merch_close_tx_helper::<N>(
+                escrow_txid_be,
+                cust_pk,
+                merch_pk,
+                merch_close_pk,
+                merch_child_pk,
+                cust_bal_sats,
+                merch_bal_sats,
+                fee_mc,
+                val_cpfp,
+                self_delay_be,
+            )
+        })
+    }
+
     #[no_mangle]
     pub extern "C" fn mpc_free_string(pointer: *mut c_char) {
         unsafe {
@@ -100,7 +174,7 @@ pub mod ffishim_mpc {
         cser.into_raw()
     }
 
-    // CHANNEL SETUP - define name, self-delay, third-party-support
+    // CHANNEL SETUP - define name, self-delay, third-party-support, bitcoin network
 
     #[no_mangle]
     pub extern "C" fn mpc_channel_setup(
@@ -110,6 +184,7 @@ pub mod ffishim_mpc {
         bal_min_merch: i64,
         val_cpfp: i64,
         third_party_support: u32,
+        ser_network: *mut c_char,
     ) -> *mut c_char {
         let bytes = unsafe { CStr::from_ptr(channel_name).to_bytes() };
         let name: &str = str::from_utf8(bytes).unwrap(); // make sure the bytes are UTF-8
@@ -118,7 +193,8 @@ pub mod ffishim_mpc {
         if third_party_support >= 1 {
             tps = true;
         }
-        let channel_state = mpc::ChannelMPCState::new(
+        let network = handle_errors!(deserialize_network(ser_network));
+        let mut channel_state = mpc::ChannelMPCState::new(
             name.to_string(),
             self_delay,
             bal_min_cust,
@@ -126,6 +202,7 @@ pub mod ffishim_mpc {
             val_cpfp,
             tps,
         );
+        channel_state.set_network(network);
 
         let ser = [
             "{\'channel_state\':\'",
@@ -242,6 +319,7 @@ pub mod ffishim_mpc {
         merch_bal: i64,
         ser_tx_fee_info: *mut c_char,
         name_ptr: *const c_char,
+        ser_network: *mut c_char,
     ) -> *mut c_char {
         let rng = &mut rand::thread_rng();
 
@@ -259,9 +337,12 @@ pub mod ffishim_mpc {
         let bytes = unsafe { CStr::from_ptr(name_ptr).to_bytes() };
         let name: &str = str::from_utf8(bytes).unwrap(); // make sure the bytes are UTF-8
 
+        // Deserialize the network the customer expects the channel on
+        let network = handle_errors!(deserialize_network(ser_network));
+
         // We change the channel state
         let (channel_token, cust_state) =
-            mpc::init_customer(rng, &pk_m, cust_bal, merch_bal, &tx_fee_info, name);
+            mpc::init_customer(rng, &pk_m, cust_bal, merch_bal, &tx_fee_info, name, network);
         let ser = [
             "{\'cust_state\':\'",
             serde_json::to_string(&cust_state).unwrap().as_str(),
@@ -547,6 +628,44 @@ pub mod ffishim_mpc {
         cser.into_raw()
     }
 
+    #[no_mangle]
+    pub extern "C" fn mpc_merchant_issue_refund_voucher(
+        ser_nonce: *mut c_char,
+        amount: i64,
+        expiry: i64,
+        ser_merch_state: *mut c_char,
+    ) -> *mut c_char {
+        let rng = &mut rand::thread_rng();
+
+        // Deserialize nonce
+        let nonce_result = deserialize_hex_string(ser_nonce);
+        let nonce = handle_errors!(nonce_result);
+        check_vec_length!(nonce, 16);
+        let mut nonce_ar = [0u8; 16];
+        nonce_ar.copy_from_slice(nonce.as_slice());
+
+        // Deserialize the merch_state
+        let merch_state_result: ResultSerdeType<MerchantMPCState> =
+            deserialize_result_object(ser_merch_state);
+        let merch_state = handle_errors!(merch_state_result);
+
+        let voucher = handle_errors!(mpc::merchant_issue_refund_voucher(
+            rng,
+            nonce_ar,
+            amount,
+            expiry,
+            &merch_state
+        ));
+        let ser = [
+            "{\'refund_voucher\':\'",
+            serde_json::to_string(&voucher).unwrap().as_str(),
+            "\'}",
+        ]
+        .concat();
+        let cser = CString::new(ser).unwrap();
+        cser.into_raw()
+    }
+
     #[no_mangle]
     pub extern "C" fn mpc_prepare_payment_merchant(
         ser_channel_state: *mut c_char,
@@ -1401,11 +1520,14 @@ pub mod ffishim_mpc {
         fee_mc: i64,
         val_cpfp: i64,
         ser_self_delay: *mut c_char,
+        ser_network: *mut c_char,
     ) -> *mut c_char {
         let escrow_txid_le_result = deserialize_hex_string(ser_escrow_txid);
         let mut escrow_txid_be = handle_errors!(escrow_txid_le_result);
         escrow_txid_be.reverse(); // now it's in big endian
 
+        let network = handle_errors!(deserialize_network(ser_network));
+
         let cust_pk_result = deserialize_hex_string(ser_cust_pk);
         let cust_pk = handle_errors!(cust_pk_result);
 
@@ -1424,20 +1546,19 @@ pub mod ffishim_mpc {
         let mut self_delay_be = [0u8; 2];
         self_delay_be.copy_from_slice(&self_delay);
 
-        let (merch_tx_preimage, _) = handle_errors!(
-            zkchan_tx::transactions::btc::merchant_form_close_transaction::<Testnet>(
-                escrow_txid_be,
-                cust_pk,
-                merch_pk,
-                merch_close_pk,
-                merch_child_pk,
-                cust_bal_sats,
-                merch_bal_sats,
-                fee_mc,
-                val_cpfp,
-                self_delay_be
-            )
-        );
+        let (merch_tx_preimage, _, _) = handle_errors!(form_merch_close_tx(
+            network,
+            escrow_txid_be,
+            cust_pk,
+            merch_pk,
+            merch_close_pk,
+            merch_child_pk,
+            cust_bal_sats,
+            merch_bal_sats,
+            fee_mc,
+            val_cpfp,
+            self_delay_be
+        ));
 
         let ser = [
             "{\'merch_tx_preimage\':\'",
@@ -1509,20 +1630,19 @@ pub mod ffishim_mpc {
         let merch_child_pk = merch_state.child_pk.serialize().to_vec();
         let merch_close_pk = merch_state.payout_pk.serialize().to_vec();
 
-        let (merch_tx_preimage, tx_params) = handle_errors!(
-            zkchan_tx::transactions::btc::merchant_form_close_transaction::<Testnet>(
-                escrow_txid_be.clone(),
-                cust_pk.clone(),
-                merch_pk,
-                merch_close_pk,
-                merch_child_pk,
-                cust_bal_sats,
-                merch_bal_sats,
-                fee_mc,
-                val_cpfp,
-                self_delay_be
-            )
-        );
+        let (merch_tx_preimage, txid_be, prevout) = handle_errors!(form_merch_close_tx(
+            merch_state.get_network(),
+            escrow_txid_be.clone(),
+            cust_pk.clone(),
+            merch_pk,
+            merch_close_pk,
+            merch_child_pk,
+            cust_bal_sats,
+            merch_bal_sats,
+            fee_mc,
+            val_cpfp,
+            self_delay_be
+        ));
 
         let is_ok = handle_errors!(zkchan_tx::txutil::merchant_verify_merch_close_transaction(
             &merch_tx_preimage,
@@ -1545,9 +1665,6 @@ pub mod ffishim_mpc {
             );
         }
 
-        let (txid_be, prevout) = handle_errors!(
-            zkchan_tx::txutil::merchant_generate_transaction_id(tx_params)
-        );
         let mut txid_le = txid_be.to_vec();
         txid_le.reverse();
 
@@ -1609,8 +1726,8 @@ pub mod ffishim_mpc {
             deserialize_result_object(ser_merch_state);
         let merch_state = handle_errors!(merch_state_result);
 
-        let (escrow_sig, merch_sig) = handle_errors!(merch_state
-            .sign_initial_closing_transaction::<Testnet>(
+        let sign_result = with_bitcoin_network!(merch_state.get_network(), N => {
+            merch_state.sign_initial_closing_transaction::<N>(
                 funding_tx,
                 rl,
                 cust_pk,
@@ -1619,7 +1736,9 @@ pub mod ffishim_mpc {
                 fee_cc,
                 fee_mc,
                 val_cpfp,
-            ));
+            )
+        });
+        let (escrow_sig, merch_sig) = handle_errors!(sign_result);
 
         let ser = [
             "{\'escrow_sig\': \'",
@@ -1682,12 +1801,15 @@ pub mod ffishim_mpc {
         ));
 
         // now sign the customer's initial closing txs iff escrow-sig and merch-sig are valid
-        let got_close_tx = handle_errors!(cust_state.sign_initial_closing_transaction::<Testnet>(
-            &channel_state,
-            &channel_token,
-            &escrow_sig,
-            &merch_sig
-        ));
+        let sign_result = with_bitcoin_network!(channel_state.get_network(), N => {
+            cust_state.sign_initial_closing_transaction::<N>(
+                &channel_state,
+                &channel_token,
+                &escrow_sig,
+                &merch_sig,
+            )
+        });
+        let got_close_tx = handle_errors!(sign_result);
         let ser = [
             "{\'is_ok\':",
             serde_json::to_string(&got_close_tx).unwrap().as_str(),
@@ -2098,6 +2220,220 @@ pub mod ffishim_mpc {
         cser.into_raw()
     }
 
+    fn deserialize_hex_32(serialized: *mut c_char) -> Result<[u8; 32], String> {
+        let bytes = deserialize_hex_string(serialized).map_err(|e| e.to_string())?;
+        if bytes.len() != 32 {
+            return Err(String::from("expected 32 bytes"));
+        }
+        let mut buf = [0u8; 32];
+        buf.copy_from_slice(&bytes);
+        Ok(buf)
+    }
+
+    fn deserialize_pk(serialized: *mut c_char) -> Result<secp256k1::PublicKey, String> {
+        let bytes = deserialize_hex_string(serialized).map_err(|e| e.to_string())?;
+        secp256k1::PublicKey::from_slice(&bytes).map_err(|e| e.to_string())
+    }
+
+    // Taproot mutual close txs waiting for the partial signature of the signer that made
+    // their nonces. Each holds the signer's secret nonce, which never crosses the FFI and is
+    // removed with its tx when it signs
+    static TAPROOT_SIGNING_SESSIONS: Mutex<Vec<taproot::MutualCloseTransaction>> =
+        Mutex::new(Vec::new());
+
+    fn signer_pk(sk: &[u8; 32]) -> Result<secp256k1::PublicKey, String> {
+        let secp = secp256k1::Secp256k1::signing_only();
+        let sk = secp256k1::SecretKey::from_slice(sk).map_err(|e| e.to_string())?;
+        Ok(secp256k1::PublicKey::from_secret_key(&secp, &sk))
+    }
+
+    // index of the signing session of signer for the mutual close tx with this sighash
+    fn find_taproot_signing_session(
+        sessions: &Vec<taproot::MutualCloseTransaction>,
+        sighash: &[u8; 32],
+        signer: &secp256k1::PublicKey,
+    ) -> Option<usize> {
+        sessions
+            .iter()
+            .position(|s| s.signer() == Some(signer) && s.sighash().as_ref() == Ok(sighash))
+    }
+
+    // Both parties form the same Taproot mutual close tx from the channel parameters
+    fn taproot_mutual_close_helper(
+        ser_tx_index: *mut c_char,
+        index: u32,
+        input_amount: i64,
+        cust_amount: i64,
+        merch_amount: i64,
+        ser_merch_close_pk: *mut c_char,
+        ser_cust_close_pk: *mut c_char,
+        ser_merch_pk: *mut c_char,
+        ser_cust_pk: *mut c_char,
+    ) -> Result<taproot::MutualCloseTransaction, String> {
+        let txid_le = deserialize_hex_32(ser_tx_index)?;
+        let merch_close_pk = deserialize_pk(ser_merch_close_pk)?;
+        let cust_close_pk = deserialize_pk(ser_cust_close_pk)?;
+        let merch_pk = deserialize_pk(ser_merch_pk)?;
+        let cust_pk = deserialize_pk(ser_cust_pk)?;
+        taproot::MutualCloseTransaction::new(
+            &txid_le,
+            index,
+            input_amount,
+            &cust_pk,
+            &merch_pk,
+            &cust_close_pk,
+            &merch_close_pk,
+            cust_amount,
+            merch_amount,
+        )
+    }
+
+    // Taproot mutual close, round 1: the nonces of the signer with sk. The public nonce
+    // goes to the other party, the secret nonce is kept for the signer's partial signature
+    #[no_mangle]
+    pub extern "C" fn taproot_mutual_close_nonce(
+        ser_tx_index: *mut c_char,
+        index: u32,
+        input_amount: i64,
+        cust_amount: i64,
+        merch_amount: i64,
+        ser_merch_close_pk: *mut c_char,
+        ser_cust_close_pk: *mut c_char,
+        ser_merch_pk: *mut c_char,
+        ser_cust_pk: *mut c_char,
+        ser_sk: *mut c_char,
+    ) -> *mut c_char {
+        let rng = &mut rand::thread_rng();
+        let mut close_tx = handle_errors!(taproot_mutual_close_helper(
+            ser_tx_index,
+            index,
+            input_amount,
+            cust_amount,
+            merch_amount,
+            ser_merch_close_pk,
+            ser_cust_close_pk,
+            ser_merch_pk,
+            ser_cust_pk
+        ));
+        let sk = handle_errors!(deserialize_hex_32(ser_sk));
+        let signer = handle_errors!(signer_pk(&sk));
+        let sighash = handle_errors!(close_tx.sighash());
+        let pub_nonce = handle_errors!(close_tx.nonce_gen(rng, &sk));
+
+        // a new nonce for the same tx and signer replaces the unused one
+        let mut sessions = TAPROOT_SIGNING_SESSIONS.lock().unwrap();
+        if let Some(i) = find_taproot_signing_session(&sessions, &sighash, &signer) {
+            sessions.remove(i);
+        }
+        sessions.push(close_tx);
+        let ser = ["{\'pub_nonce\':\'", &hex::encode(pub_nonce), "\'}"].concat();
+        let cser = CString::new(ser).unwrap();
+        cser.into_raw()
+    }
+
+    // Taproot mutual close, round 2: the partial signature of the signer with sk
+    #[no_mangle]
+    pub extern "C" fn taproot_mutual_close_partial_sign(
+        ser_tx_index: *mut c_char,
+        index: u32,
+        input_amount: i64,
+        cust_amount: i64,
+        merch_amount: i64,
+        ser_merch_close_pk: *mut c_char,
+        ser_cust_close_pk: *mut c_char,
+        ser_merch_pk: *mut c_char,
+        ser_cust_pk: *mut c_char,
+        ser_sk: *mut c_char,
+        ser_cust_pub_nonce: *mut c_char,
+        ser_merch_pub_nonce: *mut c_char,
+    ) -> *mut c_char {
+        let close_tx = handle_errors!(taproot_mutual_close_helper(
+            ser_tx_index,
+            index,
+            input_amount,
+            cust_amount,
+            merch_amount,
+            ser_merch_close_pk,
+            ser_cust_close_pk,
+            ser_merch_pk,
+            ser_cust_pk
+        ));
+        let sk = handle_errors!(deserialize_hex_32(ser_sk));
+        let cust_pub_nonce = handle_errors!(deserialize_hex_string(ser_cust_pub_nonce));
+        let merch_pub_nonce = handle_errors!(deserialize_hex_string(ser_merch_pub_nonce));
+        let signer = handle_errors!(signer_pk(&sk));
+        let sighash = handle_errors!(close_tx.sighash());
+
+        // the session is removed before signing, so its secret nonce signs at most once
+        let mut signing_tx = {
+            let mut sessions = TAPROOT_SIGNING_SESSIONS.lock().unwrap();
+            match find_taproot_signing_session(&sessions, &sighash, &signer) {
+                Some(i) => sessions.remove(i),
+                None => {
+                    return error_message(String::from(
+                        "no nonce for this mutual close tx and key: call taproot_mutual_close_nonce first",
+                    ))
+                }
+            }
+        };
+        let partial_sig =
+            handle_errors!(signing_tx.partial_sign(&sk, &cust_pub_nonce, &merch_pub_nonce));
+        let ser = ["{\'partial_sig\': \'", &hex::encode(partial_sig), "\'}"].concat();
+        let cser = CString::new(ser).unwrap();
+        cser.into_raw()
+    }
+
+    // Taproot mutual close: check and aggregate both partial signatures into the signed tx
+    #[no_mangle]
+    pub extern "C" fn taproot_mutual_close_finalize(
+        ser_tx_index: *mut c_char,
+        index: u32,
+        input_amount: i64,
+        cust_amount: i64,
+        merch_amount: i64,
+        ser_merch_close_pk: *mut c_char,
+        ser_cust_close_pk: *mut c_char,
+        ser_merch_pk: *mut c_char,
+        ser_cust_pk: *mut c_char,
+        ser_cust_pub_nonce: *mut c_char,
+        ser_merch_pub_nonce: *mut c_char,
+        ser_cust_partial_sig: *mut c_char,
+        ser_merch_partial_sig: *mut c_char,
+    ) -> *mut c_char {
+        let mut close_tx = handle_errors!(taproot_mutual_close_helper(
+            ser_tx_index,
+            index,
+            input_amount,
+            cust_amount,
+            merch_amount,
+            ser_merch_close_pk,
+            ser_cust_close_pk,
+            ser_merch_pk,
+            ser_cust_pk
+        ));
+        let cust_pub_nonce = handle_errors!(deserialize_hex_string(ser_cust_pub_nonce));
+        let merch_pub_nonce = handle_errors!(deserialize_hex_string(ser_merch_pub_nonce));
+        let cust_partial_sig = handle_errors!(deserialize_hex_32(ser_cust_partial_sig));
+        let merch_partial_sig = handle_errors!(deserialize_hex_32(ser_merch_partial_sig));
+
+        let signed_tx = handle_errors!(close_tx.finalize(
+            &cust_pub_nonce,
+            &merch_pub_nonce,
+            &cust_partial_sig,
+            &merch_partial_sig
+        ));
+        let ser = [
+            "{\'signed_tx\': \'",
+            &hex::encode(signed_tx),
+            "\', \'txid_le\':\'",
+            &hex::encode(close_tx.tx.txid_le()),
+            "\'}",
+        ]
+        .concat();
+        let cser = CString::new(ser).unwrap();
+        cser.into_raw()
+    }
+
     #[no_mangle]
     pub extern "C" fn create_child_tx_to_bump_fee_via_p2wpkh_input(
         ser_tx_index1: *mut c_char,
@@ -1,18 +1,21 @@
 use super::*;
 use bindings::{cb_receive, cb_send, ConnType_LNDNETIO, ConnType_NETIO};
 pub use channels_mpc::{
-    ChannelMPCState, ChannelMPCToken, CustomerMPCState, MerchantMPCState, RevokedState,
-    TransactionFeeInfo,
+    ChannelMPCState, ChannelMPCToken, CustomerMPCState, CustomerPaymentSession, MerchantMPCState,
+    RefundVoucher, RevokedState, TransactionFeeInfo,
 };
 pub use channels_mpc::{InitCustState, NetworkConfig};
-pub use channels_util::{ChannelStatus, PaymentStatus, ProtocolStatus};
+pub use channels_util::{
+    BitcoinNetworkType, ChannelStatus, NegativePaymentPolicy, PaymentStatus, ProtocolStatus,
+};
 use database::{MaskedTxMPCInputs, StateDatabase};
 use libc::c_void;
+use mpcwrapper::with_mpc_channel;
+pub use mpcwrapper::MpcChannel;
 use rand::Rng;
 use secp256k1::PublicKey;
 pub use wallet::{State, NONCE_LEN};
 use zkchan_tx::fixed_size_array::{FixedSizeArray16, FixedSizeArray32};
-use zkchan_tx::Testnet;
 
 ///
 /// init_merchant() - takes as input the public params, merchant balance and keypair.
@@ -33,7 +36,8 @@ pub fn init_merchant<'a, R: Rng>(
 }
 
 ///
-/// init_customer() - takes as input the merchant's public key, and initial balance for customer and merchant.
+/// init_customer() - takes as input the merchant's public key, initial balance for customer and merchant
+/// and the bitcoin network of the channel.
 /// Generate initial customer channel state and channel token.
 /// output: a channel token and customer state
 ///
@@ -44,6 +48,7 @@ pub fn init_customer<'a, R: Rng>(
     b0_merch: i64,
     tx_fee_info: &TransactionFeeInfo,
     name: &str,
+    network: BitcoinNetworkType,
 ) -> (ChannelMPCToken, CustomerMPCState) {
     assert!(b0_cust > 0);
     assert!(b0_merch >= 0);
@@ -69,7 +74,8 @@ pub fn init_customer<'a, R: Rng>(
     let mut cust_state = CustomerMPCState::new(csprng, b0_cust, b0_merch, fee_cc, cust_name);
 
     // generate the initial channel token and initial state
-    let channel_token = cust_state.generate_init_state(csprng, &pk_m, min_fee, max_fee, fee_mc);
+    let channel_token =
+        cust_state.generate_init_state(csprng, &pk_m, min_fee, max_fee, fee_mc, network);
 
     (channel_token, cust_state)
 }
@@ -188,6 +194,18 @@ pub fn activate_customer_finalize(
     cust_state.store_initial_pay_token(pay_token_0)
 }
 
+///
+/// pay_begin_customer() - takes as input the customer state.
+/// Records the customer state before a payment so that a failed payment can be undone.
+/// Call before pay_prepare_customer() and finish with pay_commit_customer() or pay_abort_customer().
+/// To be able to resume the payment, record the prepare output and the MPC result in the session
+/// and persist it with the customer state
+/// output: the payment session
+///
+pub fn pay_begin_customer(cust_state: &CustomerMPCState) -> CustomerPaymentSession {
+    CustomerPaymentSession::new(cust_state)
+}
+
 ///
 /// pay_prepare_customer() - takes as input an rng, the channel state, the payment amount, and the customer state.
 /// Prepare payment for customer
@@ -247,9 +265,28 @@ pub fn pay_prepare_customer<R: Rng>(
     }
 }
 
+///
+/// merchant_issue_refund_voucher() - takes as input an rng, the nonce of the customer's current state, the (negative)
+/// refund amount, an expiry (unix time in seconds) and the merchant state.
+/// Issues a one-time voucher that the customer presents as justification for the negative payment from that state.
+/// The voucher is spent once the customer revokes that state.
+/// Has no effect unless the merchant refund policy is set to CHECK_JUSTIFICATION.
+/// output: signed refund voucher
+///
+pub fn merchant_issue_refund_voucher<R: Rng>(
+    csprng: &mut R,
+    nonce: [u8; NONCE_LEN],
+    amount: i64,
+    expiry: i64,
+    merch_state: &MerchantMPCState,
+) -> Result<RefundVoucher, String> {
+    merch_state.issue_refund_voucher(csprng, nonce, amount, expiry)
+}
+
 ///
 /// pay_prepare_merchant() - takes as input an rng, the channel state, the nonce of the old state, rev lock commitment, amount and the merchant state.
-/// Prepare payment for merchant
+/// Prepare payment for merchant. A retry on the same nonce (see pay_abort_customer()) supersedes
+/// the previous session for that nonce as long as its MPC has not run yet
 /// output: commitment of the payment token mask
 ///
 pub fn pay_prepare_merchant<R: Rng>(
@@ -276,6 +313,16 @@ pub fn pay_prepare_merchant<R: Rng>(
     )
 }
 
+fn mpc_channel_network_config() -> NetworkConfig {
+    NetworkConfig {
+        conn_type: ConnType_LNDNETIO,
+        dest_ip: String::new(),
+        dest_port: 0,
+        path: String::new(),
+        proxy: None,
+    }
+}
+
 ///
 /// pay_update_customer() - takes as input the channel state, the channel token, the intial state, the final state, a commitment for the mask for the pay token,
 /// the revocation lock commitment, the payment amount, and the customer state.
@@ -311,9 +358,10 @@ pub fn pay_update_customer(
                 dest_ip: String::from("127.0.0.1"),
                 dest_port: 2424,
                 path: String::new(),
+                proxy: None,
             });
         }
-        let circuit = cust_state.get_circuit_file();
+        let circuit = cust_state.get_circuit_file(channel_state)?;
         cust_state.execute_mpc_context(
             &channel_state,
             &channel_token,
@@ -335,6 +383,43 @@ pub fn pay_update_customer(
     }
 }
 
+///
+/// pay_update_customer_with_channel() - same as pay_update_customer() but runs the MPC over
+/// a caller-provided MpcChannel instead of a raw peer pointer and C callbacks
+/// output: a success boolean, or error
+///
+pub fn pay_update_customer_with_channel(
+    channel_state: &ChannelMPCState,
+    channel_token: &ChannelMPCToken,
+    s0: State,
+    s1: State,
+    pay_token_mask_com: [u8; 32],
+    rev_lock_com: [u8; 32],
+    amount: i64,
+    cust_state: &mut CustomerMPCState,
+    mpc_channel: &mut dyn MpcChannel,
+) -> Result<String, String> {
+    // the channel replaces the configured connection for this session only
+    let net_config = cust_state.net_config.replace(mpc_channel_network_config());
+    let result = with_mpc_channel(mpc_channel, |p_ptr, send_cb, receive_cb| {
+        pay_update_customer(
+            channel_state,
+            channel_token,
+            s0,
+            s1,
+            pay_token_mask_com,
+            rev_lock_com,
+            amount,
+            cust_state,
+            p_ptr,
+            send_cb,
+            receive_cb,
+        )
+    });
+    cust_state.net_config = net_config;
+    result
+}
+
 ///
 /// pay_update_merchant() - takes as input an rng, the channel state, the intial state, a commitment for the mask for the pay token,
 /// the revocation lock commitment, the payment amount, and the merchant state.
@@ -363,9 +448,10 @@ pub fn pay_update_merchant<R: Rng>(
             dest_ip: String::from("127.0.0.1"),
             dest_port: 2424,
             path: String::new(),
+            proxy: None,
         });
     }
-    let circuit = merch_state.get_circuit_file();
+    let circuit = merch_state.get_circuit_file(channel)?;
     return merch_state.execute_mpc_context(
         csprng,
         db,
@@ -379,6 +465,39 @@ pub fn pay_update_merchant<R: Rng>(
     );
 }
 
+///
+/// pay_update_merchant_with_channel() - same as pay_update_merchant() but runs the MPC over
+/// a caller-provided MpcChannel instead of a raw peer pointer and C callbacks
+/// output: the transaction masks (escrow and merch tx), or error
+///
+pub fn pay_update_merchant_with_channel<R: Rng>(
+    csprng: &mut R,
+    db: &mut dyn StateDatabase,
+    channel: &ChannelMPCState,
+    session_id: [u8; 16],
+    pay_token_mask_com: [u8; 32],
+    merch_state: &mut MerchantMPCState,
+    mpc_channel: &mut dyn MpcChannel,
+) -> Result<bool, String> {
+    // the channel replaces the configured connection for this session only
+    let net_config = merch_state.net_config.replace(mpc_channel_network_config());
+    let result = with_mpc_channel(mpc_channel, |p_ptr, send_cb, receive_cb| {
+        pay_update_merchant(
+            csprng,
+            db,
+            channel,
+            session_id,
+            pay_token_mask_com,
+            merch_state,
+            p_ptr,
+            send_cb,
+            receive_cb,
+        )
+    });
+    merch_state.net_config = net_config;
+    result
+}
+
 ///
 /// pay_confirm_mpc_result() - takes as input a db, session identifier, mpc result and merch state
 /// output: masked input if the mpc result was successful and there is a masked input for a given session_id
@@ -393,24 +512,46 @@ pub fn pay_confirm_mpc_result(
     db.is_connected()?;
 
     let session_id_hex = hex::encode(session_id);
-    let mask_bytes = match db.get_masked_mpc_inputs(&session_id_hex) {
-        Ok(n) => Some(n),
-        Err(e) => return Err(e.to_string()),
-    };
-    let mask_bytes_unwrapped = mask_bytes.unwrap();
-    if hex::encode(mask_bytes_unwrapped.verify_success.0) == success {
-        return Ok(mask_bytes_unwrapped.get_tx_masks());
-    } else {
-        let mut session_state = match db.load_session_state(&session_id_hex) {
-            Ok(s) => s,
-            Err(e) => return Err(e.to_string()),
-        };
-        session_state.status = PaymentStatus::Error;
-        db.update_session_state(&session_id_hex, &session_state);
+    let mut session_state = db.load_session_state(&session_id_hex)?;
+    // failed sessions never release their masks
+    if session_state.status == PaymentStatus::Error {
         return Err(format!(
-            "pay_confirm_mpc_result: will need to restart MPC session"
+            "pay_confirm_mpc_result: session has failed: {}",
+            session_id_hex
         ));
     }
+    let mask_bytes = db.get_masked_mpc_inputs(&session_id_hex)?;
+    if hex::encode(mask_bytes.verify_success.0) == success {
+        if !session_state.masks_released {
+            session_state.masks_released = true;
+            db.update_session_state(&session_id_hex, &session_state);
+        }
+        return Ok(mask_bytes.get_tx_masks());
+    }
+    // once the masks are out, the customer can close on the new state: the session must not
+    // fail, or it would be cleared and the old state could be paid from again
+    if !session_state.masks_released {
+        session_state.status = PaymentStatus::Error;
+        db.update_session_state(&session_id_hex, &session_state);
+    }
+    Err(format!(
+        "pay_confirm_mpc_result: will need to restart MPC session"
+    ))
+}
+
+///
+/// pay_session_status_merchant() - takes as input a db and session identifier.
+/// Reports the last completed step of a payment session, so the merchant can serve a customer resuming it:
+/// the tx masks can be re-sent once the status is Update, and the pay token mask once it is Revoked
+/// output: the payment status
+///
+pub fn pay_session_status_merchant(
+    db: &mut dyn StateDatabase,
+    session_id: [u8; 16],
+) -> Result<PaymentStatus, String> {
+    db.is_connected()?;
+    let session_state = db.load_session_state(&hex::encode(session_id))?;
+    Ok(session_state.status)
 }
 
 ///
@@ -427,11 +568,10 @@ pub fn pay_unmask_sigs_customer(
     if (cust_state.protocol_status == ProtocolStatus::Activated
         || cust_state.protocol_status == ProtocolStatus::Established)
     {
-        cust_state.unmask_and_verify_transactions::<Testnet>(
-            channel_state,
-            channel_token,
-            mask_bytes,
-        )
+        channel_state.validate_network(channel_token)?;
+        with_bitcoin_network!(channel_state.get_network(), N => {
+            cust_state.unmask_and_verify_transactions::<N>(channel_state, channel_token, mask_bytes)
+        })
     } else {
         return Err(format!(
             "Invalid channel status for pay_unmask_sigs_customer(): {}",
@@ -487,6 +627,47 @@ pub fn pay_unmask_pay_token_customer(
     }
 }
 
+///
+/// pay_commit_customer() - takes as input the payment session and the customer state.
+/// Checks that the payment completed, i.e. the customer holds closing signatures and a pay token on the new state
+/// output: success or error (in which case the caller should call pay_abort_customer())
+///
+pub fn pay_commit_customer(
+    session: CustomerPaymentSession,
+    cust_state: &CustomerMPCState,
+) -> Result<(), String> {
+    session.commit(cust_state)
+}
+
+///
+/// pay_resume_customer() - takes as input the payment session and the customer state.
+/// Determines the last completed step of an interrupted payment, so the customer knows how to resume it:
+/// Prepare - the MPC did not complete; abort the payment with pay_abort_customer()
+/// Update - the MPC completed; re-request the tx masks with the session id and the MPC result
+/// Revoked - the closing signatures were obtained; resend the revoked state to get the pay token mask
+/// Complete - nothing left to do; commit with pay_commit_customer()
+/// output: the payment status
+///
+pub fn pay_resume_customer(
+    session: &CustomerPaymentSession,
+    cust_state: &CustomerMPCState,
+) -> PaymentStatus {
+    session.get_status(cust_state)
+}
+
+///
+/// pay_abort_customer() - takes as input the payment session and the customer state.
+/// Restores the customer state from before the payment so the channel stays usable.
+/// Fails once the closing signatures on the new state were obtained, since the old state may already be revoked
+/// output: success or error
+///
+pub fn pay_abort_customer(
+    session: CustomerPaymentSession,
+    cust_state: &mut CustomerMPCState,
+) -> Result<(), String> {
+    session.abort(cust_state)
+}
+
 ///
 /// force_customer_close() - takes as input the channel_state, channel_token, from_escrow and customer state.
 /// signs the closing tx on the current state of the channel
@@ -498,8 +679,11 @@ pub fn force_customer_close(
     from_escrow: bool,
     cust_state: &mut CustomerMPCState,
 ) -> Result<(Vec<u8>, Vec<u8>, Vec<u8>), String> {
+    channel_state.validate_network(channel_token)?;
     // (close_tx, close_txid_be, close_txid_le) that spends from escrow (if from_escrow = true)
-    cust_state.customer_close::<Testnet>(&channel_state, &channel_token, from_escrow)
+    with_bitcoin_network!(channel_state.get_network(), N => {
+        cust_state.customer_close::<N>(&channel_state, &channel_token, from_escrow)
+    })
 }
 
 ///
@@ -520,5 +704,7 @@ pub fn force_merchant_close(
     }
     let mut txid = [0u8; 32];
     txid.copy_from_slice(escrow_txid.as_slice());
-    merch_state.get_closing_tx::<Testnet>(txid, val_cpfp)
+    with_bitcoin_network!(merch_state.get_network(), N => {
+        merch_state.get_closing_tx::<N>(txid, val_cpfp)
+    })
 }
@@ -1,25 +1,187 @@
 use bindings::{
     build_masked_tokens_cust, build_masked_tokens_merch, cb_receive, cb_send, get_netio_ptr,
-    Balance_l, BitcoinPublicKey_l, CommitmentRandomness_l, ConnType_NETIO, ConnType_TORNETIO,
-    Conn_l, EcdsaSig_l, HMACKeyCommitment_l, HMACKey_l, MaskCommitment_l, Mask_l, Nonce_l,
-    PayToken_l, PublicKeyHash_l, Randomness_l, RevLockCommitment_l, RevLock_l, State_l, Txid_l,
+    load_circuit_file, Balance_l, BitcoinPublicKey_l, CommitmentRandomness_l, ConnType_LNDNETIO,
+    ConnType_NETIO, ConnType_TORNETIO, Conn_l, EcdsaSig_l, HMACKeyCommitment_l, HMACKey_l,
+    MaskCommitment_l, Mask_l, Nonce_l, PayToken_l, PublicKeyHash_l, Randomness_l, Receive_return,
+    RevLockCommitment_l, RevLock_l, State_l, Txid_l,
 };
 // ConnType_CUSTOM, get_gonetio_ptr
 use channels_mpc::NetworkConfig;
+use channels_util::MpcSecurity;
 use ecdsa_partial::EcdsaPartialSig;
-use libc::{c_int, c_void};
-// c_uint, c_char
+use libc::{c_char, c_int, c_void};
+// c_uint
 use rand::Rng;
 use secp256k1;
+use sha2::{Digest, Sha256};
+use socks5;
+use std::env;
 use std::ffi::{CStr, CString};
+use std::fs;
+use std::io::Write;
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
 use std::ptr;
 use std::str;
+use std::sync::Mutex;
 use std::time::Instant;
 use wallet::State;
 
 static MPC_ERROR: &str = "ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff";
 pub static CIRCUIT_FILE: &str = "/include/emp-tool/circuits/files/tokens.circuit.txt";
 
+// sha256 of the circuit the library was built against (checked and set by build.rs)
+pub static CIRCUIT_SHA256: &str = env!("ZK_CIRCUIT_SHA256");
+
+#[cfg(all(ag2pc, feature = "embed-circuit"))]
+static EMBEDDED_CIRCUIT: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/tokens.circuit.txt"));
+
+// loaded circuit pointer (as usize since raw pointers are not Send)
+static CIRCUIT: Mutex<Option<usize>> = Mutex::new(None);
+
+// the token-update backend selected at runtime (AG2PC env var)
+pub fn using_ag2pc() -> bool {
+    match env::var("AG2PC") {
+        Ok(_s) => true,
+        Err(_e) => false,
+    }
+}
+
+// the backend this library is linked against (AG2PC env var when building, see build.rs)
+pub fn linked_mpc_security() -> MpcSecurity {
+    match cfg!(ag2pc) {
+        true => MpcSecurity::Malicious,
+        false => MpcSecurity::SemiHonest,
+    }
+}
+
+// the default security model for new channels, selected at runtime. Payments always run the
+// model recorded in the channel state
+pub fn selected_mpc_security() -> MpcSecurity {
+    match using_ag2pc() {
+        true => MpcSecurity::Malicious,
+        false => MpcSecurity::SemiHonest,
+    }
+}
+
+///
+/// check_mpc_backend() - checks that a payment MPC with the given security model can run in
+/// this process, i.e. that the linked token-update library implements it. Both libraries export
+/// the same symbols, so only one of them can be linked at a time, and a process can only serve
+/// channels of the linked model. Payments call it with the channel's own model (see get_circuit()).
+///
+pub fn check_mpc_backend(mpc_security: MpcSecurity) -> Result<(), String> {
+    let linked = linked_mpc_security();
+    if mpc_security != linked {
+        let hint = match mpc_security {
+            MpcSecurity::Malicious => "build with AG2PC set",
+            MpcSecurity::SemiHonest => "build without AG2PC",
+        };
+        return Err(format!(
+            "{} MPC is not available, the library is linked against the {} MPC backend ({})",
+            mpc_security, linked, hint
+        ));
+    }
+    Ok(())
+}
+
+///
+/// circuit_hash() - the pinned sha256 hash of the token-update circuit. The AG2PC backend loads
+/// this circuit, the semi-honest one has it compiled in from the same update-state release.
+///
+pub fn circuit_hash() -> [u8; 32] {
+    // build.rs only sets a well-formed hash
+    let hash = hex::decode(CIRCUIT_SHA256).expect("invalid pinned circuit hash");
+    let mut hash_buf = [0u8; 32];
+    hash_buf.copy_from_slice(&hash);
+    hash_buf
+}
+
+pub fn check_circuit_hash(circuit: &[u8], expected: &[u8; 32]) -> Result<(), String> {
+    let actual = Sha256::digest(circuit);
+    if actual.as_slice() != expected {
+        return Err(format!(
+            "circuit hash mismatch: expected {}, found {}",
+            hex::encode(expected),
+            hex::encode(actual)
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(all(ag2pc, feature = "embed-circuit"))]
+fn read_circuit() -> Result<Vec<u8>, String> {
+    Ok(EMBEDDED_CIRCUIT.to_vec())
+}
+
+#[cfg(not(all(ag2pc, feature = "embed-circuit")))]
+fn read_circuit() -> Result<Vec<u8>, String> {
+    let circuit_file = match env::var("ZK_DEPS_INSTALL") {
+        Ok(s) => format!("{}{}", s, CIRCUIT_FILE),
+        Err(e) => return Err(format!("ZK_DEPS_INSTALL env not set: {}", e)),
+    };
+    match fs::read(&circuit_file) {
+        Ok(c) => Ok(c),
+        Err(e) => Err(format!("could not read circuit {}: {}", circuit_file, e)),
+    }
+}
+
+// the emp loader only reads from a path, so the checked circuit is written to a new directory
+// that only this user can access, and loaded from there. The loader parses the whole file up
+// front, so the copy is removed right after
+fn load_checked_circuit(circuit: &[u8]) -> Result<*mut c_void, String> {
+    let mut dir_name = [0u8; 16];
+    rand::thread_rng().fill(&mut dir_name);
+    let dir = env::temp_dir().join(format!("zkchannels-{}", hex::encode(&dir_name)));
+    if let Err(e) = fs::DirBuilder::new().mode(0o700).create(&dir) {
+        return Err(format!("could not create {}: {}", dir.display(), e));
+    }
+    let path = dir.join("tokens.circuit.txt");
+    let written = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&path)
+        .and_then(|mut f| f.write_all(circuit));
+
+    let cf_ptr = match written {
+        Ok(_) => unsafe {
+            let c_str = CString::new(path.to_string_lossy().to_string()).unwrap();
+            Ok(load_circuit_file(c_str.as_ptr() as *const i8))
+        },
+        Err(e) => Err(format!("could not write circuit {}: {}", path.display(), e)),
+    };
+    let _ = fs::remove_dir_all(&dir);
+    cf_ptr
+}
+
+///
+/// get_circuit() - returns the token-update circuit for a channel of the given security model
+/// (the one recorded in its channel state), or an error if the linked backend does not
+/// implement it.
+/// On first use the circuit is checked against the pinned hash, then the same bytes are loaded
+/// and shared by all later payments in this process. The semi-honest backend has the circuit
+/// compiled in (null pointer).
+/// Concurrent payments use the same circuit, so update-state must only read it. The MPC tests
+/// run both parties in one process on this circuit (see test_shared_circuit_across_payments).
+///
+pub fn get_circuit(mpc_security: MpcSecurity) -> Result<*mut c_void, String> {
+    check_mpc_backend(mpc_security)?;
+    if mpc_security != MpcSecurity::Malicious {
+        return Ok(ptr::null_mut());
+    }
+    let mut circuit = CIRCUIT.lock().unwrap();
+    if let Some(cf_ptr) = *circuit {
+        return Ok(cf_ptr as *mut c_void);
+    }
+
+    let contents = read_circuit()?;
+    check_circuit_hash(&contents, &circuit_hash())?;
+
+    let cf_ptr = load_checked_circuit(&contents)?;
+    *circuit = Some(cf_ptr as usize);
+    Ok(cf_ptr)
+}
+
 extern "C" fn io_callback(net_config: *mut c_void, party: c_int) -> *mut c_void {
     // unsafe is needed because we dereference a raw pointer to network config
     let nc: &mut Conn_l = unsafe { &mut *(net_config as *mut Conn_l) };
@@ -29,20 +191,129 @@ extern "C" fn io_callback(net_config: *mut c_void, party: c_int) -> *mut c_void
         _ => "Unsupported connection type",
     };
     println!("IO callback: {}", conn_debug);
-    if (nc.conn_type == ConnType_NETIO) {
+    // outgoing Tor connections have already been replaced by a loopback relay through the
+    // proxy (see translate_network_config), so a Tor connection here is the listening side
+    if nc.conn_type == ConnType_NETIO || nc.conn_type == ConnType_TORNETIO {
         let bytes = unsafe { CStr::from_ptr(nc.dest_ip).to_bytes() };
         let ip: &str = str::from_utf8(bytes).unwrap();
         println!("Opening a connection: {}:{}", ip, nc.dest_port);
         let io_ptr = unsafe { get_netio_ptr(nc.dest_ip, nc.dest_port as i32, party) };
         return io_ptr;
-    } else {
-        /* use regular tcp conn */
-        let io_ptr =
-            unsafe { get_netio_ptr(CString::new("127.0.0.1").unwrap().into_raw(), 12345, party) };
-        return io_ptr;
+    }
+    // never guess an address for a connection that wasn't configured
+    println!("IO callback: refusing to open an unconfigured connection");
+    ptr::null_mut()
+}
+
+fn translate_network_config(net_conn: NetworkConfig, dial_out: bool) -> Result<Conn_l, String> {
+    let (conn_type, dest_ip, dest_port) = match (net_conn.conn_type, dial_out) {
+        (ConnType_TORNETIO, true) => {
+            // never fall back to a direct connection: that would reveal our address
+            let proxy = match net_conn.proxy {
+                Some(p) => p,
+                None => return Err(String::from("Tor connection requires a SOCKS5 proxy")),
+            };
+            let local_port =
+                socks5::open_relay(&proxy, &net_conn.dest_ip, net_conn.dest_port as u16)?;
+            (ConnType_NETIO, String::from("127.0.0.1"), local_port)
+        }
+        (ConnType_NETIO, _) | (ConnType_TORNETIO, _) | (ConnType_LNDNETIO, _) => (
+            net_conn.conn_type,
+            net_conn.dest_ip,
+            net_conn.dest_port as u16,
+        ),
+        (t, _) => return Err(format!("unsupported MPC connection type: {}", t)),
+    };
+
+    let path_ar = CString::new(net_conn.path).unwrap().into_raw();
+    let ip_ar = CString::new(dest_ip).unwrap().into_raw();
+    Ok(Conn_l {
+        conn_type,
+        path: path_ar,
+        dest_port,
+        dest_ip: ip_ar,
+        peer_raw_fd: ptr::null_mut(),
+    })
+}
+
+/// A bidirectional message channel to the other party that carries the 2PC traffic.
+/// Implementing this lets an application run the MPC over its own (authenticated)
+/// connection; the crate adapts it to the C callback interface.
+pub trait MpcChannel {
+    /// Sends a single message to the other party
+    fn send(&mut self, data: &[u8]) -> Result<(), String>;
+    /// Blocks until the next message from the other party is available
+    fn receive(&mut self) -> Result<Vec<u8>, String>;
+}
+
+// the callback interface hands ownership of the returned buffers (received data in r0,
+// error strings in r0/r2) to the MPC library, which releases them with free(). This is
+// the contract the Go callbacks follow with C.CBytes/C.CString, so the buffers must come
+// from malloc and are never freed on the Rust side
+fn malloc_copy(bytes: &[u8], nul_terminate: bool) -> *mut c_char {
+    let len = bytes.len() + nul_terminate as usize;
+    unsafe {
+        let buf = libc::malloc(std::cmp::max(len, 1)) as *mut u8;
+        if buf.is_null() {
+            return ptr::null_mut();
+        }
+        ptr::copy_nonoverlapping(bytes.as_ptr(), buf, bytes.len());
+        if nul_terminate {
+            *buf.add(bytes.len()) = 0;
+        }
+        buf as *mut c_char
+    }
+}
+
+// what the callbacks get through the peer pointer: the channel of a single MPC run. Every run
+// has its own context, so runs over different channels never share anything
+struct MpcChannelContext<'a> {
+    channel: &'a mut dyn MpcChannel,
+}
+
+extern "C" fn mpc_channel_send(data: *mut c_void, len: c_int, peer: *mut c_void) -> *mut c_char {
+    // peer is the context set up by with_mpc_channel(), which outlives the MPC
+    let context = unsafe { &mut *(peer as *mut MpcChannelContext) };
+    let bytes = match data.is_null() || len <= 0 {
+        true => &[][..],
+        false => unsafe { std::slice::from_raw_parts(data as *const u8, len as usize) },
+    };
+    match context.channel.send(bytes) {
+        Ok(_) => ptr::null_mut(),
+        Err(e) => malloc_copy(e.as_bytes(), true),
+    }
+}
+
+extern "C" fn mpc_channel_receive(peer: *mut c_void) -> Receive_return {
+    let context = unsafe { &mut *(peer as *mut MpcChannelContext) };
+    match context.channel.receive() {
+        Ok(data) => Receive_return {
+            r0: malloc_copy(&data, false),
+            r1: data.len() as c_int,
+            r2: ptr::null_mut(),
+        },
+        Err(e) => Receive_return {
+            r0: ptr::null_mut(),
+            r1: 0,
+            r2: malloc_copy(e.as_bytes(), true),
+        },
     }
 }
 
+///
+/// with_mpc_channel() - calls run with the peer pointer and C callbacks expected by the MPC,
+/// adapted to the given MpcChannel. The peer pointer refers to a context on this call's stack
+/// that only holds this channel, and is valid until run returns.
+///
+pub fn with_mpc_channel<T, F>(channel: &mut dyn MpcChannel, run: F) -> T
+where
+    F: FnOnce(*mut c_void, cb_send, cb_receive) -> T,
+{
+    let mut context = MpcChannelContext { channel };
+    let p_ptr = &mut context as *mut MpcChannelContext as *mut c_void;
+    run(p_ptr, Some(mpc_channel_send), Some(mpc_channel_receive))
+}
+
 pub fn mpc_build_masked_tokens_cust(
     net_conn: NetworkConfig,
     p_ptr: *mut c_void,
@@ -120,16 +391,8 @@ pub fn mpc_build_masked_tokens_cust(
         randomness: success_ar,
     };
 
-    // set the network config
-    let path_ar = CString::new(net_conn.path).unwrap().into_raw();
-    let ip_ar = CString::new(net_conn.dest_ip).unwrap().into_raw();
-    let conn = Conn_l {
-        conn_type: net_conn.conn_type,
-        path: path_ar,
-        dest_port: net_conn.dest_port as u16,
-        dest_ip: ip_ar,
-        peer_raw_fd: ptr::null_mut(),
-    };
+    // set the network config (the customer dials out to the merchant)
+    let conn = translate_network_config(net_conn, true)?;
 
     // Uncomment to benchmark the C/C++ wrapper
     // let timer = Instant::now();
@@ -373,7 +636,7 @@ pub fn mpc_build_masked_tokens_merch<R: Rng>(
     pay_mask_r: &[u8; 16],
     escrow_mask: &[u8; 32],
     verify_success_r: &[u8; 16],
-) -> ([u8; 32], [u8; 32]) {
+) -> Result<([u8; 32], [u8; 32]), String> {
     // translate revlock commitment
     let rl_c = translate_revlock_com(rev_lock_com);
 
@@ -428,15 +691,7 @@ pub fn mpc_build_masked_tokens_merch<R: Rng>(
     };
 
     // set the network config
-    let path_ar = CString::new(net_conn.path).unwrap().into_raw();
-    let ip_ar = CString::new(net_conn.dest_ip).unwrap().into_raw();
-    let conn = Conn_l {
-        conn_type: net_conn.conn_type,
-        path: path_ar,
-        dest_port: net_conn.dest_port as u16,
-        dest_ip: ip_ar,
-        peer_raw_fd: ptr::null_mut(),
-    };
+    let conn = translate_network_config(net_conn, false)?;
 
     let timer = Instant::now();
     // let self_delay = bytes_to_u32(&(self_delay_int as u32).to_le_bytes(), 4)[0];
@@ -477,20 +732,18 @@ pub fn mpc_build_masked_tokens_merch<R: Rng>(
     let stop = timer.elapsed();
     println!("Time to execute MPC: {} ms", stop.as_millis());
 
-    (pp1.getK(), pp2.getK())
+    Ok((pp1.getK(), pp2.getK()))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use bindings::load_circuit_file;
     use num::bigint::Sign;
     use num::BigInt;
     use rand::rngs::mock::StepRng;
     use rand::RngCore;
     use secp256k1::{Message, PartialSignature, PublicKey, Secp256k1, Signature};
     use sha2::{Digest, Sha256};
-    use std::env;
     use std::ffi::CStr;
     use std::str;
     use util::{compute_hash160, hash_to_slice, hmac_sign};
@@ -603,31 +856,10 @@ mod tests {
                 path: String::from("127.0.0.1"),
                 dest_ip: String::from("127.0.0.1"),
                 dest_port: 12345,
+                proxy: None,
             };
 
-            let using_ag2pc = match env::var("AG2PC") {
-                Ok(_s) => true,
-                Err(_e) => false
-            };
-
-            let circuit_file = match using_ag2pc {
-                true => match env::var("ZK_DEPS_INSTALL") {
-                    Ok(s) => format!("{}{}", s, CIRCUIT_FILE),
-                    Err(e) => panic!("ZK_DEPS_INSTALL env not set: {}", e)
-                },
-                false => String::new()
-            };
-
-            let cf_ptr = match using_ag2pc {
-                true => {
-                    let cf_ptr = unsafe {
-                        let c_str = CString::new(circuit_file).unwrap();
-                        load_circuit_file(c_str.as_ptr() as *const i8)
-                    };
-                    cf_ptr
-                },
-                false => ptr::null_mut()
-            };
+            let cf_ptr = get_circuit(linked_mpc_security()).unwrap();
 
             let (r1, r2) = mpc_build_masked_tokens_merch(
                 &mut csprng,
@@ -657,7 +889,8 @@ mod tests {
                 &paytoken_mask_r,
                 &escrow_mask_bytes,
                 &verify_success_r
-            );
+            )
+            .unwrap();
 
             assert_eq!(
                 r1.to_vec(),
@@ -896,33 +1129,11 @@ mod tests {
             path: String::from("127.0.0.1"),
             dest_ip: String::from("127.0.0.1"),
             dest_port: 12345,
+            proxy: None,
         };
 
-        // check if AGPC=1 in env
-        let using_ag2pc = match env::var("AG2PC") {
-            Ok(_s) => true,
-            Err(_e) => false,
-        };
-
-        let circuit_file = match using_ag2pc {
-            true => match env::var("ZK_DEPS_INSTALL") {
-                Ok(s) => format!("{}{}", s, CIRCUIT_FILE),
-                Err(e) => panic!("ZK_DEPS_INSTALL env not set: {}", e),
-            },
-            false => String::new(),
-        };
-
-        let cf_ptr = match using_ag2pc {
-            true => {
-                let cf_ptr = unsafe {
-                    let c_str = CString::new(circuit_file).unwrap();
-                    // TODO: need a way to verify circuit is correctly generated
-                    load_circuit_file(c_str.as_ptr() as *const i8)
-                };
-                cf_ptr
-            }
-            false => ptr::null_mut(),
-        };
+        // load the circuit (AG2PC only)
+        let cf_ptr = get_circuit(linked_mpc_security()).unwrap();
 
         let fee_cc = 1500;
         let bal_min_cust = 546;
@@ -1170,4 +1381,145 @@ mod tests {
             )
             .is_ok());
     }
+
+    #[test]
+    fn tor_connection_requires_proxy() {
+        let nc = NetworkConfig {
+            conn_type: ConnType_TORNETIO,
+            path: String::new(),
+            dest_ip: String::from("merchant.onion"),
+            dest_port: 2424,
+            proxy: None,
+        };
+        // the dialing side must not silently fall back to a direct tcp connection
+        assert!(translate_network_config(nc.clone(), true).is_err());
+        // the listening side is reached through the onion service and needs no proxy
+        let conn = translate_network_config(nc, false).unwrap();
+        assert_eq!(conn.conn_type, ConnType_TORNETIO);
+        assert_eq!(conn.dest_port, 2424);
+    }
+
+    struct LoopbackChannel {
+        queue: std::collections::VecDeque<Vec<u8>>,
+        fail: bool,
+    }
+
+    impl MpcChannel for LoopbackChannel {
+        fn send(&mut self, data: &[u8]) -> Result<(), String> {
+            if self.fail {
+                return Err(String::from("peer disconnected"));
+            }
+            self.queue.push_back(data.to_vec());
+            Ok(())
+        }
+
+        fn receive(&mut self) -> Result<Vec<u8>, String> {
+            match self.queue.pop_front() {
+                Some(data) => Ok(data),
+                None => Err(String::from("peer disconnected")),
+            }
+        }
+    }
+
+    unsafe fn take_c_bytes(ptr: *mut c_char, len: usize) -> Vec<u8> {
+        let out = std::slice::from_raw_parts(ptr as *const u8, len).to_vec();
+        libc::free(ptr as *mut c_void);
+        out
+    }
+
+    #[test]
+    fn mpc_channel_adapts_to_callbacks() {
+        let mut loopback = LoopbackChannel {
+            queue: std::collections::VecDeque::new(),
+            fail: false,
+        };
+        // messages may contain nul bytes and are passed through unchanged
+        let mut msg = vec![0x01u8, 0x00, 0xff, 0x42];
+        with_mpc_channel(&mut loopback, |p_ptr, send_cb, receive_cb| {
+            let (send_cb, receive_cb) = (send_cb.unwrap(), receive_cb.unwrap());
+            let err =
+                unsafe { send_cb(msg.as_mut_ptr() as *mut c_void, msg.len() as c_int, p_ptr) };
+            assert!(err.is_null());
+
+            let r = unsafe { receive_cb(p_ptr) };
+            assert!(r.r2.is_null());
+            assert_eq!(unsafe { take_c_bytes(r.r0, r.r1 as usize) }, msg);
+
+            // errors from the channel are reported back to the MPC
+            let r = unsafe { receive_cb(p_ptr) };
+            assert!(r.r0.is_null());
+            let err = unsafe { CStr::from_ptr(r.r2) }
+                .to_str()
+                .unwrap()
+                .to_string();
+            unsafe { libc::free(r.r2 as *mut c_void) };
+            assert_eq!(err, "peer disconnected");
+        });
+
+        loopback.fail = true;
+        with_mpc_channel(&mut loopback, |p_ptr, send_cb, _| {
+            let err = unsafe { send_cb.unwrap()(msg.as_mut_ptr() as *mut c_void, 4, p_ptr) };
+            assert!(!err.is_null());
+            unsafe { libc::free(err as *mut c_void) };
+        });
+    }
+
+    #[test]
+    fn mpc_channel_contexts_are_independent() {
+        let mut first = LoopbackChannel {
+            queue: std::collections::VecDeque::new(),
+            fail: false,
+        };
+        let mut second = LoopbackChannel {
+            queue: std::collections::VecDeque::new(),
+            fail: false,
+        };
+        // two runs in flight at once each reach their own channel
+        with_mpc_channel(&mut first, |p_first, send_cb, _| {
+            with_mpc_channel(&mut second, |p_second, _, _| {
+                let send_cb = send_cb.unwrap();
+                let mut msg = vec![0x01u8];
+                unsafe { send_cb(msg.as_mut_ptr() as *mut c_void, 1, p_first) };
+                msg[0] = 0x02;
+                unsafe { send_cb(msg.as_mut_ptr() as *mut c_void, 1, p_second) };
+            });
+        });
+        assert_eq!(first.queue, vec![vec![0x01u8]]);
+        assert_eq!(second.queue, vec![vec![0x02u8]]);
+    }
+
+    #[test]
+    fn circuit_is_loaded_once() {
+        let circuit = get_circuit(linked_mpc_security()).unwrap();
+        if linked_mpc_security() == MpcSecurity::Malicious {
+            assert!(!circuit.is_null());
+        } else {
+            assert!(circuit.is_null());
+        }
+        assert_eq!(get_circuit(linked_mpc_security()).unwrap(), circuit);
+    }
+
+    #[test]
+    fn mpc_backend_is_checked() {
+        let linked = linked_mpc_security();
+        assert!(check_mpc_backend(linked).is_ok());
+        let other = match linked {
+            MpcSecurity::SemiHonest => MpcSecurity::Malicious,
+            MpcSecurity::Malicious => MpcSecurity::SemiHonest,
+        };
+        assert!(check_mpc_backend(other).unwrap_err().contains("AG2PC"));
+    }
+
+    #[test]
+    fn circuit_hash_is_checked() {
+        let circuit = b"1 3\n2 1 1\n1 1\n\n2 1 0 1 2 AND\n".to_vec();
+        let mut expected = [0u8; 32];
+        expected.copy_from_slice(&Sha256::digest(&circuit));
+        assert!(check_circuit_hash(&circuit, &expected).is_ok());
+
+        let mut tampered = circuit.clone();
+        tampered[tampered.len() - 4] = b'X';
+        let err = check_circuit_hash(&tampered, &expected).unwrap_err();
+        assert!(err.contains(&hex::encode(expected)), "{}", err);
+    }
 }
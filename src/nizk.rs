@@ -7,7 +7,7 @@ use ped92::{CSMultiParams, Commitment, CommitmentProof};
 use rand::Rng;
 use serde::{Deserialize, Serialize};
 use util;
-use wallet::Wallet;
+use wallet::{PendingAmount, Wallet};
 
 /// NIZKProof is the object that represents the NIZK Proof of Knowledge during the payment and closing protocol
 #[derive(Clone, Serialize, Deserialize)]
@@ -29,6 +29,8 @@ pub struct NIZKProof<E: Engine> {
     pub comBarProof: CommitmentProof<E>,
     pub rpBC: ProofUL<E>,
     pub rpBM: ProofUL<E>,
+    pub amountComProof: Option<CommitmentProof<E>>,
+    pub rpAmount: Option<ProofUL<E>>,
 }
 
 /// NIZKPublicParams are public parameters to perform a NIZK Proof of Knowledge during the payment and closing protocol
@@ -102,6 +104,70 @@ impl<E: Engine> NIZKSecretParams<E> {
         epsilon: E::Fr,
         coms: &ClosedCommitments<E>,
         nonce: E::Fr,
+    ) -> bool {
+        let closeTag = util::hash_to_fr::<E>("close".as_bytes().to_vec());
+        self.verify_internal(proof, Some(epsilon), None, None, coms, nonce, closeTag)
+    }
+
+    /**
+        Verify a NIZK Proof of Knowledge for a conditional payment
+        Input:
+        proof: A NIZK proof created by the Customer
+        epsilon: The transaction amount of the payment
+        com: Commitment of the new wallet that needs to be signed
+        nonce: reveal of the nonce of the old wallet.
+        pending: The amount held by the hash lock in the new wallet
+    */
+    pub fn verify_conditional(
+        &self,
+        proof: NIZKProof<E>,
+        epsilon: E::Fr,
+        coms: &ClosedCommitments<E>,
+        nonce: E::Fr,
+        pending: &PendingAmount,
+    ) -> bool {
+        let closeTag = pending.close_tag::<E>();
+        self.verify_internal(proof, Some(epsilon), None, None, coms, nonce, closeTag)
+    }
+
+    /**
+        Verify a NIZK Proof of Knowledge for a payment whose amount is hidden
+        Input:
+        proof: A NIZK proof created by the Customer with prove_hidden_amount()
+        amountCom: Commitment to the transaction amount of the payment
+        amountFloor: if set, the proof must also show that the amount is at least amountFloor
+        com: Commitment of the new wallet that needs to be signed
+        nonce: reveal of the nonce of the old wallet.
+    */
+    pub fn verify_hidden_amount(
+        &self,
+        proof: NIZKProof<E>,
+        amountCom: &Commitment<E>,
+        amountFloor: Option<i64>,
+        coms: &ClosedCommitments<E>,
+        nonce: E::Fr,
+    ) -> bool {
+        let closeTag = util::hash_to_fr::<E>("close".as_bytes().to_vec());
+        self.verify_internal(
+            proof,
+            None,
+            Some(amountCom),
+            amountFloor,
+            coms,
+            nonce,
+            closeTag,
+        )
+    }
+
+    fn verify_internal(
+        &self,
+        proof: NIZKProof<E>,
+        epsilon: Option<E::Fr>,
+        amountCom: Option<&Commitment<E>>,
+        amountFloor: Option<i64>,
+        coms: &ClosedCommitments<E>,
+        nonce: E::Fr,
+        closeTag: E::Fr,
     ) -> bool {
         //verify signature is not the identity
         if proof.sig.h == E::G1::zero() {
@@ -117,8 +183,57 @@ impl<E: Engine> NIZKSecretParams<E> {
             proof.rpBC.D,
             proof.rpBM.D,
         ]);
+        if let Some(amountComProof) = &proof.amountComProof {
+            T.push(amountComProof.T);
+        }
+        if let Some(rpAmount) = &proof.rpAmount {
+            T.push(rpAmount.D);
+        }
         let challenge = NIZKPublicParams::<E>::hash(proof.sigProof.a, T);
 
+        //the amount enters the linear relationship either in the clear or
+        //through the response of the PoK of the amount commitment
+        let epsC = match (epsilon, amountCom, &proof.amountComProof) {
+            (Some(eps), None, None) if proof.rpAmount.is_none() => {
+                let mut epsC = eps.clone();
+                epsC.mul_assign(&challenge);
+                epsC
+            }
+            (None, Some(com), Some(amountComProof)) => {
+                if amountComProof.z.len() != 2
+                    || !amountComProof.verify_proof(
+                        &self.pubParams.comParams,
+                        &com.c,
+                        &challenge,
+                        None,
+                    )
+                {
+                    return false;
+                }
+                //the range proof is on the amount minus the floor, i.e. on the amount
+                //commitment shifted by the floor
+                match (amountFloor, &proof.rpAmount) {
+                    (None, None) => (),
+                    (Some(floor), Some(rpAmount)) => {
+                        let floorCom = self
+                            .pubParams
+                            .comParams
+                            .commit(&vec![util::convert_int_to_fr::<E>(floor)], &E::Fr::zero());
+                        let mut shiftedCom = com.c.clone();
+                        shiftedCom.sub_assign(&floorCom.c);
+                        if rpAmount.comm.c != shiftedCom
+                            || !self.rpParams.verify_ul(rpAmount, challenge.clone(), 1)
+                        {
+                            return false;
+                        }
+                    }
+                    _ => return false,
+                }
+                amountComProof.z[1]
+            }
+            _ => return false,
+        };
+
         //verify knowledge of signature
         let mut r1 = self.keypair.public.verify_proof(
             &self.pubParams.mpk,
@@ -143,12 +258,14 @@ impl<E: Engine> NIZKSecretParams<E> {
                 .comProof
                 .verify_proof(&self.pubParams.comParams, &coms.s_com.c, &challenge, None);
 
-        let r2_3 = proof.comBarProof.verify_proof(
-            &self.pubParams.comParams,
-            &coms.s_bar_com.c,
-            &challenge,
-            None,
-        );
+        //the close tag of the new wallet is revealed
+        let r2_3 = proof.comBarProof.z.len() == 6
+            && proof.comBarProof.verify_proof(
+                &self.pubParams.comParams,
+                &coms.s_bar_com.c,
+                &challenge,
+                Some(vec![None, None, None, None, None, Some(closeTag)]),
+            );
 
         //verify range proofs
         let r3 = self.rpParams.verify_ul(&proof.rpBC, challenge.clone(), 4);
@@ -157,8 +274,6 @@ impl<E: Engine> NIZKSecretParams<E> {
         //verify linear relationship
         let mut r5 = proof.comProof.z[1] == proof.sigProof.zsig[0];
         let mut zsig2 = proof.sigProof.zsig[3].clone();
-        let mut epsC = epsilon.clone();
-        epsC.mul_assign(&challenge);
         zsig2.sub_assign(&epsC);
         r5 = r5 && proof.comProof.z[4] == zsig2;
         let mut zsig3 = proof.sigProof.zsig[4].clone();
@@ -200,6 +315,66 @@ impl<E: Engine> NIZKPublicParams<E> {
         newTau: E::Fr,
         newTauBar: E::Fr,
         paymentToken: &Signature<E>,
+    ) -> NIZKProof<E> {
+        self.prove_internal(
+            rng,
+            oldWallet,
+            newWallet,
+            newWalletCom,
+            rho,
+            newTau,
+            newTauBar,
+            paymentToken,
+            None,
+            None,
+        )
+    }
+
+    /** Same as prove(), but the payment amount (the difference between the customer balances of
+        the old and new wallet) is not revealed to the verifier. Instead, the proof shows knowledge
+        of an opening of the amount commitment with blinding value amountTau. If amountFloor is
+        set, the proof also shows that the amount is at least amountFloor, without revealing it.
+        The amount must then be in [amountFloor, amountFloor + u^l).
+    */
+    pub fn prove_hidden_amount<R: Rng>(
+        &self,
+        rng: &mut R,
+        oldWallet: Wallet<E>,
+        newWallet: Wallet<E>,
+        newWalletCom: Commitment<E>,
+        rho: E::Fr,
+        newTau: E::Fr,
+        newTauBar: E::Fr,
+        paymentToken: &Signature<E>,
+        amountTau: E::Fr,
+        amountFloor: Option<i64>,
+    ) -> NIZKProof<E> {
+        self.prove_internal(
+            rng,
+            oldWallet,
+            newWallet,
+            newWalletCom,
+            rho,
+            newTau,
+            newTauBar,
+            paymentToken,
+            Some(amountTau),
+            amountFloor,
+        )
+    }
+
+    fn prove_internal<R: Rng>(
+        &self,
+        rng: &mut R,
+        oldWallet: Wallet<E>,
+        newWallet: Wallet<E>,
+        newWalletCom: Commitment<E>,
+        rho: E::Fr,
+        newTau: E::Fr,
+        newTauBar: E::Fr,
+        paymentToken: &Signature<E>,
+        amountTau: Option<E::Fr>,
+        amountFloor: Option<i64>,
     ) -> NIZKProof<E> {
         //Commitment phase
         //commit commitment
@@ -217,17 +392,36 @@ impl<E: Engine> NIZKPublicParams<E> {
             None,
         );
 
+        let zero = E::Fr::zero();
         let t3_0 = E::Fr::rand(rng);
         let (D3, t3) = CommitmentProof::<E>::prove_commitment(
             rng,
             &self.comParams,
             &newWallet.as_fr_vec_bar(),
-            Some(vec![t3_0, t2[1], t2[3], t2[4], t2[5]]),
+            Some(vec![t3_0, t2[1], t2[3], t2[4], t2[5], zero]),
         );
 
+        //commit amount
+        let amountInt = oldWallet.bc - newWallet.bc;
+        let amount = util::convert_int_to_fr::<E>(amountInt);
+        let amountCommitment = match amountTau {
+            Some(_) => Some(CommitmentProof::<E>::prove_commitment(
+                rng,
+                &self.comParams,
+                &vec![amount],
+                None,
+            )),
+            None => None,
+        };
+
         //commit signature
-        let zero = E::Fr::zero();
-        let tOptional = Some(vec![t2[1], zero, t1[1], t2[4], t2[5]]);
+        let mut tBC = t2[4].clone();
+        let mut tBM = t2[5].clone();
+        if let Some((_, t4)) = &amountCommitment {
+            tBC.add_assign(&t4[1]);
+            tBM.sub_assign(&t4[1]);
+        }
+        let tOptional = Some(vec![t2[1], zero, t1[1], tBC, tBM]);
         let proofState = self
             .pk
             .prove_commitment(rng, &self.mpk, &paymentToken, tOptional, None);
@@ -240,9 +434,26 @@ impl<E: Engine> NIZKPublicParams<E> {
             .rpParams
             .prove_ul_commitment(rng, newWallet.bm.clone(), 5, None, None);
 
+        //commit range proof of the amount above the floor
+        let rpStateAmount = match (amountTau, amountFloor) {
+            (Some(_), Some(floor)) => {
+                Some(
+                    self.rpParams
+                        .prove_ul_commitment(rng, amountInt - floor, 1, None, None),
+                )
+            }
+            _ => None,
+        };
+
         //Compute challenge
         let mut T = self.comParams.pub_bases.clone();
         T.append(&mut vec![D1, D2, D3, rpStateBC.D, rpStateBM.D]);
+        if let Some((D4, _)) = &amountCommitment {
+            T.push(D4.clone());
+        }
+        if let Some(rpState) = &rpStateAmount {
+            T.push(rpState.D.clone());
+        }
         let challenge = NIZKPublicParams::<E>::hash(proofState.a, T);
 
         //Response phase
@@ -295,6 +506,37 @@ impl<E: Engine> NIZKPublicParams<E> {
             vec01.to_vec(),
         );
 
+        //response range proof of the amount, over a commitment that only holds amount - floor
+        let rpAmount = match (amountTau, amountFloor, &rpStateAmount) {
+            (Some(tau), Some(floor), Some(rpState)) => {
+                let shiftedCom = self
+                    .comParams
+                    .commit(&vec![util::convert_int_to_fr::<E>(amountInt - floor)], &tau);
+                let otherM = vec![E::Fr::zero(); self.comParams.pub_bases.len() - 2];
+                Some(self.rpParams.prove_ul_response(
+                    tau,
+                    shiftedCom,
+                    rpState,
+                    challenge.clone(),
+                    1,
+                    otherM,
+                ))
+            }
+            _ => None,
+        };
+
+        //response amount commitment
+        let amountComProof = match (amountTau, amountCommitment) {
+            (Some(tau), Some((D4, t4))) => Some(CommitmentProof::<E>::prove_response(
+                &vec![amount],
+                &tau,
+                D4,
+                &t4,
+                &challenge,
+            )),
+            _ => None,
+        };
+
         NIZKProof {
             sig: proofState.blindSig,
             sigProof,
@@ -303,6 +545,8 @@ impl<E: Engine> NIZKPublicParams<E> {
             comBarProof,
             rpBC,
             rpBM,
+            amountComProof,
+            rpAmount,
         }
     }
 
@@ -383,6 +627,7 @@ pub fn verify_opening_bar<E: Engine>(
 #[cfg(test)]
 mod tests {
     use super::*;
+    use channels_util::HashLock;
     use ff::PrimeField;
     use pairing::bls12_381::{Bls12, Fr};
     use util::convert_int_to_fr;
@@ -414,6 +659,7 @@ mod tests {
             rev_lock: rl,
             bc,
             bm,
+            pending: None,
         };
         let s_com1 = secParams
             .pubParams
@@ -425,6 +671,7 @@ mod tests {
             rev_lock: rl2,
             bc: bc2,
             bm: bm2,
+            pending: None,
         };
         let rl_com2 = secParams.pubParams.comParams.commit(&vec![rl], &rho);
         let s_com2 = secParams
@@ -466,6 +713,88 @@ mod tests {
         );
     }
 
+    #[test]
+    fn nizk_proof_conditional_works() {
+        let rng = &mut rand::thread_rng();
+        let channelId = Fr::rand(rng);
+        let nonce = Fr::rand(rng);
+        let nonce2 = Fr::rand(rng);
+        let rl = Fr::rand(rng);
+        let rl2 = Fr::rand(rng);
+        let bc = rng.gen_range(100, 1000);
+        let bm = rng.gen_range(100, 1000);
+        let epsilon = rng.gen_range(1, 100);
+        let tau = Fr::rand(rng);
+        let rho = Fr::rand(rng);
+        let tau2 = Fr::rand(rng);
+        let tau_bar2 = Fr::rand(rng);
+        let pending = PendingAmount {
+            amount: epsilon,
+            hash_lock: HashLock::new(&[0x11u8; 32], 1000),
+        };
+
+        let secParams = NIZKSecretParams::<Bls12>::setup(rng, 5);
+        let wallet1 = Wallet {
+            channelId: channelId,
+            nonce: nonce,
+            rev_lock: rl,
+            bc,
+            bm,
+            pending: None,
+        };
+        let s_com1 = secParams
+            .pubParams
+            .comParams
+            .commit(&wallet1.as_fr_vec(), &tau);
+        let wallet2 = Wallet {
+            channelId: channelId,
+            nonce: nonce2,
+            rev_lock: rl2,
+            bc: bc - epsilon,
+            bm: bm + epsilon,
+            pending: Some(pending.clone()),
+        };
+        let rl_com2 = secParams.pubParams.comParams.commit(&vec![rl], &rho);
+        let s_com2 = secParams
+            .pubParams
+            .comParams
+            .commit(&wallet2.as_fr_vec(), &tau2);
+        let s_bar_com2 = secParams
+            .pubParams
+            .comParams
+            .commit(&wallet2.as_fr_vec_bar(), &tau_bar2);
+        let blindPaymentToken = secParams
+            .keypair
+            .sign_blind(rng, &secParams.pubParams.mpk, s_com1);
+        let paymentToken = secParams.keypair.unblind(&tau, &blindPaymentToken);
+
+        let proof = secParams.pubParams.prove(
+            rng,
+            wallet1,
+            wallet2,
+            s_com2.clone(),
+            rho,
+            tau2,
+            tau_bar2,
+            &paymentToken,
+        );
+        let fr = convert_int_to_fr::<Bls12>(epsilon);
+        let coms = ClosedCommitments {
+            s_com: s_com2,
+            s_bar_com: s_bar_com2,
+            rl_com: rl_com2,
+        };
+        assert!(secParams.verify_conditional(proof.clone(), fr, &coms, nonce, &pending));
+
+        // the close wallet must carry the pending amount the merchant agreed to
+        assert!(!secParams.verify(proof.clone(), fr, &coms, nonce));
+        let other_pending = PendingAmount {
+            amount: epsilon,
+            hash_lock: HashLock::new(&[0x11u8; 32], 2000),
+        };
+        assert!(!secParams.verify_conditional(proof, fr, &coms, nonce, &other_pending));
+    }
+
     #[test]
     fn nizk_proof_negative_value_works() {
         let rng = &mut rand::thread_rng();
@@ -492,6 +821,7 @@ mod tests {
             rev_lock: rl,
             bc,
             bm,
+            pending: None,
         };
         let s_com1 = secParams
             .pubParams
@@ -503,6 +833,7 @@ mod tests {
             rev_lock: rlprime,
             bc: bc2,
             bm: bm2,
+            pending: None,
         };
         let rl_com2 = secParams.pubParams.comParams.commit(&vec![rl], &rho);
         let s_com2 = secParams
@@ -544,6 +875,139 @@ mod tests {
         );
     }
 
+    #[test]
+    fn nizk_proof_hidden_amount_works() {
+        let rng = &mut rand::thread_rng();
+        let channelId = Fr::rand(rng);
+        let nonce = Fr::rand(rng);
+        let nonce2 = Fr::rand(rng);
+        let rl = Fr::rand(rng);
+        let rl2 = Fr::rand(rng);
+        let bc = rng.gen_range(100, 1000);
+        let bm = rng.gen_range(100, 1000);
+        let epsilon = rng.gen_range(1, 100);
+        let tau = Fr::rand(rng);
+        let rho = Fr::rand(rng);
+        let tau2 = Fr::rand(rng);
+        let tau_bar2 = Fr::rand(rng);
+        let amount_tau = Fr::rand(rng);
+
+        let secParams = NIZKSecretParams::<Bls12>::setup(rng, 5);
+        let wallet1 = Wallet {
+            channelId: channelId,
+            nonce: nonce,
+            rev_lock: rl,
+            bc,
+            bm,
+            pending: None,
+        };
+        let s_com1 = secParams
+            .pubParams
+            .comParams
+            .commit(&wallet1.as_fr_vec(), &tau);
+        let wallet2 = Wallet {
+            channelId: channelId,
+            nonce: nonce2,
+            rev_lock: rl2,
+            bc: bc - epsilon,
+            bm: bm + epsilon,
+            pending: None,
+        };
+        let rl_com2 = secParams.pubParams.comParams.commit(&vec![rl], &rho);
+        let s_com2 = secParams
+            .pubParams
+            .comParams
+            .commit(&wallet2.as_fr_vec(), &tau2);
+        let s_bar_com2 = secParams
+            .pubParams
+            .comParams
+            .commit(&wallet2.as_fr_vec_bar(), &tau_bar2);
+        let blindPaymentToken = secParams
+            .keypair
+            .sign_blind(rng, &secParams.pubParams.mpk, s_com1);
+        let paymentToken = secParams.keypair.unblind(&tau, &blindPaymentToken);
+
+        let proof = secParams.pubParams.prove_hidden_amount(
+            rng,
+            wallet1.clone(),
+            wallet2.clone(),
+            s_com2.clone(),
+            rho,
+            tau2,
+            tau_bar2,
+            &paymentToken,
+            amount_tau,
+            None,
+        );
+        let floor_proof = secParams.pubParams.prove_hidden_amount(
+            rng,
+            wallet1,
+            wallet2,
+            s_com2.clone(),
+            rho,
+            tau2,
+            tau_bar2,
+            &paymentToken,
+            amount_tau,
+            Some(1),
+        );
+        let coms = ClosedCommitments {
+            s_com: s_com2,
+            s_bar_com: s_bar_com2,
+            rl_com: rl_com2,
+        };
+        let amount_com = secParams
+            .pubParams
+            .comParams
+            .commit(&vec![convert_int_to_fr::<Bls12>(epsilon)], &amount_tau);
+        let wrong_amount_com = secParams
+            .pubParams
+            .comParams
+            .commit(&vec![convert_int_to_fr::<Bls12>(epsilon + 1)], &amount_tau);
+
+        assert!(secParams.verify_hidden_amount(proof.clone(), &amount_com, None, &coms, nonce));
+        assert!(!secParams.verify_hidden_amount(
+            proof.clone(),
+            &wrong_amount_com,
+            None,
+            &coms,
+            nonce
+        ));
+        // the floor has to be proven when the verifier asks for it, and only then
+        assert!(!secParams.verify_hidden_amount(proof.clone(), &amount_com, Some(1), &coms, nonce));
+        assert!(secParams.verify_hidden_amount(
+            floor_proof.clone(),
+            &amount_com,
+            Some(1),
+            &coms,
+            nonce
+        ));
+        assert!(!secParams.verify_hidden_amount(
+            floor_proof.clone(),
+            &amount_com,
+            None,
+            &coms,
+            nonce
+        ));
+        assert!(!secParams.verify_hidden_amount(
+            floor_proof.clone(),
+            &amount_com,
+            Some(epsilon + 1),
+            &coms,
+            nonce
+        ));
+        assert!(!secParams.verify_hidden_amount(
+            floor_proof,
+            &wrong_amount_com,
+            Some(1),
+            &coms,
+            nonce
+        ));
+        // a hidden amount proof does not verify against a public amount
+        let fr = convert_int_to_fr::<Bls12>(epsilon);
+        assert!(!secParams.verify(proof, fr, &coms, nonce));
+    }
+
     #[test]
     fn nizk_proof_close_works() {
         let rng = &mut rand::thread_rng();
@@ -571,6 +1035,7 @@ mod tests {
             rev_lock: rl,
             bc,
             bm,
+            pending: None,
         };
         let s_com = secParams
             .pubParams
@@ -582,6 +1047,7 @@ mod tests {
             rev_lock: rlprime,
             bc: bc2,
             bm: bm2,
+            pending: None,
         };
         let rl_com2 = secParams.pubParams.comParams.commit(&vec![rl], &rho);
         let s_com2 = secParams
@@ -670,6 +1136,7 @@ mod tests {
             rev_lock: rl,
             bc,
             bm,
+            pending: None,
         };
 
         let bc2Prime = bc;
@@ -679,6 +1146,7 @@ mod tests {
             rev_lock: rlprime,
             bc: bc2Prime,
             bm: bm2,
+            pending: None,
         };
         let s_com = secParams
             .pubParams
@@ -728,6 +1196,7 @@ mod tests {
             rev_lock: rlprime,
             bc: bc2,
             bm: bm2Prime,
+            pending: None,
         };
         let commitment2 = secParams
             .pubParams
@@ -763,6 +1232,7 @@ mod tests {
             rev_lock: rlprime,
             bc: bc2,
             bm: bm2,
+            pending: None,
         };
         let commitment2 = secParams
             .pubParams
@@ -0,0 +1,296 @@
+//! Minimal SOCKS5 client (RFC 1928) used to reach the other party through Tor.
+//!
+//! Only the unauthenticated CONNECT command is supported. The destination is always
+//! sent as a domain name so that name resolution (including `.onion` addresses)
+//! happens at the proxy and never leaks through the local resolver.
+
+use std::io::{ErrorKind, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const SOCKS_VERSION: u8 = 0x05;
+const METHOD_NO_AUTH: u8 = 0x00;
+const CMD_CONNECT: u8 = 0x01;
+const ATYP_IPV4: u8 = 0x01;
+const ATYP_DOMAIN: u8 = 0x03;
+const ATYP_IPV6: u8 = 0x04;
+const REPLY_SUCCEEDED: u8 = 0x00;
+// how long the relay waits for the MPC to dial in before closing the upstream connection
+const RELAY_ACCEPT_TIMEOUT: Duration = Duration::from_secs(60);
+const RELAY_ACCEPT_POLL: Duration = Duration::from_millis(10);
+
+fn reply_error(code: u8) -> &'static str {
+    match code {
+        0x01 => "general SOCKS server failure",
+        0x02 => "connection not allowed by ruleset",
+        0x03 => "network unreachable",
+        0x04 => "host unreachable",
+        0x05 => "connection refused",
+        0x06 => "TTL expired",
+        0x07 => "command not supported",
+        0x08 => "address type not supported",
+        _ => "unknown error",
+    }
+}
+
+///
+/// connect() - opens a TCP stream to dest_host:dest_port through the SOCKS5 proxy
+/// listening at proxy_addr (e.g. "127.0.0.1:9050" for a local Tor daemon).
+/// output: the connected stream, or an error if the proxy refuses the request
+///
+pub fn connect(proxy_addr: &str, dest_host: &str, dest_port: u16) -> Result<TcpStream, String> {
+    if dest_host.is_empty() || dest_host.len() > 255 {
+        return Err(format!("invalid SOCKS5 destination host: '{}'", dest_host));
+    }
+    let mut stream = TcpStream::connect(proxy_addr)
+        .map_err(|e| format!("failed to connect to SOCKS5 proxy {}: {}", proxy_addr, e))?;
+    let io_err = |e: std::io::Error| format!("SOCKS5 handshake failed: {}", e);
+
+    // method negotiation: we only offer "no authentication"
+    stream
+        .write_all(&[SOCKS_VERSION, 1, METHOD_NO_AUTH])
+        .map_err(io_err)?;
+    let mut method = [0u8; 2];
+    stream.read_exact(&mut method).map_err(io_err)?;
+    if method[0] != SOCKS_VERSION || method[1] != METHOD_NO_AUTH {
+        return Err(String::from(
+            "SOCKS5 proxy does not accept unauthenticated connections",
+        ));
+    }
+
+    // CONNECT request with the destination as a domain name
+    let mut request = vec![SOCKS_VERSION, CMD_CONNECT, 0x00, ATYP_DOMAIN];
+    request.push(dest_host.len() as u8);
+    request.extend_from_slice(dest_host.as_bytes());
+    request.extend_from_slice(&dest_port.to_be_bytes());
+    stream.write_all(&request).map_err(io_err)?;
+
+    let mut reply = [0u8; 4];
+    stream.read_exact(&mut reply).map_err(io_err)?;
+    if reply[0] != SOCKS_VERSION {
+        return Err(format!("unexpected SOCKS version in reply: {}", reply[0]));
+    }
+    if reply[1] != REPLY_SUCCEEDED {
+        return Err(format!(
+            "SOCKS5 proxy could not connect to {}:{}: {}",
+            dest_host,
+            dest_port,
+            reply_error(reply[1])
+        ));
+    }
+
+    // skip over the bound address and port
+    let addr_len = match reply[3] {
+        ATYP_IPV4 => 4,
+        ATYP_IPV6 => 16,
+        ATYP_DOMAIN => {
+            let mut len = [0u8; 1];
+            stream.read_exact(&mut len).map_err(io_err)?;
+            len[0] as usize
+        }
+        t => return Err(format!("unexpected SOCKS5 address type: {}", t)),
+    };
+    let mut bound = vec![0u8; addr_len + 2];
+    stream.read_exact(&mut bound).map_err(io_err)?;
+
+    Ok(stream)
+}
+
+fn pipe(mut from: TcpStream, mut to: TcpStream) {
+    let _ = std::io::copy(&mut from, &mut to);
+    let _ = to.shutdown(Shutdown::Write);
+}
+
+// waits up to `timeout` for the first connection to the listener
+fn accept_with_timeout(listener: &TcpListener, timeout: Duration) -> Option<TcpStream> {
+    if listener.set_nonblocking(true).is_err() {
+        return None;
+    }
+    let deadline = Instant::now() + timeout;
+    loop {
+        match listener.accept() {
+            Ok((s, _)) => return s.set_nonblocking(false).ok().map(|_| s),
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock && Instant::now() < deadline => {
+                thread::sleep(RELAY_ACCEPT_POLL)
+            }
+            Err(_) => return None,
+        }
+    }
+}
+
+///
+/// open_relay() - connects to dest_host:dest_port through the SOCKS5 proxy and exposes the
+/// resulting stream on a loopback port. This lets transports that can only dial a plain
+/// TCP address (such as the NetIO used by the MPC) be routed through the proxy.
+/// The relay forwards the first connection to its port, stops listening, and exits when
+/// either side closes. If nothing connects within a minute, the relay closes the
+/// upstream connection and exits.
+/// output: the loopback port to connect to
+///
+pub fn open_relay(proxy_addr: &str, dest_host: &str, dest_port: u16) -> Result<u16, String> {
+    open_relay_with_timeout(proxy_addr, dest_host, dest_port, RELAY_ACCEPT_TIMEOUT)
+}
+
+fn open_relay_with_timeout(
+    proxy_addr: &str,
+    dest_host: &str,
+    dest_port: u16,
+    accept_timeout: Duration,
+) -> Result<u16, String> {
+    let upstream = connect(proxy_addr, dest_host, dest_port)?;
+    // only reachable from this host
+    let listener = TcpListener::bind("127.0.0.1:0")
+        .map_err(|e| format!("failed to bind local relay: {}", e))?;
+    let relay_addr = listener
+        .local_addr()
+        .map_err(|e| format!("failed to bind local relay: {}", e))?;
+
+    thread::spawn(move || {
+        let local = match accept_with_timeout(&listener, accept_timeout) {
+            Some(s) => s,
+            None => {
+                let _ = upstream.shutdown(Shutdown::Both);
+                return;
+            }
+        };
+        drop(listener);
+        let (local_rx, upstream_rx) = match (local.try_clone(), upstream.try_clone()) {
+            (Ok(l), Ok(u)) => (l, u),
+            _ => return,
+        };
+        let outbound = thread::spawn(move || pipe(local_rx, upstream));
+        pipe(upstream_rx, local);
+        let _ = outbound.join();
+    });
+
+    Ok(relay_addr.port())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader};
+    use std::sync::mpsc;
+
+    // A local stand-in for the Tor SOCKS port: performs the server side of the handshake,
+    // reports the requested destination and then connects to `target` regardless.
+    fn spawn_stub_proxy(target: u16, reply_code: u8) -> (u16, mpsc::Receiver<(String, u16)>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            let (mut client, _) = listener.accept().unwrap();
+            let mut greeting = [0u8; 3];
+            client.read_exact(&mut greeting).unwrap();
+            assert_eq!(greeting, [SOCKS_VERSION, 1, METHOD_NO_AUTH]);
+            client.write_all(&[SOCKS_VERSION, METHOD_NO_AUTH]).unwrap();
+
+            let mut header = [0u8; 5];
+            client.read_exact(&mut header).unwrap();
+            assert_eq!(header[..4], [SOCKS_VERSION, CMD_CONNECT, 0, ATYP_DOMAIN]);
+            let mut host = vec![0u8; header[4] as usize];
+            client.read_exact(&mut host).unwrap();
+            let mut port = [0u8; 2];
+            client.read_exact(&mut port).unwrap();
+            tx.send((String::from_utf8(host).unwrap(), u16::from_be_bytes(port)))
+                .unwrap();
+
+            client
+                .write_all(&[SOCKS_VERSION, reply_code, 0, ATYP_IPV4, 0, 0, 0, 0, 0, 0])
+                .unwrap();
+            if reply_code != REPLY_SUCCEEDED {
+                return;
+            }
+            let upstream = TcpStream::connect(("127.0.0.1", target)).unwrap();
+            let client_rx = client.try_clone().unwrap();
+            let upstream_rx = upstream.try_clone().unwrap();
+            thread::spawn(move || pipe(client_rx, upstream));
+            pipe(upstream_rx, client);
+        });
+        (port, rx)
+    }
+
+    fn spawn_echo_server() -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut writer = stream;
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            writer.write_all(line.as_bytes()).unwrap();
+        });
+        port
+    }
+
+    #[test]
+    fn socks5_connect_resolves_at_proxy() {
+        let echo_port = spawn_echo_server();
+        let (proxy_port, requested) = spawn_stub_proxy(echo_port, REPLY_SUCCEEDED);
+        let proxy = format!("127.0.0.1:{}", proxy_port);
+
+        let onion = "zkchannelsmerchantxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxx.onion";
+        let mut stream = connect(&proxy, onion, 4242).unwrap();
+        assert_eq!(requested.recv().unwrap(), (String::from(onion), 4242));
+
+        stream.write_all(b"hello merchant\n").unwrap();
+        let mut line = String::new();
+        BufReader::new(stream).read_line(&mut line).unwrap();
+        assert_eq!(line, "hello merchant\n");
+    }
+
+    #[test]
+    fn socks5_relay_forwards_loopback_traffic() {
+        let echo_port = spawn_echo_server();
+        let (proxy_port, requested) = spawn_stub_proxy(echo_port, REPLY_SUCCEEDED);
+        let proxy = format!("127.0.0.1:{}", proxy_port);
+
+        let local_port = open_relay(&proxy, "merchant.example", 2424).unwrap();
+        assert_eq!(
+            requested.recv().unwrap(),
+            (String::from("merchant.example"), 2424)
+        );
+
+        let mut stream = TcpStream::connect(("127.0.0.1", local_port)).unwrap();
+        stream.write_all(b"mpc round\n").unwrap();
+        let mut line = String::new();
+        BufReader::new(stream).read_line(&mut line).unwrap();
+        assert_eq!(line, "mpc round\n");
+    }
+
+    #[test]
+    fn socks5_relay_stops_listening_after_timeout() {
+        let echo_port = spawn_echo_server();
+        let (proxy_port, _requested) = spawn_stub_proxy(echo_port, REPLY_SUCCEEDED);
+        let proxy = format!("127.0.0.1:{}", proxy_port);
+
+        let local_port =
+            open_relay_with_timeout(&proxy, "merchant.example", 2424, Duration::from_millis(50))
+                .unwrap();
+        thread::sleep(Duration::from_millis(500));
+        assert!(TcpStream::connect(("127.0.0.1", local_port)).is_err());
+    }
+
+    #[test]
+    fn socks5_connect_reports_proxy_failure() {
+        let (proxy_port, _requested) = spawn_stub_proxy(0, 0x05);
+        let proxy = format!("127.0.0.1:{}", proxy_port);
+
+        let err = connect(&proxy, "merchant.example", 2424).unwrap_err();
+        assert!(err.contains("connection refused"), "{}", err);
+    }
+
+    #[test]
+    fn socks5_connect_fails_without_proxy() {
+        // bind and drop to get a port with nothing listening on it
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let proxy = format!("127.0.0.1:{}", port);
+        assert!(connect(&proxy, "merchant.example", 2424).is_err());
+    }
+}
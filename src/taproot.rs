@@ -0,0 +1,1169 @@
+//! Taproot (BIP340/341) outputs for zkChannels.
+//!
+//! Builds the output keys and tapscript leaves of a Taproot channel: the escrow is a
+//! MuSig2 (BIP327) key-path output of pk_c and pk_m, and the close/dispute paths of
+//! the close txs are tapscript leaves. Cooperative spends of these outputs look like
+//! ordinary single-key spends on chain.
+//!
+//! The escrow tx and the mutual close tx are built here, and the mutual close spends the
+//! escrow via the key path with a two-round MuSig2 signature of both parties. Schnorr and
+//! MuSig2 signing use the schnorrsig and musig modules of libsecp256k1-zkp. The unilateral
+//! close txs are signed by the merchant inside the MPC, and the circuit only produces ECDSA
+//! signatures over segwit v0 sighashes, so channels cannot close unilaterally from a Taproot
+//! escrow until the circuit signs BIP341 sighashes. Forming a Taproot escrow tx is therefore
+//! only built with the `taproot-escrow` feature.
+
+use super::*;
+use rand::Rng;
+use secp256k1_zkp::{
+    new_musig_nonce_pair, schnorr, Keypair, Message, MusigAggNonce, MusigKeyAggCache,
+    MusigPartialSignature, MusigPubNonce, MusigSecNonce, MusigSession, MusigSessionId, Scalar,
+    XOnlyPublicKey,
+};
+use sha2::{Digest, Sha256};
+use util::compute_hash160;
+
+const TAPSCRIPT_LEAF_VERSION: u8 = 0xc0;
+const OP_CHECKSIG: u8 = 0xac;
+const OP_CHECKSEQUENCEVERIFY: u8 = 0xb2;
+const OP_DROP: u8 = 0x75;
+const OP_EQUALVERIFY: u8 = 0x88;
+const OP_SHA256: u8 = 0xa8;
+const OP_1: u8 = 0x51;
+
+// BIP341 NUMS point with no known discrete log, used as internal key when an
+// output must not have a key path
+pub const NUMS_INTERNAL_KEY: [u8; 32] = [
+    0x50, 0x92, 0x9b, 0x74, 0xc1, 0xa0, 0x49, 0x54, 0xb7, 0x8b, 0x4b, 0x60, 0x35, 0xe9, 0x7a, 0x5e,
+    0x07, 0x8a, 0x5a, 0x0f, 0x28, 0xec, 0x96, 0xd5, 0x47, 0xbf, 0xee, 0x9a, 0xce, 0x80, 0x3a, 0xc0,
+];
+
+pub fn tagged_hash(tag: &str, msg: &[u8]) -> [u8; 32] {
+    let tag_hash = Sha256::digest(tag.as_bytes());
+    let mut hasher = Sha256::new();
+    hasher.input(&tag_hash);
+    hasher.input(&tag_hash);
+    hasher.input(msg);
+    let mut hash_buf = [0u8; 32];
+    hash_buf.copy_from_slice(&hasher.result());
+    return hash_buf;
+}
+
+pub fn x_only(pk: &secp256k1::PublicKey) -> [u8; 32] {
+    let mut x = [0u8; 32];
+    x.copy_from_slice(&pk.serialize()[1..]);
+    return x;
+}
+
+// the point with even y for the x-only key
+fn lift_x(x: &[u8; 32]) -> Result<secp256k1::PublicKey, String> {
+    let mut ser = vec![0x02];
+    ser.extend_from_slice(x);
+    Ok(handle_error_util!(secp256k1::PublicKey::from_slice(&ser)))
+}
+
+// the keys of the channel are secp256k1 keys, libsecp256k1-zkp has its own types for them
+fn to_zkp_pk(pk: &secp256k1::PublicKey) -> Result<secp256k1_zkp::PublicKey, String> {
+    Ok(handle_error_util!(secp256k1_zkp::PublicKey::from_slice(
+        &pk.serialize()
+    )))
+}
+
+fn from_zkp_pk(pk: &secp256k1_zkp::PublicKey) -> Result<secp256k1::PublicKey, String> {
+    Ok(handle_error_util!(secp256k1::PublicKey::from_slice(
+        &pk.serialize()
+    )))
+}
+
+fn keypair_from_bytes(sk: &[u8; 32]) -> Result<Keypair, String> {
+    let secp = secp256k1_zkp::Secp256k1::signing_only();
+    let sk = handle_error_util!(secp256k1_zkp::SecretKey::from_slice(sk));
+    Ok(Keypair::from_secret_key(&secp, &sk))
+}
+
+///
+/// KeyAggContext - BIP327 key aggregation context: the aggregate key Q of the signers
+/// together with the tweaks applied to it (kept in the musig key aggregation cache)
+///
+#[derive(Clone)]
+pub struct KeyAggContext {
+    pub pubkeys: Vec<secp256k1::PublicKey>,
+    pub agg_key: secp256k1::PublicKey,
+    cache: MusigKeyAggCache,
+}
+
+impl KeyAggContext {
+    ///
+    /// new() - BIP327 KeyAgg of the (plain) public keys of the signers
+    ///
+    pub fn new(pubkeys: &Vec<secp256k1::PublicKey>) -> Result<KeyAggContext, String> {
+        if pubkeys.is_empty() {
+            return Err(String::from("key_agg: no public keys"));
+        }
+        let secp = secp256k1_zkp::Secp256k1::verification_only();
+        let mut zkp_pubkeys = Vec::new();
+        for pk in pubkeys.iter() {
+            zkp_pubkeys.push(to_zkp_pk(pk)?);
+        }
+        let cache = MusigKeyAggCache::new(&secp, &zkp_pubkeys);
+        Ok(KeyAggContext {
+            pubkeys: pubkeys.clone(),
+            agg_key: from_zkp_pk(&cache.agg_pk_full())?,
+            cache,
+        })
+    }
+
+    ///
+    /// apply_xonly_tweak() - BIP327 ApplyTweak for an x-only tweak t:
+    /// Q' = lift_x(x(Q)) + t*G, as used by the BIP341 TapTweak of the output key
+    ///
+    pub fn apply_xonly_tweak(&mut self, tweak: &[u8; 32]) -> Result<(), String> {
+        let secp = secp256k1_zkp::Secp256k1::verification_only();
+        let tweak = handle_error_util!(Scalar::from_be_bytes(*tweak));
+        let agg_key = handle_error_util!(self.cache.pubkey_xonly_tweak_add(&secp, &tweak));
+        self.agg_key = from_zkp_pk(&agg_key)?;
+        Ok(())
+    }
+}
+
+///
+/// key_agg() - BIP327 KeyAgg of the (plain) public keys of the signers
+/// output: the aggregate key, whose x-only form is the MuSig2 internal key
+///
+pub fn key_agg(pubkeys: &Vec<secp256k1::PublicKey>) -> Result<secp256k1::PublicKey, String> {
+    Ok(KeyAggContext::new(pubkeys)?.agg_key)
+}
+
+pub fn tap_leaf_hash(script: &[u8]) -> [u8; 32] {
+    // scripts built here are always shorter than 0xfd bytes
+    let mut input = vec![TAPSCRIPT_LEAF_VERSION, script.len() as u8];
+    input.extend_from_slice(script);
+    return tagged_hash("TapLeaf", &input);
+}
+
+pub fn tap_branch_hash(a: &[u8; 32], b: &[u8; 32]) -> [u8; 32] {
+    let (first, second) = match a <= b {
+        true => (a, b),
+        false => (b, a),
+    };
+    let mut input = first.to_vec();
+    input.extend_from_slice(second);
+    return tagged_hash("TapBranch", &input);
+}
+
+///
+/// tweak_output_key() - BIP341 output key Q = lift_x(P) + H_TapTweak(P || root) * G
+/// output: the output key (its parity goes into the control block)
+///
+pub fn tweak_output_key(
+    internal_key: &[u8; 32],
+    merkle_root: Option<[u8; 32]>,
+) -> Result<secp256k1::PublicKey, String> {
+    let secp = secp256k1::Secp256k1::verification_only();
+    let mut input = internal_key.to_vec();
+    if let Some(root) = merkle_root {
+        input.extend_from_slice(&root);
+    }
+    let tweak = tagged_hash("TapTweak", &input);
+    let mut output_key = lift_x(internal_key)?;
+    handle_error_util!(output_key.add_exp_assign(&secp, &tweak));
+    Ok(output_key)
+}
+
+// minimal script push of a relative timelock
+fn push_self_delay(script: &mut Vec<u8>, self_delay: u16) {
+    if self_delay > 0 && self_delay <= 16 {
+        script.push(OP_1 + self_delay as u8 - 1);
+        return;
+    }
+    let mut num = self_delay.to_le_bytes().to_vec();
+    while num.last() == Some(&0) {
+        num.pop();
+    }
+    if num.last().map_or(false, |b| b & 0x80 != 0) {
+        num.push(0x00);
+    }
+    script.push(num.len() as u8);
+    script.extend(num);
+}
+
+// <self_delay> OP_CSV OP_DROP <pk> OP_CHECKSIG
+pub fn delayed_key_script(pk: &secp256k1::PublicKey, self_delay: u16) -> Vec<u8> {
+    let mut script = Vec::new();
+    push_self_delay(&mut script, self_delay);
+    script.extend_from_slice(&[OP_CHECKSEQUENCEVERIFY, OP_DROP, 0x20]);
+    script.extend_from_slice(&x_only(pk));
+    script.push(OP_CHECKSIG);
+    return script;
+}
+
+// OP_SHA256 <rev_lock> OP_EQUALVERIFY <merch_disp_pk> OP_CHECKSIG
+pub fn revocation_script(rev_lock: &[u8; 32], merch_disp_pk: &secp256k1::PublicKey) -> Vec<u8> {
+    let mut script = vec![OP_SHA256, 0x20];
+    script.extend_from_slice(rev_lock);
+    script.extend_from_slice(&[OP_EQUALVERIFY, 0x20]);
+    script.extend_from_slice(&x_only(merch_disp_pk));
+    script.push(OP_CHECKSIG);
+    return script;
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct TaprootOutput {
+    pub internal_key: [u8; 32],
+    pub leaves: Vec<Vec<u8>>,
+    pub output_key: secp256k1::PublicKey,
+}
+
+impl TaprootOutput {
+    // at most two leaves, which covers every output of a channel
+    pub fn new(internal_key: [u8; 32], leaves: Vec<Vec<u8>>) -> Result<TaprootOutput, String> {
+        let leaf_hashes: Vec<[u8; 32]> = leaves.iter().map(|s| tap_leaf_hash(s)).collect();
+        let merkle_root = match leaf_hashes.len() {
+            0 => None,
+            1 => Some(leaf_hashes[0]),
+            2 => Some(tap_branch_hash(&leaf_hashes[0], &leaf_hashes[1])),
+            _ => return Err(String::from("taproot output supports at most two leaves")),
+        };
+        let output_key = tweak_output_key(&internal_key, merkle_root)?;
+        Ok(TaprootOutput {
+            internal_key,
+            leaves,
+            output_key,
+        })
+    }
+
+    // OP_1 <x-only output key>
+    pub fn script_pubkey(&self) -> Vec<u8> {
+        let mut script = vec![OP_1, 0x20];
+        script.extend_from_slice(&x_only(&self.output_key));
+        return script;
+    }
+
+    // control block for spending via leaves[index]
+    pub fn control_block(&self, index: usize) -> Result<Vec<u8>, String> {
+        if index >= self.leaves.len() {
+            return Err(format!("no taproot leaf at index {}", index));
+        }
+        let parity = self.output_key.serialize()[0] & 0x01;
+        let mut control_block = vec![TAPSCRIPT_LEAF_VERSION | parity];
+        control_block.extend_from_slice(&self.internal_key);
+        if self.leaves.len() == 2 {
+            control_block.extend_from_slice(&tap_leaf_hash(&self.leaves[1 - index]));
+        }
+        Ok(control_block)
+    }
+}
+
+///
+/// escrow_output() - escrow spendable only via the MuSig2 key path of pk_c and pk_m
+///
+pub fn escrow_output(
+    cust_pk: &secp256k1::PublicKey,
+    merch_pk: &secp256k1::PublicKey,
+) -> Result<TaprootOutput, String> {
+    let agg_key = key_agg(&vec![cust_pk.clone(), merch_pk.clone()])?;
+    TaprootOutput::new(x_only(&agg_key), Vec::new())
+}
+
+///
+/// merch_close_output() - MuSig2 key path of pk_c and pk_m (spent by cust-close),
+/// or the merchant's payout key after self_delay
+///
+pub fn merch_close_output(
+    cust_pk: &secp256k1::PublicKey,
+    merch_pk: &secp256k1::PublicKey,
+    merch_close_pk: &secp256k1::PublicKey,
+    self_delay: u16,
+) -> Result<TaprootOutput, String> {
+    let agg_key = key_agg(&vec![cust_pk.clone(), merch_pk.clone()])?;
+    let leaves = vec![delayed_key_script(merch_close_pk, self_delay)];
+    TaprootOutput::new(x_only(&agg_key), leaves)
+}
+
+///
+/// cust_close_output() - the to_customer output of cust-close: the merchant's dispute
+/// path with the revocation secret, or the customer's payout key after self_delay.
+/// there is no key path
+///
+pub fn cust_close_output(
+    rev_lock: &[u8; 32],
+    merch_disp_pk: &secp256k1::PublicKey,
+    cust_close_pk: &secp256k1::PublicKey,
+    self_delay: u16,
+) -> Result<TaprootOutput, String> {
+    let leaves = vec![
+        revocation_script(rev_lock, merch_disp_pk),
+        delayed_key_script(cust_close_pk, self_delay),
+    ];
+    TaprootOutput::new(NUMS_INTERNAL_KEY, leaves)
+}
+
+///
+/// schnorr_sign() - BIP340 signature of msg with the secret key sk
+/// input: aux_rand, fresh randomness mixed into the nonce
+///
+pub fn schnorr_sign(
+    sk: &[u8; 32],
+    msg: &[u8; 32],
+    aux_rand: &[u8; 32],
+) -> Result<[u8; 64], String> {
+    let secp = secp256k1_zkp::Secp256k1::signing_only();
+    let keypair = keypair_from_bytes(sk)?;
+    let msg = Message::from_digest(*msg);
+    let sig = secp.sign_schnorr_with_aux_rand(&msg, &keypair, aux_rand);
+    Ok(sig.serialize())
+}
+
+///
+/// schnorr_verify() - BIP340 verification of sig on msg for the x-only public key
+///
+pub fn schnorr_verify(pubkey: &[u8; 32], msg: &[u8; 32], sig: &[u8; 64]) -> bool {
+    let secp = secp256k1_zkp::Secp256k1::verification_only();
+    let pk = match XOnlyPublicKey::from_slice(pubkey) {
+        Ok(pk) => pk,
+        Err(_) => return false,
+    };
+    let sig = match schnorr::Signature::from_slice(sig) {
+        Ok(sig) => sig,
+        Err(_) => return false,
+    };
+    secp.verify_schnorr(&sig, &Message::from_digest(*msg), &pk)
+        .is_ok()
+}
+
+///
+/// escrow_key_agg() - MuSig2 context for the key path of the escrow output: the
+/// aggregate key of pk_c and pk_m with the BIP341 TapTweak (no script tree) applied
+///
+pub fn escrow_key_agg(
+    cust_pk: &secp256k1::PublicKey,
+    merch_pk: &secp256k1::PublicKey,
+) -> Result<KeyAggContext, String> {
+    let mut ctx = KeyAggContext::new(&vec![cust_pk.clone(), merch_pk.clone()])?;
+    let tweak = tagged_hash("TapTweak", &x_only(&ctx.agg_key));
+    ctx.apply_xonly_tweak(&tweak)?;
+    Ok(ctx)
+}
+
+// public nonce (R1 || R2) sent by a signer
+fn parse_pub_nonce(pub_nonce: &[u8]) -> Result<MusigPubNonce, String> {
+    Ok(handle_error_util!(MusigPubNonce::from_slice(pub_nonce)))
+}
+
+///
+/// musig_nonce_gen() - BIP327 NonceGen for signing msg with sk under ctx
+/// output: the secret nonce, which can sign once and never leaves this process, and the
+/// public nonce (R1 || R2) sent to the other signer
+///
+pub fn musig_nonce_gen<R: Rng>(
+    csprng: &mut R,
+    sk: &[u8; 32],
+    ctx: &KeyAggContext,
+    msg: &[u8; 32],
+) -> Result<(MusigSecNonce, Vec<u8>), String> {
+    let secp = secp256k1_zkp::Secp256k1::signing_only();
+    let sk = handle_error_util!(secp256k1_zkp::SecretKey::from_slice(sk));
+    let pk = secp256k1_zkp::PublicKey::from_secret_key(&secp, &sk);
+    let mut session_id = [0u8; 32];
+    csprng.fill_bytes(&mut session_id);
+    let (sec_nonce, pub_nonce) = handle_error_util!(new_musig_nonce_pair(
+        &secp,
+        MusigSessionId::assume_unique_per_nonce_gen(session_id),
+        Some(&ctx.cache),
+        Some(sk),
+        pk,
+        Some(Message::from_digest(*msg)),
+        None,
+    ));
+    Ok((sec_nonce, pub_nonce.serialize().to_vec()))
+}
+
+// signing session of BIP327 GetSessionValues for the public nonces of all signers
+fn musig_session(
+    ctx: &KeyAggContext,
+    pub_nonces: &Vec<Vec<u8>>,
+    msg: &[u8; 32],
+) -> Result<MusigSession, String> {
+    if pub_nonces.is_empty() {
+        return Err(String::from("musig: no public nonces"));
+    }
+    let secp = secp256k1_zkp::Secp256k1::new();
+    let mut nonces = Vec::new();
+    for pub_nonce in pub_nonces.iter() {
+        nonces.push(parse_pub_nonce(pub_nonce)?);
+    }
+    let agg_nonce = MusigAggNonce::new(&secp, &nonces);
+    Ok(MusigSession::new(
+        &secp,
+        &ctx.cache,
+        agg_nonce,
+        Message::from_digest(*msg),
+    ))
+}
+
+///
+/// musig_partial_sign() - BIP327 Sign: the partial signature of the signer with sk
+/// input: the signer's secret nonce, which is consumed, and the public nonces of all signers
+///
+pub fn musig_partial_sign(
+    sec_nonce: MusigSecNonce,
+    sk: &[u8; 32],
+    ctx: &KeyAggContext,
+    pub_nonces: &Vec<Vec<u8>>,
+    msg: &[u8; 32],
+) -> Result<[u8; 32], String> {
+    let secp = secp256k1_zkp::Secp256k1::new();
+    let keypair = keypair_from_bytes(sk)?;
+    let session = musig_session(ctx, pub_nonces, msg)?;
+    let partial_sig =
+        handle_error_util!(session.partial_sign(&secp, sec_nonce, &keypair, &ctx.cache));
+    Ok(partial_sig.serialize())
+}
+
+///
+/// musig_partial_verify() - BIP327 PartialSigVerify of the partial signature of the
+/// signer with public key pk and public nonce pub_nonce
+///
+pub fn musig_partial_verify(
+    partial_sig: &[u8; 32],
+    pub_nonce: &Vec<u8>,
+    pk: &secp256k1::PublicKey,
+    ctx: &KeyAggContext,
+    pub_nonces: &Vec<Vec<u8>>,
+    msg: &[u8; 32],
+) -> bool {
+    let check = || -> Result<bool, String> {
+        let secp = secp256k1_zkp::Secp256k1::new();
+        let partial_sig = handle_error_util!(MusigPartialSignature::from_slice(partial_sig));
+        let pub_nonce = parse_pub_nonce(pub_nonce)?;
+        let session = musig_session(ctx, pub_nonces, msg)?;
+        Ok(session.partial_verify(&secp, &ctx.cache, partial_sig, pub_nonce, to_zkp_pk(pk)?))
+    };
+    check().unwrap_or(false)
+}
+
+///
+/// musig_partial_sig_agg() - BIP327 PartialSigAgg: the BIP340 signature for the
+/// (tweaked) aggregate key from the partial signatures of all signers
+///
+pub fn musig_partial_sig_agg(
+    ctx: &KeyAggContext,
+    pub_nonces: &Vec<Vec<u8>>,
+    partial_sigs: &Vec<[u8; 32]>,
+    msg: &[u8; 32],
+) -> Result<[u8; 64], String> {
+    let session = musig_session(ctx, pub_nonces, msg)?;
+    let mut sigs = Vec::new();
+    for partial_sig in partial_sigs.iter() {
+        sigs.push(handle_error_util!(MusigPartialSignature::from_slice(
+            partial_sig
+        )));
+    }
+    Ok(session.partial_sig_agg(&sigs).serialize())
+}
+
+fn write_compact_size(buf: &mut Vec<u8>, len: usize) {
+    if len < 0xfd {
+        buf.push(len as u8);
+    } else if len <= 0xffff {
+        buf.push(0xfd);
+        buf.extend_from_slice(&(len as u16).to_le_bytes());
+    } else {
+        buf.push(0xfe);
+        buf.extend_from_slice(&(len as u32).to_le_bytes());
+    }
+}
+
+fn double_sha256(input: &[u8]) -> [u8; 32] {
+    let mut hash_buf = [0u8; 32];
+    hash_buf.copy_from_slice(&Sha256::digest(&Sha256::digest(input)));
+    return hash_buf;
+}
+
+fn single_sha256(input: &[u8]) -> [u8; 32] {
+    let mut hash_buf = [0u8; 32];
+    hash_buf.copy_from_slice(&Sha256::digest(input));
+    return hash_buf;
+}
+
+// OP_0 <hash160(pk)>
+pub fn p2wpkh_script_pubkey(pk: &secp256k1::PublicKey) -> Vec<u8> {
+    let mut script = vec![0x00, 0x14];
+    script.extend_from_slice(&compute_hash160(&pk.serialize().to_vec()));
+    return script;
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct TxInput {
+    pub txid_le: [u8; 32],
+    pub index: u32,
+    pub sequence: u32,
+    pub witness: Vec<Vec<u8>>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct TxOutput {
+    pub value: i64,
+    pub script_pubkey: Vec<u8>,
+}
+
+impl TxOutput {
+    fn serialize(&self) -> Vec<u8> {
+        let mut output = self.value.to_le_bytes().to_vec();
+        write_compact_size(&mut output, self.script_pubkey.len());
+        output.extend_from_slice(&self.script_pubkey);
+        return output;
+    }
+}
+
+///
+/// TaprootTransaction - a version 2 tx with segwit inputs, for the txs of a Taproot
+/// channel that are formed outside the MPC (escrow and mutual close)
+///
+#[derive(Clone, Debug, PartialEq)]
+pub struct TaprootTransaction {
+    pub inputs: Vec<TxInput>,
+    pub outputs: Vec<TxOutput>,
+    pub lock_time: u32,
+}
+
+impl TaprootTransaction {
+    const VERSION: u32 = 2;
+
+    fn serialize_outputs(&self) -> Vec<u8> {
+        self.outputs
+            .iter()
+            .map(|o| o.serialize())
+            .collect::<Vec<Vec<u8>>>()
+            .concat()
+    }
+
+    fn serialize(&self, with_witness: bool) -> Vec<u8> {
+        let mut tx = TaprootTransaction::VERSION.to_le_bytes().to_vec();
+        if with_witness {
+            tx.extend_from_slice(&[0x00, 0x01]); // segwit marker and flag
+        }
+        write_compact_size(&mut tx, self.inputs.len());
+        for input in self.inputs.iter() {
+            tx.extend_from_slice(&input.txid_le);
+            tx.extend_from_slice(&input.index.to_le_bytes());
+            tx.push(0x00); // empty scriptSig
+            tx.extend_from_slice(&input.sequence.to_le_bytes());
+        }
+        write_compact_size(&mut tx, self.outputs.len());
+        tx.extend(self.serialize_outputs());
+        if with_witness {
+            for input in self.inputs.iter() {
+                write_compact_size(&mut tx, input.witness.len());
+                for item in input.witness.iter() {
+                    write_compact_size(&mut tx, item.len());
+                    tx.extend_from_slice(item);
+                }
+            }
+        }
+        tx.extend_from_slice(&self.lock_time.to_le_bytes());
+        return tx;
+    }
+
+    // the signed tx for broadcast
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.serialize(true)
+    }
+
+    pub fn txid_le(&self) -> [u8; 32] {
+        double_sha256(&self.serialize(false))
+    }
+
+    ///
+    /// p2wpkh_sighash() - BIP143 sighash (SIGHASH_ALL) for spending the P2WPKH output
+    /// of pk with the given value via the input at index
+    ///
+    pub fn p2wpkh_sighash(
+        &self,
+        index: usize,
+        value: i64,
+        pk: &secp256k1::PublicKey,
+    ) -> Result<[u8; 32], String> {
+        let input = match self.inputs.get(index) {
+            Some(i) => i,
+            None => return Err(format!("no input at index {}", index)),
+        };
+        let mut prevouts = Vec::new();
+        let mut sequences = Vec::new();
+        for i in self.inputs.iter() {
+            prevouts.extend_from_slice(&i.txid_le);
+            prevouts.extend_from_slice(&i.index.to_le_bytes());
+            sequences.extend_from_slice(&i.sequence.to_le_bytes());
+        }
+        let mut preimage = TaprootTransaction::VERSION.to_le_bytes().to_vec();
+        preimage.extend_from_slice(&double_sha256(&prevouts));
+        preimage.extend_from_slice(&double_sha256(&sequences));
+        preimage.extend_from_slice(&input.txid_le);
+        preimage.extend_from_slice(&input.index.to_le_bytes());
+        // scriptCode: OP_DUP OP_HASH160 <hash160(pk)> OP_EQUALVERIFY OP_CHECKSIG
+        preimage.extend_from_slice(&[0x19, 0x76, 0xa9, 0x14]);
+        preimage.extend_from_slice(&compute_hash160(&pk.serialize().to_vec()));
+        preimage.extend_from_slice(&[0x88, 0xac]);
+        preimage.extend_from_slice(&value.to_le_bytes());
+        preimage.extend_from_slice(&input.sequence.to_le_bytes());
+        preimage.extend_from_slice(&double_sha256(&self.serialize_outputs()));
+        preimage.extend_from_slice(&self.lock_time.to_le_bytes());
+        preimage.extend_from_slice(&1u32.to_le_bytes()); // SIGHASH_ALL
+        Ok(double_sha256(&preimage))
+    }
+
+    ///
+    /// key_path_sighash() - BIP341 sighash (SIGHASH_DEFAULT) for a key path spend via
+    /// the input at index, given the outputs spent by all inputs
+    ///
+    pub fn key_path_sighash(
+        &self,
+        index: usize,
+        spent_outputs: &Vec<TxOutput>,
+    ) -> Result<[u8; 32], String> {
+        if index >= self.inputs.len() || spent_outputs.len() != self.inputs.len() {
+            return Err(String::from("spent outputs do not match the tx inputs"));
+        }
+        let mut prevouts = Vec::new();
+        let mut amounts = Vec::new();
+        let mut script_pubkeys = Vec::new();
+        let mut sequences = Vec::new();
+        for (i, spent) in self.inputs.iter().zip(spent_outputs.iter()) {
+            prevouts.extend_from_slice(&i.txid_le);
+            prevouts.extend_from_slice(&i.index.to_le_bytes());
+            amounts.extend_from_slice(&spent.value.to_le_bytes());
+            write_compact_size(&mut script_pubkeys, spent.script_pubkey.len());
+            script_pubkeys.extend_from_slice(&spent.script_pubkey);
+            sequences.extend_from_slice(&i.sequence.to_le_bytes());
+        }
+        let mut msg = vec![0x00, 0x00]; // epoch and SIGHASH_DEFAULT
+        msg.extend_from_slice(&TaprootTransaction::VERSION.to_le_bytes());
+        msg.extend_from_slice(&self.lock_time.to_le_bytes());
+        msg.extend_from_slice(&single_sha256(&prevouts));
+        msg.extend_from_slice(&single_sha256(&amounts));
+        msg.extend_from_slice(&single_sha256(&script_pubkeys));
+        msg.extend_from_slice(&single_sha256(&sequences));
+        msg.extend_from_slice(&single_sha256(&self.serialize_outputs()));
+        msg.push(0x00); // key path, no annex
+        msg.extend_from_slice(&(index as u32).to_le_bytes());
+        Ok(tagged_hash("TapSighash", &msg))
+    }
+}
+
+///
+/// form_escrow_transaction() - the customer's escrow tx for a Taproot channel: spends
+/// the customer's P2WPKH funding output into the escrow output of pk_c and pk_m, with
+/// the rest (minus tx_fee) going back to the change pk
+/// output: the signed escrow tx
+/// The escrow can only be spent by the mutual close for now (see the module docs)
+///
+#[cfg(feature = "taproot-escrow")]
+pub fn form_escrow_transaction(
+    txid_le: &[u8; 32],
+    index: u32,
+    cust_sk: &[u8; 32],
+    input_sats: i64,
+    output_sats: i64,
+    cust_pk: &secp256k1::PublicKey,
+    merch_pk: &secp256k1::PublicKey,
+    change_pk: &secp256k1::PublicKey,
+    tx_fee: i64,
+) -> Result<TaprootTransaction, String> {
+    let change_sats = input_sats - output_sats - tx_fee;
+    if output_sats <= 0 || tx_fee < 0 || change_sats < 0 {
+        return Err(format!(
+            "escrow output ({}) and fee ({}) do not fit in the input ({})",
+            output_sats, tx_fee, input_sats
+        ));
+    }
+    let escrow = escrow_output(cust_pk, merch_pk)?;
+    let mut outputs = vec![TxOutput {
+        value: output_sats,
+        script_pubkey: escrow.script_pubkey(),
+    }];
+    if change_sats > 0 {
+        outputs.push(TxOutput {
+            value: change_sats,
+            script_pubkey: p2wpkh_script_pubkey(change_pk),
+        });
+    }
+    let mut tx = TaprootTransaction {
+        inputs: vec![TxInput {
+            txid_le: txid_le.clone(),
+            index,
+            sequence: 0xffffffff,
+            witness: Vec::new(),
+        }],
+        outputs,
+        lock_time: 0,
+    };
+
+    // the funding output is a P2WPKH output of the customer's key
+    let secp = secp256k1::Secp256k1::signing_only();
+    let sk = handle_error_util!(secp256k1::SecretKey::from_slice(cust_sk));
+    let funding_pk = secp256k1::PublicKey::from_secret_key(&secp, &sk);
+    let sighash = tx.p2wpkh_sighash(0, input_sats, &funding_pk)?;
+    let msg = handle_error_util!(secp256k1::Message::from_slice(&sighash));
+    let mut sig = secp.sign(&msg, &sk).serialize_der().to_vec();
+    sig.push(0x01); // SIGHASH_ALL
+    tx.inputs[0].witness = vec![sig, funding_pk.serialize().to_vec()];
+    Ok(tx)
+}
+
+///
+/// MutualCloseTransaction - the mutual close tx of a Taproot channel, which spends the
+/// escrow via the key path. The outputs are BIP86 key path outputs of the close keys, so
+/// the tx looks like an ordinary single-key spend
+///
+pub struct MutualCloseTransaction {
+    pub tx: TaprootTransaction,
+    pub escrow: TxOutput,
+    pub key_agg_ctx: KeyAggContext,
+    // the signer's key and secret nonce from nonce_gen(), taken by partial_sign()
+    sec_nonce: Option<(secp256k1::PublicKey, MusigSecNonce)>,
+}
+
+impl MutualCloseTransaction {
+    pub fn new(
+        escrow_txid_le: &[u8; 32],
+        index: u32,
+        input_amount: i64,
+        cust_pk: &secp256k1::PublicKey,
+        merch_pk: &secp256k1::PublicKey,
+        cust_close_pk: &secp256k1::PublicKey,
+        merch_close_pk: &secp256k1::PublicKey,
+        cust_amount: i64,
+        merch_amount: i64,
+    ) -> Result<MutualCloseTransaction, String> {
+        if cust_amount < 0 || merch_amount < 0 || cust_amount + merch_amount > input_amount {
+            return Err(format!(
+                "mutual close amounts ({} + {}) exceed the escrow ({})",
+                cust_amount, merch_amount, input_amount
+            ));
+        }
+        let mut outputs = Vec::new();
+        for (pk, amount) in [(cust_close_pk, cust_amount), (merch_close_pk, merch_amount)].iter() {
+            if *amount > 0 {
+                outputs.push(TxOutput {
+                    value: *amount,
+                    script_pubkey: TaprootOutput::new(x_only(pk), Vec::new())?.script_pubkey(),
+                });
+            }
+        }
+        let tx = TaprootTransaction {
+            inputs: vec![TxInput {
+                txid_le: escrow_txid_le.clone(),
+                index,
+                sequence: 0xffffffff,
+                witness: Vec::new(),
+            }],
+            outputs,
+            lock_time: 0,
+        };
+        let escrow = TxOutput {
+            value: input_amount,
+            script_pubkey: escrow_output(cust_pk, merch_pk)?.script_pubkey(),
+        };
+        let key_agg_ctx = escrow_key_agg(cust_pk, merch_pk)?;
+        Ok(MutualCloseTransaction {
+            tx,
+            escrow,
+            key_agg_ctx,
+            sec_nonce: None,
+        })
+    }
+
+    pub fn sighash(&self) -> Result<[u8; 32], String> {
+        self.tx.key_path_sighash(0, &vec![self.escrow.clone()])
+    }
+
+    // the key of the signer whose secret nonce is waiting for partial_sign(), if any
+    pub fn signer(&self) -> Option<&secp256k1::PublicKey> {
+        self.sec_nonce.as_ref().map(|(pk, _)| pk)
+    }
+
+    // round 1: nonces of the signer with sk. The secret nonce stays in this tx until
+    // partial_sign(), the public nonce goes to the other party
+    pub fn nonce_gen<R: Rng>(&mut self, csprng: &mut R, sk: &[u8; 32]) -> Result<Vec<u8>, String> {
+        let secp = secp256k1::Secp256k1::signing_only();
+        let signer_sk = handle_error_util!(secp256k1::SecretKey::from_slice(sk));
+        let signer = secp256k1::PublicKey::from_secret_key(&secp, &signer_sk);
+        let (sec_nonce, pub_nonce) =
+            musig_nonce_gen(csprng, sk, &self.key_agg_ctx, &self.sighash()?)?;
+        self.sec_nonce = Some((signer, sec_nonce));
+        Ok(pub_nonce)
+    }
+
+    // round 2: partial signature of the signer with sk, given both public nonces.
+    // uses up the secret nonce, so a second call fails instead of reusing it
+    pub fn partial_sign(
+        &mut self,
+        sk: &[u8; 32],
+        cust_pub_nonce: &Vec<u8>,
+        merch_pub_nonce: &Vec<u8>,
+    ) -> Result<[u8; 32], String> {
+        let (signer, sec_nonce) = match self.sec_nonce.take() {
+            Some(n) => n,
+            None => {
+                return Err(String::from(
+                    "musig: no secret nonce, nonce_gen() must be run once per signature",
+                ))
+            }
+        };
+        let secp = secp256k1::Secp256k1::signing_only();
+        let signer_sk = handle_error_util!(secp256k1::SecretKey::from_slice(sk));
+        if secp256k1::PublicKey::from_secret_key(&secp, &signer_sk) != signer {
+            return Err(String::from(
+                "musig: secret nonce was not generated for this key",
+            ));
+        }
+        let pub_nonces = vec![cust_pub_nonce.clone(), merch_pub_nonce.clone()];
+        musig_partial_sign(
+            sec_nonce,
+            sk,
+            &self.key_agg_ctx,
+            &pub_nonces,
+            &self.sighash()?,
+        )
+    }
+
+    ///
+    /// finalize() - checks both partial signatures, aggregates them and adds the
+    /// signature to the tx
+    /// output: the signed mutual close tx
+    ///
+    pub fn finalize(
+        &mut self,
+        cust_pub_nonce: &Vec<u8>,
+        merch_pub_nonce: &Vec<u8>,
+        cust_partial_sig: &[u8; 32],
+        merch_partial_sig: &[u8; 32],
+    ) -> Result<Vec<u8>, String> {
+        let msg = self.sighash()?;
+        let pub_nonces = vec![cust_pub_nonce.clone(), merch_pub_nonce.clone()];
+        let signers = [
+            (
+                cust_partial_sig,
+                cust_pub_nonce,
+                &self.key_agg_ctx.pubkeys[0],
+                "customer",
+            ),
+            (
+                merch_partial_sig,
+                merch_pub_nonce,
+                &self.key_agg_ctx.pubkeys[1],
+                "merchant",
+            ),
+        ];
+        for (partial_sig, pub_nonce, pk, party) in signers.iter() {
+            if !musig_partial_verify(
+                partial_sig,
+                pub_nonce,
+                pk,
+                &self.key_agg_ctx,
+                &pub_nonces,
+                &msg,
+            ) {
+                return Err(format!("invalid partial signature from the {}", party));
+            }
+        }
+        let partial_sigs = vec![cust_partial_sig.clone(), merch_partial_sig.clone()];
+        let sig = musig_partial_sig_agg(&self.key_agg_ctx, &pub_nonces, &partial_sigs, &msg)?;
+        if !schnorr_verify(&x_only(&self.key_agg_ctx.agg_key), &msg, &sig) {
+            return Err(String::from("could not verify the aggregate signature"));
+        }
+        self.tx.inputs[0].witness = vec![sig.to_vec()];
+        Ok(self.tx.to_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pk_from_hex(s: &str) -> secp256k1::PublicKey {
+        secp256k1::PublicKey::from_slice(&hex::decode(s).unwrap()).unwrap()
+    }
+
+    fn xonly_from_hex(s: &str) -> [u8; 32] {
+        let mut x = [0u8; 32];
+        x.copy_from_slice(&hex::decode(s).unwrap());
+        x
+    }
+
+    #[test]
+    fn key_agg_matches_bip327_vector() {
+        let pubkeys = vec![
+            pk_from_hex("02f9308a019258c31049344f85f89d5229b531c845836f99b08601f113bce036f9"),
+            pk_from_hex("03dff1d77f2a671c5f36183726db2341be58feae1da2deced843240f7b502ba659"),
+            pk_from_hex("023590a94e768f8e1815c2f24b4d80a8e3149316c3518ce7b7ad338368d038ca66"),
+        ];
+        let agg_key = key_agg(&pubkeys).unwrap();
+        assert_eq!(
+            hex::encode(x_only(&agg_key)),
+            "90539eede565f5d054f32cc0c220126889ed1e5d193baf15aef344fe59d4610c"
+        );
+    }
+
+    #[test]
+    fn output_key_matches_bip86_and_bip341_vectors() {
+        // BIP86: key path only
+        let internal_key =
+            xonly_from_hex("cc8a4bc64d897bddc5fbc2f670f7a8ba0b386779106cf1223c6fc5d7cd6fc115");
+        let output = TaprootOutput::new(internal_key, Vec::new()).unwrap();
+        assert_eq!(
+            hex::encode(output.script_pubkey()),
+            "5120a60869f0dbcf1dc659c9cecbaf8050135ea9e8cdc487053f1dc6880949dc684c"
+        );
+
+        // BIP341: a single tapscript leaf
+        let internal_key =
+            xonly_from_hex("187791b6f712a8ea41c8ecdd0ee77fab3e85263b37e1ec18a3651926b3a6cf27");
+        let script =
+            hex::decode("20d85a959b0290bf19bb89ed43c916be835475d013da4b362117393e25a48229b8ac")
+                .unwrap();
+        assert_eq!(
+            hex::encode(tap_leaf_hash(&script)),
+            "5b75adecf53548f3ec6ad7d78383bf84cc57b55a3127c72b9a2481752dd88b21"
+        );
+        let output = TaprootOutput::new(internal_key, vec![script]).unwrap();
+        assert_eq!(
+            hex::encode(x_only(&output.output_key)),
+            "147c9c57132f6e7ecddba9800bb0c4449251c92a1e60371ee77557b6620f3ea3"
+        );
+    }
+
+    fn pk_from_scalar(k: u8) -> secp256k1::PublicKey {
+        let secp = secp256k1::Secp256k1::new();
+        let mut sk = [0u8; 32];
+        sk[31] = k;
+        let sk = secp256k1::SecretKey::from_slice(&sk).unwrap();
+        secp256k1::PublicKey::from_secret_key(&secp, &sk)
+    }
+
+    #[test]
+    fn channel_outputs_work() {
+        let cust_close_pk = pk_from_scalar(0x11);
+        let merch_disp_pk = pk_from_scalar(0x22);
+        let cust_pk = pk_from_scalar(0x33);
+        let merch_pk = pk_from_scalar(0x44);
+        let merch_close_pk = pk_from_scalar(0x55);
+        let rev_lock = [0xabu8; 32];
+        let self_delay = 1487;
+
+        assert_eq!(
+            hex::encode(delayed_key_script(&cust_close_pk, self_delay)),
+            "02cf05b27520defdea4cdb677750a420fee807eacf21eb9898ae79b9768766e4faa04a2d4a34ac"
+        );
+
+        let escrow = escrow_output(&cust_pk, &merch_pk).unwrap();
+        assert_eq!(
+            hex::encode(escrow.script_pubkey()),
+            "5120e9b87e7b8c945cb8a2f3f7e778e2cdbebd9d47a1a34a8629b7b150fd44646420"
+        );
+        assert!(escrow.control_block(0).is_err());
+
+        let merch_close =
+            merch_close_output(&cust_pk, &merch_pk, &merch_close_pk, self_delay).unwrap();
+        assert_eq!(
+            hex::encode(merch_close.script_pubkey()),
+            "5120bded7332088161c28fc4f8ae53bc0d3e2b01801b842f6964fd45abfce6352161"
+        );
+        // same key path as the escrow, but committing to the merchant's timelocked leaf
+        assert_eq!(merch_close.internal_key, escrow.internal_key);
+
+        let cust_close =
+            cust_close_output(&rev_lock, &merch_disp_pk, &cust_close_pk, self_delay).unwrap();
+        assert_eq!(
+            hex::encode(cust_close.script_pubkey()),
+            "512037e249f08ff15eafcd4f241e0be0d77a014c7318576f3ff854a0dd3dabf4c8ce"
+        );
+        // merchant dispute path reveals the customer's delayed leaf as sibling
+        assert_eq!(
+            hex::encode(cust_close.control_block(0).unwrap()),
+            "c050929b74c1a04954b78b4b6035e97a5e078a5a0f28ec96d547bfee9ace803ac0\
+             f6e625c722d13d819ffdbf25dd83909d83ffd576aaca3d54ac8630cb112c53ba"
+        );
+    }
+
+    #[test]
+    fn schnorr_matches_bip340_vectors() {
+        let vectors = [
+            (
+                "0000000000000000000000000000000000000000000000000000000000000003",
+                "f9308a019258c31049344f85f89d5229b531c845836f99b08601f113bce036f9",
+                "0000000000000000000000000000000000000000000000000000000000000000",
+                "0000000000000000000000000000000000000000000000000000000000000000",
+                "e907831f80848d1069a5371b402410364bdf1c5f8307b0084c55f1ce2dca8215\
+                 25f66a4a85ea8b71e482a74f382d2ce5ebeee8fdb2172f477df4900d310536c0",
+            ),
+            (
+                "b7e151628aed2a6abf7158809cf4f3c762e7160f38b4da56a784d9045190cfef",
+                "dff1d77f2a671c5f36183726db2341be58feae1da2deced843240f7b502ba659",
+                "0000000000000000000000000000000000000000000000000000000000000001",
+                "243f6a8885a308d313198a2e03707344a4093822299f31d0082efa98ec4e6c89",
+                "6896bd60eeae296db48a229ff71dfe071bde413e6d43f917dc8dcf8c78de3341\
+                 8906d11ac976abccb20b091292bff4ea897efcb639ea871cfa95f6de339e4b0a",
+            ),
+        ];
+        for (sk, pk, aux, msg, sig) in vectors.iter() {
+            let (sk, pk, aux, msg) = (
+                xonly_from_hex(sk),
+                xonly_from_hex(pk),
+                xonly_from_hex(aux),
+                xonly_from_hex(msg),
+            );
+            let signature = schnorr_sign(&sk, &msg, &aux).unwrap();
+            assert_eq!(hex::encode(&signature[..]), *sig);
+            assert!(schnorr_verify(&pk, &msg, &signature));
+
+            let mut bad_sig = signature;
+            bad_sig[63] ^= 0x01;
+            assert!(!schnorr_verify(&pk, &msg, &bad_sig));
+        }
+    }
+
+    fn sk_from_scalar(k: u8) -> [u8; 32] {
+        let mut sk = [0u8; 32];
+        sk[31] = k;
+        sk
+    }
+
+    #[test]
+    #[cfg(feature = "taproot-escrow")]
+    fn escrow_tx_works() {
+        let funding_sk = sk_from_scalar(0x66);
+        let cust_pk = pk_from_scalar(0x33);
+        let merch_pk = pk_from_scalar(0x44);
+        let change_pk = pk_from_scalar(0x77);
+
+        // customer forms and signs the escrow tx
+        let funding_txid = [0x42u8; 32];
+        let escrow_tx = form_escrow_transaction(
+            &funding_txid,
+            1,
+            &funding_sk,
+            5_000_000,
+            1_000_000,
+            &cust_pk,
+            &merch_pk,
+            &change_pk,
+            1000,
+        )
+        .unwrap();
+        let escrow = escrow_output(&cust_pk, &merch_pk).unwrap();
+        assert_eq!(escrow_tx.outputs[0].script_pubkey, escrow.script_pubkey());
+        assert_eq!(escrow_tx.outputs[1].value, 3_999_000);
+        let witness = &escrow_tx.inputs[0].witness;
+        assert_eq!(witness[1], pk_from_scalar(0x66).serialize().to_vec());
+        assert!(form_escrow_transaction(
+            &funding_txid,
+            1,
+            &funding_sk,
+            1000,
+            1000,
+            &cust_pk,
+            &merch_pk,
+            &change_pk,
+            1000,
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn mutual_close_works() {
+        let rng = &mut rand::thread_rng();
+        let (cust_sk, merch_sk) = (sk_from_scalar(0x33), sk_from_scalar(0x44));
+        let cust_pk = pk_from_scalar(0x33);
+        let merch_pk = pk_from_scalar(0x44);
+        let cust_close_pk = pk_from_scalar(0x11);
+        let merch_close_pk = pk_from_scalar(0x55);
+
+        // key path of the escrow output is the tweaked MuSig2 key of pk_c and pk_m
+        let escrow = escrow_output(&cust_pk, &merch_pk).unwrap();
+        let key_agg_ctx = escrow_key_agg(&cust_pk, &merch_pk).unwrap();
+        assert_eq!(x_only(&key_agg_ctx.agg_key), x_only(&escrow.output_key));
+
+        // both parties build the same mutual close tx and run the two signing rounds
+        let escrow_txid = [0x42u8; 32];
+        let new_close_tx = || {
+            MutualCloseTransaction::new(
+                &escrow_txid,
+                0,
+                1_000_000,
+                &cust_pk,
+                &merch_pk,
+                &cust_close_pk,
+                &merch_close_pk,
+                700_000,
+                299_000,
+            )
+            .unwrap()
+        };
+        let mut cust_close_tx = new_close_tx();
+        let mut merch_close_tx = new_close_tx();
+        let cust_pub_nonce = cust_close_tx.nonce_gen(rng, &cust_sk).unwrap();
+        let merch_pub_nonce = merch_close_tx.nonce_gen(rng, &merch_sk).unwrap();
+        assert_eq!(merch_close_tx.signer(), Some(&merch_pk));
+
+        // a partial signature with another key than the nonce's is refused
+        let mut other_close_tx = new_close_tx();
+        other_close_tx.nonce_gen(rng, &merch_sk).unwrap();
+        assert!(other_close_tx
+            .partial_sign(&cust_sk, &cust_pub_nonce, &merch_pub_nonce)
+            .is_err());
+
+        let cust_partial_sig = cust_close_tx
+            .partial_sign(&cust_sk, &cust_pub_nonce, &merch_pub_nonce)
+            .unwrap();
+        let merch_partial_sig = merch_close_tx
+            .partial_sign(&merch_sk, &cust_pub_nonce, &merch_pub_nonce)
+            .unwrap();
+        // the secret nonce is used up by the first partial signature
+        assert!(cust_close_tx.signer().is_none());
+        assert!(cust_close_tx
+            .partial_sign(&cust_sk, &cust_pub_nonce, &merch_pub_nonce)
+            .is_err());
+
+        // a wrong partial signature is caught before aggregation
+        assert!(merch_close_tx
+            .finalize(
+                &cust_pub_nonce,
+                &merch_pub_nonce,
+                &merch_partial_sig,
+                &merch_partial_sig
+            )
+            .is_err());
+
+        let signed_tx = merch_close_tx
+            .finalize(
+                &cust_pub_nonce,
+                &merch_pub_nonce,
+                &cust_partial_sig,
+                &merch_partial_sig,
+            )
+            .unwrap();
+        let sig = &merch_close_tx.tx.inputs[0].witness[0];
+        let mut signature = [0u8; 64];
+        signature.copy_from_slice(sig);
+        let sighash = merch_close_tx.sighash().unwrap();
+        assert!(schnorr_verify(
+            &x_only(&escrow.output_key),
+            &sighash,
+            &signature
+        ));
+        assert_eq!(merch_close_tx.tx.outputs.len(), 2);
+        assert_eq!(
+            merch_close_tx.tx.outputs[0].script_pubkey,
+            TaprootOutput::new(x_only(&cust_close_pk), Vec::new())
+                .unwrap()
+                .script_pubkey()
+        );
+        // version, segwit marker, 1 input, 2 outputs, 1 witness item of 64 bytes
+        assert_eq!(&signed_tx[..6], &[0x02, 0x00, 0x00, 0x00, 0x00, 0x01]);
+        assert_eq!(
+            signed_tx.len(),
+            4 + 2 + 1 + 41 + 1 + 2 * 43 + 1 + 1 + 64 + 4
+        );
+    }
+}
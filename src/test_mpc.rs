@@ -6,15 +6,18 @@ mod tests {
 
     use bindings::ConnType_NETIO;
     use channels_mpc;
-    use channels_util::{ChannelStatus, PaymentStatus, ProtocolStatus};
+    use channels_util::{ChannelStatus, MpcSecurity, PaymentStatus, ProtocolStatus, SessionPolicy};
     use database::{
-        get_file_from_db, store_file_in_db, HashMapDatabase, MaskedTxMPCInputs, RedisDatabase,
-        StateDatabase,
+        get_file_from_db, store_file_in_db, HashMapDatabase, MaskedMPCInputs, MaskedTxMPCInputs,
+        RedisDatabase, StateDatabase,
     };
     use mpc;
+    use mpcwrapper::{get_circuit, linked_mpc_security};
     use std::process::Command;
-    use std::{env, ptr};
-    use zkchan_tx::fixed_size_array::FixedSizeArray32;
+    use std::sync::mpsc;
+    use std::{env, ptr, thread};
+    use util::{get_unix_time, sha2_hash_to_slice};
+    use zkchan_tx::fixed_size_array::{FixedSizeArray16, FixedSizeArray32};
     use zkchan_tx::Testnet;
     use FundingTxInfo;
 
@@ -62,6 +65,7 @@ mod tests {
             b0_merch,
             &tx_fee_info,
             "Alice",
+            channel_state.get_network(),
         );
 
         // form all of the escrow and merch-close-tx transactions
@@ -128,6 +132,792 @@ mod tests {
         //TODO: test unlinking with a 0-payment of pay protocol
     }
 
+    #[test]
+    fn test_establish_mpc_channel_network_mismatch() {
+        let mut rng = XorShiftRng::seed_from_u64(0x5dbe62598d313d86);
+        let mut db = HashMapDatabase::new("", "".to_string()).unwrap();
+
+        let min_threshold = 546;
+        let val_cpfp = 1000;
+        let mut channel_state = mpc::ChannelMPCState::new(
+            String::from("Channel A -> B"),
+            1487,
+            min_threshold,
+            min_threshold,
+            val_cpfp,
+            false,
+        );
+        channel_state.set_network(mpc::BitcoinNetworkType::Regtest);
+        let mut merch_state =
+            mpc::init_merchant(&mut rng, "".to_string(), &mut channel_state, "Bob");
+
+        let b0_cust = 10000;
+        let b0_merch = 10000;
+        let tx_fee_info = mpc::TransactionFeeInfo {
+            bal_min_cust: min_threshold,
+            bal_min_merch: min_threshold,
+            val_cpfp: val_cpfp,
+            fee_cc: 1000,
+            fee_mc: 1000,
+            min_fee: 0,
+            max_fee: 10000,
+        };
+
+        // customer expects a mainnet channel
+        let (mut channel_token, mut cust_state) = mpc::init_customer(
+            &mut rng,
+            &merch_state.pk_m,
+            b0_cust,
+            b0_merch,
+            &tx_fee_info,
+            "Alice",
+            mpc::BitcoinNetworkType::Mainnet,
+        );
+        assert!(channel_state.validate_network(&channel_token).is_err());
+
+        let funding_tx_info = generate_funding_tx(&mut rng, b0_cust, b0_merch);
+        cust_state
+            .set_initial_cust_state(&mut channel_token, &funding_tx_info, &tx_fee_info)
+            .unwrap();
+        let (init_cust_state, init_hash) = mpc::get_initial_state(&cust_state).unwrap();
+
+        let res = mpc::validate_channel_params(
+            &mut db as &mut dyn StateDatabase,
+            &channel_token,
+            &init_cust_state,
+            init_hash,
+            &mut merch_state,
+        );
+        assert!(res.is_err());
+    }
+
+    // fee parameters shared by the tests that do not exercise fees
+    fn test_tx_fee_info() -> mpc::TransactionFeeInfo {
+        mpc::TransactionFeeInfo {
+            bal_min_cust: 546,
+            bal_min_merch: 546,
+            val_cpfp: 1000,
+            fee_cc: 1000,
+            fee_mc: 1000,
+            min_fee: 0,
+            max_fee: 10000,
+        }
+    }
+
+    fn activated_channel_helper<R: Rng>(
+        rng: &mut R,
+        db: &mut HashMapDatabase,
+        tx_fee_info: &mpc::TransactionFeeInfo,
+    ) -> (
+        mpc::ChannelMPCState,
+        mpc::ChannelMPCToken,
+        mpc::CustomerMPCState,
+        mpc::MerchantMPCState,
+    ) {
+        let b0_cust = 10000;
+        let b0_merch = 10000;
+        let (channel_state, channel_token, cust_state, mut merch_state) =
+            setup_new_zkchannel_helper(rng, b0_cust, b0_merch, tx_fee_info);
+        let (channel_token, cust_state) = activate_customer_channel_helper(
+            rng,
+            db,
+            &channel_state,
+            channel_token,
+            cust_state,
+            &mut merch_state,
+            tx_fee_info,
+        );
+
+        (channel_state, channel_token, cust_state, merch_state)
+    }
+
+    // opens and activates the channel of an initialized customer with the given merchant
+    fn activate_customer_channel_helper<R: Rng>(
+        rng: &mut R,
+        db: &mut dyn StateDatabase,
+        channel_state: &mpc::ChannelMPCState,
+        mut channel_token: mpc::ChannelMPCToken,
+        mut cust_state: mpc::CustomerMPCState,
+        merch_state: &mut mpc::MerchantMPCState,
+        tx_fee_info: &mpc::TransactionFeeInfo,
+    ) -> (mpc::ChannelMPCToken, mpc::CustomerMPCState) {
+        let funding_tx_info =
+            generate_funding_tx(rng, cust_state.cust_balance, cust_state.merch_balance);
+        let pubkeys = cust_state.get_pubkeys(channel_state, &channel_token);
+        let (escrow_sig, merch_sig) = merch_state
+            .sign_initial_closing_transaction::<Testnet>(
+                funding_tx_info.clone(),
+                pubkeys.rev_lock.0,
+                pubkeys.cust_pk,
+                pubkeys.cust_close_pk,
+                channel_state.get_self_delay_be(),
+                tx_fee_info.fee_cc,
+                tx_fee_info.fee_mc,
+                channel_state.get_val_cpfp(),
+            )
+            .unwrap();
+        cust_state
+            .set_initial_cust_state(&mut channel_token, &funding_tx_info, tx_fee_info)
+            .unwrap();
+        cust_state
+            .sign_initial_closing_transaction::<Testnet>(
+                channel_state,
+                &channel_token,
+                &escrow_sig,
+                &merch_sig,
+            )
+            .unwrap();
+
+        let (init_cust_state, init_hash) = mpc::get_initial_state(&cust_state).unwrap();
+        mpc::validate_channel_params(
+            db as &mut dyn StateDatabase,
+            &channel_token,
+            &init_cust_state,
+            init_hash,
+            merch_state,
+        )
+        .unwrap();
+        mpc::customer_mark_open_channel(&mut cust_state).unwrap();
+        mpc::merchant_mark_open_channel(channel_token.escrow_txid.0.clone(), merch_state).unwrap();
+
+        let s0 = mpc::activate_customer(rng, &mut cust_state).unwrap();
+        let pay_token = mpc::activate_merchant(
+            db as &mut dyn StateDatabase,
+            channel_token.clone(),
+            &s0,
+            merch_state,
+        )
+        .unwrap();
+        mpc::activate_customer_finalize(pay_token, &mut cust_state).unwrap();
+
+        (channel_token, cust_state)
+    }
+
+    #[test]
+    fn test_customer_payment_session_rollback() {
+        let mut rng = XorShiftRng::seed_from_u64(0x8a3c1e5d2b7f4906);
+        let mut db = HashMapDatabase::new("", "".to_string()).unwrap();
+        let tx_fee_info = test_tx_fee_info();
+        let (channel_state, channel_token, mut cust_state, mut merch_state) =
+            activated_channel_helper(&mut rng, &mut db, &tx_fee_info);
+        let orig_cust_state = cust_state.clone();
+        let nonce = cust_state.get_current_state().get_nonce();
+
+        // failure right after the prepare phase (e.g. the connection drops before the mpc)
+        let session = mpc::pay_begin_customer(&cust_state);
+        let (_, _, rev_lock_com, session_id) =
+            mpc::pay_prepare_customer(&mut rng, &channel_state, 1000, &mut cust_state).unwrap();
+        mpc::pay_prepare_merchant(
+            &mut rng,
+            &mut db as &mut dyn StateDatabase,
+            &channel_state,
+            session_id,
+            nonce,
+            rev_lock_com,
+            1000,
+            None,
+            &mut merch_state,
+        )
+        .unwrap();
+        assert_ne!(cust_state, orig_cust_state);
+        let (_, new_rev_secret) = cust_state.get_rev_pair();
+        assert!(mpc::pay_commit_customer(session.clone(), &cust_state).is_err());
+        assert!(mpc::pay_abort_customer(session, &mut cust_state).is_ok());
+        assert_eq!(cust_state, orig_cust_state);
+        assert_ne!(cust_state.get_rev_pair().1, new_rev_secret);
+
+        // failure during the mpc: the balance after the payment does not cover the close fees,
+        // but the pay token mask commitment was already stored
+        let session = mpc::pay_begin_customer(&cust_state);
+        let old_state = cust_state.get_current_state();
+        let (new_state, _rev_state, rev_lock_com, _session_id) =
+            mpc::pay_prepare_customer(&mut rng, &channel_state, 8000, &mut cust_state).unwrap();
+        let res = mpc::pay_update_customer(
+            &channel_state,
+            &channel_token,
+            old_state,
+            new_state,
+            [0x11; 32],
+            rev_lock_com,
+            8000,
+            &mut cust_state,
+            ptr::null_mut(),
+            None,
+            None,
+        );
+        assert!(res.is_err());
+        assert!(mpc::pay_abort_customer(session, &mut cust_state).is_ok());
+        assert!(cust_state.net_config.is_none());
+        assert_eq!(cust_state, orig_cust_state);
+
+        // failure when unmasking the closing signatures
+        let session = mpc::pay_begin_customer(&cust_state);
+        mpc::pay_prepare_customer(&mut rng, &channel_state, 1000, &mut cust_state).unwrap();
+        let masks = MaskedTxMPCInputs::new([0x22; 32], [0x33; 32], [0x44; 32], [0x55; 32]);
+        let res =
+            mpc::pay_unmask_sigs_customer(&channel_state, &channel_token, masks, &mut cust_state);
+        assert!(res.is_err());
+        assert!(mpc::pay_abort_customer(session, &mut cust_state).is_ok());
+        assert_eq!(cust_state, orig_cust_state);
+
+        // failure when unmasking the pay token
+        let session = mpc::pay_begin_customer(&cust_state);
+        mpc::pay_prepare_customer(&mut rng, &channel_state, 1000, &mut cust_state).unwrap();
+        let is_ok =
+            mpc::pay_unmask_pay_token_customer([0x66; 32], [0x77; 16], &mut cust_state).unwrap();
+        assert!(!is_ok);
+        assert!(mpc::pay_commit_customer(session.clone(), &cust_state).is_err());
+        assert!(mpc::pay_abort_customer(session, &mut cust_state).is_ok());
+        assert_eq!(cust_state, orig_cust_state);
+
+        // the channel is still usable and the initial closing tx matches the restored state
+        assert!(
+            mpc::force_customer_close(&channel_state, &channel_token, true, &mut cust_state)
+                .is_ok()
+        );
+
+        // the merchant accepts the retry from the old state: same nonce, new session id
+        let mut cust_state = orig_cust_state.clone();
+        let (_, _, rev_lock_com, retry_session_id) =
+            mpc::pay_prepare_customer(&mut rng, &channel_state, 1000, &mut cust_state).unwrap();
+        assert_ne!(retry_session_id, session_id);
+        assert!(mpc::pay_prepare_merchant(
+            &mut rng,
+            &mut db as &mut dyn StateDatabase,
+            &channel_state,
+            retry_session_id,
+            nonce,
+            rev_lock_com,
+            1000,
+            None,
+            &mut merch_state,
+        )
+        .is_ok());
+        // the aborted session was superseded and can no longer run the mpc
+        assert_eq!(
+            mpc::pay_session_status_merchant(&mut db as &mut dyn StateDatabase, session_id)
+                .unwrap(),
+            PaymentStatus::Error
+        );
+        assert_eq!(
+            mpc::pay_session_status_merchant(&mut db as &mut dyn StateDatabase, retry_session_id)
+                .unwrap(),
+            PaymentStatus::Prepare
+        );
+    }
+
+    #[test]
+    fn test_resume_pay_session_after_revocation() {
+        let mut rng = XorShiftRng::seed_from_u64(0x2e94b7a1c05d3f68);
+        let mut db = HashMapDatabase::new("", "".to_string()).unwrap();
+        let tx_fee_info = test_tx_fee_info();
+        let (channel_state, _channel_token, mut cust_state, mut merch_state) =
+            activated_channel_helper(&mut rng, &mut db, &tx_fee_info);
+        let amount = 1000;
+
+        let mut session = mpc::pay_begin_customer(&cust_state);
+        let old_nonce = cust_state.get_current_state().get_nonce();
+        let (_new_state, rev_state, rev_lock_com, session_id) =
+            mpc::pay_prepare_customer(&mut rng, &channel_state, amount, &mut cust_state).unwrap();
+        session.set_prepare_output(session_id, rev_state.clone());
+        assert_eq!(
+            mpc::pay_resume_customer(&session, &cust_state),
+            PaymentStatus::Prepare
+        );
+
+        mpc::pay_prepare_merchant(
+            &mut rng,
+            &mut db as &mut dyn StateDatabase,
+            &channel_state,
+            session_id,
+            old_nonce,
+            rev_lock_com,
+            amount,
+            None,
+            &mut merch_state,
+        )
+        .unwrap();
+        let status =
+            mpc::pay_session_status_merchant(&mut db as &mut dyn StateDatabase, session_id);
+        assert_eq!(status.unwrap(), PaymentStatus::Prepare);
+
+        // stand in for the merchant side of the mpc
+        let session_id_hex = hex::encode(session_id);
+        let (pt_mask, pt_mask_r) = db.get_mask_map_from_nonce(&hex::encode(old_nonce)).unwrap();
+        let masked_inputs = MaskedMPCInputs {
+            pt_mask: FixedSizeArray32(pt_mask),
+            pt_mask_r: FixedSizeArray16(pt_mask_r),
+            escrow_mask: FixedSizeArray32([0x01; 32]),
+            merch_mask: FixedSizeArray32([0x02; 32]),
+            r_escrow_sig: FixedSizeArray32([0x03; 32]),
+            r_merch_sig: FixedSizeArray32([0x04; 32]),
+            verify_success: FixedSizeArray16([0x05; 16]),
+        };
+        db.update_masked_mpc_inputs(&session_id_hex, masked_inputs);
+        let mut session_state = db.load_session_state(&session_id_hex).unwrap();
+        session_state.status = PaymentStatus::Update;
+        db.update_session_state(&session_id_hex, &session_state);
+        session.set_mpc_result(hex::encode([0x05; 16]));
+
+        // the tx masks can be requested again after a disconnect
+        for _ in 0..2 {
+            let masks = mpc::pay_confirm_mpc_result(
+                &mut db as &mut dyn StateDatabase,
+                session.get_session_id().unwrap(),
+                session.get_mpc_result().unwrap(),
+                &mut merch_state,
+            )
+            .unwrap();
+            assert_eq!(masks, masked_inputs.get_tx_masks());
+        }
+
+        let first = mpc::pay_validate_rev_lock_merchant(
+            &mut db as &mut dyn StateDatabase,
+            session_id,
+            rev_state,
+            &mut merch_state,
+        )
+        .unwrap();
+        assert_eq!(first, (pt_mask, pt_mask_r));
+        let status =
+            mpc::pay_session_status_merchant(&mut db as &mut dyn StateDatabase, session_id);
+        assert_eq!(status.unwrap(), PaymentStatus::Revoked);
+
+        // the connection dropped before the customer got the pay token mask: resend the revoked state
+        let resumed = mpc::pay_validate_rev_lock_merchant(
+            &mut db as &mut dyn StateDatabase,
+            session.get_session_id().unwrap(),
+            session.get_revoked_state().unwrap(),
+            &mut merch_state,
+        )
+        .unwrap();
+        assert_eq!(resumed, first);
+
+        // but the revoked state cannot be used to obtain a pay token mask in another session
+        let mut other_session_state = session_state.clone();
+        other_session_state.status = PaymentStatus::Update;
+        let other_session_id_hex = hex::encode([0x0au8; 16]);
+        db.save_new_session_state(&other_session_id_hex, &other_session_state);
+        db.update_masked_mpc_inputs(&other_session_id_hex, masked_inputs);
+        let res = mpc::pay_validate_rev_lock_merchant(
+            &mut db as &mut dyn StateDatabase,
+            [0x0au8; 16],
+            session.get_revoked_state().unwrap(),
+            &mut merch_state,
+        );
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn test_merchant_session_policy() {
+        let mut rng = XorShiftRng::seed_from_u64(0x5c17e0a98b3d4f26);
+        let mut db = HashMapDatabase::new("", "".to_string()).unwrap();
+        let tx_fee_info = test_tx_fee_info();
+        let (channel_state, _channel_token, _cust_state, mut merch_state) =
+            activated_channel_helper(&mut rng, &mut db, &tx_fee_info);
+        merch_state.set_session_policy(SessionPolicy {
+            max_concurrent_sessions: 2,
+            max_attempts_per_nonce: 2,
+            session_timeout: 600,
+        });
+
+        let rev_lock_com = [0x01u8; 32];
+        let mut prepare = |db: &mut HashMapDatabase, session_id: [u8; 16], nonce: [u8; 16]| {
+            mpc::pay_prepare_merchant(
+                &mut rng,
+                db as &mut dyn StateDatabase,
+                &channel_state,
+                session_id,
+                nonce,
+                rev_lock_com,
+                1000,
+                None,
+                &mut merch_state,
+            )
+        };
+
+        let start = get_unix_time();
+        assert!(prepare(&mut db, [0x01; 16], [0xa1; 16]).is_ok());
+        let session_state = db.load_session_state(&hex::encode([0x01u8; 16])).unwrap();
+        assert!(session_state.expiry >= start + 600);
+
+        // the nonce is in use by a session that already ran the mpc
+        let mut updated = session_state.clone();
+        updated.status = PaymentStatus::Update;
+        db.update_session_state(&hex::encode([0x01u8; 16]), &updated);
+        assert!(prepare(&mut db, [0x02; 16], [0xa1; 16]).is_err());
+
+        // too many active sessions
+        assert!(prepare(&mut db, [0x03; 16], [0xa2; 16]).is_ok());
+        assert!(prepare(&mut db, [0x04; 16], [0xa3; 16]).is_err());
+
+        // a session that failed after the mpc is cleared, but its nonce is never freed
+        let mut failed = updated.clone();
+        failed.status = PaymentStatus::Error;
+        db.update_session_state(&hex::encode([0x01u8; 16]), &failed);
+        assert!(prepare(&mut db, [0x05; 16], [0xa1; 16]).is_err());
+        assert!(!db
+            .session_state_map
+            .contains_key(&hex::encode([0x01u8; 16])));
+        assert!(db
+            .nonce_session_map
+            .contains_key(&hex::encode([0xa1u8; 16])));
+
+        // a retry supersedes a session on the same nonce that has not run the mpc yet
+        assert!(prepare(&mut db, [0x07; 16], [0xa2; 16]).is_ok());
+        let superseded = db.load_session_state(&hex::encode([0x03u8; 16])).unwrap();
+        assert_eq!(superseded.status, PaymentStatus::Error);
+
+        // but only a limited number of times, and a retry that is turned away leaves the
+        // session on the nonce alone
+        assert!(prepare(&mut db, [0x08; 16], [0xa2; 16]).is_err());
+        let in_flight = db.load_session_state(&hex::encode([0x07u8; 16])).unwrap();
+        assert_eq!(in_flight.status, PaymentStatus::Prepare);
+
+        // requests turned away by the checks do not count as attempts
+        let spent_nonce = [0xa4u8; 16];
+        db.update_spent_map(&hex::encode(spent_nonce), &hex::encode([0x02u8; 32]))
+            .unwrap();
+        for i in 0..3 {
+            assert!(prepare(&mut db, [0x10 + i; 16], spent_nonce).is_err());
+        }
+        assert!(!db
+            .nonce_attempts_map
+            .contains_key(&hex::encode(spent_nonce)));
+
+        // expired sessions are cleared and no longer count towards the limit
+        let later = get_unix_time() + 600;
+        assert_eq!(db.get_active_session_count(later).unwrap(), 0);
+        assert_eq!(db.clear_stale_sessions(later).unwrap(), 1);
+        assert!(db.session_state_map.is_empty());
+        // only the nonce that got past Prepare stays taken
+        assert_eq!(db.nonce_session_map.len(), 1);
+    }
+
+    #[test]
+    fn test_confirm_mpc_result() {
+        let mut rng = XorShiftRng::seed_from_u64(0x6c2d9e0b71f4a853);
+        let mut db = HashMapDatabase::new("", "".to_string()).unwrap();
+        let tx_fee_info = test_tx_fee_info();
+        let (channel_state, _channel_token, mut cust_state, mut merch_state) =
+            activated_channel_helper(&mut rng, &mut db, &tx_fee_info);
+        let nonce = cust_state.get_current_state().get_nonce();
+        let (_, _rev_state, rev_lock_com, session_id) =
+            mpc::pay_prepare_customer(&mut rng, &channel_state, 1000, &mut cust_state).unwrap();
+        mpc::pay_prepare_merchant(
+            &mut rng,
+            &mut db as &mut dyn StateDatabase,
+            &channel_state,
+            session_id,
+            nonce,
+            rev_lock_com,
+            1000,
+            None,
+            &mut merch_state,
+        )
+        .unwrap();
+        let session_id_hex = hex::encode(session_id);
+        let mut session_state = db.load_session_state(&session_id_hex).unwrap();
+
+        // a failed session does not release its tx masks, even for the right verify_success
+        let verify_success = [0x05u8; 16];
+        db.update_masked_mpc_inputs(
+            &session_id_hex,
+            MaskedMPCInputs {
+                pt_mask: FixedSizeArray32([0x00; 32]),
+                pt_mask_r: FixedSizeArray16([0x00; 16]),
+                escrow_mask: FixedSizeArray32([0x01; 32]),
+                merch_mask: FixedSizeArray32([0x02; 32]),
+                r_escrow_sig: FixedSizeArray32([0x03; 32]),
+                r_merch_sig: FixedSizeArray32([0x04; 32]),
+                verify_success: FixedSizeArray16(verify_success),
+            },
+        );
+        session_state.status = PaymentStatus::Error;
+        db.save_new_session_state(&session_id_hex, &session_state);
+        assert!(mpc::pay_confirm_mpc_result(
+            &mut db as &mut dyn StateDatabase,
+            session_id,
+            hex::encode(verify_success),
+            &mut merch_state
+        )
+        .is_err());
+
+        // once the masks are released, a wrong mpc result no longer fails the session
+        session_state.status = PaymentStatus::Update;
+        db.update_session_state(&session_id_hex, &session_state);
+        assert!(mpc::pay_confirm_mpc_result(
+            &mut db as &mut dyn StateDatabase,
+            session_id,
+            hex::encode(verify_success),
+            &mut merch_state
+        )
+        .is_ok());
+        assert!(mpc::pay_confirm_mpc_result(
+            &mut db as &mut dyn StateDatabase,
+            session_id,
+            hex::encode([0u8; 16]),
+            &mut merch_state
+        )
+        .is_err());
+        let session_state = db.load_session_state(&session_id_hex).unwrap();
+        assert_eq!(session_state.status, PaymentStatus::Update);
+        assert!(session_state.masks_released);
+    }
+
+    #[test]
+    fn test_mpc_security_must_match_backend() {
+        let mut rng = XorShiftRng::seed_from_u64(0x3f62c8e1a7d04b95);
+        let mut db = HashMapDatabase::new("", "".to_string()).unwrap();
+        let tx_fee_info = test_tx_fee_info();
+        let (mut channel_state, channel_token, mut cust_state, _merch_state) =
+            activated_channel_helper(&mut rng, &mut db, &tx_fee_info);
+        let linked = linked_mpc_security();
+        assert!(channel_state.set_mpc_security(linked).is_ok());
+        assert!(channel_state.validate_mpc_security().is_ok());
+
+        let other = match linked {
+            MpcSecurity::SemiHonest => MpcSecurity::Malicious,
+            MpcSecurity::Malicious => MpcSecurity::SemiHonest,
+        };
+        assert!(channel_state.set_mpc_security(other).is_err());
+        assert_eq!(channel_state.get_mpc_security(), linked);
+
+        // a channel state from a merchant running the other backend
+        let ser_channel_state = serde_json::to_string(&channel_state).unwrap().replace(
+            &format!("\"mpc_security\":\"{}\"", linked),
+            &format!("\"mpc_security\":\"{}\"", other),
+        );
+        let channel_state: mpc::ChannelMPCState = serde_json::from_str(&ser_channel_state).unwrap();
+        assert!(channel_state.validate_mpc_security().is_err());
+
+        // the payment is refused before any mpc connection is made
+        let old_state = cust_state.get_current_state();
+        let (new_state, _rev_state, rev_lock_com, _session_id) =
+            mpc::pay_prepare_customer(&mut rng, &channel_state, 1000, &mut cust_state).unwrap();
+        let res = mpc::pay_update_customer(
+            &channel_state,
+            &channel_token,
+            old_state,
+            new_state,
+            [0x11; 32],
+            rev_lock_com,
+            1000,
+            &mut cust_state,
+            ptr::null_mut(),
+            None,
+            None,
+        );
+        assert!(res.unwrap_err().contains("MPC"));
+    }
+
+    #[test]
+    fn test_circuit_hash_must_match() {
+        let mut rng = XorShiftRng::seed_from_u64(0x7b0e4d92c61a35f8);
+        let mut db = HashMapDatabase::new("", "".to_string()).unwrap();
+        let tx_fee_info = test_tx_fee_info();
+        let (channel_state, channel_token, mut cust_state, _merch_state) =
+            activated_channel_helper(&mut rng, &mut db, &tx_fee_info);
+        assert!(channel_state.validate_circuit_hash().is_ok());
+
+        // a channel state from a merchant with a different circuit
+        let mut ser_channel_state = serde_json::to_value(&channel_state).unwrap();
+        ser_channel_state["circuit_hash"] = serde_json::Value::String(hex::encode([0x42u8; 32]));
+        let channel_state: mpc::ChannelMPCState =
+            serde_json::from_value(ser_channel_state).unwrap();
+        let err = channel_state.validate_circuit_hash().unwrap_err();
+        assert!(err.contains(&hex::encode([0x42u8; 32])), "{}", err);
+
+        let old_state = cust_state.get_current_state();
+        let (new_state, _rev_state, rev_lock_com, _session_id) =
+            mpc::pay_prepare_customer(&mut rng, &channel_state, 1000, &mut cust_state).unwrap();
+        let res = mpc::pay_update_customer(
+            &channel_state,
+            &channel_token,
+            old_state,
+            new_state,
+            [0x11; 32],
+            rev_lock_com,
+            1000,
+            &mut cust_state,
+            ptr::null_mut(),
+            None,
+            None,
+        );
+        assert_eq!(res.unwrap_err(), err);
+    }
+
+    #[test]
+    fn test_refund_voucher() {
+        let mut rng = XorShiftRng::seed_from_u64(0x91d3a6f04c2e7b58);
+        let mut db = HashMapDatabase::new("", "".to_string()).unwrap();
+        let tx_fee_info = test_tx_fee_info();
+        let (channel_state, _channel_token, _cust_state, mut merch_state) =
+            activated_channel_helper(&mut rng, &mut db, &tx_fee_info);
+        // every rejected refund below counts as an attempt on the nonce
+        merch_state.set_session_policy(SessionPolicy {
+            max_concurrent_sessions: 100,
+            max_attempts_per_nonce: 10,
+            session_timeout: 600,
+        });
+
+        let nonce = [0xa1u8; 16];
+        let expiry = get_unix_time() + 3600;
+        assert!(
+            mpc::merchant_issue_refund_voucher(&mut rng, nonce, 500, expiry, &merch_state).is_err()
+        );
+        let voucher =
+            mpc::merchant_issue_refund_voucher(&mut rng, nonce, -500, expiry, &merch_state)
+                .unwrap();
+        // signed with the voucher key, not with the escrow key
+        assert!(voucher.verify(&merch_state.get_voucher_pubkey().unwrap()));
+        assert!(!voucher.verify(&merch_state.pk_m));
+        let ser_voucher = serde_json::to_string(&voucher).unwrap();
+        let expired =
+            mpc::merchant_issue_refund_voucher(&mut rng, nonce, -500, expiry - 7200, &merch_state)
+                .unwrap();
+        let ser_expired = serde_json::to_string(&expired).unwrap();
+
+        // the revocation secret of the old state, so that the refund can be completed
+        let rev_secret = [0x02u8; 32];
+        let rev_lock = sha2_hash_to_slice(&rev_secret.to_vec());
+        let t = [0x03u8; 16];
+        let mut rev_lock_com_buf = rev_lock.to_vec();
+        rev_lock_com_buf.extend_from_slice(&t);
+        let rev_lock_com = sha2_hash_to_slice(&rev_lock_com_buf);
+
+        let mut session_count = 0u8;
+        let mut refund = |db: &mut HashMapDatabase,
+                          merch_state: &mut mpc::MerchantMPCState,
+                          nonce: [u8; 16],
+                          amount: i64,
+                          justification: Option<String>| {
+            session_count += 1;
+            let session_id = [session_count; 16];
+            mpc::pay_prepare_merchant(
+                &mut rng,
+                db as &mut dyn StateDatabase,
+                &channel_state,
+                session_id,
+                nonce,
+                rev_lock_com,
+                amount,
+                justification,
+                merch_state,
+            )
+            .map(|_| session_id)
+        };
+
+        // refunds are rejected by default
+        let res = refund(
+            &mut db,
+            &mut merch_state,
+            nonce,
+            -500,
+            Some(ser_voucher.clone()),
+        );
+        assert!(res.is_err());
+        merch_state.set_refund_policy(mpc::NegativePaymentPolicy::CHECK_JUSTIFICATION);
+
+        // missing, tampered, mismatched or expired vouchers are rejected
+        assert!(refund(&mut db, &mut merch_state, nonce, -500, None).is_err());
+        let mut bad_voucher = voucher.clone();
+        bad_voucher.amount = -5000;
+        let ser_bad_voucher = serde_json::to_string(&bad_voucher).unwrap();
+        assert!(refund(
+            &mut db,
+            &mut merch_state,
+            nonce,
+            -5000,
+            Some(ser_bad_voucher)
+        )
+        .is_err());
+        let res = refund(
+            &mut db,
+            &mut merch_state,
+            nonce,
+            -400,
+            Some(ser_voucher.clone()),
+        );
+        assert!(res.is_err());
+        assert!(refund(&mut db, &mut merch_state, nonce, -500, Some(ser_expired)).is_err());
+        // the voucher can not be redeemed on another channel
+        let res = refund(
+            &mut db,
+            &mut merch_state,
+            [0xa2u8; 16],
+            -500,
+            Some(ser_voucher.clone()),
+        );
+        assert!(res.is_err());
+
+        // a session that fails before the revocation does not use up the voucher
+        let session_id = refund(
+            &mut db,
+            &mut merch_state,
+            nonce,
+            -500,
+            Some(ser_voucher.clone()),
+        )
+        .unwrap();
+        let session_id_hex = hex::encode(session_id);
+        let mut session_state = db.load_session_state(&session_id_hex).unwrap();
+        session_state.status = PaymentStatus::Error;
+        db.update_session_state(&session_id_hex, &session_state);
+        assert!(db.spent_voucher_map.is_empty());
+
+        // the retry is spent once the old state is revoked
+        let session_id = refund(
+            &mut db,
+            &mut merch_state,
+            nonce,
+            -500,
+            Some(ser_voucher.clone()),
+        )
+        .unwrap();
+        let session_id_hex = hex::encode(session_id);
+        assert!(db.spent_voucher_map.is_empty());
+        let mut session_state = db.load_session_state(&session_id_hex).unwrap();
+        session_state.status = PaymentStatus::Update;
+        db.update_session_state(&session_id_hex, &session_state);
+        db.update_masked_mpc_inputs(
+            &session_id_hex,
+            MaskedMPCInputs {
+                pt_mask: FixedSizeArray32([0x04; 32]),
+                pt_mask_r: FixedSizeArray16([0x05; 16]),
+                escrow_mask: FixedSizeArray32([0x06; 32]),
+                merch_mask: FixedSizeArray32([0x07; 32]),
+                r_escrow_sig: FixedSizeArray32([0x08; 32]),
+                r_merch_sig: FixedSizeArray32([0x09; 32]),
+                verify_success: FixedSizeArray16([0x0a; 16]),
+            },
+        );
+        let rev_state = mpc::RevokedState::new(rev_lock, rev_secret, t);
+        assert!(mpc::pay_validate_rev_lock_merchant(
+            &mut db as &mut dyn StateDatabase,
+            session_id,
+            rev_state,
+            &mut merch_state,
+        )
+        .is_ok());
+        assert!(db
+            .spent_voucher_map
+            .contains_key(&hex::encode(voucher.id.0)));
+        let res = refund(
+            &mut db,
+            &mut merch_state,
+            nonce,
+            -500,
+            Some(ser_voucher.clone()),
+        );
+        assert!(res.is_err());
+
+        // spent vouchers are dropped once they have expired
+        assert_eq!(db.prune_spent_voucher_map(voucher.expiry - 1).unwrap(), 0);
+        assert_eq!(db.prune_spent_voucher_map(voucher.expiry).unwrap(), 1);
+        assert!(db.spent_voucher_map.is_empty());
+    }
+
     fn generate_funding_tx<R: Rng>(csprng: &mut R, b0_cust: i64, b0_merch: i64) -> FundingTxInfo {
         let mut escrow_txid = [0u8; 32];
         let mut merch_txid = [0u8; 32];
@@ -193,6 +983,7 @@ mod tests {
             b0_merch,
             tx_fee_info,
             "Alice",
+            channel_state.get_network(),
         );
 
         return (channel_state, channel_token, cust_state, merch_state);
@@ -241,6 +1032,7 @@ mod tests {
             b0_merch,
             &tx_fee_info,
             "Alice",
+            channel_state.get_network(),
         );
 
         let funding_tx_info = generate_funding_tx(&mut rng, b0_cust, b0_merch);
@@ -379,7 +1171,7 @@ mod tests {
                 max_fee: max_fee
             };
 
-            let (mut channel_token, mut cust_state) = mpc::init_customer(&mut rng, &merch_state.pk_m, b0_cust, b0_merch, &tx_fee_info, "Alice");
+            let (mut channel_token, mut cust_state) = mpc::init_customer(&mut rng, &merch_state.pk_m, b0_cust, b0_merch, &tx_fee_info, "Alice", channel_state.get_network());
 
             let funding_tx_info = generate_funding_tx(&mut rng, b0_cust, b0_merch);
 
@@ -972,6 +1764,7 @@ mod tests {
             path: String::from("tmpsock"),
             dest_ip: String::from("127.0.0.1"),
             dest_port: 5002,
+            proxy: None,
         };
         cust_state.set_network_config(nc.clone());
         merch_state.set_network_config(nc.clone());
@@ -1127,6 +1920,210 @@ mod tests {
         );
     }
 
+    // in-process transport between the two parties of the MPC
+    struct LocalMpcChannel {
+        to_peer: mpsc::Sender<Vec<u8>>,
+        from_peer: mpsc::Receiver<Vec<u8>>,
+    }
+
+    impl mpc::MpcChannel for LocalMpcChannel {
+        fn send(&mut self, data: &[u8]) -> Result<(), String> {
+            self.to_peer.send(data.to_vec()).map_err(|e| e.to_string())
+        }
+
+        fn receive(&mut self) -> Result<Vec<u8>, String> {
+            self.from_peer.recv().map_err(|e| e.to_string())
+        }
+    }
+
+    fn local_mpc_channel_pair() -> (LocalMpcChannel, LocalMpcChannel) {
+        let (tx_a, rx_a) = mpsc::channel();
+        let (tx_b, rx_b) = mpsc::channel();
+        (
+            LocalMpcChannel {
+                to_peer: tx_a,
+                from_peer: rx_b,
+            },
+            LocalMpcChannel {
+                to_peer: tx_b,
+                from_peer: rx_a,
+            },
+        )
+    }
+
+    // runs pay_update_merchant() on its own thread and db connection, connected to the
+    // customer's pay_update_customer() by an in-process MpcChannel
+    fn pay_update_over_mpc_channel(
+        db_prefix: &str,
+        channel_state: &mpc::ChannelMPCState,
+        channel_token: &mpc::ChannelMPCToken,
+        session_id: [u8; 16],
+        cur_state: mpc::State,
+        new_state: mpc::State,
+        pay_mask_com: [u8; 32],
+        rev_lock_com: [u8; 32],
+        amount: i64,
+        cust_state: &mut mpc::CustomerMPCState,
+        merch_state: mpc::MerchantMPCState,
+    ) -> (Result<String, String>, mpc::MerchantMPCState) {
+        let (mut cust_channel, mut merch_channel) = local_mpc_channel_pair();
+        let merch_channel_state = channel_state.clone();
+        let merch_db_prefix = db_prefix.to_string();
+        let merch = thread::spawn(move || {
+            let mut rng = rand::thread_rng();
+            let mut db =
+                RedisDatabase::new(&merch_db_prefix, "redis://127.0.0.1/".to_string()).unwrap();
+            let mut merch_state = merch_state;
+            let res = mpc::pay_update_merchant_with_channel(
+                &mut rng,
+                &mut db as &mut dyn StateDatabase,
+                &merch_channel_state,
+                session_id,
+                pay_mask_com,
+                &mut merch_state,
+                &mut merch_channel,
+            );
+            (res, merch_state)
+        });
+
+        let res_cust = mpc::pay_update_customer_with_channel(
+            channel_state,
+            channel_token,
+            cur_state,
+            new_state,
+            pay_mask_com,
+            rev_lock_com,
+            amount,
+            cust_state,
+            &mut cust_channel,
+        );
+        // unblocks the merchant if the customer side failed
+        drop(cust_channel);
+
+        let (res_merch, merch_state) = merch.join().unwrap();
+        assert!(res_merch.is_ok(), "{:?}", res_merch);
+        (res_cust, merch_state)
+    }
+
+    #[test]
+    #[ignore]
+    fn test_unlink_and_pay_over_mpc_channel() {
+        let mut rng = &mut rand::thread_rng();
+        let db_prefix = "mpcchannel";
+        let mut db = RedisDatabase::new(db_prefix, "redis://127.0.0.1/".to_string()).unwrap();
+        db.clear_state();
+
+        let tx_fee_info = test_tx_fee_info();
+        let (channel_state, channel_token, mut cust_state, mut merch_state) =
+            zkchannel_full_establish_setup_helper(&mut rng, &mut db, &tx_fee_info);
+        let cust_bal = cust_state.cust_balance;
+
+        // unlink, then pay
+        for amount in vec![0, 200] {
+            let (session_id, cur_state, new_state, rev_state, rev_lock_com, pay_mask_com) =
+                pay_prepare_helper(
+                    &mut rng,
+                    &mut db,
+                    &channel_state,
+                    &mut cust_state,
+                    amount,
+                    &mut merch_state,
+                );
+
+            let (res_cust, merch_state_after) = pay_update_over_mpc_channel(
+                db_prefix,
+                &channel_state,
+                &channel_token,
+                session_id,
+                cur_state,
+                new_state,
+                pay_mask_com,
+                rev_lock_com,
+                amount,
+                &mut cust_state,
+                merch_state,
+            );
+            merch_state = merch_state_after;
+            assert!(res_cust.is_ok(), "{:?}", res_cust);
+            // the channel was only used for this session
+            assert!(cust_state.net_config.is_none());
+
+            complete_pay_helper(
+                &mut db,
+                session_id,
+                res_cust.unwrap(),
+                rev_state,
+                &channel_state,
+                &channel_token,
+                &mut cust_state,
+                &mut merch_state,
+            );
+            assert_eq!(cust_state.protocol_status, ProtocolStatus::Established);
+        }
+        assert_eq!(cust_state.cust_balance, cust_bal - 200);
+    }
+
+    #[test]
+    #[ignore]
+    fn test_shared_circuit_across_payments() {
+        let mut rng = &mut rand::thread_rng();
+        let db_prefix = "sharedcircuit";
+        let mut db = RedisDatabase::new(db_prefix, "redis://127.0.0.1/".to_string()).unwrap();
+        db.clear_state();
+
+        let tx_fee_info = test_tx_fee_info();
+        let (channel_state, channel_token, mut cust_state, mut merch_state) =
+            zkchannel_full_establish_setup_helper(&mut rng, &mut db, &tx_fee_info);
+        let cust_bal = cust_state.cust_balance;
+        let circuit = get_circuit(channel_state.get_mpc_security()).unwrap();
+
+        // both parties run concurrently on the same circuit, and every payment reuses it.
+        // if the MPC wrote to the circuit, a later payment would not produce a valid output
+        for amount in vec![0, 100, 200, 300] {
+            let (session_id, cur_state, new_state, rev_state, rev_lock_com, pay_mask_com) =
+                pay_prepare_helper(
+                    &mut rng,
+                    &mut db,
+                    &channel_state,
+                    &mut cust_state,
+                    amount,
+                    &mut merch_state,
+                );
+
+            let (res_cust, merch_state_after) = pay_update_over_mpc_channel(
+                db_prefix,
+                &channel_state,
+                &channel_token,
+                session_id,
+                cur_state,
+                new_state,
+                pay_mask_com,
+                rev_lock_com,
+                amount,
+                &mut cust_state,
+                merch_state,
+            );
+            merch_state = merch_state_after;
+            assert!(res_cust.is_ok(), "{:?}", res_cust);
+
+            complete_pay_helper(
+                &mut db,
+                session_id,
+                res_cust.unwrap(),
+                rev_state,
+                &channel_state,
+                &channel_token,
+                &mut cust_state,
+                &mut merch_state,
+            );
+            assert_eq!(
+                get_circuit(channel_state.get_mpc_security()).unwrap(),
+                circuit
+            );
+        }
+        assert_eq!(cust_state.cust_balance, cust_bal - 600);
+    }
+
     #[test]
     #[ignore]
     //#[should_panic(expected = "Failed to get valid output from MPC!")]
@@ -1171,6 +2168,7 @@ mod tests {
             path: String::from("tmpsock"),
             dest_ip: String::from("127.0.0.1"),
             dest_port: 5003,
+            proxy: None,
         };
         cust_state.set_network_config(nc.clone());
         merch_state.set_network_config(nc.clone());
@@ -0,0 +1,797 @@
+// Encodes the zkChannel Tezos contract's origination storage and entrypoint
+// parameters (see tezos-sandbox/tests_python/zkchannels_contract/zkchannel_contract.tz)
+// as Micheline binary, so operations can be forged without the pytezos toolchain.
+use channels_zk::ChannelToken;
+use cl;
+use ff::{PrimeField, PrimeFieldRepr};
+use pairing::bls12_381::{Bls12, Fr, G1Uncompressed, G2Uncompressed, G1, G2};
+use pairing::{CurveProjective, EncodedPoint};
+use sha2::{Digest, Sha256};
+use zkchan_tx::fixed_size_array::FixedSizeArray32;
+use zkproofs::ChannelcloseC;
+
+// Micheline expression tags
+const TAG_INT: u8 = 0x00;
+const TAG_STRING: u8 = 0x01;
+const TAG_SEQ: u8 = 0x02;
+const TAG_PRIM_NO_ARGS: u8 = 0x03;
+const TAG_PRIM_1_ARG: u8 = 0x05;
+const TAG_PRIM_2_ARGS: u8 = 0x07;
+const TAG_PRIM_N_ARGS: u8 = 0x09;
+const TAG_BYTES: u8 = 0x0a;
+
+// PACK prefix for Micheline data
+const PACK_PREFIX: u8 = 0x05;
+
+// number of merchant public key elements (Y0..Y3) checked by the contract
+const CONTRACT_NUM_Y_ELEMS: usize = 4;
+
+// contract status on origination (AWAITING_FUNDING)
+const AWAITING_FUNDING: i64 = 0;
+
+pub const ADD_FUNDING: &str = "addFunding";
+pub const RECLAIM_FUNDING: &str = "reclaimFunding";
+pub const MERCH_CLOSE: &str = "merchClose";
+pub const MERCH_CLAIM: &str = "merchClaim";
+pub const CUST_CLOSE: &str = "custClose";
+pub const MERCH_DISPUTE: &str = "merchDispute";
+pub const CUST_CLAIM: &str = "custClaim";
+pub const MUTUAL_CLOSE: &str = "mutualClose";
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Prim {
+    Left,
+    Pair,
+    Right,
+    Unit,
+}
+
+impl Prim {
+    fn code(&self) -> u8 {
+        match self {
+            Prim::Left => 0x05,
+            Prim::Pair => 0x07,
+            Prim::Right => 0x08,
+            Prim::Unit => 0x0b,
+        }
+    }
+}
+
+///
+/// Micheline data expression. Addresses, keys and signatures are carried in
+/// their readable (base58check) form as strings.
+///
+#[derive(Clone, Debug, PartialEq)]
+pub enum Micheline {
+    Int(i64),
+    String(String),
+    Bytes(Vec<u8>),
+    Prim(Prim, Vec<Micheline>),
+    Seq(Vec<Micheline>),
+}
+
+impl Micheline {
+    pub fn unit() -> Self {
+        Micheline::Prim(Prim::Unit, Vec::new())
+    }
+
+    pub fn pair(left: Micheline, right: Micheline) -> Self {
+        Micheline::Prim(Prim::Pair, vec![left, right])
+    }
+
+    ///
+    /// encode() - outputs the binary encoding of the expression (as found
+    /// in the script and parameters fields of an operation)
+    ///
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        self.encode_into(&mut out);
+        out
+    }
+
+    ///
+    /// pack() - outputs the encoding as produced by the Michelson PACK instruction
+    ///
+    pub fn pack(&self) -> Vec<u8> {
+        let mut out = vec![PACK_PREFIX];
+        self.encode_into(&mut out);
+        out
+    }
+
+    fn encode_into(&self, out: &mut Vec<u8>) {
+        match self {
+            Micheline::Int(n) => {
+                out.push(TAG_INT);
+                encode_zarith(*n, out);
+            }
+            Micheline::String(s) => {
+                out.push(TAG_STRING);
+                encode_dynamic(s.as_bytes(), out);
+            }
+            Micheline::Bytes(b) => {
+                out.push(TAG_BYTES);
+                encode_dynamic(b, out);
+            }
+            Micheline::Seq(items) => {
+                let mut inner = Vec::new();
+                for item in items {
+                    item.encode_into(&mut inner);
+                }
+                out.push(TAG_SEQ);
+                encode_dynamic(&inner, out);
+            }
+            Micheline::Prim(prim, args) => match args.len() {
+                0 => out.extend_from_slice(&[TAG_PRIM_NO_ARGS, prim.code()]),
+                1 => {
+                    out.extend_from_slice(&[TAG_PRIM_1_ARG, prim.code()]);
+                    args[0].encode_into(out);
+                }
+                2 => {
+                    out.extend_from_slice(&[TAG_PRIM_2_ARGS, prim.code()]);
+                    args[0].encode_into(out);
+                    args[1].encode_into(out);
+                }
+                _ => {
+                    let mut inner = Vec::new();
+                    for arg in args {
+                        arg.encode_into(&mut inner);
+                    }
+                    out.extend_from_slice(&[TAG_PRIM_N_ARGS, prim.code()]);
+                    encode_dynamic(&inner, out);
+                    // no annotations
+                    encode_dynamic(&[], out);
+                }
+            },
+        }
+    }
+}
+
+///
+/// Entrypoint and argument of a transaction to the zkChannel contract
+///
+#[derive(Clone, Debug, PartialEq)]
+pub struct ContractCall {
+    pub entrypoint: String,
+    pub value: Micheline,
+}
+
+impl ContractCall {
+    fn new(entrypoint: &str, value: Micheline) -> Self {
+        ContractCall {
+            entrypoint: entrypoint.to_string(),
+            value,
+        }
+    }
+
+    ///
+    /// encode() - outputs the parameters field of a transaction operation
+    /// (entrypoint followed by the length-prefixed value), without the
+    /// leading presence flag
+    ///
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        match self.entrypoint.as_str() {
+            "default" => out.push(0x00),
+            "root" => out.push(0x01),
+            "do" => out.push(0x02),
+            "set_delegate" => out.push(0x03),
+            "remove_delegate" => out.push(0x04),
+            name => {
+                out.push(0xff);
+                out.push(name.len() as u8);
+                out.extend_from_slice(name.as_bytes());
+            }
+        }
+        encode_dynamic(&self.value.encode(), &mut out);
+        out
+    }
+}
+
+fn encode_dynamic(bytes: &[u8], out: &mut Vec<u8>) {
+    out.extend_from_slice(&(bytes.len() as u32).to_be_bytes());
+    out.extend_from_slice(bytes);
+}
+
+// signed zarith: 6 bits of payload plus a sign bit in the first byte, then 7 bits per byte
+fn encode_zarith(n: i64, out: &mut Vec<u8>) {
+    let mut abs = (n as i128).abs() as u128;
+    let mut cur = (abs & 0x3f) as u8;
+    if n < 0 {
+        cur |= 0x40;
+    }
+    abs >>= 6;
+    while abs != 0 {
+        out.push(cur | 0x80);
+        cur = (abs & 0x7f) as u8;
+        abs >>= 7;
+    }
+    out.push(cur);
+}
+
+const BASE58_ALPHABET: &[u8] = b"123456789ABCDEFGHJKLMNPQRSTUVWXYZabcdefghijkmnopqrstuvwxyz";
+
+fn decode_base58check(s: &str) -> Result<Vec<u8>, String> {
+    let mut bytes: Vec<u8> = Vec::new();
+    for c in s.bytes() {
+        let mut carry = match BASE58_ALPHABET.iter().position(|&a| a == c) {
+            Some(d) => d as u32,
+            None => return Err(format!("invalid base58 character in {}", s)),
+        };
+        for b in bytes.iter_mut().rev() {
+            carry += (*b as u32) * 58;
+            *b = carry as u8;
+            carry >>= 8;
+        }
+        while carry != 0 {
+            bytes.insert(0, carry as u8);
+            carry >>= 8;
+        }
+    }
+    let zeros = s.bytes().take_while(|&c| c == b'1').count();
+    let mut out = vec![0u8; zeros];
+    out.extend(bytes);
+    if out.len() < 4 {
+        return Err(format!("invalid base58check string: {}", s));
+    }
+    let (payload, checksum) = out.split_at(out.len() - 4);
+    if &Sha256::digest(&Sha256::digest(payload))[..4] != checksum {
+        return Err(format!("invalid base58check checksum: {}", s));
+    }
+    Ok(payload.to_vec())
+}
+
+///
+/// encode_address() - address in the optimized (binary) form, as used by PACK
+///
+pub fn encode_address(address: &str) -> Result<Micheline, String> {
+    let payload = decode_base58check(address)?;
+    if payload.len() != 23 {
+        return Err(format!("invalid tezos address: {}", address));
+    }
+    let (prefix, hash) = payload.split_at(3);
+    let enc = match prefix {
+        // tz1, tz2, tz3
+        [6, 161, 159] => [&[0x00u8, 0x00][..], hash].concat(),
+        [6, 161, 161] => [&[0x00u8, 0x01][..], hash].concat(),
+        [6, 161, 164] => [&[0x00u8, 0x02][..], hash].concat(),
+        // KT1 (padded to the length of an implicit address)
+        [2, 90, 121] => [&[0x01u8][..], hash, &[0x00]].concat(),
+        _ => return Err(format!("unsupported tezos address: {}", address)),
+    };
+    Ok(Micheline::Bytes(enc))
+}
+
+fn mutez(amount: i64) -> Result<Micheline, String> {
+    if amount < 0 {
+        return Err(format!("invalid mutez amount: {}", amount));
+    }
+    Ok(Micheline::Int(amount))
+}
+
+///
+/// encode_fr() - bls12_381_fr as expected by the contract (32 bytes, little-endian)
+///
+pub fn encode_fr(fr: &Fr) -> Micheline {
+    let mut buf = Vec::new();
+    fr.into_repr().write_le(&mut buf).unwrap();
+    Micheline::Bytes(buf)
+}
+
+///
+/// encode_g1() - bls12_381_g1 as expected by the contract (uncompressed, 96 bytes)
+///
+pub fn encode_g1(point: &G1) -> Micheline {
+    let enc = G1Uncompressed::from_affine(point.into_affine());
+    Micheline::Bytes(enc.as_ref().to_vec())
+}
+
+///
+/// encode_g2() - bls12_381_g2 as expected by the contract (uncompressed, 192 bytes)
+///
+pub fn encode_g2(point: &G2) -> Micheline {
+    let enc = G2Uncompressed::from_affine(point.into_affine());
+    Micheline::Bytes(enc.as_ref().to_vec())
+}
+
+///
+/// origination_storage() - takes as input the channel token (with the customer's
+/// public key set), the Tezos addresses and public keys of both parties, their
+/// funding amounts (in mutez), the initial rev_lock and the self delay (in seconds).
+/// output: initial storage of the zkChannel contract
+///
+pub fn origination_storage(
+    channel_token: &ChannelToken<Bls12>,
+    cust_addr: &str,
+    cust_pk: &str,
+    cust_funding: i64,
+    merch_addr: &str,
+    merch_pk: &str,
+    merch_funding: i64,
+    rev_lock: &FixedSizeArray32,
+    self_delay: i64,
+) -> Result<Micheline, String> {
+    if channel_token.pk_c.is_none() {
+        return Err(String::from(
+            "customer public key is not set in the channel token",
+        ));
+    }
+    let chan_id = channel_token.compute_channel_id()?;
+    origination_storage_with_keys(
+        &chan_id,
+        &channel_token.mpk.g2,
+        &channel_token.cl_pk_m,
+        cust_addr,
+        cust_pk,
+        cust_funding,
+        merch_addr,
+        merch_pk,
+        merch_funding,
+        rev_lock,
+        self_delay,
+    )
+}
+
+///
+/// origination_storage_with_keys() - same as origination_storage() but takes the
+/// channel id, the merchant's g2 generator and close-token public key directly.
+/// output: initial storage of the zkChannel contract
+///
+pub fn origination_storage_with_keys(
+    chan_id: &Fr,
+    g2: &G2,
+    merch_cl_pk: &cl::PublicKey<Bls12>,
+    cust_addr: &str,
+    cust_pk: &str,
+    cust_funding: i64,
+    merch_addr: &str,
+    merch_pk: &str,
+    merch_funding: i64,
+    rev_lock: &FixedSizeArray32,
+    self_delay: i64,
+) -> Result<Micheline, String> {
+    let y = &merch_cl_pk.Y;
+    if y.len() < CONTRACT_NUM_Y_ELEMS {
+        return Err(format!(
+            "merchant public key has {} elements, contract expects at least {}",
+            y.len(),
+            CONTRACT_NUM_Y_ELEMS
+        ));
+    }
+
+    let cust_part = Micheline::pair(
+        Micheline::pair(
+            Micheline::pair(encode_fr(chan_id), Micheline::String(cust_addr.to_string())),
+            Micheline::pair(mutez(0)?, mutez(cust_funding)?),
+        ),
+        Micheline::pair(
+            // custPk, delayExpiry
+            Micheline::pair(Micheline::String(cust_pk.to_string()), Micheline::Int(0)),
+            Micheline::pair(
+                encode_g2(g2),
+                Micheline::pair(Micheline::String(merch_addr.to_string()), mutez(0)?),
+            ),
+        ),
+    );
+    let merch_part = Micheline::pair(
+        Micheline::pair(
+            Micheline::pair(
+                mutez(merch_funding)?,
+                Micheline::String(merch_pk.to_string()),
+            ),
+            Micheline::pair(
+                encode_g2(&y[0]),
+                Micheline::pair(encode_g2(&y[1]), encode_g2(&y[2])),
+            ),
+        ),
+        Micheline::pair(
+            // merchPk3, merchPk4 (X)
+            Micheline::pair(encode_g2(&y[3]), encode_g2(&merch_cl_pk.X)),
+            Micheline::pair(
+                Micheline::Bytes(rev_lock.0.to_vec()),
+                Micheline::pair(Micheline::Int(self_delay), Micheline::Int(AWAITING_FUNDING)),
+            ),
+        ),
+    );
+    Ok(Micheline::pair(cust_part, merch_part))
+}
+
+pub fn add_funding() -> ContractCall {
+    ContractCall::new(ADD_FUNDING, Micheline::unit())
+}
+
+pub fn reclaim_funding() -> ContractCall {
+    ContractCall::new(RECLAIM_FUNDING, Micheline::unit())
+}
+
+pub fn merch_close() -> ContractCall {
+    ContractCall::new(MERCH_CLOSE, Micheline::unit())
+}
+
+pub fn merch_claim() -> ContractCall {
+    ContractCall::new(MERCH_CLAIM, Micheline::unit())
+}
+
+pub fn cust_claim() -> ContractCall {
+    ContractCall::new(CUST_CLAIM, Micheline::unit())
+}
+
+///
+/// cust_close() - takes as input the customer's channel closure message.
+/// The contract has no claim/expire branches, so a state with a pending
+/// (conditional) amount cannot be closed on Tezos.
+/// output: custClose call carrying the closing balances, rev_lock and close token
+///
+pub fn cust_close(close: &ChannelcloseC<Bls12>) -> Result<ContractCall, String> {
+    if close.message.pending.is_some() {
+        return Err(String::from(
+            "cust_close: closing state has a pending amount, which the contract does not support",
+        ));
+    }
+    cust_close_with_signature(
+        close.message.bc,
+        close.message.bm,
+        &close.message.rev_lock,
+        &close.merch_signature,
+    )
+}
+
+///
+/// cust_close_with_signature() - takes as input the closing balances (in mutez),
+/// the rev_lock and the merchant's close token on that state.
+/// output: custClose call
+///
+pub fn cust_close_with_signature(
+    cust_bal: i64,
+    merch_bal: i64,
+    rev_lock: &Fr,
+    close_token: &cl::Signature<Bls12>,
+) -> Result<ContractCall, String> {
+    let value = Micheline::pair(
+        Micheline::pair(mutez(cust_bal)?, mutez(merch_bal)?),
+        Micheline::pair(
+            encode_fr(rev_lock),
+            Micheline::pair(encode_g1(&close_token.h), encode_g1(&close_token.H)),
+        ),
+    );
+    Ok(ContractCall::new(CUST_CLOSE, value))
+}
+
+///
+/// merch_dispute() - takes as input the revealed secret of the customer's rev_lock.
+/// output: merchDispute call
+///
+pub fn merch_dispute(rev_secret: &FixedSizeArray32) -> ContractCall {
+    ContractCall::new(MERCH_DISPUTE, Micheline::Bytes(rev_secret.0.to_vec()))
+}
+
+///
+/// mutual_close() - takes as input the final balances (in mutez) and both
+/// parties' Tezos signatures on the packed final state.
+/// output: mutualClose call
+///
+pub fn mutual_close(
+    cust_bal: i64,
+    cust_sig: &str,
+    merch_bal: i64,
+    merch_sig: &str,
+) -> Result<ContractCall, String> {
+    let value = Micheline::pair(
+        Micheline::pair(mutez(cust_bal)?, Micheline::String(cust_sig.to_string())),
+        Micheline::pair(mutez(merch_bal)?, Micheline::String(merch_sig.to_string())),
+    );
+    Ok(ContractCall::new(MUTUAL_CLOSE, value))
+}
+
+///
+/// mutual_close_message() - takes as input the channel id, the Tezos addresses of
+/// both parties and the final balances (in mutez).
+/// output: the packed final state that the contract's mutualClose entrypoint checks
+/// both parties' Tezos signatures against (e.g. sign with `tezos-client sign bytes`)
+///
+pub fn mutual_close_message(
+    chan_id: &Fr,
+    cust_addr: &str,
+    cust_bal: i64,
+    merch_addr: &str,
+    merch_bal: i64,
+) -> Result<Vec<u8>, String> {
+    let state = Micheline::pair(
+        Micheline::pair(encode_fr(chan_id), encode_address(cust_addr)?),
+        Micheline::pair(
+            mutez(cust_bal)?,
+            Micheline::pair(encode_address(merch_addr)?, mutez(merch_bal)?),
+        ),
+    );
+    Ok(state.pack())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pairing::CurveAffine;
+    use zkproofs;
+
+    // sample close state and signature from tezos-sandbox/tests_python/zkchannels_contract/sample_cust_close.json
+    const REV_LOCK: &str = "16eee90f1221a6a8c0ee8a1907f030def6e2fcc3d6ba9517410f722b1c373852";
+    const SIG_S1: &str = "0a6c72a6cbe82240fb3e26892bb8baea20951af3843f13620a401239436fa6bc77106faa0d89228ccce1514962008eda14ff80b3b23e3de09b478281cfcb32a5064633830a32da6dd9e1f8da0c4a540eb873d396197e5264c4918022c61d9841";
+    const SIG_S2: &str = "19bc007de29752594f8c006b21fc1a4806fd2a1aa48db7f2d0c0dc694bea195f52c0c0159df4776fd89afbc1d7c2e0bf0b229543fba3cbcb2d4cf1559ee2f6327e2fecdf9fc7d52bb7a13b3699844f88f7f5dc1d1077b2d7fd9a1fc13e381159";
+
+    // merchant public key from the same sample
+    const CHANNEL_ID: &str = "90988a2421c40eaba101137fecccce44177b040c671fbce4ead7b129a3d1e26f";
+    const MERCH_G2: &str = "0a6cca158299d9f2110d74aeaf0659713ff93851970e49919904abb1845a2a1e121dd1dea360f3556bdfe8f455c4dd07048c12b7a689a096b86e6c8d5b3c9be2d17d09531124d19538621c9bed7445552f14938dfdcceb52e079e0ceccf579e100b0bf57d7b73e7f9a6089e2f7de6b1044acc55c8254f30bf7417b9c765b4dafd53aaaa2226dbc7dff7b34e6f1cd7e48081979a3e7cee24ea7bf10163d237a5c8a84d2b9a4ede0e65b24446f3cdc861092960d7576cffbf1eab486d7ac067a47";
+    const MERCH_X: &str = "0b054195dc8d3458a311c57092a0cc683e4f095808ef023affafef03733003580cd53cc7f67a24ff6b7078303f86f6f3062c32845f0c74be2fd4a5e60c04da8e3a17460870ebfda4e882896c1991ffdb0be634c8b0a543bdfeb7c33dced313560ef3f6748fc3be638c0ca75e669a496e34084b3bb6657cc31c56a77709ed69df44133088aa304508c6a64f0f7100e9a105525d0156f3f3016388e7cbb5a5322daab0f302c3729d666f7df4b12b2b0ae15c78720ee85f9605f81d4ed08a094caa";
+    const MERCH_Y: [&str; 4] = [
+        "08f035b8a5be927d572523547626a4fab8e7eb70835e76ef384bebd24c00ff6a85b0276c9dc57c6193a2b7cb3c498aa10193b7a7c3acdd3de0d15da408ba6cd8f6076dfb7e65be8afa9844384230152062ad3a3a17c3cb3d4b9c059920821aba0d878c1f1e14dc4a7f1b4a35660b1a12348da93db333bb9fec00985d863e3c2c05af9b28e308a40bf8936af3ac50da0906e8fa7ee56e0e8d279e3897c19627ab5d2c72a1ae0bad6db53fa414d647077c206b751e70a14e45ce2baad8f0cc9a55",
+        "01559bc5f6d3bc8e4b910d8c8faacdd3409ee1d532d16c24ab7b06a568aa26d9e449fb3515773d3d1cdf9a655cb50d4a19f851596b8a81f6d78a5b6735a1aa67e468f132a1de6ef4a6d0b34b5a70d32fd617391b37c051e237e7442389ec92560818a7d200a9b375e17b3cb0d5fbd3428674fb8dbbb85e34b94daae0bde343c4a3f665100d35b15c1d03a94b7e6ecb5405fcb52346116077b8d5a8c4c79de042e74496934ac7881e676c6dc55565530f3cb00a319209f36562554572f655d9de",
+        "126f2dffe649539b2a9515b9dffafd037e33ff51571aaf5fa51a705943fc13c1c2cabff54788438ca660d19cc8bb0a920840798969f9b16fbf4e79dab7a1c90d762bb1dad3d86d840372449386bf19cc71f9a60e46cc322ef8e14ed2fa0e832310195756143ebd28a282f6865949caf7424b51338b694ba1eb2dce1b5993d829e27efe6d39f933efa8cf9a77847a2cc21900054d21e82c56b0cf2a27f78be1aaccf246c8c05371ed82149a0fa8665249afc73878777fae27e1570c549d673421",
+        "14750e60de640a5be80ff79850a54b747ee07a958cfc9245d686afc79b086063ba639f59a15ece280d46272f66533e0e193ed0536ce7ecd0b8a893bb35d7d28e2eb0ce67d5623f52ba0250606c5ed5c8b32bb78deb4ceab2ace4f4a0afbeb5110c8889ff6917f5b9f5beca4156d0f72a62b35f8ea9bcb33f8d8fc4b4dfe112a345cb00b6a31f0c2d92536bd8a122f1b11594a77a9b331565eaa9f5aa2ec10bdb5e04c47d36a25786b02283b2a9a6057a6aa25707119e04db445115df1555ae74",
+    ];
+
+    fn g1_from_hex(s: &str) -> G1 {
+        let mut enc = G1Uncompressed::empty();
+        enc.as_mut().copy_from_slice(&hex::decode(s).unwrap());
+        enc.into_affine().unwrap().into_projective()
+    }
+
+    fn g2_from_hex(s: &str) -> G2 {
+        let mut enc = G2Uncompressed::empty();
+        enc.as_mut().copy_from_slice(&hex::decode(s).unwrap());
+        enc.into_affine().unwrap().into_projective()
+    }
+
+    fn fr_from_hex(s: &str) -> Fr {
+        let mut repr = <Fr as PrimeField>::Repr::default();
+        repr.read_le(&hex::decode(s).unwrap()[..]).unwrap();
+        Fr::from_repr(repr).unwrap()
+    }
+
+    #[test]
+    fn micheline_int_encoding_works() {
+        let cases: Vec<(i64, &str)> = vec![
+            (0, "0000"),
+            (1, "0001"),
+            (-1, "0041"),
+            (63, "003f"),
+            (64, "008001"),
+            (-64, "00c001"),
+            (18000000, "0080a29511"),
+        ];
+        for (n, expected) in cases {
+            assert_eq!(hex::encode(Micheline::Int(n).encode()), expected);
+        }
+        assert_eq!(hex::encode(Micheline::Int(1).pack()), "050001");
+    }
+
+    #[test]
+    fn micheline_encoding_works() {
+        assert_eq!(
+            hex::encode(Micheline::String(String::from("abc")).encode()),
+            "0100000003616263"
+        );
+        assert_eq!(
+            hex::encode(Micheline::Bytes(vec![0xde, 0xad]).encode()),
+            "0a00000002dead"
+        );
+        assert_eq!(
+            hex::encode(Micheline::Seq(vec![Micheline::Int(1), Micheline::unit()]).encode()),
+            "02000000040001030b"
+        );
+        assert_eq!(
+            hex::encode(Micheline::pair(Micheline::Int(1), Micheline::unit()).encode()),
+            "07070001030b"
+        );
+        let left = Micheline::Prim(Prim::Left, vec![Micheline::unit()]);
+        assert_eq!(hex::encode(left.encode()), "0505030b");
+    }
+
+    #[test]
+    fn unit_entrypoints_work() {
+        assert_eq!(
+            hex::encode(add_funding().encode()),
+            "ff0a61646446756e64696e6700000002030b"
+        );
+        assert_eq!(
+            hex::encode(merch_close().encode()),
+            "ff0a6d65726368436c6f736500000002030b"
+        );
+        let call = ContractCall::new("default", Micheline::unit());
+        assert_eq!(hex::encode(call.encode()), "0000000002030b");
+    }
+
+    #[test]
+    fn cust_close_encoding_works() {
+        let sig = cl::Signature::<Bls12> {
+            h: g1_from_hex(SIG_S1),
+            H: g1_from_hex(SIG_S2),
+        };
+        let rev_lock = fr_from_hex(REV_LOCK);
+
+        // the custClose argument sent by scenario_cust_close in test_zkchannel.py
+        let call = cust_close_with_signature(18000000, 12000000, &rev_lock, &sig).unwrap();
+        let expected = [
+            "ff0963757374436c6f7365",
+            "00000101",
+            "0707",
+            "0707",
+            "0080a29511",
+            "0080ecb80b",
+            "0707",
+            "0a00000020",
+            REV_LOCK,
+            "0707",
+            "0a00000060",
+            SIG_S1,
+            "0a00000060",
+            SIG_S2,
+        ]
+        .concat();
+        assert_eq!(hex::encode(call.encode()), expected);
+
+        assert!(cust_close_with_signature(-1, 12000000, &rev_lock, &sig).is_err());
+    }
+
+    #[test]
+    fn merch_dispute_and_mutual_close_work() {
+        let secret = FixedSizeArray32([0x11; 32]);
+        let expected = [
+            "ff0c6d6572636844697370757465000000250a00000020",
+            &"11".repeat(32),
+        ]
+        .concat();
+        assert_eq!(hex::encode(merch_dispute(&secret).encode()), expected);
+
+        let call = mutual_close(10, "sigC", 20, "sigM").unwrap();
+        assert_eq!(
+            hex::encode(call.encode()),
+            [
+                "ff0b6d757475616c436c6f7365",
+                "0000001c",
+                "07070707000a010000000473696743",
+                "0707001401000000047369674d",
+            ]
+            .concat()
+        );
+    }
+
+    #[test]
+    fn mutual_close_message_works() {
+        let msg = mutual_close_message(
+            &fr_from_hex(CHANNEL_ID),
+            "tz1KqTpEZ7Yob7QbPE4Hy4Wo8fHG8LhKxZSx",
+            18000000,
+            "tz1gjaF81ZRRvdzjobyfVNsAeSC6PScjfQwN",
+            12000000,
+        )
+        .unwrap();
+        let expected = [
+            "05",
+            "07070707",
+            "0a00000020",
+            CHANNEL_ID,
+            "0a00000016",
+            "000002298c03ed7d454a101eb7022bc95f7e5f41ac78",
+            "0707",
+            "0080a29511",
+            "0707",
+            "0a00000016",
+            "0000e7670f32038107a59a2b9cfefae36ea21f5aa63c",
+            "0080ecb80b",
+        ]
+        .concat();
+        assert_eq!(hex::encode(msg), expected);
+
+        // bad checksum
+        assert!(encode_address("tz1KqTpEZ7Yob7QbPE4Hy4Wo8fHG8LhKxZSy").is_err());
+        assert!(mutual_close_message(
+            &fr_from_hex(CHANNEL_ID),
+            "tz1KqTpEZ7Yob7QbPE4Hy4Wo8fHG8LhKxZSx",
+            -1,
+            "tz1gjaF81ZRRvdzjobyfVNsAeSC6PScjfQwN",
+            12000000,
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn origination_storage_works() {
+        let rng = &mut rand::thread_rng();
+        let mut channel_state =
+            zkproofs::ChannelState::<Bls12>::new(String::from("Channel A -> B"), false);
+        let (channel_token, _merch_state, _channel_state) = zkproofs::merchant_init(
+            rng,
+            "redis://127.0.0.1/".to_string(),
+            &mut channel_state,
+            "Merchant Bob",
+        );
+        // customer public key is required for the channel id
+        assert!(origination_storage(
+            &channel_token,
+            "tz1KqTpEZ7Yob7QbPE4Hy4Wo8fHG8LhKxZSx",
+            "edpkuBknW28nW72KG6RoHtYW7p12T6GKc7nAbwYX5m8Wd9sDVC9yav",
+            20000000,
+            "tz1gjaF81ZRRvdzjobyfVNsAeSC6PScjfQwN",
+            "edpktzNbDAUjUk697W7gYg2CRuBQjyPxbEg8dLccYYwKSKvkPvjtV9",
+            10000000,
+            &FixedSizeArray32([0x22; 32]),
+            3
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn origination_storage_matches_sandbox() {
+        // the storage originated by scenario_cust_close in test_zkchannel.py for
+        // sample_cust_close.json (delayExpiry is given there as the string "0",
+        // which the contract reads as the same timestamp)
+        let merch_cl_pk = cl::PublicKey::<Bls12> {
+            X: g2_from_hex(MERCH_X),
+            Y: MERCH_Y.iter().map(|y| g2_from_hex(y)).collect(),
+        };
+        let mut rev_lock = FixedSizeArray32([0; 32]);
+        rev_lock.0.copy_from_slice(
+            &hex::decode("1f98c84caf714d00ede5d23142bc166d84f8cd42adc18be22c3d47453853ea49")
+                .unwrap(),
+        );
+        let storage = origination_storage_with_keys(
+            &fr_from_hex(CHANNEL_ID),
+            &g2_from_hex(MERCH_G2),
+            &merch_cl_pk,
+            "tz1KqTpEZ7Yob7QbPE4Hy4Wo8fHG8LhKxZSx",
+            "edpkuBknW28nW72KG6RoHtYW7p12T6GKc7nAbwYX5m8Wd9sDVC9yav",
+            30000000,
+            "tz1gjaF81ZRRvdzjobyfVNsAeSC6PScjfQwN",
+            "edpktzNbDAUjUk697W7gYg2CRuBQjyPxbEg8dLccYYwKSKvkPvjtV9",
+            0,
+            &rev_lock,
+            3,
+        )
+        .unwrap();
+
+        let expected = [
+            "0707070707070707",
+            "0a00000020",
+            CHANNEL_ID,
+            "0100000024",
+            "747a314b715470455a37596f62375162504534487934576f38664847384c684b785a5378",
+            "0707",
+            "0000",
+            "00808ece1c",
+            "07070707",
+            "0100000036",
+            "6564706b75426b6e5732386e5737324b4736526f48745957377031325436474b63376e4162775958356d385764397344564339796176",
+            "0000",
+            "0707",
+            "0a000000c0",
+            MERCH_G2,
+            "0707",
+            "0100000024",
+            "747a31676a614638315a525276647a6a6f627966564e7341655343365053636a6651774e",
+            "0000",
+            "070707070707",
+            "0000",
+            "0100000036",
+            "6564706b747a4e624441556a556b36393757376759673243527542516a79507862456738644c63635959774b534b766b50766a745639",
+            "0707",
+            "0a000000c0",
+            MERCH_Y[0],
+            "0707",
+            "0a000000c0",
+            MERCH_Y[1],
+            "0a000000c0",
+            MERCH_Y[2],
+            "07070707",
+            "0a000000c0",
+            MERCH_Y[3],
+            "0a000000c0",
+            MERCH_X,
+            "0707",
+            "0a00000020",
+            "1f98c84caf714d00ede5d23142bc166d84f8cd42adc18be22c3d47453853ea49",
+            "0707",
+            "0003",
+            "0000",
+        ]
+        .concat();
+        assert_eq!(hex::encode(storage.encode()), expected);
+    }
+}
@@ -7,7 +7,8 @@ use pairing::bls12_381::Bls12;
 use ped92::CSMultiParams;
 pub use wallet::Wallet;
 use zkchan_tx::fixed_size_array::{FixedSizeArray16, FixedSizeArray32};
-use {util, BoltResult};
+use zkproofs::ChannelcloseC;
+use {util, zkproofs, BoltResult};
 
 const BLS12_381_CHANNEL_TOKEN_LEN: usize = 1074;
 const BLS12_381_G1_LEN: usize = 48;
@@ -30,7 +31,10 @@ pub fn reconstruct_close_wallet_bls12(
     cust_bal: u32,
     merch_bal: u32,
 ) -> Wallet<Bls12> {
-    let channelId = channel_token.compute_channel_id();
+    // reconstruct_channel_token_bls12 always sets pk_c
+    let channelId = channel_token
+        .compute_channel_id()
+        .expect("channel token has no customer public key");
     let nonce = util::encode_short_bytes_to_fr::<Bls12>(nonce.0);
     let rl = util::hash_to_fr::<Bls12>(rev_lock.0.to_vec());
 
@@ -40,6 +44,7 @@ pub fn reconstruct_close_wallet_bls12(
         rev_lock: rl,
         bc: cust_bal as i64,
         bm: merch_bal as i64,
+        pending: None,
     };
 }
 
@@ -137,31 +142,69 @@ pub fn reconstruct_channel_token_bls12(channel_token: &Vec<u8>) -> BoltResult<Ch
 }
 
 ///
-/// Used in open-channel WTP for validating that a close_token is a valid signature
+/// Used in close WTP for validating a customer close message given in compact form:
+/// the closing state (rev_lock and balances), the close token and the customer signature.
+/// The compact form has no pending amount, so close messages on a state with a conditional
+/// payment do not verify here (use zkproofs::settle_cust_close instead)
 ///
-// pub fn tze_verify_cust_close_message(
-//     channel_token: &ChannelToken<Bls12>,
-//     wpk: &secp256k1::PublicKey,
-//     close_msg: &Wallet<Bls12>,
-//     close_token: &cl::Signature<Bls12>,
-// ) -> bool {
-//     // close_msg => <pkc> || <wpk> || <balance-cust> || <balance-merch> || CLOSE
-//     // close_token = regular CL signature on close_msg
-//     // channel_token => <pk_c, CL_PK_m, pk_m, mpk, comParams>
-
-//     // (1) check that channel token and close msg are consistent (e.g., close_msg.channelId == H(channel_token.pk_c) &&
-//     let chan_token_cid = channel_token.compute_channel_id(); // util::hash_pubkey_to_fr::<Bls12>(&pk_c);
-//     let chan_token_wpk = util::hash_pubkey_to_fr::<Bls12>(&wpk);
-
-//     let cid_thesame = (close_msg.channelId == chan_token_cid);
-//     // (2) check that wpk matches what's in the close msg
-//     let wpk_thesame = (close_msg.wpk == chan_token_wpk);
-//     return cid_thesame
-//         && wpk_thesame
-//         && channel_token
-//             .cl_pk_m
-//             .verify(&channel_token.mpk, &close_msg.as_fr_vec(), &close_token);
-// }
+pub fn tze_verify_cust_close_message(
+    channel_token: &ChannelToken<Bls12>,
+    rev_lock: &FixedSizeArray32,
+    cust_bal: u32,
+    merch_bal: u32,
+    close_token: &cl::Signature<Bls12>,
+    cust_sig: &secp256k1::Signature,
+) -> bool {
+    // the close message carries the rev_lock as the field element it encodes (not hashed, as
+    // in reconstruct_close_wallet_bls12) and no nonce
+    let channelId = match channel_token.compute_channel_id() {
+        Ok(id) => id,
+        Err(_) => return false,
+    };
+    let rl = match util::rev_lock_to_fr::<Bls12>(&rev_lock.0) {
+        Some(fr) => fr,
+        None => return false,
+    };
+    let close_msg = Wallet {
+        channelId,
+        nonce: util::encode_short_bytes_to_fr::<Bls12>([0u8; 16]),
+        rev_lock: rl,
+        bc: cust_bal as i64,
+        bm: merch_bal as i64,
+        pending: None,
+    };
+    let cust_close = ChannelcloseC {
+        version: zkproofs::CUST_CLOSE_MESSAGE_VERSION,
+        message: close_msg,
+        merch_signature: close_token.clone(),
+        cust_signature: cust_sig.clone(),
+    };
+    zkproofs::tze_verify_cust_close_message(channel_token, &cust_close)
+}
+
+///
+/// Used in merch-close WTP for validating that the rev_secret opens the rev_lock
+/// of the customer close message. The rev_lock is sha3(rev_secret) as is (the
+/// big-endian encoding of the field element, as in tze_verify_cust_close_message),
+/// the same convention as zkproofs::tze_verify_revoke_message
+///
+pub fn tze_verify_revoke_message(
+    rev_lock: &FixedSizeArray32,
+    rev_secret: &FixedSizeArray32,
+) -> bool {
+    util::sha3_hash_to_slice(&rev_secret.0.to_vec()) == rev_lock.0
+}
+
+///
+/// Used in merch-close WTP for validating that merch_sig is a valid signature under <merch_pk>
+/// on <dest_addr || revoke-token> message
+///
+pub fn tze_verify_merch_close_message(
+    channel_token: &ChannelToken<Bls12>,
+    merch_close: &ChannelcloseM,
+) -> bool {
+    zkproofs::tze_verify_merch_close_message(channel_token, merch_close)
+}
 
 pub fn tze_generate_secp_signature(seckey: &[u8; 32], msg: &[u8; 32]) -> Vec<u8> {
     let secp = secp256k1::Secp256k1::signing_only();
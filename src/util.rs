@@ -231,6 +231,23 @@ impl RevokedMessage {
     }
 }
 
+// inverse of hash_secret_to_fr: the rev_lock bytes are the big-endian encoding of the field element
+pub fn rev_lock_to_fr<E: Engine>(rev_lock: &[u8; 32]) -> Option<E::Fr> {
+    let fr = E::Fr::from_str(&BigUint::from_bytes_be(&rev_lock[..]).to_string())?;
+    let fr_str = format!("{}", fr.into_repr());
+    match fr_str[2..] == hex::encode(&rev_lock) {
+        true => Some(fr),
+        false => None,
+    }
+}
+
+pub fn get_unix_time() -> i64 {
+    match std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH) {
+        Ok(d) => d.as_secs() as i64,
+        Err(_) => 0,
+    }
+}
+
 pub fn hmac_sign(key: Vec<u8>, message: &Vec<u8>) -> [u8; 32] {
     let mut mac = HmacSha256::new_varkey(&key).expect("HMAC can take key of any size");
     mac.input(message);
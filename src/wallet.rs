@@ -1,4 +1,5 @@
 use super::*;
+use channels_util::HashLock;
 use ff::PrimeField;
 use pairing::Engine;
 use std::fmt;
@@ -14,6 +15,28 @@ pub struct Wallet<E: Engine> {
     pub rev_lock: E::Fr,
     pub bc: i64,
     pub bm: i64,
+    // amount of a conditional payment that is not final yet (already moved from bc to bm)
+    #[serde(default)]
+    pub pending: Option<PendingAmount>,
+}
+
+// an amount held by a hash lock: the close balances depend on whether
+// the preimage is revealed (claim) or the lock expires first (expire)
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PendingAmount {
+    pub amount: i64,
+    pub hash_lock: HashLock,
+}
+
+impl PendingAmount {
+    pub fn close_tag<E: Engine>(&self) -> E::Fr {
+        let mut m = Vec::new();
+        m.extend("conditional close".as_bytes());
+        m.extend(&self.amount.to_be_bytes());
+        m.extend(&self.hash_lock.hash.0);
+        m.extend(&self.hash_lock.expiry.to_be_bytes());
+        util::hash_to_fr::<E>(m)
+    }
 }
 
 impl<E: Engine> Wallet<E> {
@@ -27,15 +50,46 @@ impl<E: Engine> Wallet<E> {
         ]
     }
     pub fn as_fr_vec_bar(&self) -> Vec<E::Fr> {
-        let close_prefix = util::hash_to_fr::<E>("close".as_bytes().to_vec());
         vec![
             self.channelId,
             self.rev_lock,
             E::Fr::from_str(&self.bc.to_string()).unwrap(),
             E::Fr::from_str(&self.bm.to_string()).unwrap(),
-            close_prefix,
+            self.close_tag(),
         ]
     }
+    // the last element of the close wallet: binds the pending amount (if any) to the close token
+    pub fn close_tag(&self) -> E::Fr {
+        match &self.pending {
+            Some(p) => p.close_tag::<E>(),
+            None => util::hash_to_fr::<E>("close".as_bytes().to_vec()),
+        }
+    }
+    // balances (bc, bm) paid out on close. With a pending amount, the claim branch
+    // (bc, bm) applies if a valid preimage is given, otherwise the expire branch
+    // (bc + amount, bm - amount) once the hash lock has expired at time now.
+    pub fn close_balances(
+        &self,
+        preimage: Option<&[u8; 32]>,
+        now: i64,
+    ) -> Result<(i64, i64), String> {
+        let p = match &self.pending {
+            Some(p) => p,
+            None => return Ok((self.bc, self.bm)),
+        };
+        if let Some(preimage) = preimage {
+            if !p.hash_lock.is_unlocked_by(preimage) {
+                return Err(String::from("invalid preimage for hash lock"));
+            }
+            return Ok((self.bc, self.bm));
+        }
+        if !p.hash_lock.is_expired(now) {
+            return Err(String::from(
+                "pending amount can only be settled with the preimage until the hash lock expires",
+            ));
+        }
+        Ok((self.bc + p.amount, self.bm - p.amount))
+    }
 }
 
 impl<E: Engine> fmt::Display for Wallet<E> {
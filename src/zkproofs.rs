@@ -1,28 +1,28 @@
 use super::*;
 use cl;
 use ff::PrimeField;
-use pairing::Engine;
+use pairing::{CurveProjective, Engine};
 use rand::Rng;
 use wallet;
 // for blind signature
 use secp256k1;
-// for on-chain keys
-use HashMap;
 
-use channels_util::ProtocolStatus;
+pub use channels_util::HashLock;
+use channels_util::{PaymentStatus, ProtocolStatus};
 use channels_zk::ClosedCommitments;
 pub use channels_zk::{
     BoltError, ChannelParams, ChannelState, ChannelToken, ChannelcloseM, CustomerState,
-    MerchantState, ResultBoltType, RevLockPair,
+    MerchantState, PaymentTranscript, ResultBoltType, RevLockPair,
 };
 pub use cl::PublicParams;
 pub use cl::{PartialProducts, PublicKey, Signature};
+use database::{ConditionalPayment, StateDatabase};
 pub use nizk::NIZKProof;
 pub use ped92::Commitment;
 pub use ped92::CommitmentProof;
 use serde::{Deserialize, Serialize};
 use util::{encode_short_bytes_to_fr, sha3_hash_to_slice};
-pub use wallet::{serialize_compact, Wallet};
+pub use wallet::{serialize_compact, PendingAmount, Wallet};
 use zkchan_tx::fixed_size_array::{FixedSizeArray16, FixedSizeArray32};
 
 ////////////////////////////////// Utilities //////////////////////////////////
@@ -69,12 +69,47 @@ impl fmt::Display for TransactionFeeInfo {
                          <E as pairing::Engine>::G1: serde::Deserialize<'de>")
 )]
 pub struct ChannelcloseC<E: Engine> {
+    // close messages created before versioning was introduced deserialize as version 0
+    #[serde(default)]
+    pub version: u8,
     pub message: wallet::Wallet<E>,
     pub merch_signature: cl::Signature<E>,
     pub cust_signature: secp256k1::Signature,
     // pub pp: PartialProducts<E>, // for debug purposes
 }
 
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(bound(serialize = "<E as ff::ScalarEngine>::Fr: serde::Serialize, \
+                           <E as pairing::Engine>::G1: serde::Serialize"))]
+#[serde(
+    bound(deserialize = "<E as ff::ScalarEngine>::Fr: serde::Deserialize<'de>, \
+                         <E as pairing::Engine>::G1: serde::Deserialize<'de>")
+)]
+pub struct ChannelMutualClose<E: Engine> {
+    pub message: wallet::Wallet<E>,
+    pub nonce: FixedSizeArray16,
+    // pay token on the current state (proves the balances to the merchant)
+    pub pay_token: cl::Signature<E>,
+    pub cust_signature: secp256k1::Signature,
+    pub merch_signature: Option<secp256k1::Signature>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(bound(serialize = "<E as ff::ScalarEngine>::Fr: serde::Serialize, \
+                           <E as pairing::Engine>::G2: serde::Serialize, \
+                           <E as pairing::Engine>::G1: serde::Serialize"))]
+#[serde(
+    bound(deserialize = "<E as ff::ScalarEngine>::Fr: serde::Deserialize<'de>, \
+                         <E as pairing::Engine>::G2: serde::Deserialize<'de>,\
+                         <E as pairing::Engine>::G1: serde::Deserialize<'de>")
+)]
+pub struct MerchantStallEvidence<E: Engine> {
+    // close message on the new state (signed with the new close token)
+    pub cust_close: ChannelcloseC<E>,
+    // payment round in which the merchant withheld the pay token
+    pub transcript: PaymentTranscript<E>,
+}
+
 #[derive(Clone, Serialize, Deserialize)]
 #[serde(bound(serialize = "<E as ff::ScalarEngine>::Fr: serde::Serialize, \
                            <E as pairing::Engine>::G1: serde::Serialize, \
@@ -91,7 +126,9 @@ pub struct Payment<E: Engine> {
     coms: ClosedCommitments<E>,
     nonce: FixedSizeArray16,
     rev_lock: FixedSizeArray32,
+    // set to 0 when the amount is hidden behind amount_com
     amount: i64,
+    amount_com: Option<Commitment<E>>,
 }
 
 ///
@@ -100,13 +137,14 @@ pub struct Payment<E: Engine> {
 ///
 pub fn merchant_init<'a, R: Rng, E: Engine>(
     csprng: &mut R,
+    db_url: String,
     channel_state: &mut ChannelState<E>,
     name: &'a str,
 ) -> (ChannelToken<E>, MerchantState<E>, ChannelState<E>) {
     // create new merchant state
     let merch_name = String::from(name);
     let (mut merch_state, mut channel_state) =
-        MerchantState::<E>::new(csprng, channel_state, merch_name);
+        MerchantState::<E>::new(csprng, db_url, channel_state, merch_name);
     // initialize the merchant state
     let channel_token = merch_state.init(&mut channel_state);
 
@@ -207,13 +245,12 @@ pub mod activate {
     ///
     pub fn merchant_init<R: Rng, E: Engine>(
         csprng: &mut R,
+        db: &mut dyn StateDatabase,
         init_state: &Wallet<E>,
         merch_state: &mut MerchantState<E>,
-    ) -> cl::Signature<E> {
-        merch_state
-            .unlink_nonces
-            .insert(init_state.nonce.to_string());
-        merch_state.issue_init_pay_token(csprng, init_state)
+    ) -> Result<cl::Signature<E>, String> {
+        db.update_unlink_set(&init_state.nonce.to_string())?;
+        Ok(merch_state.issue_init_pay_token(csprng, init_state))
     }
 
     ///
@@ -249,24 +286,25 @@ pub mod unlink {
     ///
     /// unlink::customer_update_state() - takes as input the public params, channel state, channel token,
     /// merchant public keys, current customer state.
-    /// output: session id, payment proof and new customer state
+    /// output: session id, payment proof and new customer state, or error if the customer
+    /// has no pay token for its current state
     ///
     pub fn customer_update_state<R: Rng, E: Engine>(
         csprng: &mut R,
         channel_state: &ChannelState<E>,
         cust_state: &CustomerState<E>,
-    ) -> ([u8; 16], Payment<E>, CustomerState<E>) {
+    ) -> Result<([u8; 16], Payment<E>, CustomerState<E>), String> {
         // unlink payment of amount 0 (to avoid tx fees on the channel we start with an amount of -tx_fee)
         let (payment, new_cust_state) = pay::customer_update_state(
             csprng,
             channel_state,
             cust_state,
             -channel_state.get_channel_fee(),
-        );
+        )?;
         // pick new session ID
         let mut session_id = [0u8; 16];
         csprng.fill_bytes(&mut session_id);
-        return (session_id, payment, new_cust_state);
+        return Ok((session_id, payment, new_cust_state));
     }
 
     ///
@@ -276,22 +314,24 @@ pub mod unlink {
     ///
     pub fn merchant_update_state<R: Rng, E: Engine>(
         csprng: &mut R,
+        db: &mut dyn StateDatabase,
         channel_state: &ChannelState<E>,
         session_id: &[u8; 16],
         payment: &Payment<E>,
         merch_state: &mut MerchantState<E>,
-    ) -> BoltResult<cl::Signature<E>> {
-        if merch_state
-            .unlink_nonces
-            .contains(&encode_short_bytes_to_fr::<E>(payment.nonce.0).to_string())
-        {
+    ) -> BoltResult<cl::Signature<E>>
+    where
+        <E as pairing::Engine>::G1: serde::Serialize,
+    {
+        if db.is_member_unlink_set(&encode_short_bytes_to_fr::<E>(payment.nonce.0).to_string()) {
             Ok(Some(pay::merchant_update_state(
                 csprng,
+                db,
                 channel_state,
                 session_id,
                 payment,
                 merch_state,
-            )))
+            )?))
         } else {
             Err(String::from(
                 "unlink::merchant_update_state - The nonce is not a valid unlink nonce.",
@@ -366,14 +406,16 @@ pub mod pay {
     /// output: true or false if the payment would be successful
     ///
     pub fn merchant_prepare<E: Engine>(
+        db: &mut dyn StateDatabase,
         _session_id: &[u8; 16],
         nonce: FixedSizeArray16,
         amount: i64,
-        merch_state: &mut MerchantState<E>,
+        _merch_state: &mut MerchantState<E>,
     ) -> bool {
-        if !merch_state.spent_nonces.contains(&nonce.to_string()) && amount != 0 {
-            merch_state.spent_nonces.insert(nonce.to_string());
-            return true;
+        let nonce_hex = hex::encode(nonce.0);
+        if amount != 0 && !db.check_spent_map(&nonce_hex) {
+            // the rev_lock for this nonce is recorded once it is revealed in merchant_update_state()
+            return db.update_spent_map(&nonce_hex, &String::new()).is_ok();
         }
         return false;
     }
@@ -382,21 +424,23 @@ pub mod pay {
     /// pay::customer_update_state() - takes as input the public params, channel state, channel token,
     /// merchant public keys, old wallet and balance increment. Generate a new wallet commitment
     /// PoK of the committed values in new wallet and PoK of old wallet. Return new channel token,
-    /// new wallet (minus blind signature and refund token) and payment proof.
+    /// new wallet (minus blind signature and refund token) and payment proof, or error if the
+    /// customer has no pay token for its current state.
     ///
     pub fn customer_update_state<R: Rng, E: Engine>(
         csprng: &mut R,
         channel_state: &ChannelState<E>,
         cust_state: &CustomerState<E>,
         amount: i64,
-    ) -> (Payment<E>, CustomerState<E>) {
+    ) -> Result<(Payment<E>, CustomerState<E>), String> {
         let tx_fee = channel_state.get_channel_fee();
         let payment_amount = match tx_fee > 0 {
             true => amount + tx_fee,
             false => amount,
         };
-        let (proof, coms, nonce, rev_lock, new_cust_state) =
-            cust_state.generate_payment(csprng, &channel_state, payment_amount);
+        let (proof, coms, nonce, rev_lock, new_cust_state) = cust_state
+            .generate_payment(csprng, &channel_state, payment_amount)
+            .map_err(|e| e.to_string())?;
 
         let payment = Payment {
             proof,
@@ -404,22 +448,127 @@ pub mod pay {
             nonce,
             rev_lock,
             amount,
+            amount_com: None,
+        };
+        return Ok((payment, new_cust_state));
+    }
+
+    ///
+    /// pay::customer_update_state_conditional() - same as customer_update_state() but the payment
+    /// amount (including the channel fee) is held by the hash lock in the new wallet. The close
+    /// token on the new state settles the pending amount to the merchant if the preimage is
+    /// revealed and back to the customer once the hash lock expires.
+    ///
+    pub fn customer_update_state_conditional<R: Rng, E: Engine>(
+        csprng: &mut R,
+        channel_state: &ChannelState<E>,
+        cust_state: &CustomerState<E>,
+        amount: i64,
+        hash_lock: HashLock,
+    ) -> Result<(Payment<E>, CustomerState<E>), String> {
+        let tx_fee = channel_state.get_channel_fee();
+        let payment_amount = match tx_fee > 0 {
+            true => amount + tx_fee,
+            false => amount,
+        };
+        let (proof, coms, nonce, rev_lock, new_cust_state) = cust_state
+            .generate_conditional_payment(csprng, &channel_state, payment_amount, hash_lock)
+            .map_err(|e| e.to_string())?;
+
+        let payment = Payment {
+            proof,
+            coms,
+            nonce,
+            rev_lock,
+            amount,
+            amount_com: None,
+        };
+        return Ok((payment, new_cust_state));
+    }
+
+    ///
+    /// pay::generate_amount_tau() - generates the blinding value shared by the sender and the
+    /// receiver of a payment through an intermediary (see customer_update_state_hidden()). One
+    /// of them generates it and sends it to the other directly, never via the intermediary.
+    ///
+    pub fn generate_amount_tau<R: Rng, E: Engine>(csprng: &mut R) -> E::Fr {
+        E::Fr::rand(csprng)
+    }
+
+    ///
+    /// pay::customer_update_state_hidden() - same as customer_update_state() but for a payment
+    /// routed through an intermediary. Instead of revealing the amount, the payment carries a
+    /// commitment to it. The sender pays a positive amount and the receiver the negated amount.
+    /// Both pass the same amount_tau, a random blinding value they agree on out of band (see
+    /// generate_amount_tau()) and keep from the intermediary. The sender's commitment uses
+    /// amount_tau and the receiver's its negation, so that the two commitments offset each
+    /// other. The sender's payment also proves that its amount is not negative.
+    ///
+    pub fn customer_update_state_hidden<R: Rng, E: Engine>(
+        csprng: &mut R,
+        channel_state: &ChannelState<E>,
+        cust_state: &CustomerState<E>,
+        amount: i64,
+        amount_tau: E::Fr,
+    ) -> Result<(Payment<E>, CustomerState<E>), String> {
+        let tx_fee = channel_state.get_channel_fee();
+        let payment_amount = match tx_fee > 0 {
+            true => amount + tx_fee,
+            false => amount,
+        };
+        // only the sender proves the direction of the payment, see multi_customer_update_state()
+        let (amount_tau, amount_floor) = match amount {
+            a if a > 0 => (amount_tau, Some(payment_amount - amount)),
+            a if a < 0 => {
+                let mut neg_amount_tau = amount_tau;
+                neg_amount_tau.negate();
+                (neg_amount_tau, None)
+            }
+            _ => return Err(String::from("hidden payments must have a nonzero amount")),
         };
-        return (payment, new_cust_state);
+        let (proof, coms, nonce, rev_lock, new_cust_state) = cust_state
+            .generate_hidden_payment(
+                csprng,
+                &channel_state,
+                payment_amount,
+                amount_tau,
+                amount_floor,
+            )
+            .map_err(|e| e.to_string())?;
+
+        let cp = channel_state.cp.as_ref().unwrap();
+        let amount_com = cp.pub_params.comParams.commit(
+            &vec![util::convert_int_to_fr::<E>(payment_amount)],
+            &amount_tau,
+        );
+        let payment = Payment {
+            proof,
+            coms,
+            nonce,
+            rev_lock,
+            amount: 0,
+            amount_com: Some(amount_com),
+        };
+        return Ok((payment, new_cust_state));
     }
 
     ///
     /// pay::merchant_update_state() - takes as input the public params, channel state, payment proof
     /// and merchant keys. If proof is valid, then merchant returns the refund token
     /// (i.e., partially blind signature on IOU with updated balance)
+    /// output: the close token, or error if the proof is invalid or the db update fails
     ///
     pub fn merchant_update_state<R: Rng, E: Engine>(
         csprng: &mut R,
+        db: &mut dyn StateDatabase,
         channel_state: &ChannelState<E>,
         _session_id: &[u8; 16],
         payment: &Payment<E>,
         merch_state: &mut MerchantState<E>,
-    ) -> cl::Signature<E> {
+    ) -> Result<cl::Signature<E>, String>
+    where
+        <E as pairing::Engine>::G1: serde::Serialize,
+    {
         // if payment proof verifies, then returns close-token and records wpk => pay-token
         // if valid revoke_token is provided later for wpk, then release pay-token
         let tx_fee = channel_state.get_channel_fee();
@@ -427,66 +576,259 @@ pub mod pay {
             true => payment.amount + tx_fee,
             false => payment.amount,
         };
-        let new_close_token = merch_state
-            .verify_payment(
-                csprng,
-                &channel_state,
-                &payment.proof,
-                &payment.coms,
-                &payment.nonce,
-                &payment.rev_lock,
-                payment_amount,
-            )
-            .unwrap();
+        let new_close_token = match merch_state.verify_payment(
+            csprng,
+            db,
+            &channel_state,
+            &payment.proof,
+            &payment.coms,
+            &payment.nonce,
+            &payment.rev_lock,
+            payment_amount,
+        ) {
+            Ok(n) => n,
+            Err(err) => return Err(err.to_string()),
+        };
         // store the rev_lock since it has been revealed
-        update_merchant_state(&mut merch_state.keys, &payment.rev_lock, None);
-        return new_close_token;
+        db.update_spent_map(
+            &hex::encode(payment.nonce.0),
+            &hex::encode(&payment.rev_lock),
+        )?;
+        update_merchant_state(db, &payment.rev_lock, None)?;
+        Ok(new_close_token)
     }
 
     ///
-    /// Verify third party payment proof from two bi-directional channel payments with intermediary (payment amount
+    /// pay::merchant_update_state_conditional() - same as merchant_update_state() but the payment
+    /// amount is pending in the new wallet until the preimage of the hash lock is revealed.
+    /// The close token on the new state is returned right away: it only pays the merchant with
+    /// the preimage. The pay token is held back until pay::merchant_claim_conditional(), so the
+    /// customer cannot pay from the new state while the payment is pending. A second pay token,
+    /// on the balances with the pending amount back with the customer, is held for
+    /// pay::merchant_expire_conditional().
+    /// output: the close token, or error if the proof is invalid or the db update fails
+    ///
+    pub fn merchant_update_state_conditional<R: Rng, E: Engine>(
+        csprng: &mut R,
+        db: &mut dyn StateDatabase,
+        channel_state: &ChannelState<E>,
+        _session_id: &[u8; 16],
+        payment: &Payment<E>,
+        hash_lock: &HashLock,
+        merch_state: &mut MerchantState<E>,
+    ) -> Result<cl::Signature<E>, String>
+    where
+        <E as pairing::Engine>::G1: serde::Serialize,
+    {
+        if hash_lock.is_expired(util::get_unix_time()) {
+            return Err(String::from("hash lock has already expired"));
+        }
+        let tx_fee = channel_state.get_channel_fee();
+        let payment_amount = match tx_fee > 0 {
+            true => payment.amount + tx_fee,
+            false => payment.amount,
+        };
+        let pending = PendingAmount {
+            amount: payment_amount,
+            hash_lock: hash_lock.clone(),
+        };
+        let (new_close_token, expire_pay_token) = match merch_state.verify_conditional_payment(
+            csprng,
+            db,
+            &channel_state,
+            &payment.proof,
+            &payment.coms,
+            &payment.nonce,
+            &payment.rev_lock,
+            payment_amount,
+            &pending,
+        ) {
+            Ok(n) => n,
+            Err(err) => return Err(err.to_string()),
+        };
+
+        let conditional_payment = ConditionalPayment {
+            hash_lock: hash_lock.clone(),
+            nonce: payment.nonce,
+            expire_pay_token: handle_error_util!(serde_json::to_string(&expire_pay_token)),
+        };
+        let rev_lock_hex = hex::encode(&payment.rev_lock);
+        db.update_conditional_payment_map(&rev_lock_hex, &conditional_payment)?;
+        // store the rev_lock since it has been revealed
+        db.update_spent_map(&hex::encode(payment.nonce.0), &rev_lock_hex)?;
+        update_merchant_state(db, &payment.rev_lock, None)?;
+        Ok(new_close_token)
+    }
+
+    ///
+    /// pay::merchant_claim_conditional() - takes as input the rev_lock revealed in the payment,
+    /// the preimage of the hash lock and the merchant state. The customer must have revoked the
+    /// old state with pay::merchant_validate_rev_lock() first.
+    /// output: the pay token on the new state if the preimage is valid and the hash lock has not expired
+    ///
+    pub fn merchant_claim_conditional<E: Engine>(
+        db: &mut dyn StateDatabase,
+        rev_lock: &FixedSizeArray32,
+        preimage: &[u8; 32],
+        _merch_state: &mut MerchantState<E>,
+    ) -> BoltResult<cl::Signature<E>>
+    where
+        <E as pairing::Engine>::G1: for<'de> serde::Deserialize<'de>,
+    {
+        let rev_lock_hex = hex::encode(&rev_lock);
+        let conditional_payment = db.get_conditional_payment(&rev_lock_hex)?;
+        if conditional_payment
+            .hash_lock
+            .is_expired(util::get_unix_time())
+        {
+            return Err(String::from("hash lock has expired"));
+        }
+        if !conditional_payment.hash_lock.is_unlocked_by(preimage) {
+            return Err(String::from("invalid preimage for hash lock"));
+        }
+        if db.get_rev_secret(&rev_lock_hex)? == "" {
+            return Err(String::from("old state has not been revoked"));
+        }
+        let pay_token_json = db.get_pay_token(&rev_lock_hex)?;
+        let pay_token: cl::Signature<E> = handle_error_util!(serde_json::from_str(&pay_token_json));
+        db.remove_from_conditional_payment_map(&rev_lock_hex);
+        Ok(Some(pay_token))
+    }
+
+    ///
+    /// pay::merchant_expire_conditional() - takes as input the rev_lock revealed in the payment
+    /// and the merchant state. Once the hash lock has expired, the pay token on the claim balances
+    /// is dropped. If the customer has revoked the old state, the pay token on the expire balances
+    /// (pending amount back with the customer) is released instead, so the customer can keep
+    /// paying with pay::customer_unmask_expired_pay_token(). Otherwise the nonce of the old state
+    /// can be spent again.
+    /// output: the expire pay token if the old state was revoked, or error if the payment can
+    /// still be claimed
+    ///
+    pub fn merchant_expire_conditional<E: Engine>(
+        db: &mut dyn StateDatabase,
+        rev_lock: &FixedSizeArray32,
+        _merch_state: &mut MerchantState<E>,
+    ) -> BoltResult<cl::Signature<E>>
+    where
+        <E as pairing::Engine>::G1: for<'de> serde::Deserialize<'de>,
+    {
+        let rev_lock_hex = hex::encode(&rev_lock);
+        let conditional_payment = db.get_conditional_payment(&rev_lock_hex)?;
+        if !conditional_payment
+            .hash_lock
+            .is_expired(util::get_unix_time())
+        {
+            return Err(String::from("hash lock has not expired"));
+        }
+        let revoked = db.get_rev_secret(&rev_lock_hex)? != "";
+        if !revoked {
+            db.remove_from_spent_map(&hex::encode(conditional_payment.nonce.0));
+        }
+        // payments held before the expire pay token was recorded have none to release
+        let expire_pay_token: Option<cl::Signature<E>> =
+            match revoked && conditional_payment.expire_pay_token != "" {
+                true => Some(handle_error_util!(serde_json::from_str(
+                    &conditional_payment.expire_pay_token
+                ))),
+                false => None,
+            };
+        db.remove_from_pay_token_map(&rev_lock_hex);
+        db.remove_from_conditional_payment_map(&rev_lock_hex);
+        Ok(expire_pay_token)
+    }
+
+    ///
+    /// pay::multi_customer_update_state() - verify third party payment proofs from two
+    /// bi-directional channel payments with an intermediary. The payment amounts stay hidden:
+    /// the intermediary checks that the two amount commitments offset each other (up to the
+    /// channel fee on each payment), that each proof is valid for its commitment, and that the
+    /// sender's proof shows its amount (without the fee) is not negative. Together, the sender
+    /// is debited exactly what the receiver is credited. Both proofs are checked before tokens
+    /// are issued for either payment.
     ///
     pub fn multi_customer_update_state<R: Rng, E: Engine>(
         csprng: &mut R,
+        db: &mut dyn StateDatabase,
         channel_state: &ChannelState<E>,
         sender_payment: &Payment<E>,
         receiver_payment: &Payment<E>,
         merch_state: &mut MerchantState<E>,
-    ) -> BoltResult<(cl::Signature<E>, cl::Signature<E>)> {
+    ) -> BoltResult<(cl::Signature<E>, cl::Signature<E>)>
+    where
+        <E as pairing::Engine>::G1: serde::Serialize,
+    {
+        let (sender_com, receiver_com) =
+            match (&sender_payment.amount_com, &receiver_payment.amount_com) {
+                (Some(s), Some(r)) => (s, r),
+                _ => return Err(String::from("payments are missing an amount commitment")),
+            };
+
+        // the blinding values cancel out, so the sum commits to both fees with no randomness
         let tx_fee = channel_state.get_channel_fee();
-        let amount = sender_payment.amount + receiver_payment.amount;
-        if amount != 0 {
-            // we want to check this relation in ZK without knowing the amount
+        let cp = channel_state.cp.as_ref().unwrap();
+        let fees_com = cp.pub_params.comParams.commit(
+            &vec![util::convert_int_to_fr::<E>(2 * tx_fee)],
+            &E::Fr::zero(),
+        );
+        let mut sum = sender_com.c.clone();
+        sum.add_assign(&receiver_com.c);
+        if sum != fees_com.c {
             return Err(String::from("payments do not offset"));
         }
 
+        let sender_nonce = hex::encode(sender_payment.nonce.0);
+        let receiver_nonce = hex::encode(receiver_payment.nonce.0);
+        if sender_nonce == receiver_nonce
+            || db.check_spent_map(&sender_nonce)
+            || db.check_spent_map(&receiver_nonce)
+        {
+            return Err(String::from("nonce has already been spent"));
+        }
+
+        // the sender proves its amount is at least the fee, i.e. that it is debited
+        let sender_result = merch_state.verify_hidden_payment(
+            &sender_payment.proof,
+            &sender_payment.coms,
+            &sender_payment.nonce,
+            sender_com,
+            Some(tx_fee),
+        );
+        let receiver_result = merch_state.verify_hidden_payment(
+            &receiver_payment.proof,
+            &receiver_payment.coms,
+            &receiver_payment.nonce,
+            receiver_com,
+            None,
+        );
+        if let Err(err) = sender_result.and(receiver_result) {
+            return Err(err.to_string());
+        }
+
         let new_close_token = merch_state
-            .verify_payment(
+            .issue_payment_tokens(
                 csprng,
+                db,
                 &channel_state,
-                &sender_payment.proof,
                 &sender_payment.coms,
-                &sender_payment.nonce,
                 &sender_payment.rev_lock,
-                sender_payment.amount + tx_fee,
             )
-            .unwrap();
-
+            .map_err(|e| e.to_string())?;
         let cond_close_token = merch_state
-            .verify_payment(
+            .issue_payment_tokens(
                 csprng,
+                db,
                 &channel_state,
-                &receiver_payment.proof,
                 &receiver_payment.coms,
-                &receiver_payment.nonce,
                 &receiver_payment.rev_lock,
-                receiver_payment.amount + tx_fee,
             )
-            .unwrap();
+            .map_err(|e| e.to_string())?;
 
-        // store the wpk since it has been revealed
-        update_merchant_state(&mut merch_state.keys, &sender_payment.rev_lock, None);
-        update_merchant_state(&mut merch_state.keys, &receiver_payment.rev_lock, None);
+        // store the rev_locks since they have been revealed
+        db.update_spent_map(&sender_nonce, &hex::encode(&sender_payment.rev_lock))?;
+        db.update_spent_map(&receiver_nonce, &hex::encode(&receiver_payment.rev_lock))?;
+        update_merchant_state(db, &sender_payment.rev_lock, None)?;
+        update_merchant_state(db, &receiver_payment.rev_lock, None)?;
 
         return Ok(Some((new_close_token, cond_close_token)));
     }
@@ -521,27 +863,36 @@ pub mod pay {
     /// generate a new signature for the new wallet (from the PoK of committed values in new wallet).
     ///
     pub fn merchant_validate_rev_lock<E: Engine>(
+        db: &mut dyn StateDatabase,
         _session_id: &[u8; 16],
         rt: &RevLockPair,
         merch_state: &mut MerchantState<E>,
-    ) -> BoltResult<cl::Signature<E>> {
-        if merch_state.keys.contains_key(&hex::encode(&rt.rev_lock))
-            && merch_state.keys.get(&hex::encode(&rt.rev_lock)).unwrap() != ""
-        {
+    ) -> BoltResult<cl::Signature<E>>
+    where
+        <E as pairing::Engine>::G1: for<'de> serde::Deserialize<'de>,
+    {
+        let rev_lock_hex = hex::encode(&rt.rev_lock);
+        if db.check_rev_lock_map(&rev_lock_hex) && db.get_rev_secret(&rev_lock_hex)? != "" {
             return Err(String::from(
                 "pay::merchant_validate_rev_lock() - revocation lock is already known to merchant",
             ));
         }
-        let pay_token_result = merch_state.verify_revoke_message(&rt.rev_lock, &rt.rev_secret);
+        if db.get_conditional_payment(&rev_lock_hex).is_ok() {
+            // record the revocation, the pay token is released by merchant_claim_conditional()
+            if sha3_hash_to_slice(&rt.rev_secret.0.to_vec()) != rt.rev_lock.0 {
+                return Err(String::from(
+                    "pay::merchant_validate_rev_lock() - Failed to verify the rev_lock/rev_secret pair!",
+                ));
+            }
+            update_merchant_state(db, &rt.rev_lock, Some(rt.rev_secret.clone()))?;
+            return Ok(None);
+        }
+        let pay_token_result = merch_state.verify_revoke_message(db, &rt.rev_lock, &rt.rev_secret);
         let new_pay_token = match pay_token_result {
             Ok(n) => n,
             Err(err) => return Err(String::from(err.to_string())),
         };
-        update_merchant_state(
-            &mut merch_state.keys,
-            &rt.rev_lock,
-            Some(rt.rev_secret.clone()),
-        );
+        update_merchant_state(db, &rt.rev_lock, Some(rt.rev_secret.clone()))?;
         Ok(Some(new_pay_token))
     }
 
@@ -558,6 +909,64 @@ pub mod pay {
         return Ok(cust_state.pay_unmask_customer(&channel_state, &pay_token));
     }
 
+    ///
+    /// pay::customer_unmask_expired_pay_token() - takes as input the pay token released by
+    /// pay::merchant_expire_conditional() and the customer state. Verify the pay token on the
+    /// expire balances and store if true. The customer pays from those balances afterwards.
+    /// output: success boolean
+    ///
+    pub fn customer_unmask_expired_pay_token<E: Engine>(
+        pay_token: cl::Signature<E>,
+        channel_state: &ChannelState<E>,
+        cust_state: &mut CustomerState<E>,
+    ) -> Result<bool, String> {
+        return Ok(cust_state.pay_unmask_expired_customer(&channel_state, &pay_token));
+    }
+
+    ///
+    /// pay::customer_force_close_on_stall() - takes as input the channel state and the customer state.
+    /// If the merchant did not release the pay token after the customer revoked the previous state,
+    /// outputs a close message on the new state (with the new close token) together with the
+    /// transcript of the stalled payment round as evidence of the merchant's misbehavior.
+    ///
+    pub fn customer_force_close_on_stall<E: Engine>(
+        channel_state: &ChannelState<E>,
+        cust_state: &CustomerState<E>,
+    ) -> Result<MerchantStallEvidence<E>, BoltError>
+    where
+        <E as pairing::Engine>::G1: serde::Serialize,
+    {
+        // the merchant may also stall during unlink (before the channel is established)
+        if cust_state.protocol_status != ProtocolStatus::Activated
+            && cust_state.protocol_status != ProtocolStatus::Established
+        {
+            return Err(BoltError::new(
+                "pay::customer_force_close_on_stall - invalid protocol status",
+            ));
+        }
+        let transcript = match cust_state.get_last_payment_transcript() {
+            Some(t) => t,
+            None => {
+                return Err(BoltError::new(
+                    "pay::customer_force_close_on_stall - no payment round to report",
+                ))
+            }
+        };
+        if transcript.status != PaymentStatus::Revoked
+            || transcript.wallet.nonce != cust_state.get_wallet().nonce
+        {
+            return Err(BoltError::new(
+                "pay::customer_force_close_on_stall - the merchant did not stall on the current state",
+            ));
+        }
+
+        let cust_close = customer_close_message(channel_state, cust_state)?;
+        Ok(MerchantStallEvidence {
+            cust_close,
+            transcript,
+        })
+    }
+
     ///
     /// pay::multi_merchant_unmask (phase 2) - takes as input revoke messages and signatures
     /// from the sender and receiver and the merchant state of the intermediary.
@@ -565,14 +974,31 @@ pub mod pay {
     /// sender and receiver (from the PoK of committed values in new wallet).
     ///
     pub fn multi_merchant_unmask<E: Engine>(
+        db: &mut dyn StateDatabase,
         rt_sender: &RevLockPair,
         rt_receiver: &RevLockPair,
         merch_state: &mut MerchantState<E>,
-    ) -> BoltResult<(cl::Signature<E>, cl::Signature<E>)> {
+    ) -> BoltResult<(cl::Signature<E>, cl::Signature<E>)>
+    where
+        <E as pairing::Engine>::G1: for<'de> serde::Deserialize<'de>,
+    {
+        for rt in [rt_sender, rt_receiver].iter() {
+            let rev_lock_hex = hex::encode(&rt.rev_lock);
+            if db.check_rev_lock_map(&rev_lock_hex) && db.get_rev_secret(&rev_lock_hex)? != "" {
+                return Err(String::from(
+                    "pay::multi_merchant_unmask() - revocation lock is already known to merchant",
+                ));
+            }
+            if db.get_conditional_payment(&rev_lock_hex).is_ok() {
+                return Err(String::from(
+                    "pay::multi_merchant_unmask() - conditional payment has not been claimed",
+                ));
+            }
+        }
         let pay_token_sender_result =
-            merch_state.verify_revoke_message(&rt_sender.rev_lock, &rt_sender.rev_secret);
+            merch_state.verify_revoke_message(db, &rt_sender.rev_lock, &rt_sender.rev_secret);
         let pay_token_receiver_result =
-            merch_state.verify_revoke_message(&rt_receiver.rev_lock, &rt_receiver.rev_secret);
+            merch_state.verify_revoke_message(db, &rt_receiver.rev_lock, &rt_receiver.rev_secret);
         let new_pay_token_sender = match pay_token_sender_result {
             Ok(n) => n,
             Err(err) => return Err(String::from(err.to_string())),
@@ -582,21 +1008,48 @@ pub mod pay {
             Err(err) => return Err(String::from(err.to_string())),
         };
 
+        update_merchant_state(db, &rt_sender.rev_lock, Some(rt_sender.rev_secret.clone()))?;
         update_merchant_state(
-            &mut merch_state.keys,
-            &rt_sender.rev_lock,
-            Some(rt_sender.rev_secret.clone()),
-        );
-        update_merchant_state(
-            &mut merch_state.keys,
+            db,
             &rt_receiver.rev_lock,
             Some(rt_receiver.rev_secret.clone()),
-        );
+        )?;
 
         Ok(Some((new_pay_token_sender, new_pay_token_receiver)))
     }
 }
 
+///
+/// Version of the customer close message format signed in force_customer_close
+///
+pub const CUST_CLOSE_MESSAGE_VERSION: u8 = 1;
+
+///
+/// cust_close_message - takes as input the closing state and the close token.
+/// Outputs the hash that the customer signs to close the channel unilaterally:
+/// version || channel id || rev_lock || cust balance || merch balance || close token,
+/// followed by pending amount || hash || expiry if a conditional payment is pending.
+///
+pub fn cust_close_message<E: Engine>(close_msg: &Wallet<E>, close_token: &Signature<E>) -> [u8; 32]
+where
+    <E as pairing::Engine>::G1: serde::Serialize,
+{
+    let mut m = vec![CUST_CLOSE_MESSAGE_VERSION];
+    m.extend(serialize_compact::<E>(&vec![
+        close_msg.channelId,
+        close_msg.rev_lock,
+    ]));
+    m.extend_from_slice(&close_msg.bc.to_be_bytes());
+    m.extend_from_slice(&close_msg.bm.to_be_bytes());
+    m.extend(close_token.serialize_compact());
+    if let Some(p) = &close_msg.pending {
+        m.extend_from_slice(&p.amount.to_be_bytes());
+        m.extend_from_slice(&p.hash_lock.hash.0);
+        m.extend_from_slice(&p.hash_lock.expiry.to_be_bytes());
+    }
+    sha3_hash_to_slice(&m)
+}
+
 // for customer => on input a wallet w, it outputs a customer channel closure message
 ///
 /// force_customer_close - takes as input the channel state, merchant's verification
@@ -616,6 +1069,17 @@ where
         ));
     }
 
+    customer_close_message(channel_state, cust_state)
+}
+
+// signs the close message on the current state of the customer with the latest close token
+fn customer_close_message<E: Engine>(
+    channel_state: &ChannelState<E>,
+    cust_state: &CustomerState<E>,
+) -> Result<ChannelcloseC<E>, BoltError>
+where
+    <E as pairing::Engine>::G1: serde::Serialize,
+{
     let wallet = cust_state.get_wallet();
     let close_token = cust_state.get_close_token();
 
@@ -627,26 +1091,26 @@ where
     // let (res, pp) = pk.debug_verify(&cp.pub_params.mpk, &close_wallet, &close_token);
     // assert!(res);
 
-    // hash the closing wallet + close token (merch sig)
-    let mut m1 = serialize_compact::<E>(&close_wallet);
-    let m2 = close_token.serialize_compact();
-    m1.extend_from_slice(&m2);
-    let m = sha3_hash_to_slice(&m2);
-
-    // compute secp256k1 signature on the hash
-    let secp = secp256k1::Secp256k1::new();
-    let msg = secp256k1::Message::from_slice(&m).unwrap();
-    let seckey = cust_state.get_secret_key();
-    let cust_sig = secp.sign(&msg, &seckey);
     let closing_state_wallet = Wallet {
         channelId: wallet.channelId,
         nonce: E::Fr::zero(),
         rev_lock: wallet.rev_lock,
         bm: wallet.bm,
         bc: wallet.bc,
+        pending: wallet.pending.clone(),
     };
 
+    // hash the closing state + close token (merch sig)
+    let m = cust_close_message::<E>(&closing_state_wallet, &close_token);
+
+    // compute secp256k1 signature on the hash
+    let secp = secp256k1::Secp256k1::new();
+    let msg = secp256k1::Message::from_slice(&m).unwrap();
+    let seckey = cust_state.get_secret_key();
+    let cust_sig = secp.sign(&msg, &seckey);
+
     Ok(ChannelcloseC {
+        version: CUST_CLOSE_MESSAGE_VERSION,
         message: closing_state_wallet,
         merch_signature: close_token,
         cust_signature: cust_sig,
@@ -654,61 +1118,336 @@ where
     })
 }
 
+///
+/// mutual_close_message - takes as input the channel id and the final balances.
+/// Outputs the canonical message that both parties sign (with their secp256k1 channel keys)
+/// to close the channel cooperatively. This is for the Zcash/TZE close path only; the Tezos
+/// contract's mutualClose checks Tezos signatures over tezos::mutual_close_message instead.
+///
+pub fn mutual_close_message<E: Engine>(
+    channel_id: &E::Fr,
+    cust_bal: i64,
+    merch_bal: i64,
+) -> [u8; 32] {
+    let close_prefix = util::hash_to_fr::<E>("mutual close".as_bytes().to_vec());
+    let msg = vec![
+        channel_id.clone(),
+        util::convert_int_to_fr::<E>(cust_bal),
+        util::convert_int_to_fr::<E>(merch_bal),
+        close_prefix,
+    ];
+    sha3_hash_to_slice(&serialize_compact::<E>(&msg))
+}
+
+///
+/// customer_mutual_close - takes as input the channel state, channel token and customer state.
+/// Signs the current balances for a cooperative close. The request carries the current state
+/// and its pay token so that the merchant can check the balances before countersigning.
+///
+pub fn customer_mutual_close<E: Engine>(
+    channel_state: &ChannelState<E>,
+    channel_token: &ChannelToken<E>,
+    cust_state: &CustomerState<E>,
+) -> Result<ChannelMutualClose<E>, BoltError>
+where
+    <E as pairing::Engine>::G1: serde::Serialize,
+    <E as pairing::Engine>::G2: serde::Serialize,
+    <E as ff::ScalarEngine>::Fr: serde::Serialize,
+{
+    if cust_state.protocol_status != ProtocolStatus::Established {
+        return Err(BoltError::new(
+            "Cannot close a channel that has not been established!",
+        ));
+    }
+    if channel_token.pk_c != Some(cust_state.pk_c) {
+        return Err(BoltError::new(
+            "customer_mutual_close - channel token does not belong to the customer",
+        ));
+    }
+
+    let wallet = cust_state.get_pay_wallet();
+    let pay_token = match cust_state.get_pay_token() {
+        Some(t) => t,
+        None => {
+            return Err(BoltError::new(
+                "customer_mutual_close - no pay token for the current state",
+            ))
+        }
+    };
+    let cp = channel_state.cp.as_ref().unwrap();
+    let pk = cp.pub_params.pk.get_pub_key();
+    if !pk.verify(&cp.pub_params.mpk, &wallet.as_fr_vec(), &pay_token) {
+        return Err(BoltError::new(
+            "customer_mutual_close - pay token is not valid on the current state",
+        ));
+    }
+
+    let m = mutual_close_message::<E>(&wallet.channelId, wallet.bc, wallet.bm);
+    let secp = secp256k1::Secp256k1::new();
+    let msg = secp256k1::Message::from_slice(&m).unwrap();
+    let cust_sig = secp.sign(&msg, &cust_state.get_secret_key());
+
+    Ok(ChannelMutualClose {
+        message: wallet,
+        nonce: cust_state.nonce,
+        pay_token,
+        cust_signature: cust_sig,
+        merch_signature: None,
+    })
+}
+
+///
+/// merchant_mutual_close - takes as input the channel state, channel token, the customer's
+/// mutual close request and the merchant state. If the request is for the latest unspent state
+/// of the channel, the merchant marks the state as spent (no further payments can be made from it)
+/// and countersigns the balances.
+/// output: the mutual close with both signatures
+///
+pub fn merchant_mutual_close<E: Engine>(
+    db: &mut dyn StateDatabase,
+    channel_token: &ChannelToken<E>,
+    mutual_close: &ChannelMutualClose<E>,
+    merch_state: &MerchantState<E>,
+) -> Result<ChannelMutualClose<E>, BoltError>
+where
+    <E as pairing::Engine>::G1: serde::Serialize,
+    <E as pairing::Engine>::G2: serde::Serialize,
+    <E as ff::ScalarEngine>::Fr: serde::Serialize,
+{
+    let pk_c = match channel_token.pk_c {
+        Some(pk) => pk,
+        None => {
+            return Err(BoltError::new(
+                "merchant_mutual_close - customer public key not set in channel token",
+            ))
+        }
+    };
+    let wallet = &mutual_close.message;
+    let channel_id = match channel_token.compute_channel_id() {
+        Ok(id) => id,
+        Err(e) => return Err(BoltError::new(&e)),
+    };
+    if channel_id != wallet.channelId {
+        return Err(BoltError::new(
+            "merchant_mutual_close - channel id does not match the channel token",
+        ));
+    }
+    if encode_short_bytes_to_fr::<E>(mutual_close.nonce.0) != wallet.nonce {
+        return Err(BoltError::new(
+            "merchant_mutual_close - nonce does not match the state",
+        ));
+    }
+    if !channel_token.cl_pk_m.verify(
+        &channel_token.mpk,
+        &wallet.as_fr_vec(),
+        &mutual_close.pay_token,
+    ) {
+        return Err(BoltError::new(
+            "merchant_mutual_close - pay token is not valid on the state",
+        ));
+    }
+
+    let m = mutual_close_message::<E>(&wallet.channelId, wallet.bc, wallet.bm);
+    let secp = secp256k1::Secp256k1::new();
+    let msg = secp256k1::Message::from_slice(&m).unwrap();
+    if secp
+        .verify(&msg, &mutual_close.cust_signature, &pk_c)
+        .is_err()
+    {
+        return Err(BoltError::new(
+            "merchant_mutual_close - customer signature is not valid",
+        ));
+    }
+
+    // the state must be the latest one: not spent in a payment, not the
+    // pre-unlink state and not locked by a pending conditional payment
+    let nonce_hex = hex::encode(mutual_close.nonce.0);
+    let rlock = format!("{}", wallet.rev_lock.into_repr());
+    let rev_lock_hex = rlock[2..].to_string();
+    if db.check_spent_map(&nonce_hex)
+        || db.is_member_unlink_set(&wallet.nonce.to_string())
+        || db.get_conditional_payment(&rev_lock_hex).is_ok()
+    {
+        return Err(BoltError::new(
+            "merchant_mutual_close - state is not the latest state of the channel",
+        ));
+    }
+    if let Err(e) = db.update_spent_map(&nonce_hex, &rev_lock_hex) {
+        return Err(BoltError::new(&e));
+    }
+
+    let merch_sig = secp.sign(&msg, &merch_state.get_secret_key());
+    let mut signed_close = mutual_close.clone();
+    signed_close.merch_signature = Some(merch_sig);
+    Ok(signed_close)
+}
+
+///
+/// verify_mutual_close - takes as input the channel token and a mutual close.
+/// Checks that the balances are bound to the channel id and signed by both parties.
+///
+pub fn verify_mutual_close<E: Engine>(
+    channel_token: &ChannelToken<E>,
+    mutual_close: &ChannelMutualClose<E>,
+) -> bool
+where
+    <E as pairing::Engine>::G1: serde::Serialize,
+    <E as pairing::Engine>::G2: serde::Serialize,
+    <E as ff::ScalarEngine>::Fr: serde::Serialize,
+{
+    let (pk_c, merch_sig) = match (channel_token.pk_c, mutual_close.merch_signature) {
+        (Some(pk), Some(sig)) => (pk, sig),
+        _ => return false,
+    };
+    let wallet = &mutual_close.message;
+    if channel_token.compute_channel_id() != Ok(wallet.channelId) {
+        return false;
+    }
+    let m = mutual_close_message::<E>(&wallet.channelId, wallet.bc, wallet.bm);
+    let secp = secp256k1::Secp256k1::new();
+    let msg = secp256k1::Message::from_slice(&m).unwrap();
+    secp.verify(&msg, &mutual_close.cust_signature, &pk_c)
+        .is_ok()
+        && secp.verify(&msg, &merch_sig, &channel_token.pk_m).is_ok()
+}
+
+///
+/// verify_payment_transcript - takes as input the channel token and a payment transcript.
+/// Checks that the close token (and the pay token if present) are valid on the new state
+/// and that the released revocation opens its rev_lock.
+///
+pub fn verify_payment_transcript<E: Engine>(
+    channel_token: &ChannelToken<E>,
+    transcript: &PaymentTranscript<E>,
+) -> bool
+where
+    <E as pairing::Engine>::G1: serde::Serialize,
+    <E as pairing::Engine>::G2: serde::Serialize,
+{
+    let wallet = &transcript.wallet;
+    if channel_token.compute_channel_id() != Ok(wallet.channelId) {
+        return false;
+    }
+    let rt_pair = &transcript.rev_lock_pair;
+    if sha3_hash_to_slice(&rt_pair.rev_secret.0.to_vec()) != rt_pair.rev_lock.0 {
+        return false;
+    }
+    if !channel_token.cl_pk_m.verify(
+        &channel_token.mpk,
+        &wallet.as_fr_vec_bar(),
+        &transcript.close_token,
+    ) {
+        return false;
+    }
+    match (&transcript.status, &transcript.pay_token) {
+        (PaymentStatus::Revoked, None) => true,
+        (PaymentStatus::Complete, Some(pay_token)) => {
+            channel_token
+                .cl_pk_m
+                .verify(&channel_token.mpk, &wallet.as_fr_vec(), pay_token)
+        }
+        _ => false,
+    }
+}
+
+///
+/// verify_merchant_stall_evidence - takes as input the channel token and the evidence output by
+/// pay::customer_force_close_on_stall(). Checks that the customer revoked the previous state,
+/// never received the pay token and closes on the new state with the close token of that round.
+///
+pub fn verify_merchant_stall_evidence<E: Engine>(
+    channel_token: &ChannelToken<E>,
+    evidence: &MerchantStallEvidence<E>,
+) -> bool
+where
+    <E as pairing::Engine>::G1: serde::Serialize,
+    <E as pairing::Engine>::G2: serde::Serialize,
+    <E as ff::ScalarEngine>::Fr: serde::Serialize,
+{
+    let transcript = &evidence.transcript;
+    let close_msg = &evidence.cust_close.message;
+    if transcript.status != PaymentStatus::Revoked
+        || !verify_payment_transcript(channel_token, transcript)
+        || !tze_verify_cust_close_message(channel_token, &evidence.cust_close)
+    {
+        return false;
+    }
+    // the close message is on the state of the stalled round
+    close_msg.as_fr_vec_bar() == transcript.wallet.as_fr_vec_bar()
+        && evidence.cust_close.merch_signature == transcript.close_token
+}
+
 fn update_merchant_state(
-    db: &mut HashMap<String, String>,
+    db: &mut dyn StateDatabase,
     rev_lock: &FixedSizeArray32,
     rev_secret: Option<FixedSizeArray32>,
-) {
+) -> Result<bool, String> {
     let rev_lock_str = hex::encode(&rev_lock);
     let rev_secret_str = match rev_secret {
         Some(s) => hex::encode(&s),
         None => String::from(""),
     };
-    db.insert(rev_lock_str, rev_secret_str);
+    db.update_rev_lock_map(&rev_lock_str, &rev_secret_str)
 }
 
 ///
 /// force_merchant_close - takes as input the channel state, channel token, customer close msg/sig,
 /// Returns tokens for merchant close transaction (only if customer close message is found to be a
-/// double spend). If not, then None is returned.
+/// double spend). If not, then None is returned. The rev_lock of the returned pair is sha3(rev_secret)
+/// (big-endian), as expected by both tze_verify_revoke_message functions. The FFI
+/// (ffishim_bls12_merchant_close, ffishim_bls12_tze_verify_revoke_message) uses the same order.
 ///
 pub fn force_merchant_close<E: Engine>(
-    channel_state: &ChannelState<E>,
+    db: &mut dyn StateDatabase,
+    _channel_state: &ChannelState<E>,
     channel_token: &ChannelToken<E>,
     cust_close: &ChannelcloseC<E>,
-    merch_state: &MerchantState<E>,
-) -> Result<RevLockPair, BoltError> {
+    _merch_state: &MerchantState<E>,
+) -> Result<RevLockPair, BoltError>
+where
+    <E as pairing::Engine>::G1: serde::Serialize,
+    <E as pairing::Engine>::G2: serde::Serialize,
+    <E as ff::ScalarEngine>::Fr: serde::Serialize,
+{
     // if channel_state.channel_status != UNLINKED {
     //     return Err(BoltError::new("force_merchant_close - Channel not established! Cannot generate channel closure message."));
     // }
 
-    let cp = channel_state.cp.as_ref().unwrap();
-    let pk = cp.pub_params.pk.get_pub_key();
     let wallet = cust_close.message.clone();
-    let close_wallet = wallet.as_fr_vec_bar();
-    let close_token = cust_close.merch_signature.clone();
-
-    let is_valid = pk.verify(&channel_token.mpk, &close_wallet, &close_token);
-    // check that cust_close.rev_lock == close_wallet.rev_lock
+    let is_valid = tze_verify_cust_close_message(channel_token, cust_close);
 
     if is_valid {
         let rlock = format!("{}", wallet.rev_lock.into_repr());
         let rev_lock_key = rlock[2..].to_string();
         // found the rev_lock, which means close token on old state
-        if merch_state.keys.contains_key(&rev_lock_key) {
-            let rev_secret_str = merch_state.keys.get(&rev_lock_key).unwrap();
-            let rev_secret = hex::decode(&rev_secret_str).unwrap();
+        if db.check_rev_lock_map(&rev_lock_key) {
+            let rev_secret_str = match db.get_rev_secret(&rev_lock_key) {
+                Ok(s) => s,
+                Err(e) => return Err(BoltError::new(&e)),
+            };
+            let rev_secret = hex::decode(&rev_secret_str).unwrap_or(Vec::new());
+            if rev_secret.len() != 32 {
+                return Err(BoltError::new(
+                    "force_merchant_close() - rev_secret for the rev_lock is not known. Valid close!",
+                ));
+            }
             let rev_lock = hex::decode(&rev_lock_key).unwrap();
-            let mut rl_buf_le = [0u8; 32];
+            let mut rl_buf = [0u8; 32];
             let mut rs_buf = [0u8; 32];
 
-            rl_buf_le.copy_from_slice(&rev_lock);
-            rl_buf_le.reverse();
+            rl_buf.copy_from_slice(&rev_lock);
             rs_buf.copy_from_slice(&rev_secret);
-            return Ok(RevLockPair {
-                rev_lock: FixedSizeArray32(rl_buf_le),
+            let rt_pair = RevLockPair {
+                rev_lock: FixedSizeArray32(rl_buf),
                 rev_secret: FixedSizeArray32(rs_buf),
-            });
+            };
+            // check that the rev_secret opens the rev_lock of the close message
+            if !tze_verify_revoke_message(&wallet, &rt_pair) {
+                return Err(BoltError::new(
+                    "force_merchant_close() - rev_secret does not open the rev_lock of the close message",
+                ));
+            }
+            return Ok(rt_pair);
         }
         return Err(BoltError::new(
             "force_merchant_close() - Could not find entry for rev_lock/rev_secret pair. Valid close!",
@@ -719,68 +1458,94 @@ pub fn force_merchant_close<E: Engine>(
     ))
 }
 
-//
-// Used in open-channel WTP for validating that a close_token is a valid signature under <
-//
-// pub fn tze_verify_cust_close_message<E: Engine>(
-//     channel_token: &ChannelToken<E>,
-//     wpk: &secp256k1::PublicKey,
-//     close_msg: &wallet::Wallet<E>,
-//     close_token: &Signature<E>,
-// ) -> bool {
-//     // close_msg => <pkc> || <wpk> || <balance-cust> || <balance-merch> || CLOSE
-//     // close_token = regular CL signature on close_msg
-//     // channel_token => <pk_c, CL_PK_m, pk_m, mpk, comParams>
-
-//     // (1) check that channel token and close msg are consistent (e.g., close_msg.pk_c == H(channel_token.pk_c) &&
-//     let pk_c = channel_token.pk_c.unwrap();
-//     let chan_token_pk_c = util::hash_pubkey_to_fr::<E>(&pk_c);
-//     let chan_token_wpk = util::hash_pubkey_to_fr::<E>(&wpk);
-
-//     let pkc_thesame = (close_msg.channelId == chan_token_pk_c);
-//     // (2) check that wpk matches what's in the close msg
-//     let wpk_thesame = (close_msg.wpk == chan_token_wpk);
-//     return pkc_thesame
-//         && wpk_thesame
-//         && channel_token.cl_pk_m.verify(
-//             &channel_token.mpk,
-//             &close_msg.as_fr_vec(),
-//             &close_token,
-//         );
-// }
-
-//
-// Used in merch-close WTP for validating that revoke_token is a valid signature under <wpk> and the <revoked || wpk> message
-//
-// pub fn tze_verify_revoke_message(
-//     wpk: &secp256k1::PublicKey,
-//     revoke_token: &secp256k1::Signature,
-// ) -> bool {
-//     let secp = secp256k1::Secp256k1::verification_only();
-//     let revoke_msg = RevokedMessage::new(String::from("revoked"), wpk.clone());
-//     let msg = secp256k1::Message::from_slice(&revoke_msg.hash_to_slice()).unwrap();
-//     // verify that the revocation token is valid with respect to revoked || wpk
-//     return secp.verify(&msg, &revoke_token, &wpk).is_ok();
-// }
-
-//
-// Used in merch-close WTP for validating that merch_sig is a valid signature under <merch_pk> on <dest_addr || revoke-token> message
-//
-// pub fn tze_verify_merch_close_message<E: Engine>(
-//     channel_token: &ChannelToken<E>,
-//     merch_close: &ChannelcloseM,
-// ) -> bool {
-//     let secp = secp256k1::Secp256k1::verification_only();
-//     let mut msg = Vec::new();
-//     msg.extend(merch_close.address.as_bytes());
-//     if !merch_close.revoke.is_none() {
-//         // serialize signature in DER format
-//         let r = merch_close.revoke.unwrap().serialize_der().to_vec();
-//         msg.extend(r);
-//     }
-//     let msg2 = secp256k1::Message::from_slice(&hash_to_slice(&msg)).unwrap();
-//     // verify that merch sig is valid with respect to dest_address
-//     return secp
-//         .verify(&msg2, &merch_close.signature, &channel_token.pk_m)
-//         .is_ok();
-// }
+///
+/// tze_verify_cust_close_message - takes as input the channel token and a customer close message.
+/// Checks that the close message is bound to the channel id of the token, that the close token is
+/// a valid signature on the closing state and that the customer signed the closing state together
+/// with the close token.
+///
+pub fn tze_verify_cust_close_message<E: Engine>(
+    channel_token: &ChannelToken<E>,
+    cust_close: &ChannelcloseC<E>,
+) -> bool
+where
+    <E as pairing::Engine>::G1: serde::Serialize,
+    <E as pairing::Engine>::G2: serde::Serialize,
+    <E as ff::ScalarEngine>::Fr: serde::Serialize,
+{
+    let pk_c = match channel_token.pk_c {
+        Some(pk) => pk,
+        None => return false,
+    };
+    if cust_close.version != CUST_CLOSE_MESSAGE_VERSION {
+        return false;
+    }
+    // (1) check that the close message is for this channel
+    if channel_token.compute_channel_id() != Ok(cust_close.message.channelId) {
+        return false;
+    }
+    // (2) check that the close token is valid on the closing state
+    let close_wallet = cust_close.message.as_fr_vec_bar();
+    if !channel_token.cl_pk_m.verify(
+        &channel_token.mpk,
+        &close_wallet,
+        &cust_close.merch_signature,
+    ) {
+        return false;
+    }
+    // (3) check the customer signature on the closing state and close token
+    let m = cust_close_message::<E>(&cust_close.message, &cust_close.merch_signature);
+    let secp = secp256k1::Secp256k1::verification_only();
+    let msg = secp256k1::Message::from_slice(&m).unwrap();
+    secp.verify(&msg, &cust_close.cust_signature, &pk_c).is_ok()
+}
+
+///
+/// settle_cust_close - takes as input the channel token, a customer close message, the preimage
+/// of the hash lock (if revealed) and the current time. Verifies the close message and outputs
+/// the balances (cust, merch) to pay out. If a conditional payment is pending, the claim branch
+/// applies if the preimage is given and the expire branch once the hash lock has expired.
+///
+pub fn settle_cust_close<E: Engine>(
+    channel_token: &ChannelToken<E>,
+    cust_close: &ChannelcloseC<E>,
+    preimage: Option<&[u8; 32]>,
+    now: i64,
+) -> Result<(i64, i64), String>
+where
+    <E as pairing::Engine>::G1: serde::Serialize,
+    <E as pairing::Engine>::G2: serde::Serialize,
+    <E as ff::ScalarEngine>::Fr: serde::Serialize,
+{
+    if !tze_verify_cust_close_message(channel_token, cust_close) {
+        return Err(String::from("invalid customer close message"));
+    }
+    cust_close.message.close_balances(preimage, now)
+}
+
+///
+/// tze_verify_revoke_message - takes as input the closing state and the rev_lock/rev_secret pair
+/// output by force_merchant_close. Checks that the rev_secret opens the rev_lock of the closing
+/// state. As in tze_utils::tze_verify_revoke_message, the rev_lock is sha3(rev_secret) as is,
+/// i.e. the big-endian encoding of the field element (see util::rev_lock_to_fr).
+///
+pub fn tze_verify_revoke_message<E: Engine>(close_msg: &Wallet<E>, rt_pair: &RevLockPair) -> bool {
+    let rev_lock = rt_pair.rev_lock.0;
+    let rlock = format!("{}", close_msg.rev_lock.into_repr());
+    sha3_hash_to_slice(&rt_pair.rev_secret.0.to_vec()) == rev_lock
+        && rlock[2..] == hex::encode(&rev_lock)
+}
+
+///
+/// tze_verify_merch_close_message - takes as input the channel token and a merchant close message.
+/// Checks that the merchant signed the destination address and revoke token under pk_m.
+///
+pub fn tze_verify_merch_close_message<E: Engine>(
+    channel_token: &ChannelToken<E>,
+    merch_close: &ChannelcloseM,
+) -> bool {
+    let secp = secp256k1::Secp256k1::verification_only();
+    let msg = secp256k1::Message::from_slice(&merch_close.hash_to_slice()).unwrap();
+    secp.verify(&msg, &merch_close.signature, &channel_token.pk_m)
+        .is_ok()
+}